@@ -0,0 +1,78 @@
+use std::io::{Cursor, Read};
+
+use symphonia::core::io::MediaSource;
+
+use crate::audio_file::{open_file, OpenPolicy};
+
+/// Tracks inside zip archives are named `path/to/album.zip!path/in/archive.flac`
+const ARCHIVE_SEPARATOR: &str = ".zip!";
+
+/// Splits an archive track name into the archive's path and the entry's name
+pub fn split_archive_path(filename: &str) -> Option<(&str, &str)> {
+    let index = filename.find(ARCHIVE_SEPARATOR)?;
+    let archive = &filename[..index + ARCHIVE_SEPARATOR.len() - 1];
+    let entry = &filename[index + ARCHIVE_SEPARATOR.len()..];
+    if entry.is_empty() {
+        return None;
+    }
+    Some((archive, entry))
+}
+
+/// The file on disk that holds `filename`: the archive for a track inside one, otherwise the
+/// track itself
+pub fn file_on_disk(filename: &str) -> &str {
+    match split_archive_path(filename) {
+        Some((archive, _)) => archive,
+        None => filename,
+    }
+}
+
+/// Opens `filename` for symphonia. A compressed entry can't be seeked, so a track inside an
+/// archive is decompressed into memory up front and held there for as long as it's open: about
+/// 10 MB a minute for CD-quality wav, a third to half that for flac.
+pub fn open_media_source(
+    filename: &str,
+    policy: OpenPolicy,
+) -> Result<Box<dyn MediaSource>, Box<dyn std::error::Error>> {
+    match split_archive_path(filename) {
+        Some((archive, entry)) => {
+            let mut zip = zip::ZipArchive::new(open_file(archive, policy)?)?;
+            let mut entry = zip.by_name(entry)?;
+            let mut bytes = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut bytes)?;
+            Ok(Box::new(Cursor::new(bytes)))
+        }
+        None => Ok(Box::new(open_file(filename, policy)?)),
+    }
+}
+
+/// Extension of the track, to help probing when it's read from memory
+pub fn extension(filename: &str) -> Option<&str> {
+    let name = match split_archive_path(filename) {
+        Some((_, entry)) => entry,
+        None => filename,
+    };
+    std::path::Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_on_disk, split_archive_path};
+
+    #[test]
+    fn splits_archive_paths() {
+        assert_eq!(
+            split_archive_path("/music/album.zip!disc 1/track.flac"),
+            Some(("/music/album.zip", "disc 1/track.flac"))
+        );
+        assert_eq!(split_archive_path("/music/album.zip!"), None);
+        assert_eq!(split_archive_path("/music/track.flac"), None);
+        assert_eq!(
+            file_on_disk("/music/album.zip!track.flac"),
+            "/music/album.zip"
+        );
+        assert_eq!(file_on_disk("/music/track.flac"), "/music/track.flac");
+    }
+}
@@ -1,21 +1,110 @@
-use crate::audio_source::{AudioBuffer, AudioMetadata, AudioSource};
+use crate::archive;
+use crate::audio_source::{
+    AudioBuffer, AudioMetadata, AudioSource, CoverArt, TechInfo, DEFAULT_SAMPLE_RATE,
+};
+use crate::replaygain;
+use crate::resample::Resampler;
 use std::borrow::BorrowMut;
+use std::collections::VecDeque;
 use std::fs::File;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+use std::{io, thread};
 
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
-use symphonia::core::audio::SampleBuffer;
-use symphonia::core::codecs::{Decoder, DecoderOptions};
-use symphonia::core::errors::Error;
+use symphonia::core::audio::{AudioBufferRef, SampleBuffer, SignalSpec};
+use symphonia::core::codecs::{CodecParameters, Decoder, DecoderOptions};
+use symphonia::core::errors::{Error, SeekErrorKind};
 use symphonia::core::formats::{FormatOptions, FormatReader};
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::{MetadataBuilder, MetadataOptions, StandardTagKey};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::{MetadataBuilder, MetadataOptions, MetadataRevision, StandardTagKey};
 use symphonia::core::probe::Hint;
 use symphonia_metadata::id3v2::read_id3v2;
 
+/// How long a single attempt to open a file may take, and how many more attempts to make after
+/// the first fails. Files on a sleeping network mount can take a long time to open, so this can
+/// add up to seconds; opens never happen on the render thread.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OpenPolicy {
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+impl Default for OpenPolicy {
+    fn default() -> Self {
+        OpenPolicy {
+            timeout: Duration::from_millis(5000),
+            retries: 2,
+        }
+    }
+}
+
+/// Opens `path`, giving up after the policy's timeout and retries. An open that times out is
+/// left to finish on its own thread, since a blocked open can't be cancelled.
+pub fn open_file<P: AsRef<Path>>(path: P, policy: OpenPolicy) -> io::Result<File> {
+    let path = path.as_ref();
+    let OpenPolicy { timeout, retries } = policy;
+
+    let mut attempt = 0;
+    loop {
+        let (tx, rx) = mpsc::channel();
+        let open_path = path.to_path_buf();
+        thread::spawn(move || {
+            let _ = tx.send(File::open(open_path));
+        });
+
+        let err = match rx.recv_timeout(timeout) {
+            Ok(Ok(file)) => return Ok(file),
+            Ok(Err(err)) => err,
+            Err(_) => io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("timed out after {:?}", timeout),
+            ),
+        };
+        if attempt >= retries {
+            return Err(err);
+        }
+        attempt += 1;
+        warn!(
+            "error opening {}, retrying ({} of {}): {}",
+            path.display(),
+            attempt,
+            retries,
+            err
+        );
+    }
+}
+
+/// Seconds of decoded audio kept behind the playhead so short seeks back don't have to decode
+/// again
+const LOOK_BEHIND_SECS: u32 = 2;
+
+/// Seconds at the start of a track that are decoded ahead of time, and kept when it's released
+pub const PREFETCH_SECS: f64 = 3.0;
+
+fn default_sample_rate() -> u32 {
+    DEFAULT_SAMPLE_RATE
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct AudioFileSource {
     pub filename: String,
 
+    /// Identifies the track however the playlist changes around it; 0 until the player gives
+    /// it one
+    #[serde(default)]
+    pub id: u64,
+
+    /// Where playback starts, in seconds from the start of the file
+    #[serde(default)]
+    pub start_offset: Option<f64>,
+
+    /// Where playback ends, in seconds from the start of the file
+    #[serde(default)]
+    pub end_offset: Option<f64>,
+
     #[serde(skip)]
     format: Option<Box<dyn FormatReader>>,
 
@@ -25,40 +114,429 @@ pub struct AudioFileSource {
     #[serde(skip)]
     track_id: Option<u32>,
 
+    /// Sorted by offset, without overlaps
     #[serde(skip)]
-    decoded_buffers: Vec<AudioBuffer>,
+    decoded_buffers: VecDeque<AudioBuffer>,
 
+    /// Position of the next decoded frame, in output frames
     #[serde(skip)]
     seek_pos: u32,
 
+    /// Rate decoded audio is resampled to; offsets are in frames at this rate
+    #[serde(skip, default = "default_sample_rate")]
+    output_sample_rate: u32,
+
+    #[serde(skip)]
+    resampler: Option<Resampler>,
+
+    /// Spec of the most recently decoded packet
+    #[serde(skip)]
+    spec: Option<SignalSpec>,
+
     #[serde(skip)]
     metadata: Option<AudioMetadata>,
+
+    /// Decoded packets are converted to f32 in here, kept so it isn't allocated for each one
+    #[serde(skip)]
+    sample_buf: Option<SampleBuffer<f32>>,
+
+    /// Why the last read stopped, when it was because the file couldn't be opened or read rather
+    /// than because the track ended
+    #[serde(skip)]
+    read_error: Option<String>,
+
+    #[serde(skip)]
+    open_policy: OpenPolicy,
 }
 
+/// Format reader, decoder and track id for a freshly opened file
+type OpenedTrack = (Box<dyn FormatReader>, Box<dyn Decoder>, u32);
+
 impl AudioFileSource {
     pub fn new(filename: String) -> AudioFileSource {
         AudioFileSource {
             filename,
+            id: 0,
+            start_offset: None,
+            end_offset: None,
             format: None,
             decoder: None,
             track_id: None,
-            decoded_buffers: Vec::new(),
+            decoded_buffers: VecDeque::new(),
             seek_pos: 0,
+            output_sample_rate: DEFAULT_SAMPLE_RATE,
+            resampler: None,
+            spec: None,
             metadata: None,
+            sample_buf: None,
+            read_error: None,
+            open_policy: OpenPolicy::default(),
+        }
+    }
+
+    /// Metadata, if `get_metadata` has already been called
+    pub fn cached_metadata(&self) -> Option<&AudioMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Sets the rate audio is resampled to for playback. Changing it drops anything already
+    /// decoded, since offsets are in frames at this rate.
+    pub fn set_output_sample_rate(&mut self, sample_rate: u32) {
+        if sample_rate == self.output_sample_rate {
+            return;
+        }
+        self.output_sample_rate = sample_rate;
+        self.reset();
+    }
+
+    /// Sets how patiently the file is opened
+    pub fn set_open_policy(&mut self, policy: OpenPolicy) {
+        self.open_policy = policy;
+    }
+
+    /// Drops the decoder and everything decoded, so the next read starts from scratch: a way
+    /// out when a decoder gets stuck
+    pub fn reset(&mut self) {
+        self.decoded_buffers.clear();
+        self.format = None;
+        self.decoder = None;
+        self.track_id = None;
+        self.resampler = None;
+        self.spec = None;
+        self.seek_pos = 0;
+        self.read_error = None;
+    }
+
+    /// Sets in and out points, in seconds from the start of the file. `None` plays from the start
+    /// or to the end.
+    pub fn set_trim(&mut self, start: Option<f64>, end: Option<f64>) -> Result<(), String> {
+        for secs in [start, end].into_iter().flatten() {
+            if !secs.is_finite() || secs < 0.0 {
+                return Err(String::from("trim points must be non-negative numbers"));
+            }
         }
+        if let (Some(start), Some(end)) = (start, end) {
+            if end <= start {
+                return Err(String::from("end must be after start"));
+            }
+        }
+        self.start_offset = start;
+        self.end_offset = end;
+        // the duration in the metadata is the trimmed length
+        self.metadata = None;
+        Ok(())
     }
 
-    fn make_decoder(&self) -> (Box<dyn FormatReader>, Box<dyn Decoder>, u32) {
+    /// First frame to play, at the output rate
+    pub fn trim_start_frame(&self) -> u32 {
+        self.start_offset.map_or(0, |secs| {
+            (secs * self.output_sample_rate as f64).round() as u32
+        })
+    }
+
+    /// Frame playback stops at, at the output rate, if the track is trimmed
+    pub fn trim_end_frame(&self) -> Option<u32> {
+        self.end_offset
+            .map(|secs| (secs * self.output_sample_rate as f64).round() as u32)
+    }
+
+    /// Frame playback ends at, at the output rate, if it's known without reading the file
+    pub fn end_frame(&self) -> Option<u32> {
+        self.trim_end_frame().or_else(|| {
+            self.metadata
+                .as_ref()
+                .filter(|metadata| metadata.dur > 0.0)
+                .map(|metadata| {
+                    self.trim_start_frame()
+                        + (metadata.dur * self.output_sample_rate as f64).round() as u32
+                })
+        })
+    }
+
+    /// Whether playback stopped early because the file couldn't be read
+    pub fn read_failed(&self) -> bool {
+        self.read_error.is_some()
+    }
+
+    /// Why playback stopped early, if it did
+    pub fn read_error(&self) -> Option<&str> {
+        self.read_error.as_deref()
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        match File::open(&self.filename) {
+            Ok(file) => probe_seekable(Box::new(file)),
+            Err(_) => false,
+        }
+    }
+
+    /// Drops decoded buffers that ended well before `offset`, keeping a short look-behind window.
+    /// Called as playback advances so long tracks don't hold on to audio that's been played.
+    pub fn trim_behind(&mut self, offset: u32) {
+        let keep_from = offset.saturating_sub(LOOK_BEHIND_SECS * self.output_sample_rate);
+        self.decoded_buffers
+            .retain(|buffer| buffer.offset + buffer.length > keep_from);
+    }
+
+    /// Whether the file is open with a decoder ready
+    pub fn is_open(&self) -> bool {
+        self.decoder.is_some()
+    }
+
+    /// Whether `offset` can be read without opening the file: it's decoded, the file is already
+    /// open, or it's past the out point. The render thread waits on a track until it is, since
+    /// opening can take seconds.
+    pub fn is_ready_at(&self, offset: u32) -> bool {
+        self.is_open()
+            || self.find_decoded(offset).is_some()
+            || self.trim_end_frame().is_some_and(|end| offset >= end)
+    }
+
+    /// Whether the start of playback is already decoded, so starting the track won't wait on
+    /// the disk
+    pub fn is_prefetched(&self) -> bool {
+        self.find_decoded(self.trim_start_frame()).is_some()
+    }
+
+    /// A copy of the track with nothing opened or decoded yet, to prefetch away from the
+    /// playlist
+    pub fn unopened_copy(&self) -> AudioFileSource {
+        let mut src = AudioFileSource::new(self.filename.clone());
+        src.id = self.id;
+        src.start_offset = self.start_offset;
+        src.end_offset = self.end_offset;
+        src.output_sample_rate = self.output_sample_rate;
+        src.open_policy = self.open_policy;
+        src
+    }
+
+    /// Opens the file and decodes the first `secs` seconds of playback
+    pub fn prefetch(&mut self, secs: f64) {
+        let start = self.trim_start_frame();
+        let end = start + (secs * self.output_sample_rate as f64) as u32;
+        let mut offset = start;
+        while offset < end {
+            match self.get_buffer(offset) {
+                Some(buffer) if buffer.length > 0 => offset = buffer.offset + buffer.length,
+                _ => break,
+            }
+        }
+    }
+
+    /// Closes the file and drops decoded audio past the first `PREFETCH_SECS`, for a track that
+    /// isn't about to play. The file is opened again on the next read that isn't cached.
+    pub fn release(&mut self) {
+        self.format = None;
+        self.decoder = None;
+        self.track_id = None;
+        self.resampler = None;
+        self.sample_buf = None;
+        self.seek_pos = 0;
+        let keep_until =
+            self.trim_start_frame() + (PREFETCH_SECS * self.output_sample_rate as f64) as u32;
+        self.decoded_buffers
+            .retain(|buffer| buffer.offset < keep_until);
+    }
+
+    /// Takes the opened decoder, decoded audio and metadata from a copy of this track opened
+    /// away from the playlist, unless this one has been opened in the meantime. Audio this one
+    /// already decoded is kept. If the copy couldn't be opened, its error is kept instead, so
+    /// playback skips or pauses rather than waiting on the track. Returns whether the decoder
+    /// was taken.
+    pub fn adopt_prefetched(&mut self, prefetched: AudioFileSource) -> bool {
+        if !self.is_copied_by(&prefetched) {
+            return false;
+        }
+        if self.metadata.is_none() {
+            self.metadata = prefetched.metadata;
+        }
+        if self.decoder.is_some() {
+            return false;
+        }
+        if prefetched.decoder.is_none() {
+            if prefetched.read_error.is_some() {
+                self.read_error = prefetched.read_error;
+            }
+            return false;
+        }
+        self.format = prefetched.format;
+        self.decoder = prefetched.decoder;
+        self.track_id = prefetched.track_id;
+        self.seek_pos = prefetched.seek_pos;
+        self.resampler = prefetched.resampler;
+        self.spec = prefetched.spec;
+        if self.decoded_buffers.is_empty() {
+            self.decoded_buffers = prefetched.decoded_buffers;
+        }
+        true
+    }
+
+    /// Takes the tags read from a copy of this track, if they haven't been read here since
+    pub fn adopt_tags(&mut self, tagged: AudioFileSource) {
+        if self.metadata.is_none() && self.is_copied_by(&tagged) {
+            self.metadata = tagged.metadata;
+        }
+    }
+
+    /// Whether `copy` is an `unopened_copy` of this track as it is now
+    fn is_copied_by(&self, copy: &AudioFileSource) -> bool {
+        copy.id == self.id
+            && copy.filename == self.filename
+            && copy.start_offset == self.start_offset
+            && copy.end_offset == self.end_offset
+            && copy.output_sample_rate == self.output_sample_rate
+    }
+
+    #[cfg(test)]
+    pub fn decoded_buffers(&self) -> &VecDeque<AudioBuffer> {
+        &self.decoded_buffers
+    }
+
+    /// Index of the decoded buffer containing `offset`
+    fn find_decoded(&self, offset: u32) -> Option<usize> {
+        let index = self
+            .decoded_buffers
+            .partition_point(|buffer| buffer.offset + buffer.length <= offset);
+        match self.decoded_buffers.get(index) {
+            Some(buffer) if buffer.offset <= offset => Some(index),
+            _ => None,
+        }
+    }
+
+    /// Opens the file and decodes its first packet, to find files that won't play before they're
+    /// reached in the queue
+    pub fn check_decodable(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let file = archive::open_media_source(&self.filename, self.open_policy)?;
+        let mss = MediaSourceStream::new(file, Default::default());
+        let mut probed = symphonia::default::get_probe().format(
+            &Hint::new(),
+            mss,
+            &Default::default(),
+            &Default::default(),
+        )?;
+        let track = probed.format.default_track().ok_or("no default track")?;
+        let track_id = track.id;
+        let mut decoder =
+            symphonia::default::get_codecs().make(&track.codec_params, &Default::default())?;
+
+        loop {
+            let packet = probed.format.next_packet()?;
+            if packet.track_id() == track_id {
+                decoder.decode(&packet)?;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Resamples a newly decoded buffer to the output rate and caches it. Concatenated streams
+    /// can change sample rate or channel count partway through; the resampler follows the rate,
+    /// and each buffer carries its own channel count, so playback keeps going and the change is
+    /// only logged.
+    fn push_decoded(&mut self, spec: SignalSpec, signal: AudioBuffer) {
+        if let Some(previous) = self.spec {
+            if previous != spec {
+                warn!(
+                    "{} changed from {} channels at {} Hz to {} channels at {} Hz at frame {}",
+                    self.filename,
+                    previous.channels.count(),
+                    previous.rate,
+                    spec.channels.count(),
+                    spec.rate,
+                    signal.offset
+                );
+            }
+        }
+        self.spec = Some(spec);
+
+        let signal = if spec.rate == self.output_sample_rate {
+            self.resampler = None;
+            signal
+        } else {
+            if !self
+                .resampler
+                .as_ref()
+                .is_some_and(|r| r.converts(spec.rate, self.output_sample_rate))
+            {
+                self.resampler = Some(Resampler::new(spec.rate, self.output_sample_rate));
+            }
+            let samples = self.resampler.as_mut().unwrap().process(&signal.samples);
+            AudioBuffer {
+                length: samples.first().map_or(0, |channel| channel.len()) as u32,
+                samples,
+                sample_rate: self.output_sample_rate as f64,
+                offset: signal.offset,
+            }
+        };
+
+        self.seek_pos += signal.length;
+
+        // keep the buffers sorted, replacing any that the new one overlaps (after a seek back)
+        let start = signal.offset;
+        let end = signal.offset + signal.length;
+        let index = self
+            .decoded_buffers
+            .partition_point(|buffer| buffer.offset + buffer.length <= start);
+        while self
+            .decoded_buffers
+            .get(index)
+            .is_some_and(|buffer| buffer.offset < end)
+        {
+            self.decoded_buffers.remove(index);
+        }
+        self.decoded_buffers.insert(index, signal);
+
+        // only keep ~5 seconds in memory
+        // 2 * 5 * 44100 / 2000  ~ 220
+        // evict from the end farthest from what was just decoded, which is the oldest buffer
+        // unless playback seeked backwards
+        while self.decoded_buffers.len() > 220 {
+            // println!("evicting buffer");
+            if index < self.decoded_buffers.len() / 2 {
+                self.decoded_buffers.pop_back();
+            } else {
+                self.decoded_buffers.pop_front();
+            }
+        }
+    }
+
+    /// How much of `dur` seconds plays once the trims are applied
+    fn trimmed_duration(&self, dur: f64) -> f64 {
+        let trimmed_end = self.end_offset.map_or(dur, |end| end.min(dur));
+        (trimmed_end - self.start_offset.unwrap_or(0.0)).max(0.0)
+    }
+
+    /// Opens the file for decoding if it isn't already. Returns whether it's open.
+    pub fn open(&mut self) -> bool {
+        if self.format.is_none() || self.decoder.is_none() || self.track_id.is_none() {
+            let (format, decoder, track_id) = match self.make_decoder() {
+                Ok(decoder) => decoder,
+                Err(err) => {
+                    error!("error opening {}: {}", self.filename, err);
+                    self.read_error = Some(format!("error opening: {}", err));
+                    return false;
+                }
+            };
+            self.format = Some(format);
+            self.decoder = Some(decoder);
+            self.track_id = Some(track_id);
+            self.seek_pos = 0;
+        }
+        true
+    }
+
+    fn make_decoder(&self) -> Result<OpenedTrack, Box<dyn std::error::Error>> {
         // Create a media source. Note that the MediaSource trait is automatically implemented for File,
         // among other types.
-        let file = Box::new(File::open(&self.filename).unwrap());
+        let file = archive::open_media_source(&self.filename, self.open_policy)?;
 
         // Create the media source stream using the boxed media source from above.
         let mss = MediaSourceStream::new(file, Default::default());
 
-        // Create a hint to help the format registry guess what format reader is appropriate. In this
-        // example we'll leave it empty.
-        let hint = Hint::new();
+        // Create a hint to help the format registry guess what format reader is appropriate.
+        let mut hint = Hint::new();
+        if let Some(extension) = archive::extension(&self.filename) {
+            hint.with_extension(extension);
+        }
 
         // Use the default options when reading and decoding.
         let format_opts: FormatOptions = Default::default();
@@ -66,84 +544,218 @@ impl AudioFileSource {
         let decoder_opts: DecoderOptions = Default::default();
 
         // Probe the media source stream for a format.
-        let probed = symphonia::default::get_probe()
-            .format(&hint, mss, &format_opts, &metadata_opts)
-            .unwrap();
+        let probed =
+            symphonia::default::get_probe().format(&hint, mss, &format_opts, &metadata_opts)?;
 
         // Get the format reader yielded by the probe operation.
         let format = probed.format;
 
         // Get the default track.
-        let track = format.default_track().unwrap();
+        let track = format.default_track().ok_or("no default track")?;
 
         // Create a decoder for the track.
-        let decoder = symphonia::default::get_codecs()
-            .make(&track.codec_params, &decoder_opts)
-            .unwrap();
+        let decoder = symphonia::default::get_codecs().make(&track.codec_params, &decoder_opts)?;
 
         let track_id = track.id;
 
-        (format, decoder, track_id)
+        Ok((format, decoder, track_id))
+    }
+}
+
+/// Whether the track in `source` supports seeking: the source has to be seekable and the format
+/// has to be able to find a timestamp. Only the container is probed; nothing is decoded.
+pub fn probe_seekable(source: Box<dyn MediaSource>) -> bool {
+    if !source.is_seekable() {
+        return false;
+    }
+
+    let mss = MediaSourceStream::new(source, Default::default());
+    let mut format = match symphonia::default::get_probe().format(
+        &Hint::new(),
+        mss,
+        &Default::default(),
+        &Default::default(),
+    ) {
+        Ok(probed) => probed.format,
+        Err(_) => return false,
+    };
+    let track_id = match format.default_track() {
+        Some(track) => track.id,
+        None => return false,
+    };
+
+    format
+        .seek(
+            symphonia::core::formats::SeekMode::Coarse,
+            symphonia::core::formats::SeekTo::TimeStamp { ts: 0, track_id },
+        )
+        .is_ok()
+}
+
+/// Copies a decoded packet into planar f32 samples starting at `offset`, converting through
+/// `sample_buf`, which is only reallocated when it's too small for the packet
+fn to_audio_buffer(
+    audio_buf: AudioBufferRef,
+    offset: u32,
+    sample_buf: &mut Option<SampleBuffer<f32>>,
+) -> AudioBuffer {
+    // Get the audio buffer specification.
+    let spec = *audio_buf.spec();
+
+    let channel_count = spec.channels.count();
+
+    // Get the capacity of the decoded buffer. Note: This is capacity, not length! Sizing for it
+    // rather than the packet means the decoder's later packets fit too.
+    let duration = audio_buf.capacity() as u64;
+
+    if sample_buf
+        .as_ref()
+        .is_none_or(|buf| buf.capacity() < duration as usize * channel_count)
+    {
+        *sample_buf = Some(SampleBuffer::<f32>::new(duration, spec));
+    }
+    let sample_buf = sample_buf.as_mut().unwrap();
+
+    // Copy the decoded audio buffer into the sample buffer in a planar format.
+    sample_buf.copy_planar_ref(audio_buf);
+
+    let samples_per_channel = sample_buf.samples().len() / channel_count;
+
+    let samples = (0..channel_count)
+        .map(|channel| {
+            sample_buf.samples()[channel * samples_per_channel..(channel + 1) * samples_per_channel]
+                .to_vec()
+        })
+        .collect();
+
+    AudioBuffer {
+        samples,
+        sample_rate: spec.rate as f64,
+        length: samples_per_channel as u32,
+        offset,
+    }
+}
+
+/// Length of the track in seconds, if the container says how many frames it has
+fn codec_duration(codec_params: &CodecParameters) -> Option<f64> {
+    let time = codec_params.time_base?.calc_time(codec_params.n_frames?);
+    Some(time.seconds as f64 + time.frac)
+}
+
+fn track_codec_params(format: &dyn FormatReader, track_id: u32) -> Option<CodecParameters> {
+    format
+        .tracks()
+        .iter()
+        .find(|track| track.id == track_id)
+        .map(|track| track.codec_params.clone())
+}
+
+/// Copies the tags pjp uses from `revision` into `metadata`. Returns whether it had a title,
+/// artist or album.
+fn apply_tags(metadata: &mut AudioMetadata, revision: &MetadataRevision) -> bool {
+    let mut found = false;
+    let mut album_gain = (None, None);
+    for tag in revision.tags() {
+        match tag.std_key {
+            Some(StandardTagKey::TrackTitle) => {
+                metadata.title = tag.value.to_string();
+                found = true;
+            }
+            Some(StandardTagKey::Artist) => {
+                metadata.artist = tag.value.to_string();
+                found = true;
+            }
+            Some(StandardTagKey::Album) => {
+                metadata.album = tag.value.to_string();
+                found = true;
+            }
+            Some(StandardTagKey::ReplayGainTrackGain) => {
+                metadata.gain_db = replaygain::parse_gain_db(&tag.value.to_string());
+            }
+            Some(StandardTagKey::ReplayGainTrackPeak) => {
+                metadata.peak = replaygain::parse_peak(&tag.value.to_string());
+            }
+            Some(StandardTagKey::ReplayGainAlbumGain) => {
+                album_gain.0 = replaygain::parse_gain_db(&tag.value.to_string());
+            }
+            Some(StandardTagKey::ReplayGainAlbumPeak) => {
+                album_gain.1 = replaygain::parse_peak(&tag.value.to_string());
+            }
+            _ => {}
+        }
+    }
+    if metadata.gain_db.is_none() {
+        (metadata.gain_db, metadata.peak) = album_gain;
+    }
+    if let Some(visual) = revision.visuals().first() {
+        metadata.cover = Some(Arc::new(CoverArt {
+            media_type: visual.media_type.clone(),
+            data: visual.data.to_vec(),
+        }));
+    }
+    found
+}
+
+fn tech_info(filename: &str, codec_params: &CodecParameters, dur: f64) -> TechInfo {
+    let file_size = std::fs::metadata(filename).ok().map(|m| m.len());
+    TechInfo {
+        file_size,
+        bitrate: file_size
+            .filter(|_| dur > 0.0)
+            .map(|size| (size as f64 * 8.0 / dur).round() as u32),
+        codec: symphonia::default::get_codecs()
+            .get_codec(codec_params.codec)
+            .map_or_else(|| codec_params.codec.to_string(), |d| d.short_name.into()),
+        sample_rate: codec_params.sample_rate,
+        bit_depth: codec_params.bits_per_sample,
+        channels: codec_params.channels.map(|channels| channels.count()),
     }
 }
 
 impl AudioSource for AudioFileSource {
     fn get_buffer(&mut self, offset: u32) -> Option<&AudioBuffer> {
+        self.read_error = None;
+        if self.trim_end_frame().is_some_and(|end| offset >= end) {
+            return None;
+        }
+
         // FIXME: factor out this duplicated code
         // find an existing decoded buffer
-        // FIXME: O(n), fix
-        for i in 0..self.decoded_buffers.len() {
-            let buffer = &self.decoded_buffers[i];
-            if buffer.offset <= offset && buffer.offset + buffer.length > offset {
-                // println!("found existing buffer at offset {}", offset);
-                return Some(&self.decoded_buffers[i]);
-            }
+        if let Some(index) = self.find_decoded(offset) {
+            return Some(&self.decoded_buffers[index]);
         }
 
-        if self.format.is_none() || self.decoder.is_none() || self.track_id.is_none() {
-            let (format, decoder, track_id) = self.make_decoder();
-            self.format = Some(format);
-            self.decoder = Some(decoder);
-            self.track_id = Some(track_id);
-            self.seek_pos = 0;
+        if !self.open() {
+            return None;
         }
 
-        let decoder = self.decoder.as_mut().unwrap();
-        let format = self.format.as_mut().unwrap();
         let track_id = self.track_id.unwrap();
 
         // only seek if we're decently far away from the seek pos?
-        if offset != self.seek_pos {
-            self.seek_pos = match format.seek(
-                symphonia::core::formats::SeekMode::Accurate,
-                symphonia::core::formats::SeekTo::TimeStamp {
-                    ts: offset as u64,
-                    track_id,
-                },
-            ) {
-                Ok(seek_to) => seek_to.actual_ts as u32,
-                Err(_) => {
-                    println!("seek failed");
-                    return None;
-                }
-            };
+        if offset != self.seek_pos && self.seek(offset).is_err() {
+            return None;
         }
         // println!("seekedTo: {:?}", seekTo);
 
         loop {
             // find an existing decoded buffer
-            // FIXME: O(n), fix
-            for i in 0..self.decoded_buffers.len() {
-                let buffer = &self.decoded_buffers[i];
-                if buffer.offset <= offset && buffer.offset + buffer.length > offset {
-                    return Some(&self.decoded_buffers[i]);
-                }
+            if let Some(index) = self.find_decoded(offset) {
+                return Some(&self.decoded_buffers[index]);
             }
 
             // Get the next packet from the format reader.
-            let packet = match format.next_packet() {
+            let packet = match self.format.as_mut().unwrap().next_packet() {
                 Ok(packet) => packet,
-                Err(_) => {
+                // the end of the stream
+                Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return None;
+                }
+                Err(Error::ResetRequired) => {
+                    return None;
+                }
+                Err(err) => {
+                    error!("error reading {}: {}", self.filename, err);
+                    self.read_error = Some(format!("error reading: {}", err));
                     return None;
                 }
             };
@@ -154,133 +766,143 @@ impl AudioSource for AudioFileSource {
             }
 
             // Decode the packet into audio samples, ignoring any decode errors.
-            match decoder.decode(&packet) {
+            match self.decoder.as_mut().unwrap().decode(&packet) {
                 Ok(audio_buf) => {
-                    // The decoded audio samples may now be accessed via the audio buffer if per-channel
-                    // slices of samples in their native decoded format is desired. Use-cases where
-                    // the samples need to be accessed in an interleaved order or converted into
-                    // another sample format, or a byte buffer is required, are covered by copying the
-                    // audio buffer into a sample buffer or raw sample buffer, respectively. In the
-                    // example below, we will copy the audio buffer into a sample buffer in an
-                    // interleaved order while also converting to a f32 sample format.
-
-                    // FIXME: re-use the sample buf
-
-                    // Get the audio buffer specification.
                     let spec = *audio_buf.spec();
-
-                    // Get the capacity of the decoded buffer. Note: This is capacity, not length!
-                    let duration = audio_buf.capacity() as u64;
-
-                    // Create the f32 sample buffer.
-                    let mut sample_buf = Some(SampleBuffer::<f32>::new(duration, spec));
-
-                    let channel_count = spec.channels.count();
-
-                    let mut samples = Vec::new();
-                    for _channel in 0..channel_count {
-                        samples.push(Vec::new());
-                    }
-
-                    let mut signal = AudioBuffer {
-                        samples,
-                        sample_rate: 44100.0,
-                        length: 1024,
-                        offset,
-                    };
-
-                    // Copy the decoded audio buffer into the sample buffer in an interleaved format.
-                    if let Some(buf) = &mut sample_buf {
-                        buf.copy_planar_ref(audio_buf);
-
-                        let sample_count = buf.samples().len();
-
-                        let samples_per_channel = sample_count / channel_count;
-
-                        for channel in 0..channel_count {
-                            let samples = buf.samples();
-                            let channel_samples = &samples[channel * samples_per_channel
-                                ..(channel + 1) * samples_per_channel];
-                            for sample in channel_samples {
-                                signal.samples[channel].push(*sample);
-                            }
-                        }
-
-                        signal.length = samples_per_channel as u32;
-                        signal.offset = self.seek_pos;
-
-                        self.seek_pos += samples_per_channel as u32;
-
-                        // println!(
-                        //     "\rDecoded {} samples, offset {}",
-                        //     sample_count, signal.offset
-                        // );
-
-                        self.decoded_buffers.push(signal);
-
-                        // only keep ~5 seconds in memory
-                        // 2 * 5 * 44100 / 2000  ~ 220
-                        while self.decoded_buffers.len() > 220 {
-                            // println!("evicting buffer");
-                            self.decoded_buffers.remove(0);
-                        }
-                    }
+                    let signal = to_audio_buffer(audio_buf, self.seek_pos, &mut self.sample_buf);
+                    self.push_decoded(spec, signal);
                 }
                 Err(Error::DecodeError(_)) => {}
-                Err(_) => panic!("error decoding packet"),
+                Err(err) => {
+                    error!("error decoding {}: {}", self.filename, err);
+                    self.read_error = Some(format!("error decoding: {}", err));
+                    return None;
+                }
             }
         }
     }
 
+    fn seek(&mut self, offset: u32) -> Result<u32, ()> {
+        if !self.open() {
+            return Err(());
+        }
+        let track_id = self.track_id.unwrap();
+        let format = self.format.as_mut().unwrap();
+        // offsets are output frames, timestamps are in the track's own rate
+        let source_rate = format
+            .tracks()
+            .iter()
+            .find(|track| track.id == track_id)
+            .and_then(|track| track.codec_params.sample_rate)
+            .unwrap_or(self.output_sample_rate) as u64;
+        let output_rate = self.output_sample_rate as u64;
+        self.resampler = None;
+        match format.seek(
+            symphonia::core::formats::SeekMode::Accurate,
+            symphonia::core::formats::SeekTo::TimeStamp {
+                ts: offset as u64 * source_rate / output_rate,
+                track_id,
+            },
+        ) {
+            Ok(seek_to) => {
+                self.seek_pos = (seek_to.actual_ts * output_rate / source_rate) as u32;
+                Ok(self.seek_pos)
+            }
+            // past the end, which is just where the track ends
+            Err(Error::SeekError(SeekErrorKind::OutOfRange)) => Err(()),
+            Err(err) => {
+                error!("error seeking in {}: {}", self.filename, err);
+                self.read_error = Some(format!("error seeking: {}", err));
+                Err(())
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn duration(&mut self) -> Option<f64> {
+        self.open();
+        let codec_params = track_codec_params(self.format.as_deref()?, self.track_id?)?;
+        Some(self.trimmed_duration(codec_duration(&codec_params)?))
+    }
+
+    #[cfg(test)]
+    fn sample_rate(&mut self) -> Option<u32> {
+        self.open();
+        track_codec_params(self.format.as_deref()?, self.track_id?)?.sample_rate
+    }
+
+    #[cfg(test)]
+    fn channels(&mut self) -> Option<usize> {
+        self.open();
+        Some(
+            track_codec_params(self.format.as_deref()?, self.track_id?)?
+                .channels?
+                .count(),
+        )
+    }
+
     fn get_metadata(&mut self) -> &AudioMetadata {
         match self.metadata {
             Some(ref metadata) => metadata,
             None => {
                 let codec_params = match (self.format.borrow_mut(), self.track_id) {
                     (Some(ref format), Some(track_id)) => {
-                        Some(format.tracks()[track_id as usize].codec_params.clone())
-                    }
-                    _ => {
-                        let (format, decoder, track_id) = self.make_decoder();
-                        let codec_params =
-                            Some(format.tracks()[track_id as usize].codec_params.clone());
-                        self.format = Some(format);
-                        self.decoder = Some(decoder);
-                        self.track_id = Some(track_id);
-                        codec_params
+                        track_codec_params(format.as_ref(), track_id)
                     }
+                    _ => match self.make_decoder() {
+                        Ok((format, decoder, track_id)) => {
+                            let codec_params = track_codec_params(format.as_ref(), track_id);
+                            self.format = Some(format);
+                            self.decoder = Some(decoder);
+                            self.track_id = Some(track_id);
+                            codec_params
+                        }
+                        Err(err) => {
+                            error!("error opening {}: {}", self.filename, err);
+                            None
+                        }
+                    },
                 };
 
-                let codec_params = codec_params.unwrap();
-                let time_base = codec_params.time_base.unwrap();
-                let n_frames = codec_params.n_frames.unwrap();
-                let time = time_base.calc_time(n_frames);
-                let dur = time.seconds as f64 + time.frac;
+                let dur = codec_params
+                    .as_ref()
+                    .and_then(codec_duration)
+                    .unwrap_or(0.0);
+
+                let tech = codec_params
+                    .as_ref()
+                    .map(|codec_params| tech_info(&self.filename, codec_params, dur));
 
                 let mut metadata = AudioMetadata {
-                    dur,
+                    dur: self.trimmed_duration(dur),
                     artist: String::from(""),
                     title: self.filename.clone(),
                     album: String::from(""),
+                    gain_db: None,
+                    peak: None,
+                    cover: None,
+                    tech,
                 };
 
-                let mut meta = MetadataBuilder::new();
-                let file = Box::new(File::open(&self.filename).unwrap());
-                let mut mss = MediaSourceStream::new(file, Default::default());
-                if read_id3v2(mss.borrow_mut(), meta.borrow_mut()).is_ok() {
-                    let m = meta.metadata();
-                    for tag in m.tags() {
-                        match tag.std_key {
-                            Some(StandardTagKey::TrackTitle) => {
-                                metadata.title = tag.value.to_string();
-                            }
-                            Some(StandardTagKey::Artist) => {
-                                metadata.artist = tag.value.to_string();
-                            }
-                            Some(StandardTagKey::Album) => {
-                                metadata.album = tag.value.to_string();
-                            }
-                            _ => {}
+                let found_id3v2 = match archive::open_media_source(&self.filename, self.open_policy)
+                {
+                    Ok(file) => {
+                        let mut meta = MetadataBuilder::new();
+                        let mut mss = MediaSourceStream::new(file, Default::default());
+                        read_id3v2(mss.borrow_mut(), meta.borrow_mut()).is_ok()
+                            && apply_tags(&mut metadata, &meta.metadata())
+                    }
+                    Err(err) => {
+                        error!("error reading tags from {}: {}", self.filename, err);
+                        false
+                    }
+                };
+
+                // FLAC, Ogg and MP4 files keep their tags in the container instead
+                if !found_id3v2 {
+                    if let Some(format) = self.format.as_mut() {
+                        if let Some(revision) = format.metadata().current() {
+                            apply_tags(&mut metadata, revision);
                         }
                     }
                 }
@@ -291,3 +913,252 @@ impl AudioSource for AudioFileSource {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use symphonia::core::audio::{AsAudioBufferRef, Channels, Signal, SignalSpec};
+    use symphonia::core::io::ReadOnlySource;
+
+    use super::{probe_seekable, to_audio_buffer, AudioFileSource};
+    use crate::audio_source::AudioSource;
+
+    fn decoded(
+        spec: SignalSpec,
+        frames: usize,
+        value: f32,
+    ) -> symphonia::core::audio::AudioBuffer<f32> {
+        let mut buf = symphonia::core::audio::AudioBuffer::<f32>::new(frames as u64, spec);
+        buf.render_reserved(Some(frames));
+        for channel in 0..spec.channels.count() {
+            buf.chan_mut(channel).fill(value);
+        }
+        buf
+    }
+
+    #[test]
+    fn handles_spec_change_mid_stream() {
+        let stereo = SignalSpec::new(44100, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let mono = SignalSpec::new(48000, Channels::FRONT_CENTRE);
+        let mut src = AudioFileSource::new("concatenated.mp3".into());
+
+        for (spec, value) in [(stereo, 0.5), (stereo, 0.5), (mono, -0.25)] {
+            let buf = decoded(spec, 1152, value);
+            let signal =
+                to_audio_buffer(buf.as_audio_buffer_ref(), src.seek_pos, &mut src.sample_buf);
+            src.push_decoded(spec, signal);
+        }
+        assert_eq!(src.spec, Some(mono));
+
+        let before = src.get_buffer(1152).unwrap();
+        assert_eq!(before.samples.len(), 2);
+        assert_eq!(before.sample_rate, 44100.0);
+        assert_eq!(before.samples[1][0], 0.5);
+
+        // the 48 kHz part is resampled to the output rate
+        let after = src.get_buffer(2 * 1152).unwrap();
+        assert_eq!(after.offset, 2 * 1152);
+        assert_eq!(after.samples.len(), 1);
+        assert_eq!(after.sample_rate, 44100.0);
+        let resampled_frames = after.samples[0].len() as u32;
+        assert!((1056..=1059).contains(&resampled_frames));
+        assert!(after.samples[0].iter().all(|s| (s + 0.25).abs() < 1e-6));
+        assert_eq!(src.seek_pos, 2 * 1152 + resampled_frames);
+    }
+
+    #[test]
+    fn reuses_sample_buffer_across_packet_sizes() {
+        let stereo = SignalSpec::new(44100, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let mono = SignalSpec::new(44100, Channels::FRONT_CENTRE);
+        let mut sample_buf = None;
+
+        let mut offset = 0;
+        for i in 0..500 {
+            let (spec, channels) = if i % 3 == 0 { (mono, 1) } else { (stereo, 2) };
+            let frames = 1 + (i * 37) % 2000;
+            let value = i as f32 / 1000.0;
+            let buf = decoded(spec, frames, value);
+            let signal = to_audio_buffer(buf.as_audio_buffer_ref(), offset, &mut sample_buf);
+            assert_eq!(signal.offset, offset);
+            assert_eq!(signal.length as usize, frames);
+            assert_eq!(signal.samples.len(), channels);
+            for channel in &signal.samples {
+                assert_eq!(channel.len(), frames);
+                assert!(channel.iter().all(|s| *s == value));
+            }
+            offset += frames as u32;
+        }
+        // grown to the largest stereo packet, and no further
+        assert_eq!(sample_buf.unwrap().capacity(), 2 * 1962);
+    }
+
+    #[test]
+    fn probes_seekability() {
+        assert!(AudioFileSource::new("resources/ports.wav".into()).is_seekable());
+        assert!(!AudioFileSource::new("resources/missing.wav".into()).is_seekable());
+
+        let bytes = std::fs::read("resources/ports.wav").unwrap();
+        assert!(probe_seekable(Box::new(Cursor::new(bytes.clone()))));
+        assert!(!probe_seekable(Box::new(ReadOnlySource::new(Cursor::new(
+            bytes
+        )))));
+    }
+
+    #[test]
+    fn describes_the_audio_from_codec_parameters() {
+        let mut src = AudioFileSource::new("resources/ports.wav".into());
+        assert_eq!(src.sample_rate(), Some(44100));
+        assert_eq!(src.channels(), Some(1));
+        let dur = src.duration().unwrap();
+        assert!((dur - 328982.0 / 88200.0).abs() < 1e-6);
+        assert!(src.cached_metadata().is_none());
+
+        src.set_trim(Some(1.0), Some(2.5)).unwrap();
+        assert!((src.duration().unwrap() - 1.5).abs() < 1e-9);
+
+        let mut missing = AudioFileSource::new("resources/missing.wav".into());
+        assert_eq!(missing.duration(), None);
+        assert_eq!(missing.channels(), None);
+    }
+
+    #[test]
+    fn seeks_before_reading() {
+        let mut expected = AudioFileSource::new("resources/ports.wav".into());
+        let expected = expected.get_buffer(22050).unwrap();
+        let (expected_offset, expected_samples) = (expected.offset, expected.samples.clone());
+
+        let mut src = AudioFileSource::new("resources/ports.wav".into());
+        let landed = src.seek(22050).unwrap();
+        assert!(landed <= 22050);
+        assert_eq!(src.seek_pos, landed);
+        assert!(src.decoded_buffers().is_empty());
+
+        let buffer = src.get_buffer(22050).unwrap();
+        assert_eq!(buffer.offset, expected_offset);
+        assert_eq!(buffer.samples, expected_samples);
+
+        assert!(AudioFileSource::new("resources/missing.wav".into())
+            .seek(0)
+            .is_err());
+    }
+
+    #[test]
+    fn decodes_from_scratch_after_reset() {
+        let mut src = AudioFileSource::new("resources/ports.wav".into());
+        let first = src.get_buffer(0).unwrap().samples.clone();
+
+        src.reset();
+        assert!(src.decoded_buffers().is_empty());
+        assert!(src.decoder.is_none());
+
+        assert_eq!(src.get_buffer(0).unwrap().samples, first);
+        assert!(src.decoder.is_some());
+    }
+
+    #[test]
+    fn reopens_released_tracks() {
+        let mut src = AudioFileSource::new("resources/ports.wav".into());
+        let sample_at = |src: &mut AudioFileSource, offset: u32| {
+            let buffer = src.get_buffer(offset).unwrap();
+            buffer.samples[0][(offset - buffer.offset) as usize]
+        };
+        let later_offset = 44100 * 7 / 2;
+        let first = sample_at(&mut src, 100);
+        let later = sample_at(&mut src, later_offset);
+
+        src.release();
+        assert!(src.decoder.is_none());
+        assert!(src
+            .decoded_buffers()
+            .iter()
+            .all(|buffer| buffer.offset < 44100 * 3));
+
+        assert_eq!(sample_at(&mut src, 100), first);
+        assert_eq!(sample_at(&mut src, later_offset), later);
+        assert!(src.decoder.is_some());
+    }
+
+    #[test]
+    fn finds_decoded_buffers_at_boundaries() {
+        let spec = SignalSpec::new(44100, Channels::FRONT_CENTRE);
+        let mut src = AudioFileSource::new("sorted.mp3".into());
+
+        // decoded out of order, as after seeking around
+        for (offset, value) in [(2000, 0.2), (0, 0.0), (1000, 0.1)] {
+            let buf = decoded(spec, 1000, value);
+            src.push_decoded(
+                spec,
+                to_audio_buffer(buf.as_audio_buffer_ref(), offset, &mut None),
+            );
+        }
+        let offsets: Vec<u32> = src.decoded_buffers().iter().map(|b| b.offset).collect();
+        assert_eq!(offsets, vec![0, 1000, 2000]);
+
+        for (offset, expected) in [(0, 0), (999, 0), (1000, 1000), (1999, 1000), (2999, 2000)] {
+            let index = src.find_decoded(offset).unwrap();
+            assert_eq!(src.decoded_buffers()[index].offset, expected);
+        }
+        assert_eq!(src.find_decoded(3000), None);
+
+        // a buffer decoded again from a different seek point replaces the ones it overlaps
+        let buf = decoded(spec, 1000, 0.5);
+        src.push_decoded(
+            spec,
+            to_audio_buffer(buf.as_audio_buffer_ref(), 500, &mut None),
+        );
+        let offsets: Vec<u32> = src.decoded_buffers().iter().map(|b| b.offset).collect();
+        assert_eq!(offsets, vec![500, 2000]);
+        assert_eq!(src.find_decoded(499), None);
+        assert_eq!(src.get_buffer(1499).unwrap().samples[0][999], 0.5);
+        assert_eq!(src.find_decoded(1500), None);
+    }
+
+    #[test]
+    fn reports_tech_info() {
+        let mut src = AudioFileSource::new("resources/ports.wav".into());
+        let metadata = src.get_metadata().clone();
+        let tech = metadata.tech.unwrap();
+        assert_eq!(tech.codec, "pcm_s16le");
+        assert_eq!(tech.sample_rate, Some(44100));
+        assert_eq!(tech.bit_depth, Some(16));
+        assert_eq!(tech.channels, Some(1));
+
+        let size = std::fs::metadata("resources/ports.wav").unwrap().len();
+        assert_eq!(tech.file_size, Some(size));
+        // a little over 16 bit mono at 44.1 kHz, counting the header
+        let bitrate = tech.bitrate.unwrap();
+        assert!((705600..705600 + 1000).contains(&bitrate), "{}", bitrate);
+    }
+
+    #[test]
+    fn reads_container_tags_without_id3v2() {
+        for (path, prefix) in [
+            ("resources/tagged.flac", "FLAC"),
+            ("resources/tagged.m4a", "M4A"),
+        ] {
+            let mut src = AudioFileSource::new(path.into());
+            let metadata = src.get_metadata();
+            assert_eq!(metadata.title, format!("{} Title", prefix));
+            assert_eq!(metadata.artist, format!("{} Artist", prefix));
+            assert_eq!(metadata.album, format!("{} Album", prefix));
+            assert!((metadata.dur - 0.1).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn reads_attached_picture_from_id3v2() {
+        let mut src = AudioFileSource::new("resources/tagged.mp3".into());
+        let metadata = src.get_metadata().clone();
+        assert_eq!(metadata.title, "MP3 Title");
+        assert_eq!(metadata.artist, "MP3 Artist");
+        assert_eq!(metadata.album, "MP3 Album");
+
+        let cover = metadata.cover.clone().unwrap();
+        assert_eq!(cover.media_type, "image/png");
+        assert!(cover.data.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(cover.data.len(), 69);
+        // kept out of the status JSON
+        assert!(!serde_json::to_string(&metadata).unwrap().contains("cover"));
+    }
+}
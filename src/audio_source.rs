@@ -1,5 +1,10 @@
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
 
+/// Output rate assumed until the device reports its own
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
 pub struct AudioBuffer {
     pub samples: Vec<Vec<f32>>,
     pub sample_rate: f64,
@@ -7,12 +12,45 @@ pub struct AudioBuffer {
     pub offset: u32,
 }
 
+/// An embedded picture, e.g. an ID3v2 APIC frame
+#[derive(Debug)]
+pub struct CoverArt {
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Technical details of a track's file and encoding, for browsing a library
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TechInfo {
+    pub file_size: Option<u64>,
+    /// Bits per second, averaged over the file when the codec doesn't say
+    pub bitrate: Option<u32>,
+    pub codec: String,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u32>,
+    pub channels: Option<usize>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AudioMetadata {
     pub dur: f64,
     pub artist: String,
     pub title: String,
     pub album: String,
+
+    /// ReplayGain adjustment in dB (track gain, falling back to album gain)
+    #[serde(default)]
+    pub gain_db: Option<f32>,
+
+    /// ReplayGain peak sample amplitude matching `gain_db`
+    #[serde(default)]
+    pub peak: Option<f32>,
+
+    #[serde(skip)]
+    pub cover: Option<Arc<CoverArt>>,
+
+    #[serde(skip)]
+    pub tech: Option<TechInfo>,
 }
 
 pub trait AudioSource {
@@ -22,5 +60,23 @@ pub trait AudioSource {
     /// Returns None if there is no more audio to play.
     fn get_buffer(&mut self, offset: u32) -> Option<&AudioBuffer>;
 
+    /// Moves to `offset` without decoding anything, returning where it actually landed, which
+    /// can be before `offset`. Sources that can read from anywhere land exactly, so don't need
+    /// to do anything.
+    fn seek(&mut self, offset: u32) -> Result<u32, ()> {
+        Ok(offset)
+    }
+
     fn get_metadata(&mut self) -> &AudioMetadata;
+
+    /// How long the audio plays for in seconds, if it ends and that's known
+    #[cfg(test)]
+    fn duration(&mut self) -> Option<f64>;
+
+    /// The rate the audio is stored at, before any resampling
+    #[cfg(test)]
+    fn sample_rate(&mut self) -> Option<u32>;
+
+    #[cfg(test)]
+    fn channels(&mut self) -> Option<usize>;
 }
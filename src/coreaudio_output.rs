@@ -0,0 +1,68 @@
+use std::error::Error;
+
+use coreaudio::audio_unit::render_callback::{self, data};
+use coreaudio::audio_unit::{AudioUnit, IOType, SampleFormat};
+use log::info;
+
+use crate::output::{AudioOutput, OutputFormat};
+use crate::render::OutputRenderer;
+
+/// The default output device through a Core Audio output unit, which takes a buffer of f32
+/// samples per channel
+pub struct CoreAudioOutput {
+    audio_unit: AudioUnit,
+    format: OutputFormat,
+}
+
+impl CoreAudioOutput {
+    // from: https://github.com/RustAudio/coreaudio-rs/blob/master/examples/sine.rs
+    pub fn new() -> Result<CoreAudioOutput, coreaudio::Error> {
+        let audio_unit = AudioUnit::new(IOType::DefaultOutput)?;
+
+        // Read the input format. This is counterintuitive, but it's the format used when sending
+        // audio data to the AudioUnit representing the output device. This is separate from the
+        // format the AudioUnit later uses to send the data to the hardware device.
+        let stream_format = audio_unit.input_stream_format()?;
+
+        info!("stream format: {:#?}", &stream_format);
+
+        assert!(SampleFormat::F32 == stream_format.sample_format);
+        Ok(CoreAudioOutput {
+            audio_unit,
+            format: OutputFormat {
+                sample_rate: stream_format.sample_rate as u32,
+                channels: stream_format.channels as usize,
+            },
+        })
+    }
+}
+
+impl AudioOutput for CoreAudioOutput {
+    fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    fn set_renderer(&mut self, mut renderer: OutputRenderer) -> Result<(), Box<dyn Error>> {
+        type Args = render_callback::Args<data::NonInterleaved<f32>>;
+        self.audio_unit.set_render_callback(move |args| {
+            let Args {
+                num_frames,
+                mut data,
+                ..
+            } = args;
+            renderer.render(num_frames, data.channels_mut());
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<(), Box<dyn Error>> {
+        self.audio_unit.start()?;
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn Error>> {
+        self.audio_unit.stop()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+use std::{collections::HashMap, io::Cursor, sync::Arc};
+
+use image::ImageOutputFormat;
+
+use crate::audio_source::CoverArt;
+
+/// Resized covers are small, but don't let the cache grow forever on huge playlists
+const MAX_CACHED_THUMBNAILS: usize = 256;
+
+/// Returns a copy of `art` scaled to fit within `size` x `size`, keeping the aspect ratio.
+/// JPEG covers stay JPEG; everything else is re-encoded as PNG.
+pub fn thumbnail(art: &CoverArt, size: u32) -> Result<CoverArt, image::ImageError> {
+    let img = image::load_from_memory(&art.data)?;
+    let resized = img.thumbnail(size, size);
+
+    let (media_type, format) = if art.media_type == "image/jpeg" {
+        ("image/jpeg", ImageOutputFormat::Jpeg(90))
+    } else {
+        ("image/png", ImageOutputFormat::Png)
+    };
+
+    let mut data = Vec::new();
+    resized.write_to(&mut Cursor::new(&mut data), format)?;
+
+    Ok(CoverArt {
+        media_type: String::from(media_type),
+        data,
+    })
+}
+
+/// Resized covers keyed by `(filename, size)`
+#[derive(Default)]
+pub struct ThumbnailCache {
+    thumbnails: HashMap<(String, u32), Arc<CoverArt>>,
+}
+
+impl ThumbnailCache {
+    pub fn new() -> Self {
+        ThumbnailCache::default()
+    }
+
+    pub fn get(
+        &mut self,
+        filename: &str,
+        art: &CoverArt,
+        size: u32,
+    ) -> Result<Arc<CoverArt>, image::ImageError> {
+        let key = (String::from(filename), size);
+        if let Some(thumbnail) = self.thumbnails.get(&key) {
+            return Ok(thumbnail.clone());
+        }
+
+        let thumbnail = Arc::new(thumbnail(art, size)?);
+        if self.thumbnails.len() >= MAX_CACHED_THUMBNAILS {
+            self.thumbnails.clear();
+        }
+        self.thumbnails.insert(key, thumbnail.clone());
+        Ok(thumbnail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageOutputFormat};
+
+    use super::{thumbnail, ThumbnailCache};
+    use crate::audio_source::CoverArt;
+
+    fn make_png(width: u32, height: u32) -> CoverArt {
+        let img = DynamicImage::new_rgb8(width, height);
+        let mut data = Vec::new();
+        img.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+            .unwrap();
+        CoverArt {
+            media_type: String::from("image/png"),
+            data,
+        }
+    }
+
+    #[test]
+    fn thumbnail_fits_requested_size() {
+        let art = make_png(400, 200);
+        let thumb = thumbnail(&art, 100).unwrap();
+        assert_eq!(thumb.media_type, "image/png");
+
+        let img = image::load_from_memory(&thumb.data).unwrap();
+        assert!(img.width() <= 100);
+        assert!(img.height() <= 100);
+        assert_eq!(img.width(), 100);
+    }
+
+    #[test]
+    fn caches_thumbnails_by_path_and_size() {
+        let art = make_png(64, 64);
+        let mut cache = ThumbnailCache::new();
+        let a = cache.get("/music/a.mp3", &art, 32).unwrap();
+        let b = cache.get("/music/a.mp3", &art, 32).unwrap();
+        let c = cache.get("/music/a.mp3", &art, 16).unwrap();
+        assert!(std::sync::Arc::ptr_eq(&a, &b));
+        assert!(!std::sync::Arc::ptr_eq(&a, &c));
+    }
+}
@@ -0,0 +1,99 @@
+use std::error::Error;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{BufferSize, FromSample, SampleFormat, SizedSample, StreamConfig, SupportedBufferSize};
+use log::{error, info};
+
+use crate::output::{AudioOutput, OutputFormat, PREFERRED_BUFFER_FRAMES};
+use crate::render::OutputRenderer;
+
+/// The default output device of the platform's default cpal host: ALSA on Linux, WASAPI on
+/// Windows. Devices take interleaved samples, in whatever format they like.
+pub struct CpalOutput {
+    device: cpal::Device,
+    config: StreamConfig,
+    sample_format: SampleFormat,
+    stream: Option<cpal::Stream>,
+}
+
+impl CpalOutput {
+    pub fn new() -> Result<CpalOutput, Box<dyn Error>> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("no output device")?;
+        let supported = device.default_output_config()?;
+
+        info!("output config: {:#?}", &supported);
+
+        let mut config = supported.config();
+        if let SupportedBufferSize::Range { min, max } = supported.buffer_size() {
+            if (*min..=*max).contains(&PREFERRED_BUFFER_FRAMES) {
+                config.buffer_size = BufferSize::Fixed(PREFERRED_BUFFER_FRAMES);
+            }
+        }
+        Ok(CpalOutput {
+            device,
+            config,
+            sample_format: supported.sample_format(),
+            stream: None,
+        })
+    }
+
+    /// A stream of `T` samples, rendered as f32 and converted
+    fn build_stream<T>(&self, mut renderer: OutputRenderer) -> Result<cpal::Stream, Box<dyn Error>>
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let mut rendered: Vec<f32> =
+            Vec::with_capacity(PREFERRED_BUFFER_FRAMES as usize * self.config.channels as usize);
+        let stream = self.device.build_output_stream(
+            &self.config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                // only allocates if the device asks for more than it has before
+                rendered.resize(data.len(), 0.0);
+                renderer.render_interleaved(&mut rendered);
+                for (out, sample) in data.iter_mut().zip(rendered.iter()) {
+                    *out = T::from_sample(*sample);
+                }
+            },
+            |err| error!("output stream error: {}", err),
+            None,
+        )?;
+        Ok(stream)
+    }
+}
+
+impl AudioOutput for CpalOutput {
+    fn format(&self) -> OutputFormat {
+        OutputFormat {
+            sample_rate: self.config.sample_rate.0,
+            channels: self.config.channels as usize,
+        }
+    }
+
+    fn set_renderer(&mut self, renderer: OutputRenderer) -> Result<(), Box<dyn Error>> {
+        let stream = match self.sample_format {
+            SampleFormat::F32 => self.build_stream::<f32>(renderer)?,
+            SampleFormat::I16 => self.build_stream::<i16>(renderer)?,
+            SampleFormat::I32 => self.build_stream::<i32>(renderer)?,
+            SampleFormat::U16 => self.build_stream::<u16>(renderer)?,
+            format => return Err(format!("unsupported sample format {:?}", format).into()),
+        };
+        // some hosts start a stream as soon as it's built
+        let _ = stream.pause();
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<(), Box<dyn Error>> {
+        self.stream.as_ref().ok_or("no renderer set")?.play()?;
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(stream) = &self.stream {
+            stream.pause()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+/// Gain of the centre and surround channels in a 5.1 downmix, per ITU-R BS.775
+const SURROUND_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Scales a 5.1 downmix so full scale in every channel still can't clip
+const SURROUND_NORMALIZE: f32 = 1.0 / (1.0 + 2.0 * SURROUND_GAIN);
+
+/// The sample for output `channel` of `out_channels` at `index` in `samples`, which has one Vec
+/// per source channel. Mono plays on every output, stereo to mono is averaged, and 5.1 (in WAV
+/// order: L, R, C, LFE, Ls, Rs) to stereo or mono goes through the standard downmix, leaving out
+/// the LFE. Other layouts take the output channel's own source channel, wrapping around, which
+/// keeps the front left and right.
+pub fn sample(samples: &[Vec<f32>], index: usize, channel: usize, out_channels: usize) -> f32 {
+    let source_channels = samples.len();
+    let at = |source: usize| samples[source][index];
+    match (source_channels, out_channels) {
+        (sources, outs) if sources == outs => at(channel),
+        (1, _) => at(0),
+        (_, 1) => (sample(samples, index, 0, 2) + sample(samples, index, 1, 2)) / 2.0,
+        (6, 2) => {
+            let (front, surround) = if channel == 0 { (0, 4) } else { (1, 5) };
+            (at(front) + SURROUND_GAIN * (at(2) + at(surround))) * SURROUND_NORMALIZE
+        }
+        _ => at(channel % source_channels),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sample;
+
+    /// One frame with the given sample in each channel
+    fn frame(samples: &[f32]) -> Vec<Vec<f32>> {
+        samples.iter().map(|s| vec![*s]).collect()
+    }
+
+    fn mixed(samples: &[f32], out_channels: usize) -> Vec<f32> {
+        let frame = frame(samples);
+        (0..out_channels)
+            .map(|channel| sample(&frame, 0, channel, out_channels))
+            .collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn passes_matching_layouts_through() {
+        assert_eq!(mixed(&[0.1, 0.2], 2), [0.1, 0.2]);
+        assert_eq!(
+            mixed(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6], 6),
+            [0.1, 0.2, 0.3, 0.4, 0.5, 0.6]
+        );
+    }
+
+    #[test]
+    fn duplicates_mono() {
+        assert_eq!(mixed(&[0.5], 2), [0.5, 0.5]);
+        assert_eq!(mixed(&[0.5], 6), [0.5; 6]);
+    }
+
+    #[test]
+    fn averages_stereo_to_mono() {
+        assert_close(&mixed(&[0.5, -0.25], 1), &[0.125]);
+    }
+
+    #[test]
+    fn downmixes_surround() {
+        let norm = 1.0 / (1.0 + 2.0 * 0.70710677);
+        // the LFE is left out
+        let surround = [0.4, -0.4, 0.2, 1.0, 0.1, -0.1];
+        let left = (0.4 + 0.70710677 * (0.2 + 0.1)) * norm;
+        let right = (-0.4 + 0.70710677 * (0.2 - 0.1)) * norm;
+        assert_close(&mixed(&surround, 2), &[left, right]);
+        assert_close(&mixed(&surround, 1), &[(left + right) / 2.0]);
+
+        // full scale everywhere stays in range
+        assert_close(&mixed(&[1.0; 6], 2), &[1.0, 1.0]);
+    }
+
+    #[test]
+    fn keeps_the_front_of_other_layouts() {
+        assert_eq!(mixed(&[0.1, 0.2, 0.3, 0.4], 2), [0.1, 0.2]);
+        assert_eq!(mixed(&[0.1, 0.2], 4), [0.1, 0.2, 0.1, 0.2]);
+    }
+}
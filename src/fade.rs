@@ -0,0 +1,61 @@
+/// A linear gain ramp, advanced one frame at a time by the render callback
+#[derive(Clone, Copy, Debug)]
+pub struct Fade {
+    from: f32,
+    to: f32,
+    length: u32,
+    position: u32,
+}
+
+impl Fade {
+    pub fn new(from: f32, to: f32, length: u32) -> Fade {
+        Fade {
+            from,
+            to,
+            length,
+            position: 0,
+        }
+    }
+
+    /// Gain at the current position, without advancing
+    pub fn gain(&self) -> f32 {
+        if self.position >= self.length {
+            return self.to;
+        }
+        self.from + (self.to - self.from) * (self.position as f32 / self.length as f32)
+    }
+
+    /// Returns the gain for the next frame and advances the fade. Once the fade is done this
+    /// keeps returning the target gain.
+    pub fn next_gain(&mut self) -> f32 {
+        let gain = self.gain();
+        if self.position < self.length {
+            self.position += 1;
+        }
+        gain
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.position >= self.length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Fade;
+
+    #[test]
+    fn ramps_linearly_then_holds() {
+        let mut fade = Fade::new(1.0, 0.0, 4);
+        let gains: Vec<f32> = (0..6).map(|_| fade.next_gain()).collect();
+        assert_eq!(gains, vec![1.0, 0.75, 0.5, 0.25, 0.0, 0.0]);
+        assert!(fade.is_done());
+    }
+
+    #[test]
+    fn zero_length_is_done_immediately() {
+        let mut fade = Fade::new(1.0, 0.0, 0);
+        assert!(fade.is_done());
+        assert_eq!(fade.next_gain(), 0.0);
+    }
+}
@@ -0,0 +1,40 @@
+/// Samples up to this level pass through untouched; louder ones are squeezed into the headroom
+/// between it and full scale
+const KNEE: f32 = 0.9;
+
+/// Soft-clips `sample` into `[-1.0, 1.0]`. Below the knee it's unchanged; above it the excess is
+/// shaped by `tanh`, which meets the straight part with the same slope, so there's no audible
+/// corner where limiting starts.
+pub fn limit(sample: f32) -> f32 {
+    let level = sample.abs();
+    if level <= KNEE {
+        return sample;
+    }
+    let headroom = 1.0 - KNEE;
+    let limited = KNEE + headroom * ((level - KNEE) / headroom).tanh();
+    limited.copysign(sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::limit;
+
+    #[test]
+    fn brings_loud_samples_into_range() {
+        for sample in [1.5, -1.5, 1.0, -1.0, 100.0] {
+            let limited = limit(sample);
+            assert!(limited.abs() <= 1.0, "{} became {}", sample, limited);
+            assert_eq!(limited.signum(), sample.signum());
+        }
+        assert!(limit(1.5) > limit(1.0));
+    }
+
+    #[test]
+    fn leaves_quieter_samples_alone() {
+        for sample in [0.0, 0.25, -0.5, 0.9, -0.9] {
+            assert_eq!(limit(sample), sample);
+        }
+        // only gently shaped just past the knee
+        assert!((limit(0.92) - 0.92).abs() < 0.001);
+    }
+}
@@ -1,173 +1,907 @@
+mod archive;
 mod audio_file;
 mod audio_source;
+#[cfg(target_os = "macos")]
+mod coreaudio_output;
+mod cover_art;
+#[cfg(feature = "cpal")]
+mod cpal_output;
+mod downmix;
+mod fade;
+mod limiter;
+mod monitor;
+#[cfg(feature = "notifications")]
+mod notifications;
+mod output;
+#[cfg(test)]
+mod pcm;
 mod player_state;
+mod render;
+mod replaygain;
+mod resample;
+#[cfg(test)]
+mod sine;
 mod storage;
+#[cfg(test)]
+mod test_utils;
+mod transitions;
+mod wav;
+mod wav_header;
 mod web_framework;
 
-use audio_source::{AudioMetadata, AudioSource};
-use coreaudio::audio_unit::render_callback::{self, data};
-use coreaudio::audio_unit::{AudioUnit, IOType, SampleFormat};
+#[cfg(not(any(target_os = "macos", feature = "cpal")))]
+compile_error!("pjp plays through Core Audio on macOS; elsewhere, build it with `--features cpal`");
+
+use audio_source::{AudioMetadata, AudioSource, CoverArt};
 use log::{debug, error, info};
 use player_state::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json;
-use std::borrow::BorrowMut;
 
 use std::net::{TcpListener, TcpStream};
 
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use web_framework::{HttpMethod, HttpResponseCode};
 
+use crate::output::AudioOutput;
 use crate::storage::save_json;
-use crate::web_framework::HttpResponse;
+use crate::transitions::TransitionPolicy;
+use crate::web_framework::{EventSubscriber, HttpRequest, HttpResponse, RequestError};
 
-#[derive(Serialize)]
-struct PlayerStatusResponse<'a> {
-    state: String,
-    current_item: usize,
-    current_offset: f64,
-    playlist: Vec<&'a AudioMetadata>,
-}
+const DEFAULT_FADE_TO_PAUSE_SECS: f64 = 2.0;
+const DEFAULT_CROSSFADE_NOW_SECS: f64 = 5.0;
 
-// Abstraction:
-// - list of items to play
-// - prefetches those items into a buffer
-//   - start prefetching multiple items in the list (first few seconds?) so skipping songs is instant
-//   - memory goals: only the current audio source is in memory, plus the first few seconds of all other files
-// - fetches the next buffer from the current item, and plays that
-// - moves onto the next item when the current item is done
+/// How often the prefetch thread checks whether the tracks being played are open and whether the
+/// upcoming ones changed, when it isn't woken sooner
+const PREFETCH_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
-fn run_pjp() -> Result<(), coreaudio::Error> {
-    let config = storage::load_config();
-    let mut player_state = match storage::load_json::<PlayerState>("player_state") {
-        Ok(ps) => ps,
-        Err(err) => {
-            println!("error loading player state: {}", err);
-            PlayerState::default()
-        }
-    };
-    player_state.validate();
+/// Idle SSE connections get a comment this often so proxies don't drop them
+const SSE_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
 
-    // from: https://github.com/RustAudio/coreaudio-rs/blob/master/examples/sine.rs
+/// How often subscribers are told what's playing when nothing else has changed
+const NOW_PLAYING_INTERVAL: Duration = Duration::from_secs(5);
 
-    // Construct an Output audio unit that delivers audio to the default output device.
-    let mut audio_unit = AudioUnit::new(IOType::DefaultOutput)?;
+/// Longest a `/status?wait=` request is held open
+const MAX_STATUS_WAIT_SECS: f64 = 60.0;
 
-    // Read the input format. This is counterintuitive, but it's the format used when sending
-    // audio data to the AudioUnit representing the output device. This is separate from the
-    // format the AudioUnit later uses to send the data to the hardware device.
-    let stream_format = audio_unit.input_stream_format()?;
+/// Most `/status?wait=` requests held open at once, each on a thread of its own; any more are
+/// turned down with a 503
+const MAX_STATUS_WAITERS: usize = 32;
 
-    info!("stream format: {:#?}", &stream_format);
+/// Tracks can be picked out by their playlist index or their id, which doesn't change as the
+/// playlist does
+#[derive(Deserialize)]
+struct TrackRequest {
+    #[serde(default)]
+    index: Option<usize>,
+    #[serde(default)]
+    id: Option<u64>,
+}
 
-    let channels = stream_format.channels;
+#[derive(Deserialize)]
+struct MoveRequest {
+    #[serde(default)]
+    from: Option<usize>,
+    #[serde(default)]
+    id: Option<u64>,
+    to: usize,
+}
 
-    let buffer_size = 1024;
+#[derive(Deserialize)]
+struct InsertRequest {
+    index: usize,
+    paths: Vec<String>,
+}
 
-    let mut samples = Vec::new();
-    for _ in 0..channels {
-        samples.push(vec![0.0; buffer_size]);
+#[derive(Deserialize)]
+struct SavePlaylistRequest {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct LoadPlaylistRequest {
+    name: String,
+    /// Add the saved tracks after the live ones, rather than in their place
+    #[serde(default)]
+    append: bool,
+}
+
+/// The index of the track picked out by `index` or `id`, preferring `id`
+fn track_index(player_state: &PlayerState, index: Option<usize>, id: Option<u64>) -> Option<usize> {
+    match id {
+        Some(id) => player_state.index_of(id),
+        None => index,
     }
+}
+
+#[derive(Deserialize)]
+struct TrimRequest {
+    index: usize,
+    #[serde(default)]
+    start: Option<f64>,
+    #[serde(default)]
+    end: Option<f64>,
+}
 
-    // For this example, our sine wave expects `f32` data.
-    assert!(SampleFormat::F32 == stream_format.sample_format);
+#[derive(Serialize)]
+struct UpcomingItem<'a> {
+    index: usize,
+    id: u64,
+    track: &'a AudioMetadata,
+}
 
-    let player_state_mutex = Arc::new(Mutex::new(player_state));
+/// The fields asked for with `?fields=a,b`, if any
+fn status_fields(req: &HttpRequest) -> Option<Vec<&str>> {
+    req.query
+        .get("fields")
+        .map(|fields| fields.split(',').map(str::trim).collect())
+}
 
-    let ps = player_state_mutex.clone();
+/// Whether the status asked for includes the playlist, with every track's tags
+fn wants_playlist(fields: Option<&[&str]>) -> bool {
+    fields.is_none_or(|fields| fields.contains(&"playlist"))
+}
+
+/// Whether an SSE subscriber is still connected after sending it something
+fn keep_subscriber(sent: Result<(), Box<dyn std::error::Error>>) -> bool {
+    match sent {
+        Ok(_) => true,
+        Err(err) => {
+            info!("removing subscriber: {}", err);
+            false
+        }
+    }
+}
+
+/// Answers `/wav-header?path=` with a summary of the header of a wav file under the library
+/// roots. The file is read with the player state lock released.
+fn send_wav_header(
+    player_state_mutex: &Mutex<PlayerState>,
+    req: &HttpRequest,
+    res: &mut HttpResponse,
+) {
+    let path = match req.query.get("path") {
+        Some(path) => path,
+        None => {
+            res.response_code = HttpResponseCode::BadRequest;
+            return;
+        }
+    };
+    let open_policy = {
+        let player_state = player_state_mutex.lock().unwrap();
+        if !player_state.is_allowed_path(path) {
+            error!(
+                "not reading wav header from {}: it isn't under a library root",
+                path
+            );
+            res.set_error(
+                HttpResponseCode::BadRequest,
+                "path isn't under a library root",
+            );
+            return;
+        }
+        player_state.open_policy
+    };
+    let mut wav = wav::WavSource::new(path.into());
+    wav.set_open_policy(open_policy);
+    match wav.read_header() {
+        Ok(header) => {
+            res.set_json(&header.summary());
+            res.response_code = HttpResponseCode::Ok;
+        }
+        Err(err) => {
+            error!("error reading wav header from {}: {}", path, err);
+            res.response_code = HttpResponseCode::BadRequest;
+        }
+    }
+}
 
-    type Args = render_callback::Args<data::NonInterleaved<f32>>;
-    audio_unit.set_render_callback(move |args| {
-        let mut locked_ps = ps.lock().unwrap();
+/// What's left to do for a request once the player state lock is released
+struct AfterUnlock {
+    /// The player state changed in a way worth saving
+    save: bool,
+    /// A cover to send, resized to the size asked for if any
+    cover: Option<(HttpResponse, String, Arc<CoverArt>, Option<u32>)>,
+    /// Tracks just added, whose files still need looking for
+    added_ids: Vec<u64>,
+}
 
-        let _current_item = locked_ps.current_item;
+/// Answers a request that needs the player state, with its lock held. The response is sent on
+/// return, unless it's a cover left to send. `output_channels` is how many channels the output
+/// plays.
+fn handle_request(
+    player_state: &mut PlayerState,
+    output_channels: usize,
+    req: Result<HttpRequest, RequestError>,
+    mut res: HttpResponse,
+) -> AfterUnlock {
+    let mut should_save = false;
+    let mut cover_to_send = None;
+    let mut added_ids = vec![];
 
-        match locked_ps.state {
-            PlaybackState::Paused => {
-                // fill with silence
-                let Args { mut data, .. } = args;
-                for channel in data.channels_mut() {
-                    for sample in channel.as_mut() {
-                        *sample = 0.0;
+    match req {
+        Ok(req) => match (&req.method, req.path.as_str(), &req) {
+            (HttpMethod::Get, "/status", req) => {
+                let fields = status_fields(req);
+                res.set_json(&player_state.status(fields.as_deref()));
+                res.response_code = HttpResponseCode::Ok;
+            }
+            (HttpMethod::Get, "/upcoming", req) => {
+                match req.query.get("count").map(|c| c.parse::<usize>()) {
+                    Some(Ok(count)) => {
+                        let upcoming = player_state.upcoming(count);
+                        for &index in upcoming.iter() {
+                            player_state.playlist[index].get_metadata();
+                        }
+                        let items: Vec<UpcomingItem> = upcoming
+                            .iter()
+                            .map(|&index| UpcomingItem {
+                                index,
+                                id: player_state.playlist[index].id,
+                                track: player_state.playlist[index].cached_metadata().unwrap(),
+                            })
+                            .collect();
+                        res.set_json(&items);
+                        res.response_code = HttpResponseCode::Ok;
+                    }
+                    _ => {
+                        res.response_code = HttpResponseCode::BadRequest;
                     }
                 }
-                Ok(())
             }
-            PlaybackState::Playing => {
-                let Args {
-                    num_frames,
-                    mut data,
-                    ..
-                } = args;
-
-                // if the playlist is empty, fill with silence
-                if locked_ps.playlist.len() == 0 {
-                    for channel in data.channels_mut() {
-                        for sample in channel.as_mut() {
-                            *sample = 0.0;
-                        }
+            (HttpMethod::Get, "/seekable", req) => {
+                match req.query.get("index").map(|i| i.parse::<usize>()) {
+                    Some(Ok(index)) if index < player_state.playlist.len() => {
+                        res.set_json(&player_state.playlist[index].is_seekable());
+                        res.response_code = HttpResponseCode::Ok;
+                    }
+                    _ => {
+                        res.response_code = HttpResponseCode::BadRequest;
                     }
-                    return Ok(());
                 }
-
-                let current_item = locked_ps.current_item;
-                let mut current_offset = locked_ps.current_offset;
-
-                let src = locked_ps.playlist[current_item].borrow_mut();
-
-                let mut signal = match src.get_buffer(current_offset) {
-                    Some(s) => s,
-                    None => {
-                        // next track
-                        // FIXME: gapless
-                        locked_ps.next();
-                        return Ok(());
+            }
+            (HttpMethod::Post, "/clear", _) => {
+                player_state.clear();
+                should_save = true;
+                res.response_code = HttpResponseCode::Ok;
+            }
+            (HttpMethod::Post, "/next", _) => {
+                player_state.next();
+                should_save = true;
+                res.response_code = HttpResponseCode::Ok;
+            }
+            (HttpMethod::Post, "/prev", _) => {
+                player_state.prev();
+                should_save = true;
+                res.response_code = HttpResponseCode::Ok;
+            }
+            (HttpMethod::Post, "/pause", _) => {
+                player_state.soft_pause();
+                should_save = true;
+                res.response_code = HttpResponseCode::Ok;
+            }
+            (HttpMethod::Post, "/fade-to-pause", req) => {
+                let secs = if req.body.trim().is_empty() {
+                    Ok(DEFAULT_FADE_TO_PAUSE_SECS)
+                } else {
+                    serde_json::from_str::<f64>(req.body.as_str())
+                };
+                match secs {
+                    Ok(secs) if secs >= 0.0 => {
+                        let frames = secs * player_state.sample_rate as f64;
+                        player_state.fade_to_pause(frames as u32);
+                        res.response_code = HttpResponseCode::Ok;
+                    }
+                    Ok(_) => {
+                        res.response_code = HttpResponseCode::BadRequest;
                     }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Post, "/crossfade-now", req) => {
+                let secs = if req.body.trim().is_empty() {
+                    Ok(DEFAULT_CROSSFADE_NOW_SECS)
+                } else {
+                    serde_json::from_str::<f64>(req.body.as_str())
                 };
-
-                let mut consumed_frames: u32 = 0;
-
-                while (consumed_frames as usize) < num_frames {
-                    if signal.offset + signal.length <= current_offset {
-                        // grab the next buffer
-                        signal = match src.get_buffer(current_offset) {
-                            Some(s) => s,
-                            None => {
-                                // next track
-                                // FIXME: gapless
-                                locked_ps.next();
-                                return Ok(());
+                match secs {
+                    Ok(secs) if secs >= 0.0 => {
+                        let frames = secs * player_state.sample_rate as f64;
+                        match player_state.crossfade_now(frames as u32) {
+                            Ok(_) => {
+                                res.response_code = HttpResponseCode::Ok;
+                            }
+                            Err(err) => {
+                                error!("can't crossfade: {}", err);
+                                res.response_code = HttpResponseCode::BadRequest;
+                            }
+                        }
+                    }
+                    Ok(_) => {
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Get, "/transitions", _) => {
+                res.set_json(&player_state.transitions);
+                res.response_code = HttpResponseCode::Ok;
+            }
+            (HttpMethod::Post, "/transitions", req) => {
+                match serde_json::from_str::<TransitionPolicy>(req.body.as_str()) {
+                    Ok(transitions) => match transitions.validate() {
+                        Ok(_) => {
+                            player_state.transitions = transitions;
+                            should_save = true;
+                            res.response_code = HttpResponseCode::Ok;
+                        }
+                        Err(err) => {
+                            error!("invalid transition policy: {}", err);
+                            res.response_code = HttpResponseCode::BadRequest;
+                        }
+                    },
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Post, "/crossfade", req) => {
+                match serde_json::from_str::<f64>(req.body.as_str()) {
+                    Ok(secs) => {
+                        let transitions = player_state.transitions.with_crossfade(secs);
+                        match transitions.validate() {
+                            Ok(_) => {
+                                player_state.transitions = transitions;
+                                should_save = true;
+                                res.response_code = HttpResponseCode::Ok;
+                            }
+                            Err(err) => {
+                                error!("invalid crossfade: {}", err);
+                                res.response_code = HttpResponseCode::BadRequest;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Post, "/volume", req) => {
+                match serde_json::from_str::<f32>(req.body.as_str()) {
+                    Ok(volume) => {
+                        player_state.set_volume(volume);
+                        should_save = true;
+                        res.response_code = HttpResponseCode::Ok;
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Post, "/shuffle", req) => {
+                match serde_json::from_str::<bool>(req.body.as_str()) {
+                    Ok(on) => {
+                        player_state.set_shuffle(on);
+                        should_save = true;
+                        res.response_code = HttpResponseCode::Ok;
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Post, "/consume", req) => {
+                match serde_json::from_str::<bool>(req.body.as_str()) {
+                    Ok(on) => {
+                        player_state.set_consume(on);
+                        should_save = true;
+                        res.response_code = HttpResponseCode::Ok;
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Post, "/repeat", req) => {
+                match serde_json::from_str::<RepeatMode>(req.body.as_str()) {
+                    Ok(mode) => {
+                        player_state.set_repeat(mode);
+                        should_save = true;
+                        res.response_code = HttpResponseCode::Ok;
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Post, "/channel-map", req) => {
+                match serde_json::from_str::<Vec<usize>>(req.body.as_str()) {
+                    Ok(channel_map) => {
+                        match render::check_channel_map(&channel_map, output_channels) {
+                            Ok(_) => {
+                                player_state.channel_map = channel_map;
+                                res.response_code = HttpResponseCode::Ok;
                             }
+                            Err(err) => {
+                                error!("invalid channel map: {}", err);
+                                res.response_code = HttpResponseCode::BadRequest;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Post, "/play", _) => {
+                player_state.play();
+                should_save = true;
+                res.response_code = HttpResponseCode::Ok;
+            }
+            (HttpMethod::Post, "/toggle", _) => {
+                player_state.toggle();
+                should_save = true;
+                res.response_code = HttpResponseCode::Ok;
+            }
+            (HttpMethod::Post, "/add", req) => {
+                // paths are plain JSON strings, not percent-encoded: JSON already
+                // carries spaces and unicode as they are
+                match serde_json::from_str::<Vec<String>>(req.body.as_str()) {
+                    Ok(paths) => {
+                        let rejected: Vec<&String> = paths
+                            .iter()
+                            .filter(|path| !player_state.is_allowed_path(path))
+                            .collect();
+                        if paths.is_empty() {
+                            // nothing to add, so nothing to save or announce
+                            res.response_code = HttpResponseCode::NoContent;
+                        } else if rejected.is_empty() {
+                            let last_track_id = player_state.last_track_id;
+                            player_state.add_tracks(paths);
+                            // checked for files that don't exist once the lock is released
+                            added_ids = player_state.ids_added_since(last_track_id);
+                            should_save = true;
+                            res.response_code = HttpResponseCode::Ok;
+                        } else {
+                            error!("rejecting paths outside library roots: {:?}", rejected);
+                            res.set_json(&rejected);
+                            res.response_code = HttpResponseCode::BadRequest;
+                        }
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.set_error(HttpResponseCode::BadRequest, &err.to_string());
+                    }
+                }
+            }
+            (HttpMethod::Post, "/insert", req) => {
+                match serde_json::from_str::<InsertRequest>(req.body.as_str()) {
+                    Ok(InsertRequest { index, paths }) => {
+                        let rejected: Vec<&String> = paths
+                            .iter()
+                            .filter(|path| !player_state.is_allowed_path(path))
+                            .collect();
+                        if paths.is_empty() {
+                            res.response_code = HttpResponseCode::NoContent;
+                        } else if rejected.is_empty() {
+                            let last_track_id = player_state.last_track_id;
+                            player_state.insert_tracks(index, paths);
+                            // checked for files that don't exist once the lock is released
+                            added_ids = player_state.ids_added_since(last_track_id);
+                            should_save = true;
+                            res.response_code = HttpResponseCode::Ok;
+                        } else {
+                            error!("rejecting paths outside library roots: {:?}", rejected);
+                            res.set_json(&rejected);
+                            res.response_code = HttpResponseCode::BadRequest;
+                        }
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Get, "/playlist.json", _) => {
+                res.set_json(&player_state.export_playlist());
+                res.response_code = HttpResponseCode::Ok;
+            }
+            (HttpMethod::Post, "/playlist.json", req) => {
+                match serde_json::from_str::<PlaylistExport>(req.body.as_str()) {
+                    Ok(export) => match player_state.import_playlist(export) {
+                        Ok(_) => {
+                            should_save = true;
+                            res.response_code = HttpResponseCode::Ok;
+                        }
+                        Err(problems) => {
+                            error!("not importing playlist: {:?}", problems);
+                            res.set_json(&problems);
+                            res.response_code = HttpResponseCode::BadRequest;
+                        }
+                    },
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Get, "/playlist/list", _) => match storage::list_playlists() {
+                Ok(names) => {
+                    res.set_json(&names);
+                    res.response_code = HttpResponseCode::Ok;
+                }
+                Err(err) => {
+                    error!("error listing playlists: {}", err);
+                    res.response_code = HttpResponseCode::InternalServerError;
+                }
+            },
+            (HttpMethod::Post, "/playlist/save", req) => {
+                match serde_json::from_str::<SavePlaylistRequest>(req.body.as_str()) {
+                    Ok(SavePlaylistRequest { name }) => {
+                        if !storage::is_valid_playlist_name(&name) {
+                            error!("not saving playlist with name {:?}", name);
+                            res.response_code = HttpResponseCode::BadRequest;
+                        } else if let Err(err) =
+                            storage::save_playlist(&name, &player_state.export_playlist())
+                        {
+                            error!("error saving playlist {}: {}", name, err);
+                            res.response_code = HttpResponseCode::InternalServerError;
+                        } else {
+                            res.response_code = HttpResponseCode::NoContent;
+                        }
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Post, "/playlist/load", req) => {
+                match serde_json::from_str::<LoadPlaylistRequest>(req.body.as_str()) {
+                    Ok(LoadPlaylistRequest { name, append }) => {
+                        if !storage::is_valid_playlist_name(&name) {
+                            error!("not loading playlist with name {:?}", name);
+                            res.response_code = HttpResponseCode::BadRequest;
+                        } else {
+                            match storage::load_playlist::<PlaylistExport>(&name) {
+                                Ok(export) => {
+                                    let loaded = if append {
+                                        player_state.append_playlist(export)
+                                    } else {
+                                        player_state.import_playlist(export)
+                                    };
+                                    match loaded {
+                                        Ok(_) => {
+                                            should_save = true;
+                                            res.response_code = HttpResponseCode::Ok;
+                                        }
+                                        Err(problems) => {
+                                            error!("not loading playlist {}: {:?}", name, problems);
+                                            res.set_json(&problems);
+                                            res.response_code = HttpResponseCode::BadRequest;
+                                        }
+                                    }
+                                }
+                                Err(err) => {
+                                    error!("error reading playlist {}: {}", name, err);
+                                    res.response_code = HttpResponseCode::NotFound;
+                                }
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Post, "/skip-to", req) => match serde_json::from_str(req.body.as_str()) {
+                Ok(index) => {
+                    player_state.skip_to(index);
+                    should_save = true;
+                    res.response_code = HttpResponseCode::Ok;
+                }
+                Err(err) => {
+                    error!("error parsing json: {} {}", err, req.body);
+                    res.set_error(HttpResponseCode::BadRequest, &err.to_string());
+                }
+            },
+            (HttpMethod::Post, "/skip-to-id", req) => {
+                match serde_json::from_str(req.body.as_str()) {
+                    Ok(id) => match player_state.index_of(id) {
+                        Some(index) => {
+                            player_state.skip_to(index);
+                            should_save = true;
+                            res.response_code = HttpResponseCode::Ok;
+                        }
+                        None => {
+                            error!("no track with id {}", id);
+                            res.response_code = HttpResponseCode::BadRequest;
+                        }
+                    },
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Post, "/remove", req) => {
+                match serde_json::from_str::<TrackRequest>(req.body.as_str()) {
+                    Ok(TrackRequest { index, id }) => {
+                        let removed = match track_index(player_state, index, id) {
+                            Some(index) => player_state.remove(index),
+                            None => Err(String::from("no such track")),
                         };
+                        match removed {
+                            Ok(_) => {
+                                should_save = true;
+                                res.response_code = HttpResponseCode::Ok;
+                            }
+                            Err(err) => {
+                                error!("invalid remove: {}", err);
+                                res.response_code = HttpResponseCode::BadRequest;
+                            }
+                        }
                     }
-                    if signal.offset > current_offset {
-                        // panic!
-                        // or play nothing
-                        consumed_frames += 1;
-                        continue;
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
                     }
-                    let signal_index = current_offset - signal.offset;
-
-                    let mut channel_index = 0;
-                    for channel in data.channels_mut() {
-                        let sample = signal.samples[channel_index % signal.samples.len()]
-                            [signal_index as usize];
-                        channel[consumed_frames as usize] = sample;
-                        channel_index += 1;
+                }
+            }
+            (HttpMethod::Post, path @ ("/move-to-top" | "/move-to-bottom"), req) => {
+                match serde_json::from_str::<TrackRequest>(req.body.as_str()) {
+                    Ok(TrackRequest { index, id }) => {
+                        let moved = match track_index(player_state, index, id) {
+                            Some(index) if path == "/move-to-top" => {
+                                player_state.move_to_front(index)
+                            }
+                            Some(index) => player_state.move_to_back(index),
+                            None => Err(String::from("no such track")),
+                        };
+                        match moved {
+                            Ok(_) => {
+                                should_save = true;
+                                res.response_code = HttpResponseCode::Ok;
+                            }
+                            Err(err) => {
+                                error!("invalid move: {}", err);
+                                res.response_code = HttpResponseCode::BadRequest;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Post, "/move", req) => {
+                match serde_json::from_str::<MoveRequest>(req.body.as_str()) {
+                    Ok(MoveRequest { from, id, to }) => {
+                        let moved = match track_index(player_state, from, id) {
+                            Some(from) => player_state.move_track(from, to),
+                            None => Err(String::from("no such track")),
+                        };
+                        match moved {
+                            Ok(_) => {
+                                should_save = true;
+                                res.response_code = HttpResponseCode::Ok;
+                            }
+                            Err(err) => {
+                                error!("invalid move: {}", err);
+                                res.response_code = HttpResponseCode::BadRequest;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Post, "/trim", req) => {
+                match serde_json::from_str::<TrimRequest>(req.body.as_str()) {
+                    Ok(trim) if trim.index < player_state.playlist.len() => {
+                        let src = &mut player_state.playlist[trim.index];
+                        match src.set_trim(trim.start, trim.end) {
+                            Ok(_) => {
+                                src.get_metadata();
+                                should_save = true;
+                                res.response_code = HttpResponseCode::Ok;
+                            }
+                            Err(err) => {
+                                error!("invalid trim: {}", err);
+                                res.response_code = HttpResponseCode::BadRequest;
+                            }
+                        }
+                    }
+                    Ok(_) => {
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                    Err(err) => {
+                        error!("error parsing json: {} {}", err, req.body);
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            (HttpMethod::Get, "/track-info", req) => {
+                match req.query.get("index").and_then(|i| i.parse::<usize>().ok()) {
+                    Some(index) if index < player_state.playlist.len() => {
+                        match player_state.playlist[index].get_metadata().tech.clone() {
+                            Some(tech) => {
+                                res.set_json(&tech);
+                                res.response_code = HttpResponseCode::Ok;
+                            }
+                            None => {
+                                res.response_code = HttpResponseCode::NotFound;
+                            }
+                        }
+                    }
+                    _ => {
+                        res.response_code = HttpResponseCode::BadRequest;
                     }
-                    consumed_frames += 1;
-                    current_offset += 1;
                 }
+            }
+            (HttpMethod::Post, "/reset-decoder", req) => {
+                match req.query.get("index").and_then(|i| i.parse::<usize>().ok()) {
+                    Some(index) if index < player_state.playlist.len() => {
+                        player_state.playlist[index].reset();
+                        res.response_code = HttpResponseCode::Ok;
+                    }
+                    _ => {
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            // `/artwork` is another name for the same thing
+            (HttpMethod::Get, "/cover" | "/artwork", req) => {
+                let index = req.query.get("index").and_then(|i| i.parse::<usize>().ok());
+                let size = req.query.get("size").map(|s| s.parse::<u32>()).transpose();
+                match (index, size) {
+                    (Some(index), Ok(size)) if index < player_state.playlist.len() => {
+                        let src = &mut player_state.playlist[index];
+                        match src.get_metadata().cover.clone() {
+                            Some(cover) => {
+                                res.response_code = HttpResponseCode::Ok;
+                                cover_to_send = Some((res, src.filename.clone(), cover, size));
+                            }
+                            None => {
+                                res.response_code = HttpResponseCode::NotFound;
+                            }
+                        }
+                    }
+                    _ => {
+                        res.response_code = HttpResponseCode::BadRequest;
+                    }
+                }
+            }
+            _ => {
+                res.response_code = HttpResponseCode::NotFound;
+            }
+        },
+        Err(err) => {
+            error!("error parsing request: {:?}", err);
+            res.response_code = err.response_code();
+        }
+    }
 
-                locked_ps.current_offset = current_offset;
+    AfterUnlock {
+        save: should_save,
+        cover: cover_to_send,
+        added_ids,
+    }
+}
 
-                Ok(())
-            }
+// Abstraction:
+// - list of items to play
+// - prefetches those items into a buffer
+//   - start prefetching multiple items in the list (first few seconds?) so skipping songs is instant
+//   - memory goals: only the current audio source is in memory, plus the first few seconds of all other files
+// - fetches the next buffer from the current item, and plays that
+// - moves onto the next item when the current item is done
+
+/// Installs a renderer playing from the shared player state and starts `output`. Everything
+/// the renderer uses outlives it, so this can be called again on a new output, e.g. after the
+/// output device changes, without losing the playlist or position or touching the http server.
+fn start_output(
+    output: &mut dyn AudioOutput,
+    player_state_mutex: &Arc<Mutex<PlayerState>>,
+    monitor: &Arc<monitor::Monitor>,
+    callback_rate: &Arc<render::CallbackRate>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let format = output.format();
+    let position = {
+        let mut player_state = player_state_mutex.lock().unwrap();
+        player_state.set_sample_rate(format.sample_rate);
+        player_state.position.clone()
+    };
+
+    let queue = render::start_render_thread(player_state_mutex.clone(), format.channels);
+    output.set_renderer(render::OutputRenderer::new(
+        queue,
+        position,
+        monitor.clone(),
+        callback_rate.clone(),
+    ))?;
+    output.start()
+}
+
+fn run_pjp() -> Result<(), Box<dyn std::error::Error>> {
+    let config = storage::load_config();
+    let mut player_state = match storage::load_json::<serde_json::Value>("player_state") {
+        Ok(saved) => PlayerState::from_saved(saved),
+        Err(err) => {
+            println!("error loading player state: {}", err);
+            PlayerState::default()
+        }
+    };
+    player_state.set_open_policy(audio_file::OpenPolicy {
+        timeout: Duration::from_millis(config.open_timeout_ms),
+        retries: config.open_retries,
+    });
+    player_state.validate();
+    if config.validate_decodable_on_start {
+        if player_state.playlist.len() > config.validate_decodable_max_tracks {
+            info!(
+                "skipping decode check for {} tracks (validate_decodable_max_tracks is {})",
+                player_state.playlist.len(),
+                config.validate_decodable_max_tracks
+            );
+        } else {
+            let removed = player_state.remove_undecodable();
+            info!(
+                "checked {} tracks, removed {} that can't be decoded",
+                player_state.playlist.len() + removed.len(),
+                removed.len()
+            );
         }
-    })?;
-    audio_unit.start()?;
+    }
+    player_state.apply_startup_state(config.startup_state);
+    player_state.replaygain = config.replaygain;
+    player_state.limiter = config.limiter;
+    player_state.play_pause_fade_ms = config.play_pause_fade_ms;
+    player_state.on_decode_error = config.on_decode_error;
+    player_state.max_open_decoders = config.max_open_decoders;
+    player_state.set_library_roots(&config.library_roots);
+
+    let mut output = output::default_output()?;
+    let format = output.format();
+
+    match render::check_channel_map(&config.channel_map, format.channels) {
+        Ok(_) => player_state.channel_map = config.channel_map.clone(),
+        Err(err) => error!("ignoring channel_map from config: {}", err),
+    }
+
+    let player_state_mutex = Arc::new(Mutex::new(player_state));
+
+    let monitor = Arc::new(monitor::Monitor::new(format.channels, format.sample_rate));
+    let callback_rate = Arc::new(render::CallbackRate::new());
+
+    start_output(
+        output.as_mut(),
+        &player_state_mutex,
+        &monitor,
+        &callback_rate,
+    )?;
 
     let ps = player_state_mutex.clone();
 
@@ -189,152 +923,226 @@ fn run_pjp() -> Result<(), coreaudio::Error> {
         }
     });
 
-    let mut subscribers: Arc<Mutex<Vec<HttpResponse>>> = Arc::new(Mutex::new(Vec::new()));
+    let prefetch_ps = player_state_mutex.clone();
+    let prefetch_tracks = config.prefetch_tracks;
+    let open_requests = player_state_mutex.lock().unwrap().open_requests();
+    thread::spawn(move || {
+        // prefetch again whenever tracks are added or removed, or a different track starts
+        let mut prev_upcoming = None;
+        loop {
+            open_playing(&prefetch_ps);
+            let upcoming = {
+                let ps = prefetch_ps.lock().unwrap();
+                (ps.play_generation, ps.current_item, ps.playlist.len())
+            };
+            if prefetch_tracks > 0 && prev_upcoming != Some(upcoming) {
+                prefetch_upcoming(&prefetch_ps, prefetch_tracks);
+                prev_upcoming = Some(upcoming);
+            }
+            // woken early when playback is waiting on a track to be opened
+            let _ = open_requests.recv_timeout(PREFETCH_POLL_INTERVAL);
+        }
+    });
+
+    let subscribers: Arc<Mutex<Vec<EventSubscriber>>> = Arc::new(Mutex::new(Vec::new()));
+
+    #[cfg(not(feature = "notifications"))]
+    if config.notify_track_changes {
+        error!("notify_track_changes needs pjp built with the notifications feature");
+    }
+
+    // shared by everything that sends events, so ids stay unique
+    let next_sse_id = Arc::new(AtomicU32::new(0));
+
+    if config.position_event_interval_secs > 0.0 {
+        let position_subs = subscribers.clone();
+        let position_sse_id = next_sse_id.clone();
+        let position = player_state_mutex.lock().unwrap().position.clone();
+        let interval = Duration::from_secs_f64(config.position_event_interval_secs);
+        thread::spawn(move || {
+            send_position_events(&position, interval, |event| {
+                let data = serde_json::to_string(event).unwrap();
+                let id = position_sse_id.fetch_add(1, Ordering::Relaxed);
+                let mut subs = position_subs.lock().unwrap();
+                subs.retain_mut(|res| keep_subscriber(res.send_sse(id, "position", &data)));
+                true
+            });
+        });
+    }
 
     let update_loop_ps = player_state_mutex.clone();
     let update_loop_subs = subscribers.clone();
+    let player_events = player_state_mutex.lock().unwrap().subscribe();
+    #[cfg(feature = "notifications")]
+    let mut notifier = config
+        .notify_track_changes
+        .then(notifications::TrackChangeNotifier::new);
+    let update_loop_sse_id = next_sse_id.clone();
     thread::spawn(move || {
-        let mut sse_id = 0;
-        let mut prev_state = update_loop_ps.lock().unwrap().state;
-        let mut prev_playlist_len = update_loop_ps.lock().unwrap().playlist.len();
+        let mut last_sent = Instant::now();
 
-        // send now-playing events every 5 seconds
+        // forward changes to playback as they happen, and what's playing every 5 seconds
+        // in between so subscribers see the elapsed time move
         loop {
-            thread::sleep(std::time::Duration::from_secs(5));
-            debug!(
-                "sending event to {} subs",
-                update_loop_subs.lock().unwrap().len()
-            );
-            let mut ps = update_loop_ps.lock().unwrap();
+            let event = match player_events.recv_timeout(NOW_PLAYING_INTERVAL) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => update_loop_ps
+                    .lock()
+                    .unwrap()
+                    .now_playing()
+                    .map(|now_playing| PlayerEvent::NowPlaying(Box::new(now_playing))),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
 
-            if let Some(now_playing) = ps.now_playing() {
-                let now_playing_str = serde_json::to_string(&now_playing).unwrap();
-                update_loop_subs.lock().unwrap().retain_mut(|res| {
-                    match res.send_sse(sse_id, "now-playing", &now_playing_str) {
-                        Ok(_) => true,
-                        Err(err) => {
-                            info!("removing subscriber: {}", err);
-                            false
-                        }
-                    }
-                });
-                sse_id += 1;
-            } else if ps.playlist.len() == 0 && prev_playlist_len > 0 {
-                update_loop_subs.lock().unwrap().retain_mut(|res| {
-                    match res.send_sse(sse_id, "playlist-empty", "") {
-                        Ok(_) => true,
-                        Err(err) => {
-                            info!("removing subscriber: {}", err);
-                            false
-                        }
-                    }
-                });
-                sse_id += 1;
-            } else if prev_state == PlaybackState::Playing && ps.state == PlaybackState::Paused {
-                update_loop_subs.lock().unwrap().retain_mut(|res| {
-                    match res.send_sse(sse_id, "paused", "") {
-                        Ok(_) => true,
-                        Err(err) => {
-                            info!("removing subscriber: {}", err);
-                            false
-                        }
-                    }
-                });
-                sse_id += 1;
+            #[cfg(feature = "notifications")]
+            if let (Some(notifier), Some(PlayerEvent::NowPlaying(now_playing))) =
+                (notifier.as_mut(), &event)
+            {
+                notifier.now_playing(now_playing);
             }
 
-            prev_state = ps.state;
-            prev_playlist_len = ps.playlist.len();
+            // the player state lock isn't held here, so a slow client can't hold up playback or
+            // other requests
+            let mut subs = update_loop_subs.lock().unwrap();
+            debug!("sending event to {} subs", subs.len());
+            match event {
+                Some(event) => {
+                    let id = update_loop_sse_id.fetch_add(1, Ordering::Relaxed);
+                    let data = event.data();
+                    subs.retain_mut(|res| keep_subscriber(res.send_sse(id, event.name(), &data)));
+                    last_sent = Instant::now();
+                }
+                None if last_sent.elapsed() >= SSE_KEEP_ALIVE_INTERVAL => {
+                    subs.retain_mut(|res| keep_subscriber(res.send_sse_comment("keep-alive")));
+                    last_sent = Instant::now();
+                }
+                None => {}
+            }
         }
     });
 
-    for stream in listener.incoming() {
-        let mut should_save = false;
-        let mut stream = stream.unwrap();
+    let mut thumbnail_cache = cover_art::ThumbnailCache::new();
+    let position = player_state_mutex.lock().unwrap().position.clone();
+    let status_waiters = Arc::new(AtomicUsize::new(0));
 
-        {
-            let mut player_state = ps.lock().unwrap();
-
-            let (req, mut res) = web_framework::handle_connection(stream);
-
-            match req {
-                Ok(req) => match (&req.method, req.path.as_str(), &req) {
-                    (HttpMethod::Get, "/status", _) => {
-                        let status = PlayerStatusResponse {
-                            state: match player_state.state {
-                                PlaybackState::Paused => "paused".to_string(),
-                                PlaybackState::Playing => "playing".to_string(),
-                            },
-                            current_item: player_state.current_item,
-                            current_offset: player_state.current_offset as f64 / 44100.0,
-                            playlist: player_state
-                                .playlist
-                                .iter_mut()
-                                .map(|src| src.get_metadata())
-                                .collect(),
-                        };
-
-                        res.set_json(&status);
-                        res.response_code = HttpResponseCode::Ok;
-                    }
-                    (HttpMethod::Post, "/clear", _) => {
-                        player_state.clear();
-                        should_save = true;
-                        res.response_code = HttpResponseCode::Ok;
-                    }
-                    (HttpMethod::Post, "/next", _) => {
-                        player_state.next();
-                        should_save = true;
-                        res.response_code = HttpResponseCode::Ok;
-                    }
-                    (HttpMethod::Post, "/pause", _) => {
-                        player_state.pause();
-                        should_save = true;
-                        res.response_code = HttpResponseCode::Ok;
-                    }
-                    (HttpMethod::Post, "/play", _) => {
-                        player_state.play();
-                        should_save = true;
-                        res.response_code = HttpResponseCode::Ok;
-                    }
-                    (HttpMethod::Post, "/toggle", _) => {
-                        player_state.toggle();
-                        should_save = true;
-                        res.response_code = HttpResponseCode::Ok;
-                    }
-                    (HttpMethod::Post, "/add", req) => {
-                        match serde_json::from_str(req.body.as_str()) {
-                            Ok(paths) => {
-                                player_state.add_tracks(paths);
-                                should_save = true;
+    for (req, mut res) in web_framework::serve(listener, config.max_body_bytes) {
+        if let Some(origin) = &config.cors_origin {
+            res.allow_origin(origin);
+        }
+        // preflights can't carry credentials, and status is harmless to read
+        if let (Ok(req), Some(token)) = (&req, &config.auth_token) {
+            let exempt = matches!(
+                (&req.method, req.path.as_str()),
+                (HttpMethod::Options, _) | (HttpMethod::Get, "/status")
+            );
+            if !exempt && !req.has_bearer_token(token) {
+                res.set_error(
+                    HttpResponseCode::Unauthorized,
+                    "missing or wrong bearer token",
+                );
+                continue;
+            }
+        }
+        // endpoints that don't need the player state lock
+        if let Ok(req) = &req {
+            match (&req.method, req.path.as_str()) {
+                (HttpMethod::Get, "/status") if req.query.contains_key("wait") => {
+                    // the wait happens on its own thread, so other requests aren't held up
+                    let mut res = res;
+                    let wait = req.query["wait"].parse::<f64>().ok();
+                    let since = req.query.get("since").map(|since| since.parse::<u32>());
+                    match (wait, since.transpose()) {
+                        (Some(wait), Ok(_))
+                            if wait >= 0.0
+                                && status_waiters.load(Ordering::Acquire) >= MAX_STATUS_WAITERS =>
+                        {
+                            res.set_error(
+                                HttpResponseCode::ServiceUnavailable,
+                                "too many requests are waiting on the status",
+                            );
+                        }
+                        (Some(wait), Ok(since)) if wait >= 0.0 => {
+                            status_waiters.fetch_add(1, Ordering::AcqRel);
+                            let wait = Duration::from_secs_f64(wait.min(MAX_STATUS_WAIT_SECS));
+                            let fields: Option<Vec<String>> = status_fields(req)
+                                .map(|fields| fields.iter().map(|f| f.to_string()).collect());
+                            let status_ps = player_state_mutex.clone();
+                            let position = position.clone();
+                            let status_waiters = status_waiters.clone();
+                            thread::spawn(move || {
+                                position.wait_for_change(since, wait);
+                                let fields: Option<Vec<&str>> = fields
+                                    .as_ref()
+                                    .map(|fields| fields.iter().map(String::as_str).collect());
+                                if wants_playlist(fields.as_deref()) {
+                                    read_tags(&status_ps);
+                                }
+                                let status = status_ps.lock().unwrap().status(fields.as_deref());
                                 res.response_code = HttpResponseCode::Ok;
-                            }
-                            Err(err) => {
-                                error!("error parsing json: {} {}", err, req.body);
-                                res.response_code = HttpResponseCode::BadRequest;
-                            }
+                                res.set_json(&status);
+                                drop(res);
+                                status_waiters.fetch_sub(1, Ordering::AcqRel);
+                            });
+                        }
+                        _ => {
+                            res.set_error(
+                                HttpResponseCode::BadRequest,
+                                "wait must be seconds and since a frame offset",
+                            );
                         }
                     }
-                    (HttpMethod::Post, "/skip-to", req) => {
-                        match serde_json::from_str(req.body.as_str()) {
-                            Ok(index) => {
-                                player_state.skip_to(index);
-                                should_save = true;
-                                res.response_code = HttpResponseCode::Ok;
-                            }
-                            Err(err) => {
-                                error!("error parsing json: {} {}", err, req.body);
-                                res.response_code = HttpResponseCode::BadRequest;
-                            }
+                    continue;
+                }
+                (HttpMethod::Get, "/status") if wants_playlist(status_fields(req).as_deref()) => {
+                    // answered below; just read the tags first, with the lock released
+                    read_tags(&player_state_mutex);
+                }
+                (HttpMethod::Get, "/elapsed") => {
+                    let mut res = res;
+                    match position.elapsed() {
+                        Some(elapsed) => {
+                            res.set_json(&elapsed);
+                            res.response_code = HttpResponseCode::Ok;
+                        }
+                        None => {
+                            res.response_code = HttpResponseCode::NoContent;
                         }
                     }
-                    (HttpMethod::Get, "/events", req) => match req.headers.get("accept") {
+                    continue;
+                }
+                (HttpMethod::Options, _) => {
+                    // a preflight; the CORS headers are the answer
+                    let mut res = res;
+                    res.response_code = HttpResponseCode::NoContent;
+                    continue;
+                }
+                (HttpMethod::Post, "/shutdown") => {
+                    // answer before going away, since nothing's left to answer after
+                    let mut res = res;
+                    res.response_code = HttpResponseCode::NoContent;
+                    drop(res);
+                    info!("shutting down");
+                    break;
+                }
+                (HttpMethod::Get, "/ping") => {
+                    let mut res = res;
+                    res.set_json(&callback_rate.report());
+                    res.response_code = HttpResponseCode::Ok;
+                    continue;
+                }
+                (HttpMethod::Get, "/events") => {
+                    // the update loop thread owns the connection from here on
+                    let mut res = res;
+                    match req.headers.get("accept") {
                         Some(accept) if accept == "text/event-stream" => {
                             res.response_code = HttpResponseCode::Ok;
                             match res.prep_sse() {
-                                Ok(_) => {
-                                    subscribers.lock().unwrap().push(res);
-                                }
+                                Ok(_) => match EventSubscriber::new(res, &req.query) {
+                                    Ok(sub) => subscribers.lock().unwrap().push(sub),
+                                    Err(err) => {
+                                        error!("error subscribing to events: {}", err);
+                                    }
+                                },
                                 Err(err) => {
                                     error!("error preparing sse: {}", err);
                                 }
@@ -343,19 +1151,49 @@ fn run_pjp() -> Result<(), coreaudio::Error> {
                         _ => {
                             res.response_code = HttpResponseCode::BadRequest;
                         }
-                    },
-                    _ => {
-                        res.response_code = HttpResponseCode::NotFound;
                     }
-                },
-                Err(_) => {
-                    error!("error parsing request");
+                    continue;
+                }
+                (HttpMethod::Get, "/stream.wav") => {
+                    // the stream's thread owns the connection from here on
+                    monitor::spawn_stream(monitor.clone(), res);
+                    continue;
+                }
+                (HttpMethod::Get, "/wav-header") => {
+                    let mut res = res;
+                    send_wav_header(&player_state_mutex, req, &mut res);
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        let after = {
+            let mut player_state = ps.lock().unwrap();
+            handle_request(&mut player_state, format.channels, req, res)
+        }; // player_state lock scope ends here
+
+        if !after.added_ids.is_empty() {
+            let prune_ps = player_state_mutex.clone();
+            thread::spawn(move || prune_missing(&prune_ps, &after.added_ids));
+        }
+
+        // resizing happens after releasing the player state lock
+        if let Some((mut res, filename, cover, size)) = after.cover {
+            let cover = match size {
+                Some(size) => thumbnail_cache.get(&filename, &cover, size),
+                None => Ok(cover),
+            };
+            match cover {
+                Ok(cover) => res.set_body(&cover.media_type, cover.data.clone()),
+                Err(err) => {
+                    error!("error resizing cover for {}: {}", filename, err);
                     res.response_code = HttpResponseCode::InternalServerError;
                 }
             }
-        } // player_state lock scope ends here
+        }
 
-        if should_save {
+        if after.save {
             let save_res = save_json("player_state", &ps);
             if save_res.is_err() {
                 error!("error saving player state: {:?}", save_res);
@@ -363,10 +1201,121 @@ fn run_pjp() -> Result<(), coreaudio::Error> {
         }
     }
 
-    Ok(())
+    player_state::shut_down(
+        &player_state_mutex,
+        || output.stop(),
+        |ps| save_json("player_state", ps),
+    )
 }
 
 fn main() {
     env_logger::init();
     run_pjp().unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+
+    use std::sync::Mutex;
+
+    use super::{handle_request, send_wav_header, AfterUnlock};
+    use crate::player_state::PlayerState;
+    use crate::web_framework::{self, HttpRequest, HttpResponse, RequestError};
+
+    /// Reads `request` off a connection and answers it with `handle`. Returns the response as
+    /// the client got it.
+    fn respond(
+        request: &str,
+        handle: impl FnOnce(Result<HttpRequest, RequestError>, HttpResponse),
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let requests = web_framework::serve(listener, web_framework::DEFAULT_MAX_BODY_BYTES);
+        client.write_all(request.as_bytes()).unwrap();
+        // so the connection closes once the response is sent
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let (req, res) = requests.recv().unwrap();
+        handle(req, res);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    /// Answers `request` with `handle_request`, returning what was left to do after too
+    fn send(player_state: &mut PlayerState, request: &str) -> (String, AfterUnlock) {
+        let mut after = None;
+        let response = respond(request, |req, res| {
+            after = Some(handle_request(player_state, 2, req, res));
+        });
+        (response, after.unwrap())
+    }
+
+    /// The JSON body of `response`
+    fn json_body(response: &str) -> serde_json::Value {
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn adding_an_empty_list_saves_nothing() {
+        let mut ps = PlayerState::new();
+        let events = ps.subscribe();
+
+        let (response, after) = send(&mut ps, "POST /add HTTP/1.1\r\nContent-Length: 2\r\n\r\n[]");
+        assert!(response.starts_with("HTTP/1.1 204 "), "{}", response);
+        assert!(!after.save);
+        assert!(after.added_ids.is_empty());
+        assert!(ps.playlist.is_empty());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn answers_bad_bodies_with_json_errors() {
+        let mut ps = PlayerState::new();
+        for request in [
+            "POST /add HTTP/1.1\r\nContent-Length: 9\r\n\r\nnot json!",
+            "POST /skip-to HTTP/1.1\r\nContent-Length: 4\r\n\r\n\"2\"x",
+        ] {
+            let (response, after) = send(&mut ps, request);
+            assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+            assert!(response.contains("Content-Type: application/json"));
+            let body = json_body(&response);
+            assert!(body["error"]
+                .as_str()
+                .is_some_and(|error| !error.is_empty()));
+            assert!(!after.save);
+        }
+    }
+
+    #[test]
+    fn reads_wav_headers_under_the_library_roots() {
+        let resources = concat!(env!("CARGO_MANIFEST_DIR"), "/resources");
+        let mut ps = PlayerState::new();
+        ps.set_library_roots(&[resources.to_string()]);
+        let ps = Mutex::new(ps);
+        let wav_header = |path: &str| {
+            respond(
+                &format!("GET /wav-header?path={} HTTP/1.1\r\n\r\n", path),
+                |req, mut res| send_wav_header(&ps, &req.unwrap(), &mut res),
+            )
+        };
+
+        let response = wav_header(&format!("{}/ports.wav", resources));
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        let header = json_body(&response);
+        assert_eq!(header["sample_rate"], 44100);
+        assert_eq!(header["number_of_channels"], 1);
+        assert_eq!(header["bits_per_sample"], 16);
+        assert_eq!(header["data_size"], 328982);
+
+        let response = wav_header(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"));
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+        assert_eq!(
+            json_body(&response)["error"],
+            "path isn't under a library root"
+        );
+    }
+}
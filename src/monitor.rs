@@ -0,0 +1,201 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::info;
+
+use crate::web_framework::HttpResponse;
+
+/// How often a monitor stream sends whatever has been rendered since the last send
+const SEND_INTERVAL: Duration = Duration::from_millis(20);
+
+struct Ring {
+    /// Interleaved samples, `capacity` frames long
+    samples: Vec<f32>,
+    /// Total frames ever captured; the newest frame is at `(written - 1) % capacity`
+    written: u64,
+}
+
+/// Keeps the last couple of seconds of rendered output so it can be streamed to listeners over
+/// http. Capturing never blocks the render callback: if a listener is copying out of the ring at
+/// that moment, that buffer is dropped from the monitor stream.
+pub struct Monitor {
+    pub channels: usize,
+    pub sample_rate: u32,
+    capacity: usize,
+    ring: Mutex<Ring>,
+    listeners: AtomicUsize,
+}
+
+impl Monitor {
+    pub fn new(channels: usize, sample_rate: u32) -> Monitor {
+        let capacity = sample_rate as usize * 2;
+        Monitor {
+            channels,
+            sample_rate,
+            capacity,
+            ring: Mutex::new(Ring {
+                samples: vec![0.0; capacity * channels],
+                written: 0,
+            }),
+            listeners: AtomicUsize::new(0),
+        }
+    }
+
+    /// Copies the first `num_frames` frames of `out` into the ring. Called from the render
+    /// callback, so this doesn't allocate or wait.
+    pub fn capture(&self, out: &[Vec<f32>], num_frames: usize) {
+        if self.listeners.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let mut ring = match self.ring.try_lock() {
+            Ok(ring) => ring,
+            Err(_) => return,
+        };
+        let start = ring.written;
+        for frame in 0..num_frames {
+            let index = ((start + frame as u64) % self.capacity as u64) as usize * self.channels;
+            for (channel, samples) in out.iter().take(self.channels).enumerate() {
+                ring.samples[index + channel] = samples[frame];
+            }
+        }
+        ring.written += num_frames as u64;
+    }
+
+    /// Appends the interleaved frames captured since `position` to `dest` and moves `position`
+    /// up to date. A listener that's fallen more than the ring's length behind skips ahead.
+    fn read_since(&self, position: &mut u64, dest: &mut Vec<f32>) {
+        let ring = self.ring.lock().unwrap();
+        if ring.written - *position > self.capacity as u64 {
+            *position = ring.written - self.capacity as u64;
+        }
+        for frame in *position..ring.written {
+            let index = (frame % self.capacity as u64) as usize * self.channels;
+            dest.extend_from_slice(&ring.samples[index..index + self.channels]);
+        }
+        *position = ring.written;
+    }
+
+    fn written(&self) -> u64 {
+        self.ring.lock().unwrap().written
+    }
+}
+
+/// Header for a 16-bit PCM wav stream of unknown length
+pub fn wav_stream_header(channels: usize, sample_rate: u32) -> Vec<u8> {
+    let bytes_per_frame = channels as u32 * 2;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&(channels as u16).to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * bytes_per_frame).to_le_bytes());
+    header.extend_from_slice(&(bytes_per_frame as u16).to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header
+}
+
+/// Streams the live output to `res` as a wav file on its own thread, until the client
+/// disconnects
+pub fn spawn_stream(monitor: Arc<Monitor>, mut res: HttpResponse) {
+    thread::spawn(move || {
+        monitor.listeners.fetch_add(1, Ordering::AcqRel);
+        let result = send_stream(&monitor, &mut res);
+        monitor.listeners.fetch_sub(1, Ordering::AcqRel);
+        if let Err(err) = result {
+            info!("monitor stream closed: {}", err);
+        }
+    });
+}
+
+fn send_stream(monitor: &Monitor, res: &mut HttpResponse) -> Result<(), Box<dyn Error>> {
+    let mut position = monitor.written();
+    let mut samples = Vec::new();
+    let mut bytes = wav_stream_header(monitor.channels, monitor.sample_rate);
+
+    res.prep_stream("audio/wav")?;
+    loop {
+        res.send_body_chunk(&bytes)?;
+        thread::sleep(SEND_INTERVAL);
+
+        samples.clear();
+        bytes.clear();
+        monitor.read_since(&mut position, &mut samples);
+        for sample in samples.iter() {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{spawn_stream, Monitor};
+    use crate::web_framework::HttpResponse;
+
+    #[test]
+    fn streams_captured_output_as_wav() {
+        let monitor = Arc::new(Monitor::new(2, 48000));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        spawn_stream(monitor.clone(), HttpResponse::new(stream));
+
+        // skip the http headers
+        let mut headers = Vec::new();
+        let mut byte = [0u8];
+        while !headers.ends_with(b"\r\n\r\n") {
+            client.read_exact(&mut byte).unwrap();
+            headers.push(byte[0]);
+        }
+        let headers = String::from_utf8(headers).unwrap();
+        assert!(headers.contains("Content-Type: audio/wav\r\n"));
+
+        let mut wav_header = [0u8; 44];
+        client.read_exact(&mut wav_header).unwrap();
+        assert_eq!(&wav_header[0..4], b"RIFF");
+        assert_eq!(u16::from_le_bytes([wav_header[22], wav_header[23]]), 2);
+        assert_eq!(
+            u32::from_le_bytes(wav_header[24..28].try_into().unwrap()),
+            48000
+        );
+        assert_eq!(u16::from_le_bytes([wav_header[34], wav_header[35]]), 16);
+
+        // the stream is live from when the client connected
+        let out = vec![vec![0.5; 256], vec![-0.5; 256]];
+        for _ in 0..10 {
+            monitor.capture(&out, 256);
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let mut pcm = [0u8; 4 * 256];
+        client.read_exact(&mut pcm).unwrap();
+        for frame in pcm.chunks(4) {
+            let left = i16::from_le_bytes([frame[0], frame[1]]);
+            let right = i16::from_le_bytes([frame[2], frame[3]]);
+            assert_eq!(left, (0.5 * i16::MAX as f32) as i16);
+            assert_eq!(right, (-0.5 * i16::MAX as f32) as i16);
+        }
+    }
+
+    #[test]
+    fn doesnt_capture_without_listeners() {
+        let monitor = Monitor::new(1, 44100);
+        monitor.capture(&[vec![0.5; 256]], 256);
+        assert_eq!(monitor.written(), 0);
+    }
+}
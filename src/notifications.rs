@@ -0,0 +1,148 @@
+use std::io::Write;
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+
+use log::{error, info};
+
+use crate::audio_source::CoverArt;
+use crate::player_state::NowPlaying;
+
+/// A desktop notification announcing a track
+#[derive(Debug)]
+pub struct Notification {
+    pub title: String,
+    pub subtitle: String,
+    pub message: String,
+    pub cover: Option<Arc<CoverArt>>,
+}
+
+impl Notification {
+    pub fn from_now_playing(now_playing: &NowPlaying) -> Notification {
+        let track = &now_playing.track;
+        Notification {
+            title: track.title.clone(),
+            subtitle: track.artist.clone(),
+            message: track.album.clone(),
+            cover: track.cover.clone(),
+        }
+    }
+
+    /// AppleScript that posts the notification; it can't show a picture
+    fn applescript(&self) -> String {
+        format!(
+            "display notification {} with title {} subtitle {}",
+            applescript_string(&self.message),
+            applescript_string(&self.title),
+            applescript_string(&self.subtitle),
+        )
+    }
+}
+
+fn applescript_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Announces each play once, from the now-playing updates that also feed the event stream
+pub struct TrackChangeNotifier {
+    prev_generation: Option<u64>,
+}
+
+impl TrackChangeNotifier {
+    pub fn new() -> TrackChangeNotifier {
+        TrackChangeNotifier {
+            prev_generation: None,
+        }
+    }
+
+    pub fn now_playing(&mut self, now_playing: &NowPlaying) {
+        if self.prev_generation != Some(now_playing.generation) {
+            self.prev_generation = Some(now_playing.generation);
+            post(Notification::from_now_playing(now_playing));
+        }
+    }
+}
+
+/// Posts `notification` on its own thread so a slow notifier can't hold up the caller. Uses
+/// `terminal-notifier` to show the cover if it's installed, otherwise plain AppleScript.
+pub fn post(notification: Notification) {
+    thread::spawn(move || {
+        if let Err(err) = post_with_cover(&notification) {
+            info!("falling back to osascript for notifications: {}", err);
+            let result = Command::new("osascript")
+                .arg("-e")
+                .arg(notification.applescript())
+                .status();
+            if let Err(err) = result {
+                error!("error posting notification: {}", err);
+            }
+        }
+    });
+}
+
+fn post_with_cover(notification: &Notification) -> Result<(), Box<dyn std::error::Error>> {
+    let mut command = Command::new("terminal-notifier");
+    command
+        .arg("-title")
+        .arg(&notification.title)
+        .arg("-subtitle")
+        .arg(&notification.subtitle)
+        .arg("-message")
+        .arg(&notification.message);
+    if let Some(cover) = &notification.cover {
+        let extension = if cover.media_type == "image/png" {
+            "png"
+        } else {
+            "jpg"
+        };
+        let path = std::env::temp_dir().join(format!("pjp-notification-cover.{}", extension));
+        std::fs::File::create(&path)?.write_all(&cover.data)?;
+        command.arg("-contentImage").arg(path);
+    }
+    let status = command.status()?;
+    if !status.success() {
+        return Err(format!("terminal-notifier exited with {}", status).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::Notification;
+    use crate::audio_source::{AudioMetadata, CoverArt};
+    use crate::player_state::NowPlaying;
+
+    #[test]
+    fn builds_notification_from_now_playing() {
+        let now_playing = NowPlaying {
+            track: AudioMetadata {
+                dur: 200.0,
+                artist: "Nina \"Simone\"".to_string(),
+                title: "Sinnerman".to_string(),
+                album: "Pastel Blues".to_string(),
+                gain_db: None,
+                peak: None,
+                cover: Some(Arc::new(CoverArt {
+                    media_type: "image/png".to_string(),
+                    data: vec![1, 2, 3],
+                })),
+                tech: None,
+            },
+            elapsed: 0.0,
+            start_ts: 0,
+            generation: 1,
+        };
+
+        let notification = Notification::from_now_playing(&now_playing);
+        assert_eq!(notification.title, "Sinnerman");
+        assert_eq!(notification.subtitle, "Nina \"Simone\"");
+        assert_eq!(notification.message, "Pastel Blues");
+        assert_eq!(notification.cover.unwrap().data, [1, 2, 3]);
+        assert_eq!(
+            Notification::from_now_playing(&now_playing).applescript(),
+            "display notification \"Pastel Blues\" with title \"Sinnerman\" subtitle \"Nina \\\"Simone\\\"\""
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use std::error::Error;
+
+use crate::render::OutputRenderer;
+
+/// Frames per callback to ask the device for, the size Core Audio uses; it may pick another
+#[cfg(feature = "cpal")]
+pub const PREFERRED_BUFFER_FRAMES: u32 = 1024;
+
+/// The rate and channel count an output device plays at
+#[derive(Debug, Clone, Copy)]
+pub struct OutputFormat {
+    pub sample_rate: u32,
+    pub channels: usize,
+}
+
+/// An output device, played from by an `OutputRenderer`. Core Audio on macOS, or cpal with the
+/// `cpal` feature, which runs on Linux and Windows too.
+pub trait AudioOutput {
+    fn format(&self) -> OutputFormat;
+
+    /// Makes `renderer` what fills the device's buffers, in place of any before it. Playback
+    /// doesn't begin until `start`.
+    fn set_renderer(&mut self, renderer: OutputRenderer) -> Result<(), Box<dyn Error>>;
+
+    fn start(&mut self) -> Result<(), Box<dyn Error>>;
+
+    fn stop(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+/// The default output device, through cpal if it's enabled
+pub fn default_output() -> Result<Box<dyn AudioOutput>, Box<dyn Error>> {
+    #[cfg(feature = "cpal")]
+    let output = crate::cpal_output::CpalOutput::new()?;
+    #[cfg(all(target_os = "macos", not(feature = "cpal")))]
+    let output = crate::coreaudio_output::CoreAudioOutput::new()?;
+    Ok(Box::new(output))
+}
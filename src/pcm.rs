@@ -1,6 +1,119 @@
+use crate::audio_source::{AudioBuffer, AudioMetadata, AudioSource};
+
+/// Frames per buffer returned by `get_buffer`
+const BUFFER_FRAMES: u32 = 1024;
+
+/// Audio that's already in memory
 pub struct PCMSource {
     pub samples: Vec<Vec<f32>>,
     pub sample_rate: f64,
     pub length: u32,
     pub offset: u32,
+    buffer: Option<AudioBuffer>,
+    metadata: AudioMetadata,
+}
+
+impl PCMSource {
+    /// `samples` has one Vec per channel, all the same length
+    pub fn new(samples: Vec<Vec<f32>>, sample_rate: f64) -> PCMSource {
+        let length = samples.first().map_or(0, |channel| channel.len()) as u32;
+        PCMSource {
+            samples,
+            sample_rate,
+            length,
+            offset: 0,
+            buffer: None,
+            metadata: AudioMetadata {
+                dur: length as f64 / sample_rate,
+                artist: String::from(""),
+                title: String::from("pcm"),
+                album: String::from(""),
+                gain_db: None,
+                peak: None,
+                cover: None,
+                tech: None,
+            },
+        }
+    }
+}
+
+impl AudioSource for PCMSource {
+    fn get_buffer(&mut self, offset: u32) -> Option<&AudioBuffer> {
+        if offset < self.offset || offset >= self.offset + self.length {
+            return None;
+        }
+
+        let start = (offset - self.offset) / BUFFER_FRAMES * BUFFER_FRAMES;
+        let end = (start + BUFFER_FRAMES).min(self.length);
+
+        let cached = match self.buffer {
+            Some(ref buffer) => buffer.offset == self.offset + start,
+            None => false,
+        };
+        if !cached {
+            self.buffer = Some(AudioBuffer {
+                samples: self
+                    .samples
+                    .iter()
+                    .map(|channel| channel[start as usize..end as usize].to_vec())
+                    .collect(),
+                sample_rate: self.sample_rate,
+                length: end - start,
+                offset: self.offset + start,
+            });
+        }
+        self.buffer.as_ref()
+    }
+
+    fn get_metadata(&mut self) -> &AudioMetadata {
+        &self.metadata
+    }
+
+    fn duration(&mut self) -> Option<f64> {
+        Some(self.metadata.dur)
+    }
+
+    fn sample_rate(&mut self) -> Option<u32> {
+        Some(self.sample_rate as u32)
+    }
+
+    fn channels(&mut self) -> Option<usize> {
+        Some(self.samples.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PCMSource;
+    use crate::audio_source::AudioSource;
+
+    #[test]
+    fn gets_buffers_from_memory() {
+        let ramp: Vec<f32> = (0..3000).map(|i| i as f32 / 3000.0).collect();
+        let inverted: Vec<f32> = ramp.iter().map(|s| -s).collect();
+        let mut src = PCMSource::new(vec![ramp, inverted], 44100.0);
+
+        let buf = src.get_buffer(0).unwrap();
+        assert_eq!(buf.offset, 0);
+        assert_eq!(buf.length, 1024);
+        assert_eq!(buf.samples.len(), 2);
+        assert_eq!(buf.samples[0][10], 10.0 / 3000.0);
+        assert_eq!(buf.samples[1][10], -10.0 / 3000.0);
+
+        let buf = src.get_buffer(2500).unwrap();
+        assert_eq!(buf.offset, 2048);
+        assert_eq!(buf.length, 3000 - 2048);
+        assert_eq!(buf.samples[0][2500 - 2048], 2500.0 / 3000.0);
+
+        assert!(src.get_buffer(3000).is_none());
+        assert!((src.get_metadata().dur - 3000.0 / 44100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn describes_the_samples() {
+        let mut src = PCMSource::new(vec![vec![0.0; 4800]; 3], 48000.0);
+        assert_eq!(src.duration(), Some(0.1));
+        assert_eq!(src.sample_rate(), Some(48000));
+        assert_eq!(src.channels(), Some(3));
+    }
 }
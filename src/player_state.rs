@@ -1,31 +1,352 @@
-use std::borrow::BorrowMut;
+use std::{
+    borrow::BorrowMut,
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
+use log::error;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    audio_file::{self, AudioFileSource},
-    audio_source::{AudioMetadata, AudioSource},
+    archive,
+    audio_file::{self, AudioFileSource, OpenPolicy},
+    audio_source::{AudioMetadata, AudioSource, DEFAULT_SAMPLE_RATE},
+    fade::Fade,
+    storage::{DecodeErrorPolicy, StartupState},
+    transitions::TransitionPolicy,
 };
 
 // TODO?: could be AudioSource in theory, but serialization doesn't make as much sense for all formats.
 // The use case right now is just playing files, anyway.
 type Playlist = Vec<AudioFileSource>;
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
 pub enum PlaybackState {
     Playing,
     Paused,
 }
 
+/// What `next()` does after the current track
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatMode {
+    /// Play through the playlist once, then stop
+    #[default]
+    Off,
+    /// Play the current track again
+    Track,
+    /// Go back to the first track after the last one, without consuming
+    All,
+}
+
+/// Playback position published by the output as it plays each rendered block, so threads that
+/// only need to know where playback is don't have to take the player state lock
+#[derive(Default, Debug)]
+pub struct PlaybackPosition {
+    /// `current_item` in the high 32 bits, frames played of it in the low 32 bits, so a reader
+    /// always sees an offset that belongs to the item it was read with
+    item_and_offset: AtomicU64,
+    /// Id of the current track, which unlike its index doesn't shift as the playlist changes
+    track_id: AtomicU64,
+    playing: AtomicBool,
+    has_track: AtomicBool,
+    /// f64 bits of the current track's duration in seconds; negative if unknown
+    duration: AtomicU64,
+    sample_rate: AtomicU32,
+    /// `wait_for_change` parks on this. Publishing doesn't signal it, since that's done by the
+    /// output's callback, which shouldn't be making system calls; the render thread does.
+    change_lock: Mutex<()>,
+    changed: Condvar,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Elapsed {
+    pub elapsed_secs: f64,
+    pub duration_secs: Option<f64>,
+    pub state: String,
+}
+
+/// Just where playback is, for driving a progress bar without polling
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PositionEvent {
+    pub index: usize,
+    pub elapsed: f64,
+    pub duration: Option<f64>,
+}
+
+impl PlaybackPosition {
+    pub fn publish(
+        &self,
+        current_item: usize,
+        track_id: u64,
+        current_offset: u32,
+        playing: bool,
+        duration: Option<f64>,
+        sample_rate: u32,
+    ) {
+        self.item_and_offset.store(
+            ((current_item as u64) << 32) | current_offset as u64,
+            Ordering::Release,
+        );
+        self.track_id.store(track_id, Ordering::Release);
+        self.playing.store(playing, Ordering::Release);
+        self.has_track.store(true, Ordering::Release);
+        self.duration
+            .store(duration.unwrap_or(-1.0).to_bits(), Ordering::Release);
+        self.sample_rate.store(sample_rate, Ordering::Release);
+    }
+
+    /// Marks that there's nothing to play
+    pub fn publish_empty(&self) {
+        self.item_and_offset.store(0, Ordering::Release);
+        self.playing.store(false, Ordering::Release);
+        self.has_track.store(false, Ordering::Release);
+    }
+
+    /// Sets this to where `other` is
+    pub fn copy_from(&self, other: &PlaybackPosition) {
+        if !other.has_track.load(Ordering::Acquire) {
+            self.publish_empty();
+            return;
+        }
+        let (current_item, current_offset) = other.load();
+        let duration = f64::from_bits(other.duration.load(Ordering::Acquire));
+        self.publish(
+            current_item,
+            other.track_id.load(Ordering::Acquire),
+            current_offset,
+            other.is_playing(),
+            (duration >= 0.0).then_some(duration),
+            other.sample_rate.load(Ordering::Acquire),
+        );
+    }
+
+    /// Returns `(current_item, current_offset)`
+    pub fn load(&self) -> (usize, u32) {
+        let packed = self.item_and_offset.load(Ordering::Acquire);
+        ((packed >> 32) as usize, packed as u32)
+    }
+
+    /// The current track's id and frames played of it, or None when the playlist is empty
+    pub fn track_and_offset(&self) -> Option<(u64, u32)> {
+        if !self.has_track.load(Ordering::Acquire) {
+            return None;
+        }
+        Some((self.track_id.load(Ordering::Acquire), self.load().1))
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.load(Ordering::Acquire)
+    }
+
+    /// Waits up to `timeout` for playback to move on from `since` frames into the current track
+    /// (where it is now if that's not given), to another track, or to start or stop. Returns
+    /// whether it did.
+    pub fn wait_for_change(&self, since: Option<u32>, timeout: Duration) -> bool {
+        let (item, offset) = self.load();
+        let since = since.unwrap_or(offset);
+        let playing = self.is_playing();
+        let deadline = Instant::now() + timeout;
+        let mut guard = self.change_lock.lock().unwrap();
+        loop {
+            let (now_item, now_offset) = self.load();
+            if now_item != item || now_offset != since || self.is_playing() != playing {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            guard = self.changed.wait_timeout(guard, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Wakes every `wait_for_change` to look at the position again
+    pub fn notify_waiters(&self) {
+        let _guard = self.change_lock.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    /// Where playback is, or None unless something is playing
+    pub fn position_event(&self) -> Option<PositionEvent> {
+        if !self.is_playing() || !self.has_track.load(Ordering::Acquire) {
+            return None;
+        }
+        let (index, offset) = self.load();
+        let duration = f64::from_bits(self.duration.load(Ordering::Acquire));
+        Some(PositionEvent {
+            index,
+            elapsed: offset as f64 / self.sample_rate.load(Ordering::Acquire) as f64,
+            duration: if duration >= 0.0 {
+                Some(duration)
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Progress of the current track, or None when the playlist is empty
+    pub fn elapsed(&self) -> Option<Elapsed> {
+        if !self.has_track.load(Ordering::Acquire) {
+            return None;
+        }
+        let (_, offset) = self.load();
+        let duration = f64::from_bits(self.duration.load(Ordering::Acquire));
+        Some(Elapsed {
+            elapsed_secs: offset as f64 / self.sample_rate.load(Ordering::Acquire) as f64,
+            duration_secs: if duration >= 0.0 {
+                Some(duration)
+            } else {
+                None
+            },
+            state: if self.is_playing() {
+                "playing".to_string()
+            } else {
+                "paused".to_string()
+            },
+        })
+    }
+}
+
+/// Calls `send` with the position every `interval` while something is playing, until `send`
+/// returns false. Only reads `position`, so it never waits on the player state lock.
+pub fn send_position_events(
+    position: &PlaybackPosition,
+    interval: Duration,
+    mut send: impl FnMut(&PositionEvent) -> bool,
+) {
+    loop {
+        thread::sleep(interval);
+        if let Some(event) = position.position_event() {
+            if !send(&event) {
+                return;
+            }
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(default)]
 pub struct PlayerState {
+    /// Format of the saved state; missing from states saved before it was versioned
+    #[serde(default)]
+    pub version: u32,
+
     pub state: PlaybackState,
     pub playlist: Playlist,
     pub current_item: usize,
     pub current_offset: u32,
     pub current_item_start_ts: u64,
     pub consume: bool,
+
+    /// Incremented every time a track starts, so separate plays can be told apart even when
+    /// they start in the same second
+    pub play_generation: u64,
+
+    /// Output gain between 0.0 and 1.0
+    pub volume: f32,
+
+    pub transitions: TransitionPolicy,
+
+    /// Pick the next track at random; only applies when not consuming
+    pub shuffle: bool,
+
+    pub repeat: RepeatMode,
+
+    /// Last id given to a track; ids aren't reused
+    pub last_track_id: u64,
+
+    /// xorshift state for picking shuffled tracks, seeded from the clock; never 0
+    #[serde(skip)]
+    pub shuffle_rng: u64,
+
+    /// Ids of the tracks shuffle plays next, in order, picked ahead of time so they can be
+    /// listed and prefetched; topped up as it's read
+    #[serde(skip)]
+    pub shuffle_ahead: VecDeque<u64>,
+
+    /// Frames of silence left before the current track starts, for `TransitionMode::Gap`
+    #[serde(skip)]
+    pub gap_remaining: u32,
+
+    /// Gain ramp applied by the render thread, if one is in progress
+    #[serde(skip)]
+    pub fade: Option<Fade>,
+
+    /// Offset to resume from once a fade-to-pause completes
+    #[serde(skip)]
+    pub fade_to_pause_offset: Option<u32>,
+
+    /// Blend into the next track started by `crossfade_now`, if one is in progress
+    #[serde(skip)]
+    pub crossfade: Option<Crossfade>,
+
+    #[serde(skip)]
+    pub position: Arc<PlaybackPosition>,
+
+    /// Output device sample rate; offsets are in frames at this rate
+    #[serde(skip)]
+    pub sample_rate: u32,
+
+    /// Apply ReplayGain tags during playback; set from the config
+    #[serde(skip)]
+    pub replaygain: bool,
+
+    /// Soft-clip the mixed output; set from the config
+    #[serde(skip)]
+    pub limiter: bool,
+
+    /// Length of the ramps on play and pause; set from the config
+    #[serde(skip)]
+    pub play_pause_fade_ms: u32,
+
+    /// The most recent track that stopped because it couldn't be read, and why
+    #[serde(skip)]
+    pub last_error: Option<String>,
+
+    /// Source channel for each output channel; empty plays channels straight through
+    #[serde(skip)]
+    pub channel_map: Vec<usize>,
+
+    /// Canonicalized directories tracks may be added from; empty allows any path
+    #[serde(skip)]
+    pub library_roots: Vec<PathBuf>,
+
+    /// Whether to skip or pause when the current track can't be read; set from the config
+    #[serde(skip)]
+    pub on_decode_error: DecodeErrorPolicy,
+
+    /// Most tracks to keep a decoder open for at once, counting the current one; set from the
+    /// config
+    #[serde(skip, default = "default_max_open_decoders")]
+    pub max_open_decoders: usize,
+
+    /// How patiently track files are opened; see `set_open_policy`
+    #[serde(skip)]
+    pub open_policy: OpenPolicy,
+
+    /// Told about changes to playback as they happen; see `subscribe`
+    #[serde(skip)]
+    event_listeners: Vec<Sender<PlayerEvent>>,
+
+    /// Wakes the thread that opens tracks when playback is waiting on one; see `open_requests`
+    #[serde(skip)]
+    open_requests: Option<SyncSender<()>>,
+
+    /// The current track started before its tags were read, so listeners are told once they are
+    #[serde(skip)]
+    now_playing_pending: bool,
+}
+
+fn default_max_open_decoders() -> usize {
+    DEFAULT_MAX_OPEN_DECODERS
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -33,17 +354,142 @@ pub struct NowPlaying {
     pub track: AudioMetadata,
     pub elapsed: f64,
     pub start_ts: u64,
+    /// `PlayerState::play_generation` when this play started; 0 from older players
+    #[serde(default)]
+    pub generation: u64,
+}
+
+/// A change to playback, as sent to `/events` subscribers
+#[derive(Debug, Clone)]
+pub enum PlayerEvent {
+    NowPlaying(Box<NowPlaying>),
+    PlaylistEmpty,
+    Paused,
+}
+
+impl PlayerEvent {
+    /// The SSE event type
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlayerEvent::NowPlaying(_) => "now-playing",
+            PlayerEvent::PlaylistEmpty => "playlist-empty",
+            PlayerEvent::Paused => "paused",
+        }
+    }
+
+    /// The SSE event data
+    pub fn data(&self) -> String {
+        match self {
+            PlayerEvent::NowPlaying(now_playing) => serde_json::to_string(now_playing).unwrap(),
+            PlayerEvent::PlaylistEmpty | PlayerEvent::Paused => String::new(),
+        }
+    }
+}
+
+/// A blend from the current track into another one, which plays from `offset`
+#[derive(Clone, Copy, Debug)]
+pub struct Crossfade {
+    pub item: usize,
+    pub offset: u32,
+    /// Gain of the incoming track; the current one gets the rest
+    pub fade: Fade,
+}
+
+/// Version written by `GET /playlist.json`; imports from newer versions are refused
+pub const PLAYLIST_EXPORT_VERSION: u32 = 1;
+
+/// Version of the saved player state. Bump it when the format changes, with a step in
+/// `PlayerState::from_saved` to bring older states up to date.
+pub const PLAYER_STATE_VERSION: u32 = 1;
+
+pub const DEFAULT_MAX_OPEN_DECODERS: usize = 4;
+
+/// Going back further into a track than this restarts it rather than going to the one before
+const PREV_RESTART_SECS: f64 = 3.0;
+
+/// A queued track and its settings, as exported by `GET /playlist.json`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlaylistItem {
+    pub path: String,
+    #[serde(default)]
+    pub start_offset: Option<f64>,
+    #[serde(default)]
+    pub end_offset: Option<f64>,
+}
+
+/// The queue without any playback state, for backing up and restoring
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlaylistExport {
+    pub version: u32,
+    pub items: Vec<PlaylistItem>,
+}
+
+/// What `/status` reports
+#[derive(Serialize)]
+struct PlayerStatus<'a> {
+    state: &'static str,
+    current_item: usize,
+    current_id: Option<u64>,
+    current_offset: f64,
+    volume: f32,
+    transitions: TransitionPolicy,
+    shuffle: bool,
+    repeat: RepeatMode,
+    consume: bool,
+    /// Why the last track that failed to play was skipped or paused on
+    last_error: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    playlist: Option<Vec<PlaylistEntry<'a>>>,
+}
+
+/// A track in the status's playlist: its metadata, plus the id it can be picked out by
+#[derive(Serialize)]
+struct PlaylistEntry<'a> {
+    id: u64,
+    #[serde(flatten)]
+    metadata: &'a AudioMetadata,
 }
 
 impl Default for PlayerState {
     fn default() -> Self {
         PlayerState {
+            version: PLAYER_STATE_VERSION,
             state: PlaybackState::Paused,
             playlist: vec![],
             current_item: 0,
             current_offset: 0,
             current_item_start_ts: 0,
             consume: true,
+            play_generation: 0,
+            volume: 1.0,
+            transitions: TransitionPolicy::default(),
+            shuffle: false,
+            repeat: RepeatMode::Off,
+            last_track_id: 0,
+            shuffle_rng: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+                | 1,
+            shuffle_ahead: VecDeque::new(),
+            gap_remaining: 0,
+            fade: None,
+            fade_to_pause_offset: None,
+            crossfade: None,
+            position: Arc::new(PlaybackPosition::default()),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            replaygain: false,
+            limiter: false,
+            play_pause_fade_ms: 0,
+            last_error: None,
+            channel_map: vec![],
+            library_roots: vec![],
+            on_decode_error: DecodeErrorPolicy::Skip,
+            max_open_decoders: DEFAULT_MAX_OPEN_DECODERS,
+            open_policy: OpenPolicy::default(),
+            event_listeners: vec![],
+            open_requests: None,
+            now_playing_pending: false,
         }
     }
 }
@@ -53,23 +499,201 @@ impl PlayerState {
         PlayerState::default()
     }
 
+    /// Loads a saved state, upgrading it from older versions first. A state from a newer
+    /// version, or one that can't be read, is replaced with a fresh one rather than misread.
+    pub fn from_saved(mut saved: serde_json::Value) -> Self {
+        let version = saved.get("version").and_then(|v| v.as_u64()).unwrap_or(0);
+        if version > PLAYER_STATE_VERSION as u64 {
+            error!(
+                "saved player state is version {}, newer than {}; starting fresh",
+                version, PLAYER_STATE_VERSION
+            );
+            return PlayerState::default();
+        }
+        let fields = match saved.as_object_mut() {
+            Some(fields) => fields,
+            None => {
+                error!("saved player state isn't an object; starting fresh");
+                return PlayerState::default();
+            }
+        };
+        if version < 1 {
+            // settings older saves may not have
+            fields
+                .entry("consume")
+                .or_insert(serde_json::Value::Bool(true));
+            // the old start time is from the last session; let it be set again on play
+            fields.insert("current_item_start_ts".to_string(), 0.into());
+        }
+        fields.insert("version".to_string(), PLAYER_STATE_VERSION.into());
+        match serde_json::from_value(saved) {
+            Ok(ps) => ps,
+            Err(err) => {
+                error!("error reading saved player state: {}; starting fresh", err);
+                PlayerState::default()
+            }
+        }
+    }
+
+    /// Events for every change to playback from here on, until the receiver is dropped
+    pub fn subscribe(&mut self) -> Receiver<PlayerEvent> {
+        let (sender, receiver) = channel();
+        self.event_listeners.push(sender);
+        receiver
+    }
+
+    fn emit(&mut self, event: PlayerEvent) {
+        self.event_listeners
+            .retain(|listener| listener.send(event.clone()).is_ok());
+    }
+
+    /// Wakes whoever took the receiver each time playback waits on a track that isn't open, so
+    /// they can open it with the lock released. Replaces any earlier receiver.
+    pub fn open_requests(&mut self) -> Receiver<()> {
+        let (sender, receiver) = sync_channel(1);
+        self.open_requests = Some(sender);
+        receiver
+    }
+
+    /// Asks for the tracks about to play to be opened; never blocks
+    pub fn request_open(&self) {
+        if let Some(sender) = &self.open_requests {
+            let _ = sender.try_send(());
+        }
+    }
+
+    /// Tells listeners what's playing now that the current track has changed, if anything is.
+    /// Reading tags means opening the file, which isn't done with the lock held, so if they
+    /// haven't been read yet listeners are told once they have been.
+    fn emit_now_playing(&mut self) {
+        self.now_playing_pending = false;
+        if self.event_listeners.is_empty() {
+            return;
+        }
+        let tags_read = self
+            .playlist
+            .get(self.current_item)
+            .is_none_or(|src| src.cached_metadata().is_some());
+        if !tags_read && self.state == PlaybackState::Playing {
+            self.now_playing_pending = true;
+            self.request_open();
+            return;
+        }
+        if let Some(now_playing) = self.now_playing() {
+            self.emit(PlayerEvent::NowPlaying(Box::new(now_playing)));
+        } else if self.playlist.is_empty() {
+            self.emit(PlayerEvent::PlaylistEmpty);
+        }
+    }
+
+    /// Frames played of the current track, counted from its trim-in point
+    pub fn played_frames(&self) -> u32 {
+        match self.playlist.get(self.current_item) {
+            Some(src) => self.current_offset.saturating_sub(src.trim_start_frame()),
+            None => self.current_offset,
+        }
+    }
+
+    /// Makes the current position visible through `position`: the player state's own once the
+    /// output has played up to here, or one queued with the audio that gets it there
+    pub fn publish_position(&self, position: &PlaybackPosition) {
+        match self.playlist.get(self.current_item) {
+            Some(src) => position.publish(
+                self.current_item,
+                src.id,
+                self.played_frames(),
+                self.state == PlaybackState::Playing,
+                src.cached_metadata().map(|metadata| metadata.dur),
+                self.sample_rate,
+            ),
+            None => position.publish_empty(),
+        }
+    }
+
+    /// The current track and frames played of it as heard. The output plays what's rendered a
+    /// little after the player state moves on, so this can trail `current_item` and
+    /// `played_frames`; a current track that hasn't been heard yet is at 0.
+    fn heard_position(&self) -> (usize, u32) {
+        match self.position.track_and_offset() {
+            Some((id, offset)) => match self.index_of(id) {
+                Some(index) => (index, offset),
+                None => (self.current_item, 0),
+            },
+            None => (self.current_item, 0),
+        }
+    }
+
+    /// Sets how patiently tracks' files are opened, for every track from here on
+    pub fn set_open_policy(&mut self, policy: OpenPolicy) -> &mut Self {
+        self.open_policy = policy;
+        for src in self.playlist.iter_mut() {
+            src.set_open_policy(policy);
+        }
+        self
+    }
+
+    /// Sets the output rate that tracks are resampled to
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> &mut Self {
+        self.sample_rate = sample_rate;
+        for src in self.playlist.iter_mut() {
+            src.set_output_sample_rate(sample_rate);
+        }
+        self
+    }
+
     pub fn clear(&mut self) -> &mut Self {
+        self.gap_remaining = 0;
+        self.crossfade = None;
         self.playlist.clear();
         self.current_item = 0;
         self.current_offset = 0;
         self.current_item_start_ts = 0;
+        self.emit(PlayerEvent::PlaylistEmpty);
         self
     }
 
     pub fn next(&mut self) -> &mut Self {
         if !self.playlist.is_empty() {
-            self.current_offset = 0;
-            if self.consume {
-                self.playlist.remove(self.current_item);
-            } else {
-                self.current_item = (self.current_item + 1) % self.playlist.len();
+            match self.repeat {
+                RepeatMode::Track => {}
+                // never consumes, so there's something to come back around to
+                RepeatMode::All => self.current_item = self.following_item(),
+                RepeatMode::Off if self.consume => {
+                    self.playlist.remove(self.current_item);
+                    if self.current_item >= self.playlist.len() {
+                        self.current_item = 0;
+                    }
+                }
+                RepeatMode::Off => {
+                    let at_end = !self.shuffle && self.current_item + 1 >= self.playlist.len();
+                    self.current_item = self.following_item();
+                    if at_end {
+                        // stop, ready to play the playlist again from the top
+                        self.pause();
+                    }
+                }
             }
         }
+        self.restart_current();
+        self
+    }
+
+    /// Starts the current track over from the top, as a new play
+    fn restart_current(&mut self) {
+        let current_id = self.playlist.get(self.current_item).map(|src| src.id);
+        if self.shuffle_ahead.front().copied() == current_id {
+            self.shuffle_ahead.pop_front();
+        }
+        self.gap_remaining = 0;
+        self.crossfade = None;
+        if self.fade_to_pause_offset.is_some() {
+            // the faded-out track is gone; resume the new one from the start
+            self.fade_to_pause_offset = Some(0);
+        }
+        self.current_offset = 0;
+        if !self.playlist.is_empty() {
+            self.play_generation += 1;
+        }
         self.current_item_start_ts =
             if !self.playlist.is_empty() && self.state == PlaybackState::Playing {
                 std::time::SystemTime::now()
@@ -79,45 +703,320 @@ impl PlayerState {
             } else {
                 0
             };
-        self
+        self.release_inactive();
+        self.emit_now_playing();
     }
 
-    pub fn skip_to(&mut self, index: usize) -> &mut Self {
-        if index < self.playlist.len() && index < self.current_item {
-            // skipping to a previous song; never consume
-            self.current_item = index;
-            self.current_offset = 0;
-            if self.state == PlaybackState::Playing {
-                self.current_item_start_ts = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
+    /// Closes the tracks other than the current and next ones, keeping just their starts, so a
+    /// long queue doesn't hold a decoder open for every track that's been touched
+    fn release_inactive(&mut self) {
+        let next = if self.shuffling() {
+            self.shuffle_ahead
+                .front()
+                .and_then(|id| self.index_of(*id))
+                .unwrap_or(self.current_item)
+        } else {
+            self.current_item + 1
+        };
+        let crossfading = self.crossfade.as_ref().map(|crossfade| crossfade.item);
+        for (index, src) in self.playlist.iter_mut().enumerate() {
+            if index != self.current_item && index != next && Some(index) != crossfading {
+                src.release();
+            }
+        }
+    }
+
+    /// Closes the open tracks furthest ahead of the current one, past `max_open_decoders`. The
+    /// current track and any being crossfaded into stay open whatever the limit.
+    pub fn close_excess_decoders(&mut self) {
+        let len = self.playlist.len();
+        let current_item = self.current_item;
+        let crossfading = self.crossfade.as_ref().map(|crossfade| crossfade.item);
+        let mut open: Vec<usize> = (0..len)
+            .filter(|index| *index != current_item && Some(*index) != crossfading)
+            .filter(|index| self.playlist[*index].is_open())
+            .collect();
+        // tracks just behind the current one are the furthest from playing again
+        open.sort_by_key(|index| (index + len - current_item) % len);
+        let keep = self
+            .max_open_decoders
+            .saturating_sub(1 + crossfading.is_some() as usize);
+        for index in open.into_iter().skip(keep) {
+            self.playlist[index].release();
+        }
+    }
+
+    /// Makes `item` the current track, playing from `offset`, once a crossfade into it is done
+    pub fn finish_crossfade(&mut self, item: usize, offset: u32) -> &mut Self {
+        let outgoing = self.current_item;
+        self.current_item = item;
+        if self.consume && self.repeat == RepeatMode::Off {
+            self.playlist.remove(outgoing);
+            if outgoing < self.current_item {
+                self.current_item -= 1;
             }
-        } else if index > self.current_item {
-            let diff = index - self.current_item;
-            for _ in 0..diff {
-                // toggle consume behavior if necessary
-                self.next();
+        }
+        self.restart_current();
+        self.current_offset = offset;
+        self
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) -> &mut Self {
+        self.repeat = repeat;
+        self
+    }
+
+    /// Whether tracks leave the playlist once they've played. Only changes what `next` and the
+    /// like do from here on; the playlist and current track stay as they are.
+    pub fn set_consume(&mut self, on: bool) -> &mut Self {
+        self.consume = on;
+        self
+    }
+
+    pub fn set_shuffle(&mut self, on: bool) -> &mut Self {
+        self.shuffle = on;
+        self.shuffle_ahead.clear();
+        self
+    }
+
+    /// Whether the next track is picked at random
+    fn shuffling(&self) -> bool {
+        self.shuffle
+            && self.playlist.len() > 1
+            && match self.repeat {
+                RepeatMode::Track => false,
+                RepeatMode::All => true,
+                RepeatMode::Off => !self.consume,
             }
+    }
+
+    /// The track to play after the current one when not consuming
+    fn following_item(&mut self) -> usize {
+        if self.shuffle && self.playlist.len() > 1 {
+            self.shuffle_order(1)[0]
         } else {
-            // same track, reset playhead
-            self.current_offset = 0;
-            if self.state == PlaybackState::Playing {
-                self.current_item_start_ts = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
+            (self.current_item + 1) % self.playlist.len()
+        }
+    }
+
+    /// Indices of the next `count` tracks shuffle plays, picking more as needed. Each is any
+    /// track but the one before it. The playlist needs more than one track.
+    fn shuffle_order(&mut self, count: usize) -> Vec<usize> {
+        // drop picks whose tracks were removed, and any that would now repeat a track
+        let mut before = self.playlist.get(self.current_item).map(|src| src.id);
+        let playlist = &self.playlist;
+        self.shuffle_ahead.retain(|id| {
+            let keep = Some(*id) != before && playlist.iter().any(|src| src.id == *id);
+            if keep {
+                before = Some(*id);
+            }
+            keep
+        });
+
+        while self.shuffle_ahead.len() < count {
+            let before = self
+                .shuffle_ahead
+                .back()
+                .and_then(|id| self.index_of(*id))
+                .unwrap_or(self.current_item);
+            let pick = (self.next_random() % (self.playlist.len() as u64 - 1)) as usize;
+            let pick = if pick >= before { pick + 1 } else { pick };
+            self.shuffle_ahead.push_back(self.playlist[pick].id);
+        }
+        self.shuffle_ahead
+            .iter()
+            .take(count)
+            .filter_map(|id| self.index_of(*id))
+            .collect()
+    }
+
+    /// Steps the xorshift generator in `shuffle_rng`
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.shuffle_rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.shuffle_rng = x;
+        x
+    }
+
+    pub fn skip_to(&mut self, index: usize) -> &mut Self {
+        if self.playlist.is_empty() {
+            return self;
+        }
+        if index > self.current_item {
+            if self.consume && self.repeat == RepeatMode::Off {
+                // consume the current track and the ones skipped over
+                let end = index.min(self.playlist.len());
+                self.playlist.drain(self.current_item..end);
+                if self.current_item >= self.playlist.len() {
+                    self.current_item = 0;
+                }
+            } else {
+                self.current_item = index % self.playlist.len();
             }
+        } else {
+            // skipping back, or restarting the current track; never consume
+            self.current_item = index;
+        }
+        self.restart_current();
+        self
+    }
+
+    /// Restarts the current track once it's played more than `PREV_RESTART_SECS`, otherwise goes
+    /// back to the track before it. With consume on, the track before it has usually been
+    /// removed, so this just restarts the current one.
+    pub fn prev(&mut self) -> &mut Self {
+        let played_secs = self.played_frames() as f64 / self.sample_rate as f64;
+        if played_secs <= PREV_RESTART_SECS && self.current_item > 0 {
+            self.current_item -= 1;
         }
+        self.restart_current();
         self
     }
 
     pub fn pause(&mut self) -> &mut Self {
+        if self.state == PlaybackState::Playing {
+            self.emit(PlayerEvent::Paused);
+        }
         self.state = PlaybackState::Paused;
+        self.fade = None;
+        self.crossfade = None;
+        self.fade_to_pause_offset = None;
+        self
+    }
+
+    /// Starts blending into the next track over `frames` frames. It becomes the current track
+    /// once the fade is done.
+    pub fn crossfade_now(&mut self, frames: u32) -> Result<(), String> {
+        if self.state != PlaybackState::Playing {
+            return Err(String::from("not playing"));
+        }
+        let item = if self.consume && self.repeat == RepeatMode::Off {
+            self.current_item + 1
+        } else if self.playlist.is_empty() {
+            0
+        } else {
+            self.following_item()
+        };
+        if item >= self.playlist.len() || item == self.current_item {
+            return Err(String::from("no next track to crossfade into"));
+        }
+        self.crossfade_into(item, frames)
+    }
+
+    /// Starts the crossfade out of the current track for `TransitionMode::Crossfade`, over the
+    /// `frames` frames left of it. Returns false when the track should end as usual instead:
+    /// it's on repeat, it's the last one, the next track is from the same album and albums play
+    /// gaplessly, or the next track isn't open yet or can't be read.
+    pub fn begin_transition_crossfade(&mut self, frames: u32) -> bool {
+        let at_end = !self.shuffle && self.current_item + 1 >= self.playlist.len();
+        if self.repeat == RepeatMode::Track || (self.repeat == RepeatMode::Off && at_end) {
+            return false;
+        }
+        let item = if self.consume && self.repeat == RepeatMode::Off {
+            self.current_item + 1
+        } else {
+            self.following_item()
+        };
+        if item == self.current_item {
+            return false;
+        }
+        let album = |src: &AudioFileSource| {
+            src.cached_metadata()
+                .map(|metadata| metadata.album.clone())
+                .filter(|album| !album.is_empty())
+        };
+        let current_album = album(&self.playlist[self.current_item]);
+        if self.transitions.respect_album_gapless
+            && current_album.is_some()
+            && current_album == album(&self.playlist[item])
+        {
+            return false;
+        }
+        if !self.playlist[item].is_ready_at(self.playlist[item].trim_start_frame()) {
+            self.request_open();
+            return false;
+        }
+        self.crossfade_into(item, frames).is_ok()
+    }
+
+    fn crossfade_into(&mut self, item: usize, frames: u32) -> Result<(), String> {
+        let src = &mut self.playlist[item];
+        let offset = src.trim_start_frame();
+        if src.is_ready_at(offset) {
+            // decode the start now so the render thread doesn't stall on it
+            if src.get_buffer(offset).is_none() {
+                return Err(format!("can't read {}", src.filename));
+            }
+        } else {
+            // it fades in from its start once it's been opened
+            self.request_open();
+        }
+        self.crossfade = Some(Crossfade {
+            item,
+            offset,
+            fade: Fade::new(0.0, 1.0, frames),
+        });
+        Ok(())
+    }
+
+    /// Fades the output to silence over `frames` frames, then pauses. The playhead is put back
+    /// where the fade started so resuming doesn't skip the faded-out audio.
+    pub fn fade_to_pause(&mut self, frames: u32) -> &mut Self {
+        if self.state != PlaybackState::Playing || self.fade_to_pause_offset.is_some() {
+            return self;
+        }
+        let from = self.fade.map_or(1.0, |fade| fade.gain());
+        self.fade = Some(Fade::new(from, 0.0, frames));
+        self.fade_to_pause_offset = Some(self.current_offset);
+        self
+    }
+
+    /// Called by the render thread once `fade` has finished
+    pub fn finish_fade(&mut self) -> &mut Self {
+        match self.fade_to_pause_offset {
+            Some(offset) => {
+                self.pause();
+                self.current_offset = offset;
+            }
+            None => {
+                self.fade = None;
+            }
+        }
         self
     }
 
+    /// Fades out over `play_pause_fade_ms` and then pauses, so the sound doesn't stop dead
+    /// partway through a wave
+    pub fn soft_pause(&mut self) -> &mut Self {
+        match self.play_pause_fade_frames() {
+            0 => self.pause(),
+            frames => self.fade_to_pause(frames),
+        }
+    }
+
+    fn play_pause_fade_frames(&self) -> u32 {
+        (self.play_pause_fade_ms as u64 * self.sample_rate as u64 / 1000) as u32
+    }
+
+    /// Starts playing, fading in over `play_pause_fade_ms`. A fade to pause that's under way is
+    /// called off, fading back up from where it got to.
     pub fn play(&mut self) -> &mut Self {
+        let was_playing = self.state == PlaybackState::Playing;
+        let fade_in_from = if !was_playing {
+            Some(0.0)
+        } else if self.fade_to_pause_offset.is_some() {
+            Some(self.fade.map_or(1.0, |fade| fade.gain()))
+        } else {
+            // keep any fade in that's under way
+            None
+        };
+        if let Some(from) = fade_in_from {
+            let frames = self.play_pause_fade_frames();
+            self.fade = (frames > 0).then(|| Fade::new(from, 1.0, frames));
+        }
+        self.fade_to_pause_offset = None;
         self.state = PlaybackState::Playing;
         if self.current_item_start_ts == 0 {
             self.current_item_start_ts = std::time::SystemTime::now()
@@ -125,24 +1024,107 @@ impl PlayerState {
                 .unwrap()
                 .as_secs();
         }
+        if !was_playing && !self.playlist.is_empty() {
+            self.emit_now_playing();
+        }
+        self
+    }
+
+    /// Sets the output volume, clamped to `[0.0, 1.0]`. NaN mutes rather than passing through.
+    pub fn set_volume(&mut self, volume: f32) -> &mut Self {
+        self.volume = if volume.is_nan() {
+            0.0
+        } else {
+            volume.clamp(0.0, 1.0)
+        };
         self
     }
 
+    /// Applies the configured startup state to freshly loaded state
+    pub fn apply_startup_state(&mut self, startup_state: StartupState) -> &mut Self {
+        match startup_state {
+            StartupState::Resume => self,
+            StartupState::Paused => self.pause(),
+            StartupState::Playing => self.play(),
+        }
+    }
+
+    /// Plays if paused or fading out to a pause, otherwise pauses
     pub fn toggle(&mut self) -> &mut Self {
         match self.state {
             PlaybackState::Paused => self.play(),
-            PlaybackState::Playing => self.pause(),
+            PlaybackState::Playing if self.fade_to_pause_offset.is_some() => self.play(),
+            PlaybackState::Playing => self.soft_pause(),
+        }
+    }
+
+    /// Restricts `add_tracks` to paths under `roots`
+    pub fn set_library_roots(&mut self, roots: &[String]) -> &mut Self {
+        self.library_roots = roots
+            .iter()
+            .map(|root| match Path::new(root).canonicalize() {
+                Ok(root) => root,
+                Err(err) => {
+                    // keep it so the list isn't accidentally left empty (unrestricted)
+                    error!("can't resolve library root {}: {}", root, err);
+                    PathBuf::from(root)
+                }
+            })
+            .collect();
+        self
+    }
+
+    /// Whether `path` resolves to somewhere under one of the library roots
+    pub fn is_allowed_path(&self, path: &str) -> bool {
+        if self.library_roots.is_empty() {
+            return true;
+        }
+        match Path::new(archive::file_on_disk(path)).canonicalize() {
+            Ok(path) => self.library_roots.iter().any(|root| path.starts_with(root)),
+            Err(_) => false,
         }
     }
 
+    /// Adds tracks to the end of the playlist. Adding nothing leaves the state untouched. Whether
+    /// the files exist isn't checked, since that can be slow and this runs with the lock held;
+    /// `prune_missing` does it afterwards.
     pub fn add_tracks(&mut self, paths: Vec<String>) -> &mut Self {
+        if paths.is_empty() {
+            return self;
+        }
         let init_playlist_len = self.playlist.len();
         for path in paths {
-            let src = audio_file::AudioFileSource::new(path);
+            if !self.is_allowed_path(&path) {
+                error!("not adding {}: it isn't under a library root", path);
+                continue;
+            }
+            let mut src = audio_file::AudioFileSource::new(path);
+            src.set_output_sample_rate(self.sample_rate);
+            src.set_open_policy(self.open_policy);
             self.playlist.push(src);
         }
-        self.validate();
-        if !self.playlist.is_empty()
+        self.assign_track_ids();
+        self.started_playlist(init_playlist_len);
+        self
+    }
+
+    /// Ids of the tracks added since `last_track_id` was the last id given out, for
+    /// `prune_missing` to check
+    pub fn ids_added_since(&self, last_track_id: u64) -> Vec<u64> {
+        self.playlist
+            .iter()
+            .map(|src| src.id)
+            .filter(|id| *id > last_track_id)
+            .collect()
+    }
+
+    /// After tracks were added to a playlist that had `init_playlist_len` tracks: if it was
+    /// empty, the first of them is a new play, and starts now if the player is playing
+    fn started_playlist(&mut self, init_playlist_len: usize) {
+        if !self.playlist.is_empty() && init_playlist_len == 0 {
+            self.play_generation += 1;
+        }
+        if !self.playlist.is_empty()
             && init_playlist_len == 0
             && self.state == PlaybackState::Playing
         {
@@ -150,28 +1132,1450 @@ impl PlayerState {
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs();
+            self.emit_now_playing();
+        }
+    }
+
+    /// Inserts tracks so the first lands at `index`, like right after the current one to play
+    /// them next. An `index` past the end adds them to the end. The current track keeps playing;
+    /// paths outside the library roots are left out. Like `add_tracks`, missing files are left
+    /// for `prune_missing`.
+    pub fn insert_tracks(&mut self, index: usize, paths: Vec<String>) -> &mut Self {
+        if index >= self.playlist.len() {
+            return self.add_tracks(paths);
+        }
+        let mut inserted = vec![];
+        for path in paths {
+            if !self.is_allowed_path(&path) {
+                error!("not inserting {}: it isn't under a library root", path);
+                continue;
+            }
+            let mut src = audio_file::AudioFileSource::new(path);
+            src.set_output_sample_rate(self.sample_rate);
+            src.set_open_policy(self.open_policy);
+            inserted.push(src);
         }
+        if inserted.is_empty() {
+            return self;
+        }
+        self.crossfade = None;
+        if index <= self.current_item {
+            self.current_item += inserted.len();
+        }
+        self.playlist.splice(index..index, inserted);
+        self.assign_track_ids();
         self
     }
 
-    /// Remove all non-existent tracks from the playlist
+    /// Removes the track at `index`. Removing the current track moves on to the one after it.
+    pub fn remove(&mut self, index: usize) -> Result<(), String> {
+        if index >= self.playlist.len() {
+            return Err(format!(
+                "can't remove {} from a playlist of {} tracks",
+                index,
+                self.playlist.len()
+            ));
+        }
+        self.crossfade = None;
+        if index == self.current_item {
+            // the next track slides into the current slot
+            self.playlist.remove(index);
+            if self.current_item >= self.playlist.len() {
+                self.current_item = 0;
+            }
+            self.restart_current();
+        } else {
+            self.playlist.remove(index);
+            if index < self.current_item {
+                self.current_item -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the track at `from` so it ends up at `to`, keeping the current track playing
+    pub fn move_track(&mut self, from: usize, to: usize) -> Result<(), String> {
+        let len = self.playlist.len();
+        if from >= len || to >= len {
+            return Err(format!(
+                "can't move {} to {} in a playlist of {} tracks",
+                from, to, len
+            ));
+        }
+        self.crossfade = None;
+        let src = self.playlist.remove(from);
+        self.playlist.insert(to, src);
+        if from == self.current_item {
+            self.current_item = to;
+        } else if from < self.current_item && to >= self.current_item {
+            self.current_item -= 1;
+        } else if from > self.current_item && to <= self.current_item {
+            self.current_item += 1;
+        }
+        Ok(())
+    }
+
+    /// Moves the track at `index` to play right after the current one
+    pub fn move_to_front(&mut self, index: usize) -> Result<(), String> {
+        if index == self.current_item && index < self.playlist.len() {
+            return Ok(());
+        }
+        // taking out a track before the current one shifts the slot after it back by one
+        let to = if index < self.current_item {
+            self.current_item
+        } else {
+            self.current_item + 1
+        };
+        self.move_track(index, to)
+    }
+
+    /// Moves the track at `index` to the end of the playlist
+    pub fn move_to_back(&mut self, index: usize) -> Result<(), String> {
+        self.move_track(index, self.playlist.len().saturating_sub(1))
+    }
+
+    /// The status as JSON, with only `fields` if they're given. Fields are named as in the full
+    /// status, or without their `current_` prefix. The playlist's metadata is only read, which
+    /// can mean opening every file, when `playlist` is one of them.
+    pub fn status(&mut self, fields: Option<&[&str]>) -> serde_json::Value {
+        let wants = |field: &str| match fields {
+            Some(fields) => fields
+                .iter()
+                .any(|wanted| *wanted == field || Some(*wanted) == field.strip_prefix("current_")),
+            None => true,
+        };
+        let (current_item, played_frames) = self.heard_position();
+        let status = PlayerStatus {
+            state: match self.state {
+                PlaybackState::Paused => "paused",
+                PlaybackState::Playing => "playing",
+            },
+            current_item,
+            current_id: self.playlist.get(current_item).map(|src| src.id),
+            current_offset: played_frames as f64 / self.sample_rate as f64,
+            volume: self.volume,
+            transitions: self.transitions,
+            shuffle: self.shuffle,
+            repeat: self.repeat,
+            consume: self.consume,
+            last_error: self.last_error.as_deref(),
+            playlist: wants("playlist").then(|| {
+                self.playlist
+                    .iter_mut()
+                    .map(|src| PlaylistEntry {
+                        id: src.id,
+                        metadata: src.get_metadata(),
+                    })
+                    .collect()
+            }),
+        };
+        let mut status = serde_json::to_value(status).unwrap();
+        if let Some(status) = status.as_object_mut() {
+            status.retain(|field, _| wants(field));
+        }
+        status
+    }
+
+    pub fn export_playlist(&self) -> PlaylistExport {
+        PlaylistExport {
+            version: PLAYLIST_EXPORT_VERSION,
+            items: self
+                .playlist
+                .iter()
+                .map(|src| PlaylistItem {
+                    path: src.filename.clone(),
+                    start_offset: src.start_offset,
+                    end_offset: src.end_offset,
+                })
+                .collect(),
+        }
+    }
+
+    /// Replaces the playlist with an exported one. If any item is missing, outside the library
+    /// roots or has bad settings, nothing changes and the problems are returned.
+    pub fn import_playlist(&mut self, export: PlaylistExport) -> Result<(), Vec<String>> {
+        let playlist = self.sources_from_export(export)?;
+        self.clear();
+        self.playlist = playlist;
+        self.assign_track_ids();
+        self.play_generation += 1;
+        if !self.playlist.is_empty() && self.state == PlaybackState::Playing {
+            self.current_item_start_ts = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+        }
+        Ok(())
+    }
+
+    /// Adds an exported playlist's tracks to the end, checked the same way as by
+    /// `import_playlist`
+    pub fn append_playlist(&mut self, export: PlaylistExport) -> Result<(), Vec<String>> {
+        let sources = self.sources_from_export(export)?;
+        let init_playlist_len = self.playlist.len();
+        self.playlist.extend(sources);
+        self.assign_track_ids();
+        self.started_playlist(init_playlist_len);
+        Ok(())
+    }
+
+    /// Sources for each item of `export`, or what's wrong with the ones that can't be added
+    fn sources_from_export(
+        &self,
+        export: PlaylistExport,
+    ) -> Result<Vec<audio_file::AudioFileSource>, Vec<String>> {
+        if export.version > PLAYLIST_EXPORT_VERSION {
+            return Err(vec![format!(
+                "unsupported playlist version {}",
+                export.version
+            )]);
+        }
+        let mut playlist = vec![];
+        let mut problems = vec![];
+        for item in export.items {
+            if !Path::new(archive::file_on_disk(&item.path)).exists() {
+                problems.push(format!("{}: no such file", item.path));
+                continue;
+            }
+            if !self.is_allowed_path(&item.path) {
+                problems.push(format!("{}: not under a library root", item.path));
+                continue;
+            }
+            let mut src = audio_file::AudioFileSource::new(item.path);
+            src.set_output_sample_rate(self.sample_rate);
+            src.set_open_policy(self.open_policy);
+            match src.set_trim(item.start_offset, item.end_offset) {
+                Ok(_) => playlist.push(src),
+                Err(err) => problems.push(format!("{}: {}", src.filename, err)),
+            }
+        }
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+        Ok(playlist)
+    }
+
+    /// Playlist index of the track with `id`, if it's still there
+    pub fn index_of(&self, id: u64) -> Option<usize> {
+        self.playlist.iter().position(|src| src.id == id)
+    }
+
+    /// Gives an id to each track that doesn't have one yet
+    fn assign_track_ids(&mut self) {
+        for src in self.playlist.iter_mut().filter(|src| src.id == 0) {
+            self.last_track_id += 1;
+            src.id = self.last_track_id;
+        }
+    }
+
+    /// Remove all non-existent tracks from the playlist, and give the rest ids if they're new
     pub fn validate(&mut self) -> &mut Self {
-        self.playlist
-            .retain(|src| std::path::Path::new(&src.filename).exists());
+        self.assign_track_ids();
+        let mut index = 0;
+        while index < self.playlist.len() {
+            if Path::new(archive::file_on_disk(&self.playlist[index].filename)).exists() {
+                index += 1;
+                continue;
+            }
+            self.playlist.remove(index);
+            if index < self.current_item {
+                self.current_item -= 1;
+            } else if index == self.current_item {
+                // the track after it starts from the beginning
+                self.current_offset = 0;
+            }
+        }
+        if self.current_item >= self.playlist.len() {
+            self.current_item = 0;
+            self.current_offset = 0;
+        }
+        self
+    }
+
+    /// Called when the current track stopped because its file couldn't be read. If the file is
+    /// gone it's dropped from the playlist, so it isn't saved and retried; then playback moves on
+    /// or pauses according to `on_decode_error`. Why is kept for the status.
+    pub fn handle_read_error(&mut self) -> &mut Self {
+        let missing = match self.playlist.get(self.current_item) {
+            Some(src) => {
+                self.last_error = Some(format!(
+                    "{}: {}",
+                    src.filename,
+                    src.read_error().unwrap_or("couldn't be read")
+                ));
+                !Path::new(archive::file_on_disk(&src.filename)).exists()
+            }
+            None => return self,
+        };
+        if self.on_decode_error == DecodeErrorPolicy::Pause {
+            self.pause();
+            self.current_item_start_ts = 0;
+            // try opening it again on resume
+            self.playlist[self.current_item].reset();
+        }
+        if missing {
+            let _ = self.remove(self.current_item);
+        } else if self.on_decode_error == DecodeErrorPolicy::Skip {
+            self.next();
+        } else {
+            self.current_offset = 0;
+        }
         self
     }
 
+    /// Playlist indices of up to `count` tracks that will play after the current one, in the
+    /// order they'll play
+    pub fn upcoming(&mut self, count: usize) -> Vec<usize> {
+        if self.shuffling() {
+            return self.shuffle_order(count);
+        }
+        let start = (self.current_item + 1).min(self.playlist.len());
+        let end = start.saturating_add(count).min(self.playlist.len());
+        (start..end).collect()
+    }
+
+    /// Remove tracks that can't be decoded, returning their filenames. This opens every file in the
+    /// playlist, so it's slow for long playlists.
+    pub fn remove_undecodable(&mut self) -> Vec<String> {
+        let mut removed = vec![];
+        let mut index = 0;
+        while index < self.playlist.len() {
+            match self.playlist[index].check_decodable() {
+                Ok(_) => index += 1,
+                Err(err) => {
+                    error!("can't decode {}: {}", self.playlist[index].filename, err);
+                    removed.push(self.playlist.remove(index).filename);
+                    if index < self.current_item {
+                        self.current_item -= 1;
+                    }
+                }
+            }
+        }
+        if self.current_item >= self.playlist.len() {
+            self.current_item = 0;
+            self.current_offset = 0;
+        }
+        removed
+    }
+
     pub fn now_playing(&mut self) -> Option<NowPlaying> {
         if !self.playlist.is_empty() && self.state == PlaybackState::Playing {
             let playlist: &mut Playlist = self.playlist.borrow_mut();
             let track = playlist.get_mut(self.current_item).unwrap();
             Some(NowPlaying {
                 track: track.get_metadata().clone(),
-                elapsed: self.current_offset as f64 / 44100.0,
+                elapsed: self.played_frames() as f64 / self.sample_rate as f64,
                 start_ts: self.current_item_start_ts,
+                generation: self.play_generation,
             })
         } else {
             None
         }
     }
+
+    /// Unopened copies of the tracks being played, the current one and any being crossfaded
+    /// into, that aren't open or haven't had their tags read, with their indices
+    fn unopened_playing(&self) -> Vec<(usize, AudioFileSource)> {
+        let crossfading = self.crossfade.as_ref().map(|crossfade| crossfade.item);
+        [Some(self.current_item), crossfading]
+            .into_iter()
+            .flatten()
+            .filter(|index| {
+                self.playlist.get(*index).is_some_and(|src| {
+                    !src.read_failed() && (!src.is_open() || src.cached_metadata().is_none())
+                })
+            })
+            .map(|index| (index, self.playlist[index].unopened_copy()))
+            .collect()
+    }
+
+    /// Tells listeners about the current track if it started before its tags were read
+    fn emit_deferred_now_playing(&mut self) {
+        if self.now_playing_pending {
+            self.emit_now_playing();
+        }
+    }
+
+    /// Unopened copies of up to `count` of the tracks after the current one that haven't been
+    /// prefetched, with their indices
+    fn unprefetched_upcoming(&mut self, count: usize) -> Vec<(usize, AudioFileSource)> {
+        let len = self.playlist.len();
+        let upcoming: Vec<usize> = if self.shuffling() {
+            self.shuffle_order(count)
+        } else {
+            (1..len)
+                .map(|n| (self.current_item + n) % len)
+                // only wrap around when the playlist will
+                .filter(|index| *index > self.current_item || self.repeat == RepeatMode::All)
+                .take(count)
+                .collect()
+        };
+        upcoming
+            .into_iter()
+            // one that couldn't be opened is skipped when it's reached rather than retried
+            .filter(|index| {
+                let src = &self.playlist[*index];
+                !src.is_prefetched() && !src.read_failed()
+            })
+            .map(|index| (index, self.playlist[index].unopened_copy()))
+            .collect()
+    }
+}
+
+/// Opens and decodes the start of the `count` tracks after the current one, so skipping to them
+/// is instant. The decoding happens on copies with the lock released, since a slow disk would
+/// otherwise hold up the render thread; the lock is only taken to pick the tracks and to hand
+/// each one over.
+pub fn prefetch_upcoming(ps: &Mutex<PlayerState>, count: usize) {
+    let upcoming = ps.lock().unwrap().unprefetched_upcoming(count);
+    for (index, mut src) in upcoming {
+        src.prefetch(audio_file::PREFETCH_SECS);
+        // the duration is needed to know when to start crossfading out of it
+        src.get_metadata();
+        // the playlist may have changed meanwhile; a track that's different or already started
+        // turns the copy down
+        let mut ps = ps.lock().unwrap();
+        if let Some(track) = ps.playlist.get_mut(index) {
+            if track.adopt_prefetched(src) {
+                ps.close_excess_decoders();
+            }
+        }
+    }
+}
+
+/// Opens the tracks being played and reads their tags, with the lock released. The render
+/// thread never opens files, since with retries on a network mount that can take seconds; it
+/// plays silence while it waits on a track, and skips or pauses if it can't be opened.
+pub fn open_playing(ps: &Mutex<PlayerState>) {
+    let playing = ps.lock().unwrap().unopened_playing();
+    for (index, mut src) in playing {
+        if src.open() {
+            src.get_metadata();
+        }
+        let mut ps = ps.lock().unwrap();
+        if let Some(track) = ps.playlist.get_mut(index) {
+            track.adopt_prefetched(src);
+        }
+        ps.emit_deferred_now_playing();
+    }
+}
+
+/// Reads the tags of the tracks that haven't had them read, with the lock released, so listing
+/// the playlist doesn't open every file while the render thread waits on the lock
+pub fn read_tags(ps: &Mutex<PlayerState>) {
+    let mut untagged: Vec<(usize, AudioFileSource)> = {
+        let ps = ps.lock().unwrap();
+        ps.playlist
+            .iter()
+            .enumerate()
+            .filter(|(_, src)| src.cached_metadata().is_none())
+            .map(|(index, src)| (index, src.unopened_copy()))
+            .collect()
+    };
+    if untagged.is_empty() {
+        return;
+    }
+    for (_, src) in untagged.iter_mut() {
+        src.get_metadata();
+    }
+    let mut ps = ps.lock().unwrap();
+    for (index, src) in untagged {
+        if let Some(track) = ps.playlist.get_mut(index) {
+            track.adopt_tags(src);
+        }
+    }
+}
+
+/// Drops the tracks with `ids` whose files don't exist, like ones `add_tracks` just added. The
+/// files are looked for with the lock released, since on a network mount that can take seconds.
+pub fn prune_missing(ps: &Mutex<PlayerState>, ids: &[u64]) {
+    let tracks: Vec<(u64, String)> = {
+        let ps = ps.lock().unwrap();
+        ps.playlist
+            .iter()
+            .filter(|src| ids.contains(&src.id))
+            .map(|src| (src.id, src.filename.clone()))
+            .collect()
+    };
+    let missing: Vec<(u64, String)> = tracks
+        .into_iter()
+        .filter(|(_, filename)| !Path::new(archive::file_on_disk(filename)).exists())
+        .collect();
+    if missing.is_empty() {
+        return;
+    }
+    let mut ps = ps.lock().unwrap();
+    for (id, filename) in missing {
+        // it may have been removed meanwhile
+        if let Some(index) = ps.index_of(id) {
+            error!("dropping {}: it doesn't exist", filename);
+            let _ = ps.remove(index);
+        }
+    }
+}
+
+/// Saves the player state one last time before pjp exits. The output is stopped first, so the
+/// render thread can't move the position on after it's saved; the save happens even if stopping
+/// fails.
+pub fn shut_down(
+    ps: &Mutex<PlayerState>,
+    stop_output: impl FnOnce() -> Result<(), Box<dyn std::error::Error>>,
+    save: impl FnOnce(&Mutex<PlayerState>) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Err(err) = stop_output() {
+        error!("error stopping output: {}", err);
+    }
+    save(ps)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        open_playing, prefetch_upcoming, prune_missing, send_position_events, shut_down,
+        PlaybackPosition, PlaybackState, PlayerEvent, PlayerState, PlaylistExport, PlaylistItem,
+        PositionEvent, RepeatMode, PLAYER_STATE_VERSION,
+    };
+    use crate::audio_file::AudioFileSource;
+    use crate::audio_source::AudioSource;
+    use crate::storage::{read_json, write_json, StartupState};
+    use crate::test_utils::temp_path;
+
+    #[test]
+    fn applies_startup_state() {
+        for (saved, startup_state, expected) in [
+            ("Playing", StartupState::Resume, PlaybackState::Playing),
+            ("Paused", StartupState::Resume, PlaybackState::Paused),
+            ("Playing", StartupState::Paused, PlaybackState::Paused),
+            ("Paused", StartupState::Paused, PlaybackState::Paused),
+            ("Playing", StartupState::Playing, PlaybackState::Playing),
+            ("Paused", StartupState::Playing, PlaybackState::Playing),
+        ] {
+            let mut ps: PlayerState =
+                serde_json::from_value(serde_json::json!({ "state": saved })).unwrap();
+            ps.apply_startup_state(startup_state);
+            assert_eq!(ps.state, expected);
+        }
+    }
+
+    #[test]
+    fn clamps_volume() {
+        let mut ps = PlayerState::new();
+        assert_eq!(ps.volume, 1.0);
+        assert_eq!(ps.set_volume(0.5).volume, 0.5);
+        assert_eq!(ps.set_volume(4.0).volume, 1.0);
+        assert_eq!(ps.set_volume(-1.0).volume, 0.0);
+        assert_eq!(ps.set_volume(f32::INFINITY).volume, 1.0);
+        assert_eq!(ps.set_volume(f32::NAN).volume, 0.0);
+
+        let restored: PlayerState = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert_eq!(restored.volume, 1.0);
+    }
+
+    #[test]
+    fn lists_upcoming_tracks() {
+        let mut ps: PlayerState = serde_json::from_value(serde_json::json!({
+            "playlist": [
+                {"filename": "a.mp3"},
+                {"filename": "b.mp3"},
+                {"filename": "c.mp3"},
+                {"filename": "d.mp3"},
+            ],
+            "current_item": 1,
+        }))
+        .unwrap();
+        assert_eq!(ps.upcoming(2), vec![2, 3]);
+        assert_eq!(ps.upcoming(10), vec![2, 3]);
+        assert_eq!(ps.upcoming(0), Vec::<usize>::new());
+
+        ps.current_item = 3;
+        assert!(ps.upcoming(2).is_empty());
+
+        ps.playlist.clear();
+        ps.current_item = 0;
+        assert!(ps.upcoming(2).is_empty());
+    }
+
+    #[test]
+    fn lists_upcoming_tracks_in_shuffle_order() {
+        let mut ps = PlayerState::new();
+        ps.consume = false;
+        ps.shuffle_rng = 7;
+        ps.set_shuffle(true);
+        for name in ["a", "b", "c", "d", "e"] {
+            ps.playlist
+                .push(AudioFileSource::new(format!("{}.mp3", name)));
+        }
+        ps.assign_track_ids();
+
+        let upcoming = ps.upcoming(4);
+        assert_eq!(upcoming.len(), 4);
+        let mut before = ps.current_item;
+        for &index in upcoming.iter() {
+            assert_ne!(index, before);
+            before = index;
+        }
+        // asking again, or for more, doesn't reshuffle what's already lined up
+        assert_eq!(ps.upcoming(2), upcoming[..2]);
+        assert_eq!(ps.upcoming(6)[..4], upcoming);
+
+        // and that's the order they play in
+        for &index in upcoming.iter() {
+            ps.next();
+            assert_eq!(ps.current_item, index);
+        }
+
+        // a removed track drops out of the order
+        let removed = ps.upcoming(1)[0];
+        let removed_id = ps.playlist[removed].id;
+        ps.remove(removed).unwrap();
+        let upcoming = ps.upcoming(4);
+        assert_eq!(upcoming.len(), 4);
+        assert!(upcoming
+            .iter()
+            .all(|&index| ps.playlist[index].id != removed_id));
+        ps.next();
+        assert_eq!(ps.current_item, upcoming[0]);
+    }
+
+    #[test]
+    fn drops_missing_tracks_when_restoring() {
+        let restored = serde_json::json!({
+            "playlist": [
+                {"filename": "resources/ports.wav"},
+                {"filename": "/no/such/track.wav"},
+                {"filename": "resources/ports.wav"},
+            ],
+            "current_item": 2,
+            "current_offset": 5000,
+        });
+        let mut ps: PlayerState = serde_json::from_value(restored.clone()).unwrap();
+        ps.validate();
+        assert_eq!(ps.playlist.len(), 2);
+        assert_eq!((ps.current_item, ps.current_offset), (1, 5000));
+
+        // the track after a missing current one plays from its start
+        let mut restored = restored;
+        restored["current_item"] = 1.into();
+        let mut ps: PlayerState = serde_json::from_value(restored).unwrap();
+        ps.validate();
+        assert_eq!(ps.playlist.len(), 2);
+        assert_eq!((ps.current_item, ps.current_offset), (1, 0));
+    }
+
+    #[test]
+    fn removes_undecodable_tracks() {
+        let garbage = temp_path("garbage.mp3");
+        std::fs::write(&garbage, b"definitely not audio").unwrap();
+        let garbage = garbage.to_str().unwrap().to_string();
+
+        let restored = serde_json::json!({
+            "playlist": [
+                {"filename": "resources/ports.wav"},
+                {"filename": garbage},
+                {"filename": "resources/ports.wav"},
+            ],
+            "current_item": 2,
+        });
+        let mut ps: PlayerState = serde_json::from_value(restored).unwrap();
+        ps.validate();
+        assert_eq!(ps.playlist.len(), 3);
+
+        assert_eq!(ps.remove_undecodable(), vec![garbage]);
+        assert_eq!(ps.playlist.len(), 2);
+        assert_eq!(ps.current_item, 1);
+    }
+
+    #[test]
+    fn only_adds_tracks_under_library_roots() {
+        let base = temp_path("roots");
+        let roots: Vec<_> = ["a", "b", "c"].iter().map(|name| base.join(name)).collect();
+        for root in roots.iter() {
+            std::fs::create_dir_all(root).unwrap();
+            std::fs::copy("resources/ports.wav", root.join("ports.wav")).unwrap();
+        }
+        let track = |root: usize| roots[root].join("ports.wav").to_str().unwrap().to_string();
+        let escaped = roots[1]
+            .join("../c/ports.wav")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let mut ps = PlayerState::new();
+        assert!(ps.is_allowed_path(&track(2)));
+
+        ps.set_library_roots(&[
+            roots[0].to_str().unwrap().to_string(),
+            roots[1].to_str().unwrap().to_string(),
+        ]);
+        assert!(ps.is_allowed_path(&track(1)));
+        assert!(!ps.is_allowed_path(&track(2)));
+        assert!(!ps.is_allowed_path(&escaped));
+        assert!(!ps.is_allowed_path("resources/ports.wav"));
+
+        ps.add_tracks(vec![track(2), track(1), escaped]);
+        assert_eq!(ps.playlist.len(), 1);
+        assert_eq!(ps.playlist[0].filename, track(1));
+
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn prunes_missing_tracks_after_adding_them() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav").to_string();
+        let missing = "/no/such/track.wav".to_string();
+        let ps = Mutex::new(PlayerState::new());
+        ps.lock().unwrap().consume = false;
+        ps.lock()
+            .unwrap()
+            .add_tracks(vec![path.clone(), missing.clone(), path.clone()]);
+
+        // adding doesn't go looking for the files
+        let ids: Vec<u64> = {
+            let ps = ps.lock().unwrap();
+            assert_eq!(ps.playlist.len(), 3);
+            ps.playlist.iter().map(|src| src.id).collect()
+        };
+        assert_eq!(ids, [1, 2, 3]);
+
+        prune_missing(&ps, &ids);
+        let ps = ps.lock().unwrap();
+        let files: Vec<&str> = ps
+            .playlist
+            .iter()
+            .map(|src| src.filename.as_str())
+            .collect();
+        assert_eq!(files, [path.as_str(), path.as_str()]);
+        assert_eq!(ps.index_of(3), Some(1));
+    }
+
+    #[test]
+    fn adding_no_tracks_changes_nothing() {
+        let mut ps: PlayerState = serde_json::from_value(serde_json::json!({
+            "state": "Playing",
+            "playlist": [{"filename": "missing.mp3"}],
+            "current_item_start_ts": 1234,
+            "play_generation": 5,
+        }))
+        .unwrap();
+        ps.add_tracks(vec![]);
+
+        // not even validated, which would drop the missing file
+        assert_eq!(ps.playlist.len(), 1);
+        assert_eq!(ps.current_item_start_ts, 1234);
+        assert_eq!(ps.play_generation, 5);
+    }
+
+    #[test]
+    fn round_trips_playlist_export() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav").to_string();
+
+        let mut ps = PlayerState::new();
+        ps.add_tracks(vec![path.clone(), path.clone()]);
+        ps.playlist[1].set_trim(Some(0.25), Some(0.75)).unwrap();
+        let json = serde_json::to_string(&ps.export_playlist()).unwrap();
+
+        let mut imported = PlayerState::new();
+        imported.add_tracks(vec![path.clone()]);
+        imported.current_offset = 1000;
+        let export: PlaylistExport = serde_json::from_str(&json).unwrap();
+        imported.import_playlist(export).unwrap();
+
+        assert_eq!(imported.current_offset, 0);
+        assert_eq!(imported.export_playlist(), ps.export_playlist());
+        assert_eq!(imported.playlist[1].start_offset, Some(0.25));
+        assert_eq!(imported.playlist[1].end_offset, Some(0.75));
+        assert_eq!(imported.playlist[1].trim_start_frame(), 11025);
+
+        // a missing file rejects the whole import
+        let mut export = ps.export_playlist();
+        export.items.push(PlaylistItem {
+            path: "/nonexistent/missing.wav".to_string(),
+            start_offset: None,
+            end_offset: None,
+        });
+        assert_eq!(imported.import_playlist(export).unwrap_err().len(), 1);
+        assert_eq!(imported.playlist.len(), 2);
+    }
+
+    #[test]
+    fn waits_for_the_position_to_change() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let mut ps = PlayerState::new();
+        for _ in 0..2 {
+            ps.playlist.push(AudioFileSource::new(path.to_string()));
+        }
+        ps.consume = false;
+        ps.current_offset = 100;
+        ps.publish_position(&ps.position);
+        let position = ps.position.clone();
+
+        // nothing moves while paused
+        let start = Instant::now();
+        assert!(!position.wait_for_change(None, Duration::from_millis(100)));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        // ...but a client that saw an older position hears about it straight away
+        assert!(position.wait_for_change(Some(50), Duration::ZERO));
+
+        let ps = Arc::new(Mutex::new(ps));
+        let skipper = ps.clone();
+        let skip = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let mut ps = skipper.lock().unwrap();
+            ps.next();
+            ps.publish_position(&ps.position);
+            ps.position.notify_waiters();
+        });
+        let start = Instant::now();
+        assert!(position.wait_for_change(Some(100), Duration::from_secs(5)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(position.load().0, 1);
+        skip.join().unwrap();
+    }
+
+    #[test]
+    fn filters_status_fields() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let mut ps = PlayerState::new();
+        for _ in 0..2 {
+            ps.playlist.push(AudioFileSource::new(path.to_string()));
+        }
+        ps.current_offset = 22050;
+        // what the output has played so far
+        ps.publish_position(&ps.position);
+
+        let status = ps.status(Some(&["state", "offset"]));
+        assert_eq!(
+            status,
+            serde_json::json!({ "state": "paused", "current_offset": 0.5 })
+        );
+        assert!(ps
+            .playlist
+            .iter()
+            .all(|src| src.cached_metadata().is_none()));
+
+        let status = ps.status(None);
+        let playlist = status["playlist"].as_array().unwrap();
+        assert_eq!(playlist.len(), 2);
+        for (entry, src) in playlist.iter().zip(ps.playlist.iter()) {
+            assert_eq!(entry["id"], src.id);
+            assert!(entry["title"].is_string());
+        }
+        assert!(ps
+            .playlist
+            .iter()
+            .all(|src| src.cached_metadata().is_some()));
+    }
+
+    #[test]
+    fn appends_exported_playlists() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav").to_string();
+
+        let mut ps = PlayerState::new();
+        ps.add_tracks(vec![path.clone()]);
+        ps.playlist[0].set_trim(Some(0.25), None).unwrap();
+        let export = ps.export_playlist();
+        ps.current_offset = 1000;
+
+        ps.append_playlist(export.clone()).unwrap();
+        assert_eq!(ps.current_offset, 1000);
+        assert_eq!(ps.playlist.len(), 2);
+        assert_eq!(ps.playlist[1].start_offset, Some(0.25));
+        assert_ne!(ps.playlist[0].id, ps.playlist[1].id);
+
+        let mut empty = PlayerState::new();
+        empty.append_playlist(export).unwrap();
+        assert_eq!(empty.play_generation, 1);
+        assert_eq!(
+            empty.export_playlist().items,
+            ps.export_playlist().items[..1]
+        );
+    }
+
+    #[test]
+    fn moves_tracks_around_the_current_one() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let mut ps = PlayerState::new();
+        ps.consume = false;
+        for _ in 0..4 {
+            ps.playlist.push(AudioFileSource::new(path.to_string()));
+        }
+        for (i, src) in ps.playlist.iter_mut().enumerate() {
+            src.start_offset = Some(i as f64);
+        }
+        ps.current_item = 1;
+
+        let order = |ps: &PlayerState| -> Vec<f64> {
+            ps.playlist
+                .iter()
+                .map(|src| src.start_offset.unwrap())
+                .collect()
+        };
+
+        // the current track itself
+        ps.move_track(1, 3).unwrap();
+        assert_eq!(order(&ps), vec![0.0, 2.0, 3.0, 1.0]);
+        assert_eq!(ps.current_item, 3);
+
+        // both ends before it
+        ps.move_track(2, 0).unwrap();
+        assert_eq!(order(&ps), vec![3.0, 0.0, 2.0, 1.0]);
+        assert_eq!(ps.current_item, 3);
+
+        ps.move_track(0, 3).unwrap();
+        assert_eq!(order(&ps), vec![0.0, 2.0, 1.0, 3.0]);
+        assert_eq!(ps.current_item, 2);
+
+        ps.move_track(3, 0).unwrap();
+        assert_eq!(order(&ps), vec![3.0, 0.0, 2.0, 1.0]);
+        assert_eq!(ps.current_item, 3);
+
+        assert!(ps.move_track(4, 0).is_err());
+        assert!(ps.move_track(0, 4).is_err());
+        assert_eq!(order(&ps), vec![3.0, 0.0, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn moves_tracks_to_play_next_or_last() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let mut ps = PlayerState::new();
+        ps.consume = false;
+        for i in 0..5 {
+            let mut src = AudioFileSource::new(path.to_string());
+            src.start_offset = Some(i as f64);
+            ps.playlist.push(src);
+        }
+        ps.current_item = 2;
+        ps.current_offset = 1000;
+        let order = |ps: &PlayerState| -> Vec<f64> {
+            ps.playlist
+                .iter()
+                .map(|src| src.start_offset.unwrap())
+                .collect()
+        };
+        let current = |ps: &PlayerState| ps.playlist[ps.current_item].start_offset.unwrap();
+
+        // from after the current track, and from before it
+        ps.move_to_front(4).unwrap();
+        assert_eq!(order(&ps), vec![0.0, 1.0, 2.0, 4.0, 3.0]);
+        ps.move_to_front(0).unwrap();
+        assert_eq!(order(&ps), vec![1.0, 2.0, 0.0, 4.0, 3.0]);
+        assert_eq!(current(&ps), 2.0);
+
+        ps.move_to_back(0).unwrap();
+        assert_eq!(order(&ps), vec![2.0, 0.0, 4.0, 3.0, 1.0]);
+        ps.move_to_back(2).unwrap();
+        assert_eq!(order(&ps), vec![2.0, 0.0, 3.0, 1.0, 4.0]);
+        assert_eq!(current(&ps), 2.0);
+        assert_eq!(ps.current_offset, 1000);
+
+        // the current track is already next to itself
+        ps.move_to_front(ps.current_item).unwrap();
+        assert_eq!(order(&ps), vec![2.0, 0.0, 3.0, 1.0, 4.0]);
+
+        assert!(ps.move_to_front(5).is_err());
+        assert!(ps.move_to_back(5).is_err());
+        assert!(PlayerState::new().move_to_back(0).is_err());
+    }
+
+    #[test]
+    fn inserts_tracks_after_the_playing_one() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let flac = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/tagged.flac");
+        let mut ps = PlayerState::new();
+        ps.consume = false;
+        ps.add_tracks(vec![path.to_string(), path.to_string()]);
+        ps.play();
+        ps.current_offset = 1000;
+
+        // play next, between the playing track and the one after it
+        let missing = "/no/such/track.wav";
+        ps.insert_tracks(1, vec![flac.to_string(), missing.to_string()]);
+        let files = |ps: &PlayerState| -> Vec<String> {
+            ps.playlist.iter().map(|src| src.filename.clone()).collect()
+        };
+        // inserting doesn't go looking for the files either
+        assert_eq!(files(&ps), [path, flac, missing, path]);
+        assert_eq!(ps.ids_added_since(2), [3, 4]);
+        let ps = Mutex::new(ps);
+        prune_missing(&ps, &[3, 4]);
+        let mut ps = ps.into_inner().unwrap();
+        assert_eq!(files(&ps), [path, flac, path]);
+        assert_eq!(ps.current_item, 0);
+        assert_eq!(ps.current_offset, 1000);
+        assert_eq!(ps.playlist[1].id, 3);
+
+        // before the playing track, which keeps playing
+        ps.current_item = 2;
+        ps.insert_tracks(0, vec![flac.to_string()]);
+        assert_eq!(files(&ps), [flac, path, flac, path]);
+        assert_eq!(ps.current_item, 3);
+        assert_eq!(ps.current_offset, 1000);
+
+        // past the end
+        ps.insert_tracks(10, vec![flac.to_string()]);
+        assert_eq!(files(&ps).last().unwrap(), flac);
+        assert_eq!(ps.current_item, 3);
+    }
+
+    #[test]
+    fn removes_tracks_around_the_current_one() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let mut ps = PlayerState::new();
+        ps.consume = false;
+        for i in 0..4 {
+            let mut src = AudioFileSource::new(path.to_string());
+            src.start_offset = Some(i as f64);
+            ps.playlist.push(src);
+        }
+        ps.current_item = 2;
+        ps.current_offset = 1000;
+        let current = |ps: &PlayerState| ps.playlist[ps.current_item].start_offset.unwrap();
+
+        ps.remove(0).unwrap();
+        assert_eq!(current(&ps), 2.0);
+        assert_eq!(ps.current_offset, 1000);
+
+        ps.remove(2).unwrap();
+        assert_eq!(current(&ps), 2.0);
+
+        // the next track takes the removed one's place
+        ps.remove(ps.current_item).unwrap();
+        assert_eq!(ps.playlist.len(), 1);
+        assert_eq!(current(&ps), 1.0);
+        assert_eq!(ps.current_offset, 0);
+
+        assert!(ps.remove(1).is_err());
+        assert_eq!(ps.playlist.len(), 1);
+    }
+
+    #[test]
+    fn shuffle_visits_every_track() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let mut ps = PlayerState::new();
+        ps.consume = false;
+        ps.shuffle_rng = 42;
+        ps.set_shuffle(true);
+        for _ in 0..3 {
+            ps.playlist.push(AudioFileSource::new(path.to_string()));
+        }
+        ps.assign_track_ids();
+
+        let mut visited = [false; 3];
+        visited[ps.current_item] = true;
+        for _ in 0..50 {
+            let previous = ps.current_item;
+            ps.next();
+            assert_ne!(ps.current_item, previous);
+            visited[ps.current_item] = true;
+        }
+        assert_eq!(visited, [true; 3]);
+    }
+
+    #[test]
+    fn releases_tracks_that_arent_current_or_next() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let mut ps = PlayerState::new();
+        ps.consume = false;
+        for _ in 0..4 {
+            ps.playlist.push(AudioFileSource::new(path.to_string()));
+        }
+        for src in ps.playlist.iter_mut() {
+            src.get_buffer(44100 * 7 / 2).unwrap();
+        }
+
+        ps.next();
+        // only the starts of the others are kept
+        let holds_later_audio: Vec<bool> = ps
+            .playlist
+            .iter()
+            .map(|src| {
+                src.decoded_buffers()
+                    .iter()
+                    .any(|buffer| buffer.offset >= 44100 * 3)
+            })
+            .collect();
+        assert_eq!(holds_later_audio, [false, true, true, false]);
+    }
+
+    #[test]
+    fn sends_position_events_at_the_interval() {
+        let position = PlaybackPosition::default();
+        position.publish(2, 3, 22050, true, Some(3.0), 44100);
+
+        let started = Instant::now();
+        let mut events = vec![];
+        send_position_events(&position, Duration::from_millis(20), |event| {
+            events.push((started.elapsed(), event.elapsed));
+            // a second of playback between events
+            position.publish(
+                2,
+                3,
+                22050 + 44100 * events.len() as u32,
+                true,
+                Some(3.0),
+                44100,
+            );
+            events.len() < 3
+        });
+
+        assert_eq!(
+            events
+                .iter()
+                .map(|(_, elapsed)| *elapsed)
+                .collect::<Vec<_>>(),
+            [0.5, 1.5, 2.5]
+        );
+        for (i, (at, _)) in events.iter().enumerate() {
+            assert!(*at >= Duration::from_millis(20 * (i as u64 + 1)));
+        }
+        assert_eq!(
+            position.position_event(),
+            Some(PositionEvent {
+                index: 2,
+                elapsed: 3.5,
+                duration: Some(3.0),
+            })
+        );
+
+        position.publish(2, 3, 0, false, Some(3.0), 44100);
+        assert_eq!(position.position_event(), None);
+    }
+
+    #[test]
+    fn keeps_the_nearest_decoders_open() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let mut ps = PlayerState::new();
+        for _ in 0..6 {
+            ps.playlist.push(AudioFileSource::new(path.to_string()));
+        }
+        ps.current_item = 1;
+        ps.max_open_decoders = 3;
+        ps.playlist[1].get_buffer(0).unwrap();
+        let ps = Mutex::new(ps);
+
+        prefetch_upcoming(&ps, 4);
+        let ps = ps.lock().unwrap();
+        let open: Vec<bool> = ps.playlist.iter().map(|src| src.is_open()).collect();
+        assert_eq!(open, [false, true, true, true, false, false]);
+        // the closed ones still start straight away
+        let prefetched: Vec<bool> = ps.playlist.iter().map(|src| src.is_prefetched()).collect();
+        assert_eq!(prefetched, [false, true, true, true, true, true]);
+    }
+
+    #[test]
+    fn prefetches_upcoming_tracks() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let mut ps = PlayerState::new();
+        for _ in 0..4 {
+            ps.playlist.push(AudioFileSource::new(path.to_string()));
+        }
+        ps.current_item = 1;
+        let ps = Mutex::new(ps);
+
+        prefetch_upcoming(&ps, 2);
+        let prefetched: Vec<bool> = ps
+            .lock()
+            .unwrap()
+            .playlist
+            .iter()
+            .map(|src| src.is_prefetched())
+            .collect();
+        assert_eq!(prefetched, [false, false, true, true]);
+
+        // what was prefetched is what plays
+        let mut ps = ps.lock().unwrap();
+        let buffer = ps.playlist[2].get_buffer(0).unwrap();
+        assert_eq!(buffer.offset, 0);
+        assert!(buffer.length > 0);
+    }
+
+    #[test]
+    fn toggles_consume_between_tracks() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let mut ps = PlayerState::new();
+        for i in 0..4 {
+            let mut src = AudioFileSource::new(path.to_string());
+            src.start_offset = Some(i as f64);
+            ps.playlist.push(src);
+        }
+        ps.current_item = 1;
+        let current = |ps: &PlayerState| ps.playlist[ps.current_item].start_offset;
+
+        ps.next();
+        assert_eq!(ps.playlist.len(), 3);
+        assert_eq!(current(&ps), Some(2.0));
+
+        // the played track stays, and the one after it is up
+        ps.set_consume(false);
+        ps.next();
+        assert_eq!(ps.playlist.len(), 3);
+        assert_eq!(current(&ps), Some(3.0));
+
+        // the last track goes, back around to the top
+        ps.set_consume(true);
+        ps.next();
+        assert_eq!(ps.playlist.len(), 2);
+        assert_eq!(ps.current_item, 0);
+        assert_eq!(current(&ps), Some(0.0));
+    }
+
+    #[test]
+    fn goes_back_or_restarts_on_prev() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let mut ps = PlayerState::new();
+        ps.consume = false;
+        for i in 0..3 {
+            let mut src = AudioFileSource::new(path.to_string());
+            src.id = i;
+            ps.playlist.push(src);
+        }
+        ps.current_item = 2;
+        let current = |ps: &PlayerState| ps.playlist[ps.current_item].id;
+
+        // well into the track, it starts over
+        ps.current_offset = 4 * ps.sample_rate;
+        ps.prev();
+        assert_eq!(current(&ps), 2);
+        assert_eq!(ps.current_offset, 0);
+
+        // near the start, it's the one before
+        ps.current_offset = ps.sample_rate;
+        ps.prev();
+        assert_eq!(current(&ps), 1);
+        assert_eq!(ps.current_offset, 0);
+
+        ps.prev();
+        ps.prev();
+        assert_eq!(ps.current_item, 0);
+        assert_eq!(ps.playlist.len(), 3);
+    }
+
+    #[test]
+    fn restarts_on_prev_once_played_tracks_are_consumed() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let mut ps = PlayerState::new();
+        ps.consume = true;
+        for i in 0..3 {
+            let mut src = AudioFileSource::new(path.to_string());
+            src.id = i;
+            ps.playlist.push(src);
+        }
+        ps.next();
+        assert_eq!(ps.playlist.len(), 2);
+
+        // the track before was consumed, so there's nowhere to go back to
+        ps.current_offset = ps.sample_rate;
+        ps.prev();
+        assert_eq!(ps.current_item, 0);
+        assert_eq!(ps.playlist[0].id, 1);
+        assert_eq!(ps.current_offset, 0);
+        assert_eq!(ps.playlist.len(), 2);
+    }
+
+    #[test]
+    fn end_of_playlist_follows_repeat_mode() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let playlist = |consume: bool, repeat: RepeatMode| {
+            let mut ps = PlayerState::new();
+            ps.consume = consume;
+            ps.set_repeat(repeat);
+            for _ in 0..2 {
+                ps.playlist.push(AudioFileSource::new(path.to_string()));
+            }
+            ps.current_item = 1;
+            ps.current_offset = 100;
+            ps.play();
+            ps
+        };
+
+        // off stops at the end, back at the top
+        let mut ps = playlist(false, RepeatMode::Off);
+        ps.next();
+        assert_eq!(ps.current_item, 0);
+        assert_eq!(ps.playlist.len(), 2);
+        assert_eq!(ps.state, PlaybackState::Paused);
+
+        // ...or runs out of tracks when consuming
+        let mut ps = playlist(true, RepeatMode::Off);
+        ps.next();
+        assert_eq!(ps.playlist.len(), 1);
+        ps.next();
+        assert!(ps.playlist.is_empty());
+
+        // track plays the same one again from the top
+        let mut ps = playlist(true, RepeatMode::Track);
+        let generation = ps.play_generation;
+        ps.next();
+        assert_eq!(ps.current_item, 1);
+        assert_eq!(ps.current_offset, 0);
+        assert_eq!(ps.playlist.len(), 2);
+        assert_eq!(ps.play_generation, generation + 1);
+        assert_eq!(ps.state, PlaybackState::Playing);
+
+        // all wraps around and keeps playing, even when consuming
+        let mut ps = playlist(true, RepeatMode::All);
+        ps.next();
+        assert_eq!(ps.current_item, 0);
+        assert_eq!(ps.playlist.len(), 2);
+        assert_eq!(ps.state, PlaybackState::Playing);
+    }
+
+    #[test]
+    fn enqueues_tracks_from_zip_archives() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/resources/album.zip!disc 1/sine.wav"
+        );
+        let mut ps = PlayerState::new();
+        ps.add_tracks(vec![path.to_string()]);
+        assert_eq!(ps.playlist.len(), 1);
+
+        let buffer = ps.playlist[0].get_buffer(0).unwrap();
+        assert_eq!(buffer.offset, 0);
+        assert!(buffer.samples[0].iter().any(|sample| sample.abs() > 0.4));
+        assert!((ps.playlist[0].get_metadata().dur - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn tells_subscribers_about_playback_changes() {
+        let mut ps = PlayerState::new();
+        let events = ps.subscribe();
+        ps.play();
+        ps.pause();
+        assert!(matches!(events.try_recv(), Ok(PlayerEvent::Paused)));
+        // already paused
+        ps.pause();
+        assert!(events.try_recv().is_err());
+
+        ps.add_tracks(vec![
+            concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav").to_string(),
+            concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav").to_string(),
+        ]);
+        ps.playlist[1].get_metadata();
+        ps.play();
+        // the first track's tags haven't been read, so it waits until they are
+        assert!(events.try_recv().is_err());
+        let ps = Mutex::new(ps);
+        open_playing(&ps);
+        let mut ps = ps.into_inner().unwrap();
+        match events.try_recv() {
+            Ok(PlayerEvent::NowPlaying(now_playing)) => assert_eq!(now_playing.generation, 1),
+            event => panic!("expected now-playing, got {:?}", event),
+        }
+        // the second one's were read already
+        ps.next();
+        match events.try_recv() {
+            Ok(PlayerEvent::NowPlaying(now_playing)) => assert_eq!(now_playing.generation, 2),
+            event => panic!("expected now-playing, got {:?}", event),
+        }
+        ps.clear();
+        assert!(matches!(events.try_recv(), Ok(PlayerEvent::PlaylistEmpty)));
+
+        drop(events);
+        ps.pause();
+        assert!(ps.event_listeners.is_empty());
+    }
+
+    #[test]
+    fn tracks_keep_their_ids() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav").to_string();
+        let mut ps = PlayerState::new();
+        ps.consume = false;
+        ps.add_tracks(vec![path.clone(), path.clone(), path.clone()]);
+        ps.add_tracks(vec![path.clone()]);
+        let ids: Vec<u64> = ps.playlist.iter().map(|src| src.id).collect();
+        assert_eq!(ids, [1, 2, 3, 4]);
+
+        ps.move_track(3, 0).unwrap();
+        let ids: Vec<u64> = ps.playlist.iter().map(|src| src.id).collect();
+        assert_eq!(ids, [4, 1, 2, 3]);
+        assert_eq!(ps.index_of(2), Some(2));
+
+        // indices shift under the removal, ids don't
+        ps.remove(0).unwrap();
+        let index = ps.index_of(2).unwrap();
+        ps.remove(index).unwrap();
+        let ids: Vec<u64> = ps.playlist.iter().map(|src| src.id).collect();
+        assert_eq!(ids, [1, 3]);
+        assert_eq!(ps.index_of(2), None);
+
+        // ids survive a restart, and new ones carry on from the last
+        let mut ps: PlayerState =
+            serde_json::from_str(&serde_json::to_string(&ps).unwrap()).unwrap();
+        ps.add_tracks(vec![path]);
+        let ids: Vec<u64> = ps.playlist.iter().map(|src| src.id).collect();
+        assert_eq!(ids, [1, 3, 5]);
+    }
+
+    #[test]
+    fn migrates_unversioned_saved_state() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let ps = PlayerState::from_saved(serde_json::json!({
+            "state": "Playing",
+            "playlist": [{ "filename": path }],
+            "current_item": 0,
+            "current_offset": 1000,
+            "current_item_start_ts": 1600000000,
+            "volume": 0.5,
+        }));
+        assert_eq!(ps.version, PLAYER_STATE_VERSION);
+        assert_eq!(ps.state, PlaybackState::Playing);
+        assert_eq!(ps.playlist[0].filename, path);
+        assert_eq!(ps.current_offset, 1000);
+        assert_eq!(ps.volume, 0.5);
+        assert!(ps.consume);
+        assert_eq!(ps.current_item_start_ts, 0);
+
+        // saved again, it's read as is
+        let saved = serde_json::to_value(&ps).unwrap();
+        assert_eq!(saved["version"], PLAYER_STATE_VERSION);
+        let mut ps = PlayerState::from_saved(saved);
+        ps.consume = false;
+        ps.current_item_start_ts = 1600000000;
+        let ps = PlayerState::from_saved(serde_json::to_value(&ps).unwrap());
+        assert!(!ps.consume);
+        assert_eq!(ps.current_item_start_ts, 1600000000);
+    }
+
+    #[test]
+    fn starts_fresh_from_newer_or_unreadable_saved_state() {
+        for saved in [
+            serde_json::json!({ "version": PLAYER_STATE_VERSION + 1, "volume": 0.5 }),
+            serde_json::json!({ "volume": "loud" }),
+            serde_json::json!([]),
+        ] {
+            let ps = PlayerState::from_saved(saved);
+            assert_eq!(ps.version, PLAYER_STATE_VERSION);
+            assert_eq!(ps.volume, 1.0);
+        }
+    }
+
+    #[test]
+    fn saves_where_playback_stopped_on_shutdown() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ports.wav");
+        let saved = temp_path("shutdown.json");
+        let ps = Mutex::new(PlayerState::new());
+        ps.lock().unwrap().add_tracks(vec![path.to_string()]).play();
+
+        shut_down(
+            &ps,
+            || {
+                // the render thread's last callback
+                ps.lock().unwrap().current_offset = 4410;
+                Err("device went away".into())
+            },
+            |ps| write_json(&saved, ps),
+        )
+        .unwrap();
+
+        let restored = PlayerState::from_saved(read_json(&saved).unwrap());
+        assert_eq!(restored.state, PlaybackState::Playing);
+        assert_eq!(restored.playlist.len(), 1);
+        assert_eq!(restored.current_offset, 4410);
+        std::fs::remove_file(&saved).unwrap();
+    }
 }
@@ -0,0 +1,1365 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::audio_file::AudioFileSource;
+use crate::audio_source::AudioSource;
+use crate::downmix;
+use crate::limiter;
+use crate::monitor::Monitor;
+use crate::player_state::{PlaybackPosition, PlaybackState, PlayerState};
+use crate::replaygain;
+use crate::transitions::TransitionMode;
+
+fn fill_silence(out: &mut [Vec<f32>], from: usize, to: usize) {
+    for channel in out.iter_mut() {
+        for sample in channel.iter_mut().take(to).skip(from) {
+            *sample = 0.0;
+        }
+    }
+}
+
+/// Checks that `channel_map` routes every one of `channels` output channels from a source
+/// channel that exists. An empty map is the identity.
+pub fn check_channel_map(channel_map: &[usize], channels: usize) -> Result<(), String> {
+    if channel_map.is_empty() {
+        return Ok(());
+    }
+    if channel_map.len() != channels {
+        return Err(format!(
+            "channel map has {} entries but the output has {} channels",
+            channel_map.len(),
+            channels
+        ));
+    }
+    match channel_map.iter().find(|source| **source >= channels) {
+        Some(source) => Err(format!(
+            "channel {} is out of range for {} channels",
+            source, channels
+        )),
+        None => Ok(()),
+    }
+}
+
+/// How long the render callback is timed for before its rate is published
+const RATE_WINDOW_NANOS: u64 = 1_000_000_000;
+
+/// Measures how often the render callback runs and how many frames it's asked for, over windows
+/// of about a second. Only the callback writes to it, so it's plain atomic loads and stores.
+pub struct CallbackRate {
+    epoch: Instant,
+    window_start: AtomicU64,
+    window_ticks: AtomicU64,
+    window_frames: AtomicU64,
+    /// f64 bits of the callbacks per second in the last full window
+    ticks_per_sec: AtomicU64,
+    /// f64 bits of the average frames per callback in the last full window
+    frames_per_tick: AtomicU64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CallbackRateReport {
+    pub callbacks_per_sec: f64,
+    pub frames_per_callback: f64,
+    /// Should match the device's sample rate
+    pub frames_per_sec: f64,
+}
+
+impl CallbackRate {
+    pub fn new() -> CallbackRate {
+        CallbackRate {
+            epoch: Instant::now(),
+            window_start: AtomicU64::new(0),
+            window_ticks: AtomicU64::new(0),
+            window_frames: AtomicU64::new(0),
+            ticks_per_sec: AtomicU64::new(0.0f64.to_bits()),
+            frames_per_tick: AtomicU64::new(0.0f64.to_bits()),
+        }
+    }
+
+    /// Records a render callback for `num_frames` frames. Called from the render callback.
+    pub fn tick(&self, num_frames: usize) {
+        self.tick_at(self.epoch.elapsed().as_nanos() as u64, num_frames);
+    }
+
+    /// Records a callback at `now`, in nanoseconds. The window runs from its first callback, so
+    /// the frames of that one aren't counted: they were rendered for the time before it.
+    fn tick_at(&self, now: u64, num_frames: usize) {
+        let ticks = self.window_ticks.load(Ordering::Relaxed);
+        if ticks == 0 {
+            self.window_start.store(now, Ordering::Relaxed);
+            self.window_ticks.store(1, Ordering::Relaxed);
+            self.window_frames.store(0, Ordering::Relaxed);
+            return;
+        }
+        let frames = self.window_frames.load(Ordering::Relaxed) + num_frames as u64;
+        let elapsed = now.saturating_sub(self.window_start.load(Ordering::Relaxed));
+        if elapsed >= RATE_WINDOW_NANOS {
+            // the `ticks` callbacks before this one each start an interval of the window
+            let secs = elapsed as f64 / 1e9;
+            self.ticks_per_sec
+                .store((ticks as f64 / secs).to_bits(), Ordering::Relaxed);
+            self.frames_per_tick
+                .store((frames as f64 / ticks as f64).to_bits(), Ordering::Relaxed);
+            // this callback starts the next window
+            self.window_start.store(now, Ordering::Relaxed);
+            self.window_ticks.store(1, Ordering::Relaxed);
+            self.window_frames.store(0, Ordering::Relaxed);
+        } else {
+            self.window_ticks.store(ticks + 1, Ordering::Relaxed);
+            self.window_frames.store(frames, Ordering::Relaxed);
+        }
+    }
+
+    /// The rate over the last full window; zero until a second of callbacks has been timed
+    pub fn report(&self) -> CallbackRateReport {
+        let callbacks_per_sec = f64::from_bits(self.ticks_per_sec.load(Ordering::Relaxed));
+        let frames_per_callback = f64::from_bits(self.frames_per_tick.load(Ordering::Relaxed));
+        CallbackRateReport {
+            callbacks_per_sec,
+            frames_per_callback,
+            frames_per_sec: callbacks_per_sec * frames_per_callback,
+        }
+    }
+}
+
+/// Frames the render thread renders at a time, and that the output's position moves by
+const BLOCK_FRAMES: usize = 256;
+
+/// Most blocks the queue to the output holds
+const QUEUE_BLOCKS: usize = 64;
+
+/// Frames the render thread keeps queued for the output, at least. A request can hold the player
+/// state lock for about this long before the output runs dry, and it's how far what's heard
+/// trails the player state: ~90 ms at 44.1kHz.
+const MIN_QUEUED_FRAMES: usize = 4096;
+
+/// How often the render thread checks whether the output needs more
+const RENDER_POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Rendered audio on its way from the render thread to the output's callback, a block at a time,
+/// with where playback is once each block has played. There's one writer and one reader and
+/// neither ever waits on the other; when it runs dry the output plays silence.
+pub struct RenderQueue {
+    channels: usize,
+    /// f32 bits of each block's samples, a channel at a time
+    samples: Vec<AtomicU32>,
+    positions: Vec<PlaybackPosition>,
+    /// Blocks written and read so far; the ones in between are queued
+    written: AtomicUsize,
+    read: AtomicUsize,
+    /// Frames played of the block being read
+    read_frames: AtomicUsize,
+    /// Frames the output last asked for at once
+    callback_frames: AtomicUsize,
+}
+
+impl RenderQueue {
+    pub fn new(channels: usize) -> RenderQueue {
+        RenderQueue {
+            channels,
+            samples: (0..QUEUE_BLOCKS * channels * BLOCK_FRAMES)
+                .map(|_| AtomicU32::new(0))
+                .collect(),
+            positions: (0..QUEUE_BLOCKS)
+                .map(|_| PlaybackPosition::default())
+                .collect(),
+            written: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            read_frames: AtomicUsize::new(0),
+            callback_frames: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether the render thread should queue another block: there's room, and less is queued
+    /// than a couple of callbacks' worth or `MIN_QUEUED_FRAMES`, whichever is more
+    fn wants_block(&self) -> bool {
+        let blocks = self.written.load(Ordering::Acquire) - self.read.load(Ordering::Acquire);
+        let queued_frames =
+            (blocks * BLOCK_FRAMES).saturating_sub(self.read_frames.load(Ordering::Relaxed));
+        let target = MIN_QUEUED_FRAMES.max(2 * self.callback_frames.load(Ordering::Relaxed));
+        blocks < QUEUE_BLOCKS && queued_frames < target
+    }
+
+    /// Queues `block`, with `publish` filling in the position for once it's played. Only the
+    /// render thread calls this, once `wants_block` says there's room.
+    fn push(&self, block: &[Vec<f32>], publish: impl FnOnce(&PlaybackPosition)) {
+        let written = self.written.load(Ordering::Relaxed);
+        let slot = written % QUEUE_BLOCKS;
+        for (channel_index, channel) in block.iter().enumerate() {
+            let start = (slot * self.channels + channel_index) * BLOCK_FRAMES;
+            let stored = &self.samples[start..start + BLOCK_FRAMES];
+            for (sample, stored) in channel.iter().zip(stored) {
+                stored.store(sample.to_bits(), Ordering::Relaxed);
+            }
+        }
+        publish(&self.positions[slot]);
+        self.written.store(written + 1, Ordering::Release);
+    }
+
+    /// Plays the next `num_frames` queued frames into `out`, then silence if the queue runs dry.
+    /// As each block finishes, `position` is set to where it got playback to. Only the output's
+    /// callback calls this.
+    fn pop(&self, out: &mut [Vec<f32>], num_frames: usize, position: &PlaybackPosition) {
+        self.callback_frames.store(num_frames, Ordering::Relaxed);
+        let mut filled = 0;
+        while filled < num_frames {
+            let read = self.read.load(Ordering::Relaxed);
+            if read == self.written.load(Ordering::Acquire) {
+                break;
+            }
+            let slot = read % QUEUE_BLOCKS;
+            let from = self.read_frames.load(Ordering::Relaxed);
+            let frames = (BLOCK_FRAMES - from).min(num_frames - filled);
+            for (channel_index, channel) in out.iter_mut().enumerate() {
+                let start = (slot * self.channels + channel_index) * BLOCK_FRAMES + from;
+                let stored = &self.samples[start..start + frames];
+                for (sample, stored) in channel[filled..filled + frames].iter_mut().zip(stored) {
+                    *sample = f32::from_bits(stored.load(Ordering::Relaxed));
+                }
+            }
+            filled += frames;
+            if from + frames == BLOCK_FRAMES {
+                position.copy_from(&self.positions[slot]);
+                self.read_frames.store(0, Ordering::Relaxed);
+                self.read.store(read + 1, Ordering::Release);
+            } else {
+                self.read_frames.store(from + frames, Ordering::Relaxed);
+            }
+        }
+        fill_silence(out, filled, num_frames);
+    }
+}
+
+/// Starts the thread that renders playback ahead of the output, for `channels` channels, and
+/// returns the queue it renders into. Decoding and moving between tracks happen there, with the
+/// player state locked; the output's callback only reads the queue, so neither a request holding
+/// the lock nor a slow read makes it wait, and a request never waits on decoding for long. The
+/// thread stops once the queue is dropped. It also wakes anyone waiting on the playback position
+/// once the output has moved it, since the output's callback can't.
+pub fn start_render_thread(ps: Arc<Mutex<PlayerState>>, channels: usize) -> Arc<RenderQueue> {
+    let queue = Arc::new(RenderQueue::new(channels));
+    let weak_queue = Arc::downgrade(&queue);
+    let position = ps
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .position
+        .clone();
+    thread::spawn(move || {
+        let mut block = vec![vec![0.0; BLOCK_FRAMES]; channels];
+        let mut seen = None;
+        while let Some(queue) = weak_queue.upgrade() {
+            render_ahead(&ps, &queue, &mut block);
+            drop(queue);
+            let now = Some((position.load(), position.is_playing()));
+            if now != seen {
+                seen = now;
+                position.notify_waiters();
+            }
+            thread::sleep(RENDER_POLL_INTERVAL);
+        }
+    });
+    queue
+}
+
+/// Renders blocks into `queue` until it holds as much as it should, taking the lock for each one
+fn render_ahead(ps: &Mutex<PlayerState>, queue: &RenderQueue, block: &mut [Vec<f32>]) {
+    while queue.wants_block() {
+        let mut ps = ps.lock().unwrap_or_else(PoisonError::into_inner);
+        render(&mut ps, block, BLOCK_FRAMES);
+        queue.push(block, |position| ps.publish_position(position));
+    }
+}
+
+/// What the output device's render callback runs, whichever backend it's on. It plays what the
+/// render thread queued and never touches the player state, so when the output is rebuilt a new
+/// renderer picks up the same playlist and position.
+pub struct OutputRenderer {
+    queue: Arc<RenderQueue>,
+    position: Arc<PlaybackPosition>,
+    monitor: Arc<Monitor>,
+    callback_rate: Arc<CallbackRate>,
+    samples: Vec<Vec<f32>>,
+}
+
+impl OutputRenderer {
+    /// Plays from `queue`, setting `position` as it goes
+    pub fn new(
+        queue: Arc<RenderQueue>,
+        position: Arc<PlaybackPosition>,
+        monitor: Arc<Monitor>,
+        callback_rate: Arc<CallbackRate>,
+    ) -> OutputRenderer {
+        let channels = queue.channels;
+        OutputRenderer {
+            queue,
+            position,
+            monitor,
+            callback_rate,
+            samples: vec![vec![0.0; 1024]; channels],
+        }
+    }
+
+    /// Renders the next `num_frames` frames into the device's channels
+    #[cfg(any(test, target_os = "macos"))]
+    pub fn render<'a>(&mut self, num_frames: usize, channels: impl Iterator<Item = &'a mut [f32]>) {
+        self.render_frames(num_frames);
+        for (channel, rendered) in channels.zip(self.samples.iter()) {
+            let frames = num_frames.min(channel.len());
+            channel[..frames].copy_from_slice(&rendered[..frames]);
+        }
+    }
+
+    /// Renders the next frames into `data` with the channels interleaved, as devices outside
+    /// Core Audio take them. Its length should be a whole number of frames.
+    pub fn render_interleaved(&mut self, data: &mut [f32]) {
+        let channels = self.samples.len();
+        let num_frames = data.len() / channels;
+        self.render_frames(num_frames);
+        for (frame, out) in data.chunks_exact_mut(channels).enumerate() {
+            for (sample, rendered) in out.iter_mut().zip(self.samples.iter()) {
+                *sample = rendered[frame];
+            }
+        }
+    }
+
+    /// Renders the next `num_frames` frames into `samples`
+    fn render_frames(&mut self, num_frames: usize) {
+        self.callback_rate.tick(num_frames);
+        if self.samples[0].len() < num_frames {
+            for channel in self.samples.iter_mut() {
+                channel.resize(num_frames, 0.0);
+            }
+        }
+
+        self.queue
+            .pop(&mut self.samples, num_frames, &self.position);
+        self.monitor.capture(&self.samples, num_frames);
+    }
+}
+
+/// Fills the first `num_frames` frames of each output channel with the next chunk of playback,
+/// advancing the player state. This is what the render thread runs for each block, kept separate
+/// so it can be driven without one.
+/// If a channel is shorter than `num_frames`, only as many frames as the shortest channel holds
+/// are played, and the rest of the longer channels is silence.
+pub fn render(ps: &mut PlayerState, out: &mut [Vec<f32>], num_frames: usize) {
+    let frames = out
+        .iter()
+        .map(|channel| channel.len())
+        .fold(num_frames, usize::min);
+    fill(ps, out, frames);
+    if ps.limiter {
+        for channel in out.iter_mut() {
+            for sample in channel[..frames].iter_mut() {
+                *sample = limiter::limit(*sample);
+            }
+        }
+    }
+    fill_silence(out, frames, num_frames);
+}
+
+fn fill(ps: &mut PlayerState, out: &mut [Vec<f32>], num_frames: usize) {
+    if ps.state == PlaybackState::Paused {
+        fill_silence(out, 0, num_frames);
+        return;
+    }
+
+    let mut consumed_frames: usize = 0;
+    // several short tracks can end within one buffer, but don't spin if none of them has audio
+    let mut tracks_ended = 0;
+
+    while consumed_frames < num_frames {
+        // if the playlist is empty, fill with silence
+        if ps.playlist.is_empty()
+            || tracks_ended > ps.playlist.len()
+            || ps.state == PlaybackState::Paused
+        {
+            fill_silence(out, consumed_frames, num_frames);
+            break;
+        }
+
+        if ps.gap_remaining > 0 {
+            let gap_end = num_frames.min(consumed_frames + ps.gap_remaining as usize);
+            fill_silence(out, consumed_frames, gap_end);
+            ps.gap_remaining -= (gap_end - consumed_frames) as u32;
+            consumed_frames = gap_end;
+            continue;
+        }
+
+        if ps.crossfade.is_some() {
+            consumed_frames = fill_crossfade(ps, out, consumed_frames, num_frames);
+            continue;
+        }
+
+        let offset = ps
+            .current_offset
+            .max(ps.playlist[ps.current_item].trim_start_frame());
+        if !ps.playlist[ps.current_item].is_ready_at(offset) {
+            if ps.playlist[ps.current_item].read_failed() {
+                // it couldn't be opened
+                ps.handle_read_error();
+                tracks_ended += 1;
+                continue;
+            }
+            // opening can take seconds, so it's left to the prefetch thread; wait for it
+            ps.request_open();
+            fill_silence(out, consumed_frames, num_frames);
+            break;
+        }
+
+        // stop at the start of the crossfade out of this track, to begin it on the next pass
+        let crossfade_start = crossfade_start(ps);
+        let until = match crossfade_start {
+            Some(start) if start == offset => {
+                let frames = ps.transitions.crossfade_frames(ps.sample_rate);
+                if ps.begin_transition_crossfade(frames) {
+                    continue;
+                }
+                num_frames
+            }
+            Some(start) if start > offset => {
+                num_frames.min(consumed_frames + (start - offset) as usize)
+            }
+            _ => num_frames,
+        };
+
+        consumed_frames = fill_from_current(ps, out, consumed_frames, until);
+
+        if consumed_frames < until && !ps.playlist[ps.current_item].is_ready_at(ps.current_offset) {
+            // played up to where the file has to be opened again
+            continue;
+        }
+
+        if consumed_frames < until && ps.playlist[ps.current_item].read_failed() {
+            ps.handle_read_error();
+            tracks_ended += 1;
+            continue;
+        }
+
+        if consumed_frames < until {
+            // the track ended partway through the buffer; carry on with the next one
+            let album = match ps.transitions.mode {
+                TransitionMode::Gap => current_album(ps),
+                _ => None,
+            };
+            ps.next();
+            tracks_ended += 1;
+            let same_album = album.is_some() && album == current_album(ps);
+            ps.gap_remaining = ps.transitions.gap_frames(same_album, ps.sample_rate);
+        }
+    }
+
+    if ps.fade.is_some_and(|fade| fade.is_done()) {
+        ps.finish_fade();
+    }
+}
+
+/// Frame of the current track to start crossfading into the next one at, with
+/// `TransitionMode::Crossfade`. None if the track's length isn't known, or it's no longer than
+/// the crossfade.
+fn crossfade_start(ps: &PlayerState) -> Option<u32> {
+    let frames = ps.transitions.crossfade_frames(ps.sample_rate);
+    if frames == 0 {
+        return None;
+    }
+    let src = &ps.playlist[ps.current_item];
+    let end = src.end_frame()?;
+    if end.saturating_sub(src.trim_start_frame()) <= frames {
+        return None;
+    }
+    Some(end - frames)
+}
+
+fn current_album(ps: &PlayerState) -> Option<String> {
+    ps.playlist
+        .get(ps.current_item)
+        .and_then(|src| src.cached_metadata())
+        .map(|metadata| metadata.album.clone())
+        .filter(|album| !album.is_empty())
+}
+
+/// Renders the current track into `out` starting at frame `from`, until either `out` is full or
+/// the track runs out. Returns how many frames of `out` are filled.
+fn fill_from_current(
+    ps: &mut PlayerState,
+    out: &mut [Vec<f32>],
+    from: usize,
+    num_frames: usize,
+) -> usize {
+    let current_item = ps.current_item;
+
+    let src = &mut ps.playlist[current_item];
+    // a trimmed track starts at its in point and ends at its out point
+    let mut current_offset = ps.current_offset.max(src.trim_start_frame());
+    let end_offset = src.trim_end_frame();
+
+    let track_gain = track_gain(ps.replaygain, src);
+
+    let mut consumed_frames = from;
+
+    let mut signal = match src.get_buffer(current_offset) {
+        Some(s) => s,
+        None => return consumed_frames,
+    };
+
+    while consumed_frames < num_frames {
+        if end_offset.is_some_and(|end| current_offset >= end) {
+            break;
+        }
+        if signal.offset + signal.length <= current_offset {
+            if !src.is_ready_at(current_offset) {
+                break;
+            }
+            // grab the next buffer
+            signal = match src.get_buffer(current_offset) {
+                Some(s) => s,
+                None => break,
+            };
+        }
+        if signal.offset > current_offset {
+            // play nothing
+            fill_silence(out, consumed_frames, consumed_frames + 1);
+            consumed_frames += 1;
+            continue;
+        }
+        let signal_index = (current_offset - signal.offset) as usize;
+
+        let gain = track_gain
+            * ps.volume
+            * match ps.fade.as_mut() {
+                Some(fade) => fade.next_gain(),
+                None => 1.0,
+            };
+
+        let out_channels = out.len();
+        for (channel_index, channel) in out.iter_mut().enumerate() {
+            let source_channel = ps
+                .channel_map
+                .get(channel_index)
+                .copied()
+                .unwrap_or(channel_index);
+            let sample =
+                downmix::sample(&signal.samples, signal_index, source_channel, out_channels);
+            channel[consumed_frames] = sample * gain;
+        }
+        consumed_frames += 1;
+        current_offset += 1;
+    }
+
+    src.trim_behind(current_offset);
+    ps.current_offset = current_offset;
+    consumed_frames
+}
+
+fn track_gain(replaygain: bool, src: &AudioFileSource) -> f32 {
+    // only use metadata that's already loaded; reading tags is too slow for this thread
+    match src.cached_metadata() {
+        Some(metadata) if replaygain => replaygain::linear_gain(metadata.gain_db, metadata.peak),
+        _ => 1.0,
+    }
+}
+
+/// The sample for `channel` of `out_channels` at `offset`, or silence if `src` has nothing there
+/// or isn't open
+fn sample_at(
+    src: &mut AudioFileSource,
+    offset: u32,
+    channel: usize,
+    out_channels: usize,
+    channel_map: &[usize],
+) -> f32 {
+    if src.trim_end_frame().is_some_and(|end| offset >= end) || !src.is_ready_at(offset) {
+        return 0.0;
+    }
+    match src.get_buffer(offset) {
+        Some(signal) if signal.offset <= offset => {
+            let source_channel = channel_map.get(channel).copied().unwrap_or(channel);
+            let index = (offset - signal.offset) as usize;
+            downmix::sample(&signal.samples, index, source_channel, out_channels)
+        }
+        _ => 0.0,
+    }
+}
+
+/// Mixes the current track out and the crossfade's track in, starting at frame `from`, until
+/// either `out` is full or the fade is done. Then the incoming track becomes the current one.
+/// Returns how many frames of `out` are filled.
+fn fill_crossfade(
+    ps: &mut PlayerState,
+    out: &mut [Vec<f32>],
+    from: usize,
+    num_frames: usize,
+) -> usize {
+    let mut crossfade = match ps.crossfade {
+        Some(crossfade)
+            if crossfade.item < ps.playlist.len() && crossfade.item != ps.current_item =>
+        {
+            crossfade
+        }
+        _ => {
+            ps.crossfade = None;
+            return from;
+        }
+    };
+
+    let outgoing_gain = track_gain(ps.replaygain, &ps.playlist[ps.current_item]);
+    let incoming_gain = track_gain(ps.replaygain, &ps.playlist[crossfade.item]);
+    let mut outgoing_offset = ps
+        .current_offset
+        .max(ps.playlist[ps.current_item].trim_start_frame());
+
+    let mut consumed_frames = from;
+    while consumed_frames < num_frames && !crossfade.fade.is_done() {
+        let mix = crossfade.fade.next_gain();
+        let gain = ps.volume
+            * match ps.fade.as_mut() {
+                Some(fade) => fade.next_gain(),
+                None => 1.0,
+            };
+        let out_channels = out.len();
+        for (channel_index, channel) in out.iter_mut().enumerate() {
+            let outgoing = sample_at(
+                &mut ps.playlist[ps.current_item],
+                outgoing_offset,
+                channel_index,
+                out_channels,
+                &ps.channel_map,
+            );
+            let incoming = sample_at(
+                &mut ps.playlist[crossfade.item],
+                crossfade.offset,
+                channel_index,
+                out_channels,
+                &ps.channel_map,
+            );
+            channel[consumed_frames] =
+                (outgoing * outgoing_gain * (1.0 - mix) + incoming * incoming_gain * mix) * gain;
+        }
+        consumed_frames += 1;
+        outgoing_offset += 1;
+        // the incoming track starts once it's open
+        if ps.playlist[crossfade.item].is_ready_at(crossfade.offset) {
+            crossfade.offset += 1;
+        } else {
+            ps.request_open();
+        }
+    }
+
+    ps.playlist[ps.current_item].trim_behind(outgoing_offset);
+    ps.playlist[crossfade.item].trim_behind(crossfade.offset);
+    if crossfade.fade.is_done() {
+        ps.finish_crossfade(crossfade.item, crossfade.offset);
+    } else {
+        ps.current_offset = outgoing_offset;
+        ps.crossfade = Some(crossfade);
+    }
+    consumed_frames
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        check_channel_map, render, render_ahead, start_render_thread, CallbackRate, OutputRenderer,
+        RenderQueue, BLOCK_FRAMES, MIN_QUEUED_FRAMES,
+    };
+    use crate::audio_file::{AudioFileSource, PREFETCH_SECS};
+    use crate::audio_source::AudioSource;
+    use crate::monitor::Monitor;
+    use crate::player_state::{open_playing, PlaybackState, PlayerState};
+    use crate::storage::DecodeErrorPolicy;
+    use crate::test_utils::{prepend_txxx_tags, temp_path, write_wav};
+    use crate::transitions::{TransitionMode, TransitionPolicy};
+    #[cfg(unix)]
+    use crate::{audio_file::OpenPolicy, test_utils::make_fifo};
+
+    fn playing_state(samples: &[Vec<f32>]) -> PlayerState {
+        playing_tracks(&[samples])
+    }
+
+    fn playing_tracks(tracks: &[&[Vec<f32>]]) -> PlayerState {
+        let mut ps = PlayerState::new();
+        for samples in tracks {
+            let path = temp_path("render.wav");
+            write_wav(&path, 44100, samples);
+            ps.playlist.push(prefetched(&path));
+        }
+        ps.play();
+        ps
+    }
+
+    /// A track for `path`, opened and with its start decoded, as the prefetch thread leaves the
+    /// tracks about to play
+    fn prefetched(path: &Path) -> AudioFileSource {
+        let mut src = AudioFileSource::new(path.to_str().unwrap().to_string());
+        src.prefetch(PREFETCH_SECS);
+        src
+    }
+
+    #[test]
+    fn renders_track_samples() {
+        let mut ps = playing_state(&[vec![0.5; 44100], vec![-0.5; 44100]]);
+        let mut out = vec![vec![0.0; 1024]; 2];
+        render(&mut ps, &mut out, 1024);
+
+        assert_eq!(ps.current_offset, 1024);
+        assert!(out[0].iter().all(|s| (s - 0.5).abs() < 0.001));
+        assert!(out[1].iter().all(|s| (s + 0.5).abs() < 0.001));
+    }
+
+    #[test]
+    fn plays_between_trim_points() {
+        let mut samples = vec![0.1; 1000];
+        samples.extend(vec![0.5; 2000]);
+        samples.extend(vec![0.9; 2000]);
+        let mut ps = playing_tracks(&[&[samples], &[vec![-0.5; 44100]]]);
+        ps.playlist[0]
+            .set_trim(Some(1000.0 / 44100.0), Some(3000.0 / 44100.0))
+            .unwrap();
+        let dur = ps.playlist[0].get_metadata().dur;
+        assert!((dur - 2000.0 / 44100.0).abs() < 1e-6);
+
+        let mut out = vec![vec![0.0; 4096]; 1];
+        render(&mut ps, &mut out, 4096);
+
+        assert!(out[0][..2000].iter().all(|s| (s - 0.5).abs() < 0.001));
+        assert!(out[0][2000..].iter().all(|s| (s + 0.5).abs() < 0.001));
+        assert_eq!(ps.current_offset, 2096);
+    }
+
+    #[test]
+    fn skips_or_pauses_on_deleted_files() {
+        for policy in [DecodeErrorPolicy::Skip, DecodeErrorPolicy::Pause] {
+            let mut ps =
+                playing_tracks(&[&[vec![0.5; 600]], &[vec![0.9; 44100]], &[vec![-0.5; 44100]]]);
+            ps.consume = false;
+            ps.on_decode_error = policy;
+            std::fs::remove_file(&ps.playlist[1].filename).unwrap();
+            ps.playlist[1].reset();
+            let ps = Mutex::new(ps);
+
+            // playback waits for the deleted track to be opened
+            let mut out = vec![vec![0.0; 1024]; 1];
+            render(&mut ps.lock().unwrap(), &mut out, 1024);
+            assert!(out[0][..600].iter().all(|s| (s - 0.5).abs() < 0.001));
+            assert!(out[0][600..].iter().all(|s| *s == 0.0));
+
+            open_playing(&ps);
+            render(&mut ps.lock().unwrap(), &mut out, 1024);
+            let ps = ps.lock().unwrap();
+            // the deleted track isn't kept around to be saved
+            assert_eq!(ps.playlist.len(), 2);
+            assert_eq!(ps.current_item, 1);
+            match policy {
+                DecodeErrorPolicy::Skip => {
+                    assert_eq!(ps.state, PlaybackState::Playing);
+                    assert!(out[0].iter().all(|s| (s + 0.5).abs() < 0.001));
+                }
+                DecodeErrorPolicy::Pause => {
+                    assert_eq!(ps.state, PlaybackState::Paused);
+                    assert_eq!(ps.current_offset, 0);
+                    assert!(out[0].iter().all(|s| *s == 0.0));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn skips_truncated_files_and_says_why() {
+        let mut ps =
+            playing_tracks(&[&[vec![0.5; 600]], &[vec![0.9; 44100]], &[vec![-0.5; 44100]]]);
+        ps.consume = false;
+        let truncated = ps.playlist[1].filename.clone();
+        let bytes = std::fs::read(&truncated).unwrap();
+        std::fs::write(&truncated, &bytes[..20]).unwrap();
+        ps.playlist[1].reset();
+        assert_eq!(
+            ps.status(Some(&["last_error"]))["last_error"],
+            serde_json::Value::Null
+        );
+        let ps = Mutex::new(ps);
+
+        let mut out = vec![vec![0.0; 1024]; 1];
+        render(&mut ps.lock().unwrap(), &mut out, 1024);
+        open_playing(&ps);
+        render(&mut ps.lock().unwrap(), &mut out, 1024);
+
+        // it's still there to retry, but playback has moved on
+        let mut ps = ps.lock().unwrap();
+        assert_eq!(ps.playlist.len(), 3);
+        assert_eq!(ps.current_item, 2);
+        assert!(out[0].iter().all(|s| (s + 0.5).abs() < 0.001));
+        let last_error = ps.status(Some(&["last_error"]))["last_error"].clone();
+        let last_error = last_error.as_str().unwrap();
+        assert!(last_error.starts_with(&format!("{}: error opening: ", truncated)));
+    }
+
+    #[test]
+    fn crossfades_between_tracks() {
+        let mut ps = playing_tracks(&[&[vec![0.5; 44100]], &[vec![-0.5; 44100]]]);
+        ps.transitions = ps.transitions.with_crossfade(0.1);
+        ps.playlist[0].get_metadata();
+        let incoming = ps.playlist[1].filename.clone();
+
+        let mut out = vec![vec![0.0; 44100]; 1];
+        render(&mut ps, &mut out, 44100);
+
+        // the last 4410 frames of the first track blend into the start of the second
+        let start = 44100 - 4410;
+        assert!(out[0][..start].iter().all(|s| (s - 0.5).abs() < 0.001));
+        assert!(out[0][start + 2205].abs() < 0.01);
+        assert!((out[0][44099] + 0.5).abs() < 0.01);
+        assert_eq!(ps.playlist[ps.current_item].filename, incoming);
+        assert_eq!(ps.current_offset, 4410);
+    }
+
+    #[test]
+    fn doesnt_crossfade_out_of_short_tracks() {
+        let mut ps = playing_tracks(&[&[vec![0.5; 2000]], &[vec![-0.5; 44100]]]);
+        ps.transitions = ps.transitions.with_crossfade(0.1);
+        ps.playlist[0].get_metadata();
+
+        let mut out = vec![vec![0.0; 4096]; 1];
+        render(&mut ps, &mut out, 4096);
+
+        assert!(out[0][..2000].iter().all(|s| (s - 0.5).abs() < 0.001));
+        assert!(out[0][2000..].iter().all(|s| (s + 0.5).abs() < 0.001));
+    }
+
+    #[test]
+    fn crossfades_into_next_track_on_demand() {
+        let mut ps = playing_tracks(&[&[vec![0.5; 44100]], &[vec![-0.5; 44100]]]);
+        let mut out = vec![vec![0.0; 1000]; 1];
+        render(&mut ps, &mut out, 1000);
+
+        ps.crossfade_now(2000).unwrap();
+        let mut out = vec![vec![0.0; 4000]; 1];
+        render(&mut ps, &mut out, 4000);
+
+        // both tracks play while the fade is in progress
+        assert!((out[0][0] - 0.5).abs() < 0.001);
+        assert!(out[0][1000].abs() < 0.001);
+        assert!((out[0][1500] + 0.25).abs() < 0.001);
+        // then only the new one, carrying on from where the fade left it
+        assert!(out[0][2000..].iter().all(|s| (s + 0.5).abs() < 0.001));
+        assert!(ps.crossfade.is_none());
+        assert_eq!(ps.playlist.len(), 1);
+        assert_eq!(ps.current_offset, 4000);
+
+        // there's nothing left to crossfade into
+        assert!(ps.crossfade_now(2000).is_err());
+    }
+
+    #[test]
+    fn fills_only_what_short_channels_hold() {
+        let mut ps = playing_state(&[vec![0.5; 44100], vec![-0.5; 44100]]);
+        let mut out = vec![vec![1.0; 1024], vec![1.0; 500]];
+        render(&mut ps, &mut out, 1024);
+
+        assert_eq!(ps.current_offset, 500);
+        assert!(out[0][..500].iter().all(|s| (s - 0.5).abs() < 0.001));
+        assert!(out[0][500..].iter().all(|s| *s == 0.0));
+        assert!(out[1].iter().all(|s| (s + 0.5).abs() < 0.001));
+
+        // the silence path is bounded too
+        ps.pause();
+        render(&mut ps, &mut out, 1024);
+        assert!(out.iter().all(|channel| channel.iter().all(|s| *s == 0.0)));
+    }
+
+    #[test]
+    fn continues_into_next_track_without_a_gap() {
+        let mut ps = playing_tracks(&[&[vec![0.5; 600]], &[vec![-0.5; 44100]]]);
+        let mut out = vec![vec![0.0; 1024]; 1];
+        render(&mut ps, &mut out, 1024);
+
+        assert!(out[0][..600].iter().all(|s| (s - 0.5).abs() < 0.001));
+        assert!(out[0][600..].iter().all(|s| (s + 0.5).abs() < 0.001));
+        assert_eq!(ps.playlist.len(), 1);
+        assert_eq!(ps.current_offset, 424);
+    }
+
+    #[test]
+    fn several_tracks_can_end_in_one_buffer() {
+        let mut ps = playing_tracks(&[
+            &[vec![0.1; 100]],
+            &[vec![0.2; 50]],
+            &[vec![0.3; 300]],
+            &[vec![0.4; 44100]],
+        ]);
+        ps.consume = false;
+        let mut out = vec![vec![0.0; 1024]; 1];
+        render(&mut ps, &mut out, 1024);
+
+        assert!(out[0][..100].iter().all(|s| (s - 0.1).abs() < 0.001));
+        assert!(out[0][100..150].iter().all(|s| (s - 0.2).abs() < 0.001));
+        assert!(out[0][150..450].iter().all(|s| (s - 0.3).abs() < 0.001));
+        assert!(out[0][450..].iter().all(|s| (s - 0.4).abs() < 0.001));
+        assert_eq!(ps.current_item, 3);
+        assert_eq!(ps.current_offset, 574);
+    }
+
+    #[test]
+    fn inserts_gap_between_tracks() {
+        let mut ps = playing_tracks(&[&[vec![0.5; 300]], &[vec![-0.5; 44100]]]);
+        ps.transitions = TransitionPolicy {
+            mode: TransitionMode::Gap,
+            gap_secs: 1000.0 / 44100.0,
+            ..TransitionPolicy::default()
+        };
+        let mut out = vec![vec![1.0; 1024]; 1];
+        render(&mut ps, &mut out, 1024);
+        assert!(out[0][..300].iter().all(|s| (s - 0.5).abs() < 0.001));
+        assert!(out[0][300..].iter().all(|s| *s == 0.0));
+
+        render(&mut ps, &mut out, 1024);
+        assert!(out[0][..276].iter().all(|s| *s == 0.0));
+        assert!(out[0][276..].iter().all(|s| (s + 0.5).abs() < 0.001));
+        assert_eq!(ps.current_offset, 1024 - 276);
+    }
+
+    #[test]
+    fn silence_after_the_last_track() {
+        let mut ps = playing_tracks(&[&[vec![0.5; 300]]]);
+        let mut out = vec![vec![1.0; 1024]; 1];
+        render(&mut ps, &mut out, 1024);
+
+        assert!(out[0][..300].iter().all(|s| (s - 0.5).abs() < 0.001));
+        assert!(out[0][300..].iter().all(|s| *s == 0.0));
+        assert!(ps.playlist.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_tracks_that_wont_open() {
+        let fifo = make_fifo("slow.wav");
+        let mut slow = AudioFileSource::new(fifo.to_str().unwrap().to_string());
+        slow.set_open_policy(OpenPolicy {
+            timeout: Duration::from_millis(100),
+            retries: 1,
+        });
+        let mut ps = playing_state(&[vec![0.5; 44100]]);
+        ps.playlist.insert(0, slow);
+        let open_requests = ps.open_requests();
+        let ps = Mutex::new(ps);
+
+        // rendering doesn't wait on the open; it plays silence until the track is ready
+        let mut out = vec![vec![1.0; 256]; 1];
+        render(&mut ps.lock().unwrap(), &mut out, 256);
+        assert!(out[0].iter().all(|s| *s == 0.0));
+        assert_eq!(ps.lock().unwrap().playlist.len(), 2);
+        assert!(open_requests.try_recv().is_ok());
+
+        // the prefetch thread gives up on it, so the next render skips it
+        open_playing(&ps);
+        render(&mut ps.lock().unwrap(), &mut out, 256);
+        assert!(out[0].iter().all(|s| (s - 0.5).abs() < 0.001));
+        assert_eq!(ps.lock().unwrap().playlist.len(), 1);
+
+        // unblock the abandoned opens
+        std::fs::OpenOptions::new().write(true).open(&fifo).unwrap();
+    }
+
+    #[test]
+    fn resamples_to_the_output_rate() {
+        let path = temp_path("48k.wav");
+        let samples: Vec<f32> = (0..48000).map(|i| (i as f32 * 0.01).sin() * 0.5).collect();
+        write_wav(&path, 48000, &[samples]);
+
+        let mut src = AudioFileSource::new(path.to_str().unwrap().to_string());
+        src.set_output_sample_rate(44100);
+        let mut offset = 0;
+        while let Some(buffer) = src.get_buffer(offset) {
+            assert_eq!(buffer.sample_rate, 44100.0);
+            offset = buffer.offset + buffer.length;
+        }
+        assert!((offset as i64 - 44100).abs() <= 2);
+
+        // seeking lands on the same audio at either rate
+        let mut src = AudioFileSource::new(path.to_str().unwrap().to_string());
+        let buffer = src.get_buffer(22050).unwrap();
+        let index = (22050 - buffer.offset) as usize;
+        assert!((buffer.samples[0][index] - (24000.0f32 * 0.01).sin() * 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn applies_volume() {
+        let mut ps = playing_state(&[vec![0.5; 44100]]);
+        ps.set_volume(0.5);
+        let mut out = vec![vec![0.0; 256]; 1];
+        render(&mut ps, &mut out, 256);
+        assert!(out[0].iter().all(|s| (s - 0.25).abs() < 0.001));
+    }
+
+    #[test]
+    fn limits_loud_output() {
+        // boosted past full scale by its ReplayGain tag
+        let mut ps = playing_state(&[vec![0.75; 44100]]);
+        prepend_txxx_tags(
+            Path::new(&ps.playlist[0].filename),
+            &[("REPLAYGAIN_TRACK_GAIN", "+6.02 dB")],
+        );
+        ps.playlist[0] = prefetched(Path::new(&ps.playlist[0].filename));
+        ps.replaygain = true;
+        ps.playlist[0].get_metadata();
+        let mut out = vec![vec![0.0; 256]; 1];
+        render(&mut ps, &mut out, 256);
+        assert!(out[0].iter().all(|s| (s - 1.5).abs() < 0.001));
+
+        ps.limiter = true;
+        render(&mut ps, &mut out, 256);
+        assert!(out[0].iter().all(|s| *s > 0.9 && *s <= 1.0));
+    }
+
+    #[test]
+    fn applies_replaygain_from_track_tags() {
+        let mut ps = playing_tracks(&[&[vec![0.5; 44100]], &[vec![0.5; 44100]]]);
+        prepend_txxx_tags(
+            Path::new(&ps.playlist[1].filename),
+            &[("REPLAYGAIN_TRACK_GAIN", "-6.02 dB")],
+        );
+        ps.replaygain = true;
+        ps.playlist[0].get_metadata();
+        ps.playlist[1].get_metadata();
+        assert_eq!(
+            ps.playlist[1].cached_metadata().unwrap().gain_db,
+            Some(-6.02)
+        );
+
+        // untagged tracks play at unity gain
+        let mut untagged = vec![vec![0.0; 256]; 1];
+        render(&mut ps, &mut untagged, 256);
+        ps.skip_to(1);
+        let mut tagged = vec![vec![0.0; 256]; 1];
+        render(&mut ps, &mut tagged, 256);
+
+        assert!(untagged[0].iter().all(|s| (s - 0.5).abs() < 0.001));
+        assert!(tagged[0].iter().all(|s| (s - 0.25).abs() < 0.001));
+    }
+
+    #[test]
+    fn played_buffers_are_trimmed() {
+        let mut ps = playing_state(&[vec![0.25; 44100 * 20]]);
+        let mut out = vec![vec![0.0; 1024]; 1];
+        for _ in 0..(44100 * 15 / 1024) {
+            render(&mut ps, &mut out, 1024);
+        }
+
+        let offset = ps.current_offset;
+        assert!(offset > 44100 * 14);
+        let buffers = ps.playlist[0].decoded_buffers();
+        assert!(!buffers.is_empty());
+        let earliest = buffers.iter().map(|buffer| buffer.offset).min().unwrap();
+        // a little more than the two second look-behind, allowing for buffer granularity
+        assert!(offset - earliest < 3 * 44100);
+    }
+
+    #[test]
+    fn channel_map_swaps_stereo() {
+        let mut ps = playing_state(&[vec![0.5; 44100], vec![-0.5; 44100]]);
+        ps.channel_map = vec![1, 0];
+        let mut out = vec![vec![0.0; 1024]; 2];
+        render(&mut ps, &mut out, 1024);
+
+        assert!(out[0].iter().all(|s| (s + 0.5).abs() < 0.001));
+        assert!(out[1].iter().all(|s| (s - 0.5).abs() < 0.001));
+    }
+
+    #[test]
+    fn downmixes_to_the_output_channels() {
+        let stereo = [vec![0.5; 44100], vec![-0.25; 44100]];
+        let mut ps = playing_state(&stereo);
+        let mut out = vec![vec![0.0; 1024]; 1];
+        render(&mut ps, &mut out, 1024);
+        assert!(out[0].iter().all(|s| (s - 0.125).abs() < 0.001));
+
+        // the map picks from the downmixed channels
+        let mut ps = playing_state(&[vec![0.5; 44100]]);
+        ps.channel_map = vec![1, 1];
+        let mut out = vec![vec![0.0; 1024]; 2];
+        render(&mut ps, &mut out, 1024);
+        assert!(out.iter().flatten().all(|s| (s - 0.5).abs() < 0.001));
+    }
+
+    #[test]
+    fn validates_channel_map() {
+        assert!(check_channel_map(&[], 2).is_ok());
+        assert!(check_channel_map(&[1, 0], 2).is_ok());
+        assert!(check_channel_map(&[0, 0], 2).is_ok());
+        assert!(check_channel_map(&[1, 0, 0], 2).is_err());
+        assert!(check_channel_map(&[0, 2], 2).is_err());
+    }
+
+    #[test]
+    fn fade_to_pause_ramps_to_zero_then_pauses() {
+        let mut ps = playing_state(&[vec![0.5; 44100]]);
+        let mut out = vec![vec![0.0; 512]; 1];
+        render(&mut ps, &mut out, 512);
+        assert_eq!(ps.current_offset, 512);
+
+        ps.fade_to_pause(1024);
+
+        render(&mut ps, &mut out, 512);
+        assert!((out[0][0] - 0.5).abs() < 0.001);
+        for pair in out[0].windows(2) {
+            assert!(pair[1] <= pair[0]);
+        }
+        assert_eq!(ps.state, PlaybackState::Playing);
+
+        render(&mut ps, &mut out, 512);
+        assert!(out[0][511].abs() < 0.001);
+        assert_eq!(ps.state, PlaybackState::Paused);
+        assert_eq!(ps.current_offset, 512);
+        assert!(ps.fade.is_none());
+
+        render(&mut ps, &mut out, 512);
+        assert!(out[0].iter().all(|s| *s == 0.0));
+    }
+
+    #[test]
+    fn ramps_up_on_play_and_down_on_pause() {
+        let mut ps = playing_state(&[vec![0.5; 44100]]);
+        ps.pause();
+        ps.play_pause_fade_ms = 20;
+        let fade_frames = 882;
+
+        ps.play();
+        let mut out = vec![vec![0.0; 1024]; 1];
+        render(&mut ps, &mut out, 1024);
+        assert!(out[0][0].abs() < 0.001);
+        for pair in out[0][..fade_frames].windows(2) {
+            assert!(pair[1] > pair[0]);
+        }
+        assert!(out[0][fade_frames..]
+            .iter()
+            .all(|s| (s - 0.5).abs() < 0.001));
+        assert!(ps.fade.is_none());
+
+        ps.soft_pause();
+        assert_eq!(ps.state, PlaybackState::Playing);
+        render(&mut ps, &mut out, 1024);
+        assert!((out[0][0] - 0.5).abs() < 0.001);
+        for pair in out[0][..fade_frames].windows(2) {
+            assert!(pair[1] < pair[0]);
+        }
+        assert!(out[0][fade_frames..].iter().all(|s| s.abs() < 0.001));
+        assert_eq!(ps.state, PlaybackState::Paused);
+        // picks up where the fade out began
+        assert_eq!(ps.current_offset, 1024);
+    }
+
+    #[test]
+    fn status_reads_dont_block_playback() {
+        let ps = Arc::new(Mutex::new(playing_state(&[vec![0.5; 44100 * 10]])));
+        let position = ps.lock().unwrap().position.clone();
+        let mut renderer = OutputRenderer::new(
+            start_render_thread(ps.clone(), 1),
+            position.clone(),
+            Arc::new(Monitor::new(1, 44100)),
+            Arc::new(CallbackRate::new()),
+        );
+
+        // hammer /status, keeping every offset it reports
+        let done = Arc::new(AtomicBool::new(false));
+        let status_ps = ps.clone();
+        let status_done = done.clone();
+        let poller = thread::spawn(move || {
+            let mut offsets = vec![];
+            while !status_done.load(Ordering::Relaxed) {
+                let status = status_ps.lock().unwrap().status(Some(&["item", "offset"]));
+                assert_eq!(status["current_item"], 0);
+                offsets.push(status["current_offset"].as_f64().unwrap());
+            }
+            offsets
+        });
+
+        // play half a second, a few times faster than a device would ask for it
+        let mut played = 0;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while played < 22050 {
+            assert!(Instant::now() < deadline, "playback stalled");
+            let mut device = vec![1.0; 256];
+            renderer.render(256, std::iter::once(device.as_mut_slice()));
+            // silence until the render thread has queued something
+            assert!(device
+                .iter()
+                .all(|sample| *sample == 0.0 || (sample - 0.5).abs() < 0.001));
+            played += device.iter().filter(|sample| **sample != 0.0).count();
+            // the position is what's been played, to the block
+            assert_eq!(
+                position.load(),
+                (0, (played / BLOCK_FRAMES * BLOCK_FRAMES) as u32)
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        done.store(true, Ordering::Relaxed);
+        let offsets = poller.join().unwrap();
+
+        // /status only ever reported positions that had been played, in order
+        assert!(offsets.windows(2).all(|pair| pair[0] <= pair[1]));
+        for offset in offsets.iter() {
+            let frames = (offset * 44100.0).round() as usize;
+            assert_eq!(frames % BLOCK_FRAMES, 0);
+            assert!(frames <= played);
+        }
+        let status = ps.lock().unwrap().status(Some(&["offset"]));
+        assert_eq!(
+            status["current_offset"],
+            (played / BLOCK_FRAMES * BLOCK_FRAMES) as f64 / 44100.0
+        );
+    }
+
+    #[test]
+    fn elapsed_tracks_current_offset() {
+        let path = temp_path("elapsed.wav");
+        write_wav(&path, 44100, &[vec![0.0; 44100 * 2]]);
+
+        let mut ps = PlayerState::new();
+        assert!(ps.position.elapsed().is_none());
+
+        ps.add_tracks(vec![path.to_str().unwrap().to_string()]);
+        ps.play();
+        ps.playlist[0].get_metadata();
+
+        let mut out = vec![vec![0.0; 1024]; 1];
+        render(&mut ps, &mut out, 1024);
+        ps.publish_position(&ps.position);
+        let elapsed = ps.position.elapsed().unwrap();
+        assert_eq!(elapsed.elapsed_secs, 1024.0 / 44100.0);
+        assert!((elapsed.duration_secs.unwrap() - 2.0).abs() < 0.001);
+        assert_eq!(elapsed.state, "playing");
+
+        render(&mut ps, &mut out, 1024);
+        ps.publish_position(&ps.position);
+        assert_eq!(
+            ps.position.elapsed().unwrap().elapsed_secs,
+            2048.0 / 44100.0
+        );
+
+        ps.pause();
+        ps.publish_position(&ps.position);
+        assert_eq!(ps.position.elapsed().unwrap().state, "paused");
+
+        ps.clear();
+        ps.publish_position(&ps.position);
+        assert!(ps.position.elapsed().is_none());
+    }
+
+    #[test]
+    fn measures_the_callback_rate() {
+        let rate = CallbackRate::new();
+        assert_eq!(rate.report().callbacks_per_sec, 0.0);
+
+        // 512 frames at 48kHz, like a device would ask for them, for a couple of seconds
+        let interval = 512.0 / 48000.0 * 1e9;
+        for tick in 0..200 {
+            rate.tick_at((tick as f64 * interval) as u64, 512);
+        }
+        let report = rate.report();
+        assert!((report.callbacks_per_sec - 48000.0 / 512.0).abs() < 0.01);
+        assert_eq!(report.frames_per_callback, 512.0);
+        assert!((report.frames_per_sec - 48000.0).abs() < 1.0);
+    }
+
+    /// A queue holding as much as the render thread keeps queued
+    fn rendered_ahead(ps: &Mutex<PlayerState>, channels: usize) -> Arc<RenderQueue> {
+        let queue = Arc::new(RenderQueue::new(channels));
+        render_ahead(ps, &queue, &mut vec![vec![0.0; BLOCK_FRAMES]; channels]);
+        queue
+    }
+
+    #[test]
+    fn rebuilt_renderer_carries_on_from_shared_state() {
+        let samples: Vec<f32> = (0..16384).map(|i| i as f32 / 32768.0).collect();
+        let ps = Arc::new(Mutex::new(playing_tracks(&[
+            std::slice::from_ref(&samples),
+            &[vec![0.5; 4096]],
+        ])));
+        let position = ps.lock().unwrap().position.clone();
+        let monitor = Arc::new(Monitor::new(1, 44100));
+        let callback_rate = Arc::new(CallbackRate::new());
+        let renderer = || {
+            OutputRenderer::new(
+                rendered_ahead(&ps, 1),
+                position.clone(),
+                monitor.clone(),
+                callback_rate.clone(),
+            )
+        };
+
+        let mut device = vec![0.0; 512];
+        renderer().render(512, std::iter::once(device.as_mut_slice()));
+        assert_eq!(position.load(), (0, 512));
+        // as if the audio unit were rebuilt, e.g. for a different device: what the old one had
+        // queued is dropped, and the new one plays on from the player state
+        let rendered = ps.lock().unwrap().current_offset as usize;
+        renderer().render(512, std::iter::once(device.as_mut_slice()));
+
+        assert_eq!(position.load(), (0, rendered as u32 + 512));
+        assert_eq!(ps.lock().unwrap().playlist.len(), 2);
+        for (played, expected) in device.iter().zip(&samples[rendered..rendered + 512]) {
+            assert!((played - expected).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn renders_interleaved_frames() {
+        let left: Vec<f32> = (0..8192).map(|i| i as f32 / 16384.0).collect();
+        let right: Vec<f32> = left.iter().map(|sample| -sample).collect();
+        let ps = Mutex::new(playing_tracks(&[&[left.clone(), right.clone()]]));
+        let position = ps.lock().unwrap().position.clone();
+        let mut renderer = OutputRenderer::new(
+            rendered_ahead(&ps, 2),
+            position.clone(),
+            Arc::new(Monitor::new(2, 44100)),
+            Arc::new(CallbackRate::new()),
+        );
+
+        let mut device = vec![0.0; 1024];
+        renderer.render_interleaved(&mut device);
+        renderer.render_interleaved(&mut device);
+
+        assert_eq!(position.load(), (0, 1024));
+        for (frame, out) in device.chunks_exact(2).enumerate() {
+            assert!((out[0] - left[512 + frame]).abs() < 0.001);
+            assert!((out[1] - right[512 + frame]).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn plays_silence_when_nothing_is_queued() {
+        let ps = Mutex::new(playing_state(&[vec![0.5; 44100]]));
+        let position = ps.lock().unwrap().position.clone();
+        let queue = Arc::new(RenderQueue::new(1));
+        let mut renderer = OutputRenderer::new(
+            queue.clone(),
+            position.clone(),
+            Arc::new(Monitor::new(1, 44100)),
+            Arc::new(CallbackRate::new()),
+        );
+
+        let mut device = vec![1.0; 512];
+        renderer.render(512, std::iter::once(device.as_mut_slice()));
+        assert!(device.iter().all(|sample| *sample == 0.0));
+        assert!(position.elapsed().is_none());
+
+        // the render thread tops it up to the minimum, since callbacks are smaller
+        render_ahead(&ps, &queue, &mut vec![vec![0.0; BLOCK_FRAMES]; 1]);
+        assert_eq!(
+            ps.lock().unwrap().current_offset as usize,
+            MIN_QUEUED_FRAMES
+        );
+        renderer.render(512, std::iter::once(device.as_mut_slice()));
+        assert!(device.iter().all(|sample| (sample - 0.5).abs() < 0.001));
+        assert_eq!(position.load(), (0, 512));
+    }
+}
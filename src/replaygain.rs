@@ -0,0 +1,62 @@
+/// Parses a ReplayGain gain tag like `-6.20 dB`
+pub fn parse_gain_db(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    value.trim().parse::<f32>().ok().filter(|db| db.is_finite())
+}
+
+/// Parses a ReplayGain peak tag like `0.988312`
+pub fn parse_peak(value: &str) -> Option<f32> {
+    value
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|peak| peak.is_finite() && *peak > 0.0)
+}
+
+/// Linear gain to apply for a track's ReplayGain tags. When the peak is known the gain is
+/// reduced so that the loudest sample doesn't exceed full scale; without a peak, clipping is
+/// left to the output stage.
+pub fn linear_gain(gain_db: Option<f32>, peak: Option<f32>) -> f32 {
+    let gain = match gain_db {
+        Some(db) => 10f32.powf(db / 20.0),
+        None => return 1.0,
+    };
+    match peak {
+        Some(peak) => gain.min(1.0 / peak),
+        None => gain,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{linear_gain, parse_gain_db, parse_peak};
+
+    #[test]
+    fn parses_tags() {
+        assert_eq!(parse_gain_db("-6.00 dB"), Some(-6.0));
+        assert_eq!(parse_gain_db("+3.5 dB"), Some(3.5));
+        assert_eq!(parse_gain_db("2.1"), Some(2.1));
+        assert_eq!(parse_gain_db("loud"), None);
+        assert_eq!(parse_peak("0.988312"), Some(0.988312));
+        assert_eq!(parse_peak("0"), None);
+    }
+
+    #[test]
+    fn applies_gain() {
+        assert_eq!(linear_gain(None, Some(0.5)), 1.0);
+        assert!((linear_gain(Some(-6.0), None) - 0.501).abs() < 0.001);
+        assert!((linear_gain(Some(-6.0), Some(0.9)) - 0.501).abs() < 0.001);
+    }
+
+    #[test]
+    fn high_peak_limits_gain() {
+        let peak = 0.9;
+        let gain = linear_gain(Some(6.0), Some(peak));
+        assert!(gain < 10f32.powf(6.0 / 20.0));
+        assert!(gain * peak <= 1.0);
+    }
+}
@@ -0,0 +1,102 @@
+/// Streaming linear-interpolation resampler for planar audio. Keeps the last input frame of each
+/// chunk so consecutive chunks join up without clicks.
+#[derive(Debug)]
+pub struct Resampler {
+    from_rate: u32,
+    to_rate: u32,
+    /// Input frames advanced per output frame
+    step: f64,
+    /// Position of the next output frame, in input frames from the start of `history`
+    position: f64,
+    /// Last input frame of the previous chunk, one sample per channel
+    history: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32) -> Resampler {
+        Resampler {
+            from_rate,
+            to_rate,
+            step: from_rate as f64 / to_rate as f64,
+            position: 0.0,
+            history: vec![],
+        }
+    }
+
+    pub fn converts(&self, from_rate: u32, to_rate: u32) -> bool {
+        self.from_rate == from_rate && self.to_rate == to_rate
+    }
+
+    /// Resamples the next chunk of input. Output frames that need input past the end of this
+    /// chunk are produced by the next call.
+    pub fn process(&mut self, input: &[Vec<f32>]) -> Vec<Vec<f32>> {
+        let channels = input.len();
+        if self.history.len() != channels {
+            // first chunk, or the channel count changed; start over
+            self.history.clear();
+            self.position = 0.0;
+        }
+
+        let history_frames = if self.history.is_empty() { 0 } else { 1 };
+        let input_frames = input.first().map_or(0, |channel| channel.len());
+        let total_frames = history_frames + input_frames;
+
+        let frame = |channel: usize, index: usize| -> f32 {
+            if index < history_frames {
+                self.history[channel]
+            } else {
+                input[channel][index - history_frames]
+            }
+        };
+
+        let mut output = vec![Vec::new(); channels];
+        let mut position = self.position;
+        while (position as usize) + 1 < total_frames {
+            let index = position as usize;
+            let frac = (position - index as f64) as f32;
+            for (channel, samples) in output.iter_mut().enumerate() {
+                let a = frame(channel, index);
+                let b = frame(channel, index + 1);
+                samples.push(a + (b - a) * frac);
+            }
+            position += self.step;
+        }
+
+        if total_frames > 0 {
+            self.history = (0..channels)
+                .map(|channel| frame(channel, total_frames - 1))
+                .collect();
+            self.position = position - (total_frames - 1) as f64;
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Resampler;
+
+    #[test]
+    fn resamples_across_chunks() {
+        let mut resampler = Resampler::new(48000, 44100);
+        let mut frames = 0;
+        for _ in 0..48 {
+            let output = resampler.process(&[vec![0.5; 1000], vec![-0.5; 1000]]);
+            assert_eq!(output.len(), 2);
+            assert!(output[0].iter().all(|s| (s - 0.5).abs() < 1e-6));
+            assert!(output[1].iter().all(|s| (s + 0.5).abs() < 1e-6));
+            frames += output[0].len();
+        }
+        assert!((frames as i64 - 44100).abs() <= 1);
+    }
+
+    #[test]
+    fn interpolates_between_samples() {
+        let mut resampler = Resampler::new(1, 2);
+        let output = resampler.process(&[vec![0.0, 1.0]]);
+        assert_eq!(output, vec![vec![0.0, 0.5]]);
+        let output = resampler.process(&[vec![0.0]]);
+        assert_eq!(output, vec![vec![1.0, 0.5]]);
+    }
+}
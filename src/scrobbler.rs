@@ -1,16 +1,110 @@
 // TODO: move NowPlaying out of player_state
+mod archive;
 mod audio_file;
 mod audio_source;
+mod fade;
 mod player_state;
+mod replaygain;
+mod resample;
 mod storage;
+#[cfg(test)]
+mod test_utils;
+mod transitions;
+mod web_framework;
 
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
 use std::{borrow::BorrowMut, collections::HashMap};
 
+use futures::future::LocalBoxFuture;
 use futures::stream::StreamExt;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use player_state::NowPlaying;
 use reqwest_eventsource::{Event, EventSource};
 use serde::{Deserialize, Serialize};
+use std::net::TcpListener;
+use storage::PjpConfig;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use web_framework::{HttpMethod, HttpRequest, HttpResponse, HttpResponseCode, RequestError};
+
+const LAST_FM_API_ROOT: &str = "https://ws.audioscrobbler.com/2.0/";
+
+const LISTENBRAINZ_API_ROOT: &str = "https://api.listenbrainz.org/1/";
+
+/// Most scrobbles last.fm takes in one request
+const LAST_FM_MAX_BATCH: usize = 50;
+
+/// ListenBrainz takes up to 1000, but keeps each request small
+const LISTENBRAINZ_MAX_BATCH: usize = 100;
+
+/// A play counts as a listen after this much of it, even if it's less than half the track
+const LISTEN_MIN_SECS: f64 = 4.0 * 60.0;
+
+/// How long after a restored track should have ended that it's still re-sent as now playing on
+/// startup. Leaves room for the track having been paused for a while.
+const RESUME_NOW_PLAYING_SLACK_SECS: f64 = 10.0 * 60.0;
+
+/// Delay before the first reconnect to the player's event stream after losing it
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Reconnect delays stop doubling here
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+fn default_api_root() -> String {
+    LAST_FM_API_ROOT.to_string()
+}
+
+fn default_listenbrainz_api_root() -> String {
+    LISTENBRAINZ_API_ROOT.to_string()
+}
+
+/// Delays between reconnect attempts that double with each failure in a row, up to a cap. Each
+/// is jittered down by up to half so scrobblers that lost the player together don't all retry
+/// together.
+struct Backoff {
+    base: Duration,
+    max: Duration,
+    failures: u32,
+    /// xorshift state for the jitter
+    rng: u64,
+}
+
+impl Backoff {
+    fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            base,
+            max,
+            failures: 0,
+            rng: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+                | 1,
+        }
+    }
+
+    /// Back to the base delay, once a connection has opened
+    fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .base
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(self.max);
+        self.failures = self.failures.saturating_add(1);
+
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        let jitter = (x >> 11) as f64 / (1u64 << 53) as f64;
+        delay.mul_f64(1.0 - 0.5 * jitter)
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 struct LastFMToken {
@@ -70,23 +164,450 @@ pub struct GetLovedTracksResult {
     lovedtracks: LastFMTracks,
 }
 
+#[derive(Debug, Deserialize)]
+struct LastFMTrackMatch {
+    name: String,
+    artist: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LastFMTrackMatches {
+    track: Vec<LastFMTrackMatch>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LastFMTrackSearchResults {
+    trackmatches: LastFMTrackMatches,
+}
+
+#[derive(Debug, Deserialize)]
+struct TrackSearchResult {
+    results: LastFMTrackSearchResults,
+}
+
+/// A service that takes scrobbles. Listens wait in the target's own queue until it takes them,
+/// so one target being down doesn't hold up the others.
+pub trait ScrobbleTarget {
+    /// For logs
+    fn name(&self) -> &'static str;
+
+    /// Listens still to be submitted, oldest first
+    fn queue(&mut self) -> &mut Vec<NowPlaying>;
+
+    /// The most listens `submit_listens` takes at once
+    fn max_batch(&self) -> usize;
+
+    fn submit_now_playing<'a>(
+        &'a mut self,
+        track: &'a NowPlaying,
+    ) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>>;
+
+    /// Fails with `Rejected` if the service turned the listens down for good, rather than with
+    /// an error that might go away
+    fn submit_listens<'a>(
+        &'a mut self,
+        listens: &'a [NowPlaying],
+    ) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>>;
+}
+
+/// Listens a target won't ever accept, so they're dropped rather than retried
+#[derive(Debug)]
+struct Rejected(String);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for Rejected {}
+
+/// Submits the oldest batch of `target`'s queue. Returns how many listens were submitted.
+async fn submit_batch(target: &mut dyn ScrobbleTarget) -> Result<usize, Box<dyn Error>> {
+    let len = target.queue().len().min(target.max_batch());
+    if len == 0 {
+        return Ok(0);
+    }
+
+    let batch = target.queue()[..len].to_vec();
+    match target.submit_listens(&batch).await {
+        Ok(()) => {
+            target.queue().drain(..len);
+            Ok(len)
+        }
+        Err(err) => {
+            if err.is::<Rejected>() {
+                // failure; don't retry
+                target.queue().drain(..len);
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Follows plays from the player's events and fans now playing and finished listens out to
+/// each configured target
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Scrobbler {
+    /// Flattened so sessions saved from before there were other targets still load
+    #[serde(flatten)]
+    last_fm: Option<LastFM>,
+
+    #[serde(skip)]
+    listenbrainz: Option<ListenBrainz>,
+
+    now_playing_start: Option<NowPlaying>,
+    now_playing_end: Option<NowPlaying>,
+
+    /// The most recently queued scrobble, as queued, to space out replays of the same track
+    #[serde(default)]
+    last_scrobbled: Option<NowPlaying>,
+
+    /// Set from the config
+    #[serde(skip)]
+    min_repeat_gap_secs: Option<f64>,
+
+    /// Set from the config
+    #[serde(skip)]
+    correct_metadata: bool,
+
+    /// The artist and title last.fm matched each looked up title to, or none if it had no match
+    #[serde(skip)]
+    corrections: HashMap<String, Option<(String, String)>>,
+
+    /// Where the queued listens are saved; none keeps them in memory only
+    #[serde(skip)]
+    queue_path: Option<PathBuf>,
+}
+
+/// Each target's listens that are still to be submitted. They're saved to their own file after
+/// every change, apart from the session, so a crash can't lose them and a bad save of the session
+/// can't take them with it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ScrobbleQueue {
+    #[serde(default)]
+    last_fm: Vec<NowPlaying>,
+    #[serde(default)]
+    listenbrainz: Vec<NowPlaying>,
+}
+
+impl Scrobbler {
+    fn targets(&mut self) -> Vec<&mut dyn ScrobbleTarget> {
+        let mut targets: Vec<&mut dyn ScrobbleTarget> = vec![];
+        if let Some(last_fm) = &mut self.last_fm {
+            targets.push(last_fm);
+        }
+        if let Some(listenbrainz) = &mut self.listenbrainz {
+            targets.push(listenbrainz);
+        }
+        targets
+    }
+
+    /// Submits everything that's queued rather than waiting for the next track change. Returns
+    /// how many listens were submitted, counting each target separately.
+    pub async fn flush(&mut self) -> Result<usize, Box<dyn Error>> {
+        let mut submitted = 0;
+        let mut result = Ok(());
+        for target in self.targets() {
+            while !target.queue().is_empty() {
+                match submit_batch(&mut *target).await {
+                    Ok(batch) => submitted += batch,
+                    Err(err) => {
+                        error!("error scrobbling to {}: {}", target.name(), err);
+                        result = Err(err);
+                        break;
+                    }
+                }
+            }
+        }
+        self.save_queue();
+        result.map(|_| submitted)
+    }
+
+    /// Submits a batch of queued listens to each target
+    pub async fn scrobble(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut result = Ok(());
+        for target in self.targets() {
+            if let Err(err) = submit_batch(&mut *target).await {
+                error!("error scrobbling to {}: {}", target.name(), err);
+                result = Err(err);
+            }
+        }
+        self.save_queue();
+        result
+    }
+
+    pub async fn set_now_playing(
+        &mut self,
+        track: Option<NowPlaying>,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let mut should_try_scrobble = false;
+        let mut should_update_now_playing = false;
+        let mut should_update_now_playing_end = false;
+
+        if let Some(was_playing) = &self.now_playing_start {
+            if let Some(now_playing) = &track {
+                if is_same_play(now_playing, was_playing)
+                    && now_playing.elapsed >= was_playing.elapsed
+                {
+                    debug!("we're already playing this track");
+                    // we're already playing this track
+                    should_update_now_playing_end = true;
+                } else {
+                    should_try_scrobble = true;
+                    should_update_now_playing = true;
+                }
+            } else {
+                // we were playing something else, but now we're not playing anything
+                should_try_scrobble = true;
+                should_update_now_playing = true;
+            }
+        } else {
+            // we weren't playing anything
+            should_update_now_playing = true;
+        }
+
+        if should_try_scrobble {
+            // we were playing something else
+            let mut finished_play = None;
+            match (&self.now_playing_start, &self.now_playing_end) {
+                (Some(was_playing_start), Some(was_playing_end)) => {
+                    let total_elapsed = was_playing_end.elapsed - was_playing_start.elapsed;
+                    if is_listen(was_playing_start.track.dur, total_elapsed) {
+                        finished_play = Some(was_playing_start.clone());
+                    } else {
+                        debug!("not scrobbling, only played {} seconds", total_elapsed);
+                    }
+                }
+                (_, _) => {}
+            }
+            if let Some(play) = finished_play {
+                let play = self.correct(play).await;
+                self.queue_scrobble(play);
+            }
+        }
+
+        if should_update_now_playing {
+            if let Some(track) = &track {
+                self.send_now_playing(track).await;
+            }
+            self.now_playing_start = track;
+            self.now_playing_end = None;
+        } else if should_update_now_playing_end {
+            self.now_playing_end = track;
+        }
+
+        if self
+            .targets()
+            .iter_mut()
+            .any(|target| !target.queue().is_empty())
+        {
+            match self.scrobble().await {
+                Ok(_) => debug!("scrobbled"),
+                Err(err) => error!("error scrobbling: {}", err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues a finished play for every target. last.fm rejects repeated scrobbles of a track
+    /// that are too close together, so a replay of the last scrobbled track, like a track on
+    /// repeat, has to start at least the minimum gap after it. Replays more than a second early
+    /// aren't scrobbled; ones within the second-resolution rounding of `start_ts` are nudged
+    /// later.
+    fn queue_scrobble(&mut self, mut play: NowPlaying) {
+        if let Some(last) = &self.last_scrobbled {
+            if play.track.title == last.track.title
+                && play.track.artist == last.track.artist
+                && play.track.album == last.track.album
+            {
+                let min_gap = self.min_repeat_gap_secs.unwrap_or(last.track.dur).floor() as u64;
+                let earliest = last.start_ts + min_gap;
+                if play.start_ts + 1 < earliest {
+                    debug!(
+                        "not scrobbling replay of {}, {} seconds after the last scrobble",
+                        play.track.title,
+                        play.start_ts.saturating_sub(last.start_ts)
+                    );
+                    return;
+                }
+                play.start_ts = play.start_ts.max(earliest);
+            }
+        }
+        self.last_scrobbled = Some(play.clone());
+        for target in self.targets() {
+            target.queue().push(play.clone());
+        }
+        self.save_queue();
+    }
+
+    /// Fills in the artist and title from last.fm for a play whose tags have no artist, like a
+    /// track titled after its file. Left as it is if nothing matches or the lookup fails.
+    async fn correct(&mut self, mut play: NowPlaying) -> NowPlaying {
+        if !self.correct_metadata || !needs_correction(&play) {
+            return play;
+        }
+        let last_fm = match &mut self.last_fm {
+            Some(last_fm) => last_fm,
+            None => return play,
+        };
+
+        if !self.corrections.contains_key(&play.track.title) {
+            match last_fm.search_track(&play.track.title).await {
+                Ok(found) => {
+                    self.corrections.insert(play.track.title.clone(), found);
+                }
+                Err(err) => {
+                    error!("error looking up {}: {}", play.track.title, err);
+                    return play;
+                }
+            }
+        }
+        if let Some(Some((artist, title))) = self.corrections.get(&play.track.title) {
+            debug!("scrobbling {} as {} - {}", play.track.title, artist, title);
+            play.track.artist = artist.clone();
+            play.track.title = title.clone();
+        }
+        play
+    }
+
+    /// Takes the listens queued at `path`, if it's been saved, and saves them there from now on
+    fn load_queue(&mut self, path: PathBuf) {
+        match storage::read_json::<ScrobbleQueue>(&path) {
+            Ok(queue) => {
+                if let Some(last_fm) = &mut self.last_fm {
+                    last_fm.to_scrobble = queue.last_fm;
+                }
+                if let Some(listenbrainz) = &mut self.listenbrainz {
+                    listenbrainz.to_scrobble = queue.listenbrainz;
+                }
+            }
+            // keep whatever the session had
+            Err(err) => debug!("no saved scrobble queue: {}", err),
+        }
+        self.queue_path = Some(path);
+    }
+
+    fn save_queue(&self) {
+        let path = match &self.queue_path {
+            Some(path) => path,
+            None => return,
+        };
+        let queue = ScrobbleQueue {
+            last_fm: self
+                .last_fm
+                .as_ref()
+                .map(|last_fm| last_fm.to_scrobble.clone())
+                .unwrap_or_default(),
+            listenbrainz: self
+                .listenbrainz
+                .as_ref()
+                .map(|listenbrainz| listenbrainz.to_scrobble.clone())
+                .unwrap_or_default(),
+        };
+        if let Err(err) = storage::write_json(path, &queue) {
+            error!("error saving scrobble queue: {}", err);
+        }
+    }
+
+    /// After a restart the targets have forgotten what's playing; re-send the restored track
+    /// unless it must have finished long before `now` (unix seconds). Returns whether it was
+    /// sent.
+    pub async fn resume_now_playing(&mut self, now: u64) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+
+        let track = match self
+            .now_playing_end
+            .as_ref()
+            .or(self.now_playing_start.as_ref())
+        {
+            Some(track) => track.clone(),
+            None => return false,
+        };
+
+        let ends_at = track.start_ts as f64 + track.track.dur;
+        if (now as f64) > ends_at + RESUME_NOW_PLAYING_SLACK_SECS {
+            debug!(
+                "not resuming now playing, {} ended {} seconds ago",
+                track.track.title,
+                now as f64 - ends_at
+            );
+            return false;
+        }
+
+        let sent = self.send_now_playing(&track).await;
+        if sent {
+            info!("resumed now playing: {}", track.track.title);
+        }
+        sent
+    }
+
+    /// Returns whether any target took it
+    async fn send_now_playing(&mut self, track: &NowPlaying) -> bool {
+        let mut sent = false;
+        for target in self.targets() {
+            match target.submit_now_playing(track).await {
+                Ok(()) => {
+                    debug!("set now playing on {}", target.name());
+                    sent = true;
+                }
+                Err(err) => error!("error setting now playing on {}: {}", target.name(), err),
+            }
+        }
+        sent
+    }
+}
+
+/// Whether `played_secs` of a track is enough to scrobble it: half the track, or more than four
+/// minutes of it. last.fm and ListenBrainz both go by this.
+fn is_listen(track_dur: f64, played_secs: f64) -> bool {
+    played_secs > LISTEN_MIN_SECS || played_secs > 0.5 * track_dur
+}
+
+/// Whether the tags of a play are too incomplete to scrobble as they are. Tracks without an
+/// artist tag get their filename as the title.
+fn needs_correction(play: &NowPlaying) -> bool {
+    play.track.artist.trim().is_empty()
+}
+
+/// Whether two now-playing events are from the same play of the same track. `start_ts` only has
+/// one second resolution, so the player's play generation is what separates quick skips and
+/// immediate replays.
+fn is_same_play(a: &NowPlaying, b: &NowPlaying) -> bool {
+    a.track.title == b.track.title
+        && a.track.artist == b.track.artist
+        && a.track.album == b.track.album
+        && a.start_ts == b.start_ts
+        && a.generation == b.generation
+}
+
+/// Scrobbles to last.fm with a mobile session
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LastFM {
     token: String,
     username: String,
     api_key: String,
     secret_key: String,
 
+    /// Kept in the scrobble queue file; only read from sessions saved before there was one
+    #[serde(default, skip_serializing)]
     to_scrobble: Vec<NowPlaying>,
 
-    now_playing_start: Option<NowPlaying>,
-    now_playing_end: Option<NowPlaying>,
-
     #[serde(skip)]
     client: Option<reqwest::Client>,
+
+    /// Only overridden in tests
+    #[serde(skip, default = "default_api_root")]
+    api_root: String,
 }
 
-impl Scrobbler {
+impl LastFM {
     async fn post<T: for<'a> Deserialize<'a>>(
         &mut self,
         method: String,
@@ -112,7 +633,7 @@ impl Scrobbler {
         };
 
         let res = client
-            .post("https://ws.audioscrobbler.com/2.0/")
+            .post(self.api_root.as_str())
             .form(&params)
             .send()
             .await?;
@@ -143,10 +664,7 @@ impl Scrobbler {
             }
         };
 
-        let res = client
-            .get("https://ws.audioscrobbler.com/2.0/")
-            .query(&params)
-            .send();
+        let res = client.get(self.api_root.as_str()).query(&params).send();
 
         let body = res.await?.text().await?;
 
@@ -169,15 +687,9 @@ impl Scrobbler {
             .await
     }
 
-    pub async fn scrobble(&mut self) -> Result<LastFMGenericStatus, Box<dyn std::error::Error>> {
-        let rest = if self.to_scrobble.len() > 50 {
-            self.to_scrobble.split_off(50)
-        } else {
-            vec![]
-        };
-
+    async fn scrobble(&mut self, listens: &[NowPlaying]) -> Result<(), Box<dyn Error>> {
         let mut params = HashMap::new();
-        for (i, track) in self.to_scrobble.iter().enumerate() {
+        for (i, track) in listens.iter().enumerate() {
             params.insert(format!("artist[{}]", i), track.track.artist.clone());
             params.insert(format!("track[{}]", i), track.track.title.clone());
             params.insert(format!("duration[{}]", i), format!("{}", track.track.dur));
@@ -194,96 +706,74 @@ impl Scrobbler {
                 error!("error scrobbling: {:?}", err);
 
                 // https://www.last.fm/api/scrobbling
-                if err.code != "11" && err.code != "16" {
-                    // failure; don't retry
-                    self.to_scrobble = rest;
+                if err.code == "11" || err.code == "16" {
+                    // the service is having trouble; worth retrying
+                    Err(err.text.into())
+                } else {
+                    Err(Rejected(err.text).into())
                 }
-
-                Err(err.text.into())
-            }
-            None => {
-                self.to_scrobble = rest;
-                Ok(result)
             }
+            None => Ok(()),
         }
     }
 
-    pub async fn set_now_playing(
+    /// The artist and title of the best match for `title` by any artist. `track.getCorrection`
+    /// and `track.getInfo` both need an artist to start from.
+    /// https://www.last.fm/api/show/track.search
+    async fn search_track(
         &mut self,
-        track: Option<NowPlaying>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut should_try_scrobble = false;
-        let mut should_update_now_playing = false;
-        let mut should_update_now_playing_end = false;
+        title: &str,
+    ) -> Result<Option<(String, String)>, Box<dyn Error>> {
+        let result = self
+            .get::<TrackSearchResult>(
+                "track.search",
+                HashMap::from([("track".to_string(), title), ("limit".to_string(), "1")]),
+            )
+            .await?;
+        Ok(result
+            .results
+            .trackmatches
+            .track
+            .into_iter()
+            .next()
+            .map(|track| (track.artist, track.name)))
+    }
 
-        if let Some(was_playing) = &self.now_playing_start {
-            if let Some(now_playing) = &track {
-                if now_playing.track.title == was_playing.track.title
-                    && now_playing.track.artist == was_playing.track.artist
-                    && now_playing.start_ts == was_playing.start_ts
-                    && now_playing.elapsed >= was_playing.elapsed
-                {
-                    debug!("we're already playing this track");
-                    // we're already playing this track
-                    should_update_now_playing_end = true;
-                } else {
-                    should_try_scrobble = true;
-                    should_update_now_playing = true;
-                }
-            } else {
-                // we were playing something else, but now we're not playing anything
-                should_try_scrobble = true;
-                should_update_now_playing = true;
-            }
-        } else {
-            // we weren't playing anything
-            should_update_now_playing = true;
-        }
+    /// https://www.last.fm/api/show/track.love
+    pub async fn love(&mut self, artist: &str, track: &str) -> Result<(), Box<dyn Error>> {
+        self.set_loved("track.love", artist, track).await
+    }
 
-        if should_try_scrobble {
-            // we were playing something else
-            match (&self.now_playing_start, &self.now_playing_end) {
-                (Some(was_playing_start), Some(was_playing_end)) => {
-                    let total_elapsed = was_playing_end.elapsed - was_playing_start.elapsed;
-                    if total_elapsed > 4.0 * 60.0
-                        || total_elapsed > 0.5 * was_playing_start.track.dur
-                    {
-                        // we've played half the track, or more than 4 minutes of it track
-                        self.to_scrobble.push(was_playing_start.clone());
-                    } else {
-                        debug!("not scrobbling, only played {} seconds", total_elapsed);
-                    }
-                }
-                (_, _) => {}
-            }
-        }
+    /// https://www.last.fm/api/show/track.unlove
+    pub async fn unlove(&mut self, artist: &str, track: &str) -> Result<(), Box<dyn Error>> {
+        self.set_loved("track.unlove", artist, track).await
+    }
 
-        if should_update_now_playing {
-            if let Some(track) = &track {
-                match self.send_now_playing(track).await {
-                    Ok(_) => debug!("set now playing"),
-                    Err(err) => error!("error setting now playing: {}", err),
-                }
-            }
-            self.now_playing_start = track;
-            self.now_playing_end = None;
-        } else if should_update_now_playing_end {
-            self.now_playing_end = track;
-        }
+    async fn set_loved(
+        &mut self,
+        method: &str,
+        artist: &str,
+        track: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut params = HashMap::new();
+        params.insert("artist".to_string(), artist.to_string());
+        params.insert("track".to_string(), track.to_string());
 
-        if !self.to_scrobble.is_empty() {
-            match self.scrobble().await {
-                Ok(_) => debug!("scrobbled"),
-                Err(err) => error!("error scrobbling: {}", err),
+        let result = self
+            .borrow_mut()
+            .post::<LastFMGenericStatus>(method.to_string(), params)
+            .await?;
+
+        match result.error {
+            Some(err) => {
+                error!("error calling {}: {:?}", method, err);
+                Err(err.text.into())
             }
+            None => Ok(()),
         }
-        Ok(())
     }
 
-    pub async fn send_now_playing(
-        &mut self,
-        track: &NowPlaying,
-    ) -> Result<LastFMGenericStatus, Box<dyn std::error::Error>> {
+    async fn send_now_playing(&mut self, track: &NowPlaying) -> Result<(), Box<dyn Error>> {
         let mut params = HashMap::new();
         params.insert("track".to_string(), track.track.title.clone());
         params.insert("artist".to_string(), track.track.artist.clone());
@@ -300,11 +790,229 @@ impl Scrobbler {
                 error!("error updating now playing: {:?}", err);
                 Err(err.text.into())
             }
-            None => Ok(result),
+            None => Ok(()),
+        }
+    }
+
+    /// Reuses the `saved` session if it's for the configured user, otherwise starts a new one.
+    /// None if last.fm isn't configured.
+    async fn try_new(
+        config: &PjpConfig,
+        saved: Option<LastFM>,
+    ) -> Result<Option<LastFM>, Box<dyn Error>> {
+        if let (Some(saved), Some(username)) = (saved, &config.last_fm_username) {
+            if *username == saved.username {
+                // we already have a token that matches the username
+                info!("using existing last.fm session for user {}", username);
+                return Ok(Some(saved));
+            }
+        }
+
+        match (
+            config.last_fm_username.clone(),
+            config.last_fm_password.clone(),
+            config.last_fm_api_key.clone(),
+            config.last_fm_secret_key.clone(),
+        ) {
+            (Some(username), Some(password), Some(api_key), Some(secret_key)) => {
+                let token = fetch_token(
+                    username.as_str(),
+                    password.as_str(),
+                    api_key.as_str(),
+                    secret_key.as_str(),
+                )
+                .await?;
+                info!("fetched new last.fm session");
+                Ok(Some(LastFM {
+                    token,
+                    username,
+                    api_key,
+                    secret_key,
+                    to_scrobble: vec![],
+                    client: None,
+                    api_root: default_api_root(),
+                }))
+            }
+            (None, None, None, None) => Ok(None),
+            _ => {
+                warn!("last.fm api key, secret, username, and password must all be set in config");
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl ScrobbleTarget for LastFM {
+    fn name(&self) -> &'static str {
+        "last.fm"
+    }
+
+    fn queue(&mut self) -> &mut Vec<NowPlaying> {
+        &mut self.to_scrobble
+    }
+
+    fn max_batch(&self) -> usize {
+        LAST_FM_MAX_BATCH
+    }
+
+    fn submit_now_playing<'a>(
+        &'a mut self,
+        track: &'a NowPlaying,
+    ) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        Box::pin(self.send_now_playing(track))
+    }
+
+    fn submit_listens<'a>(
+        &'a mut self,
+        listens: &'a [NowPlaying],
+    ) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        Box::pin(self.scrobble(listens))
+    }
+}
+
+#[derive(Serialize)]
+struct ListenBrainzSubmission<'a> {
+    listen_type: &'a str,
+    payload: Vec<ListenBrainzListen<'a>>,
+}
+
+#[derive(Serialize)]
+struct ListenBrainzListen<'a> {
+    /// Left out for now playing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listened_at: Option<u64>,
+    track_metadata: ListenBrainzTrack<'a>,
+}
+
+#[derive(Serialize)]
+struct ListenBrainzTrack<'a> {
+    artist_name: &'a str,
+    track_name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    release_name: Option<&'a str>,
+    additional_info: ListenBrainzInfo,
+}
+
+#[derive(Serialize)]
+struct ListenBrainzInfo {
+    duration_ms: u64,
+    submission_client: &'static str,
+}
+
+impl<'a> ListenBrainzListen<'a> {
+    fn new(track: &'a NowPlaying, listened_at: Option<u64>) -> Self {
+        ListenBrainzListen {
+            listened_at,
+            track_metadata: ListenBrainzTrack {
+                artist_name: &track.track.artist,
+                track_name: &track.track.title,
+                release_name: Some(track.track.album.as_str()).filter(|album| !album.is_empty()),
+                additional_info: ListenBrainzInfo {
+                    duration_ms: (track.track.dur * 1000.0).round() as u64,
+                    submission_client: "pjp",
+                },
+            },
+        }
+    }
+}
+
+/// Scrobbles to ListenBrainz with the user token from the config, so there's no session to save
+/// https://listenbrainz.readthedocs.io/en/latest/users/api/core.html
+#[derive(Debug)]
+pub struct ListenBrainz {
+    token: String,
+
+    to_scrobble: Vec<NowPlaying>,
+
+    client: Option<reqwest::Client>,
+
+    /// Only overridden in tests
+    api_root: String,
+}
+
+impl ListenBrainz {
+    fn new(token: String) -> Self {
+        ListenBrainz {
+            token,
+            to_scrobble: vec![],
+            client: None,
+            api_root: default_listenbrainz_api_root(),
+        }
+    }
+
+    async fn submit(
+        &mut self,
+        listen_type: &str,
+        payload: Vec<ListenBrainzListen<'_>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let body = serde_json::to_string(&ListenBrainzSubmission {
+            listen_type,
+            payload,
+        })?;
+
+        let client = self.client.get_or_insert_with(reqwest::Client::new);
+        let res = client
+            .post(format!("{}submit-listens", self.api_root))
+            .header("Authorization", format!("Token {}", self.token))
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await?;
+
+        let status = res.status();
+        let body = res.text().await?;
+
+        debug!("body: {}", body);
+
+        if status.is_success() {
+            Ok(())
+        } else if status == reqwest::StatusCode::BAD_REQUEST {
+            // the listens themselves are bad
+            Err(Rejected(body).into())
+        } else {
+            Err(format!("{}: {}", status, body).into())
         }
     }
 }
 
+impl ScrobbleTarget for ListenBrainz {
+    fn name(&self) -> &'static str {
+        "listenbrainz"
+    }
+
+    fn queue(&mut self) -> &mut Vec<NowPlaying> {
+        &mut self.to_scrobble
+    }
+
+    fn max_batch(&self) -> usize {
+        LISTENBRAINZ_MAX_BATCH
+    }
+
+    fn submit_now_playing<'a>(
+        &'a mut self,
+        track: &'a NowPlaying,
+    ) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        Box::pin(self.submit("playing_now", vec![ListenBrainzListen::new(track, None)]))
+    }
+
+    fn submit_listens<'a>(
+        &'a mut self,
+        listens: &'a [NowPlaying],
+    ) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        // "single" is for exactly one listen
+        let listen_type = if listens.len() == 1 {
+            "single"
+        } else {
+            "import"
+        };
+        let payload = listens
+            .iter()
+            .map(|listen| ListenBrainzListen::new(listen, Some(listen.start_ts)))
+            .collect();
+        Box::pin(self.submit(listen_type, payload))
+    }
+}
+
 /// Following the auth procedure here: https://www.last.fm/api/mobileauth
 async fn fetch_token(
     username: &str,
@@ -363,58 +1071,294 @@ fn make_signature(parameters: &HashMap<String, String>, secret: &str) -> String
 }
 
 impl Scrobbler {
-    pub async fn try_new() -> Result<Self, Box<dyn std::error::Error>> {
-        let scrobbler = storage::load_json::<Scrobbler>("scrobbler");
-        let config = storage::load_config();
+    /// A scrobbler with no targets that ignores all events, used until one is configured
+    pub fn disabled() -> Self {
+        Scrobbler {
+            last_fm: None,
+            listenbrainz: None,
+            now_playing_start: None,
+            now_playing_end: None,
+            last_scrobbled: None,
+            min_repeat_gap_secs: None,
+            correct_metadata: false,
+            corrections: HashMap::new(),
+            queue_path: None,
+        }
+    }
 
-        if let (Ok(scrobbler), Some(username)) = (scrobbler, config.last_fm_username.clone()) {
-            if username == scrobbler.username {
-                // we already have a token that matches the username
-                info!("using existing last.fm session for user {}", username);
-                return Ok(scrobbler);
-            }
+    pub fn is_enabled(&self) -> bool {
+        self.last_fm.is_some() || self.listenbrainz.is_some()
+    }
+
+    pub async fn try_new(config: &PjpConfig) -> Result<Self, Box<dyn Error>> {
+        let mut scrobbler =
+            storage::load_json::<Scrobbler>("scrobbler").unwrap_or_else(|_| Scrobbler::disabled());
+
+        scrobbler.last_fm = LastFM::try_new(config, scrobbler.last_fm.take()).await?;
+        scrobbler.listenbrainz = config.listenbrainz_token.clone().map(ListenBrainz::new);
+
+        if !scrobbler.is_enabled() {
+            return Err("last.fm credentials or a listenbrainz token must be set in config".into());
         }
+        scrobbler.load_queue(storage::json_path("scrobble_queue")?);
+        // before the session, which no longer carries the queue
+        scrobbler.save_queue();
+        storage::save_json("scrobbler", &scrobbler)?;
+        Ok(scrobbler)
+    }
 
-        match (
-            config.last_fm_username,
-            config.last_fm_password,
-            config.last_fm_api_key,
-            config.last_fm_secret_key,
-        ) {
-            (Some(username), Some(password), Some(api_key), Some(secret_key)) => {
-                let token = fetch_token(
-                    username.as_str(),
-                    password.as_str(),
-                    api_key.as_str(),
-                    secret_key.as_str(),
-                )
-                .await?;
-                let scrobbler = Scrobbler {
-                    token,
-                    username,
-                    api_key,
-                    secret_key,
-                    client: None,
-                    to_scrobble: vec![],
-                    now_playing_start: None,
-                    now_playing_end: None,
-                };
-                storage::save_json("scrobbler", &scrobbler)?;
-                info!("fetched new last.fm session");
-                Ok(scrobbler)
+    /// Like `try_new`, but falls back to a disabled scrobbler when no target is configured so
+    /// the binary can keep running alongside the player
+    pub async fn try_new_optional(config: &PjpConfig) -> Self {
+        let mut scrobbler = match Scrobbler::try_new(config).await {
+            Ok(scrobbler) => scrobbler,
+            Err(err) => {
+                warn!(
+                    "{}; scrobbling is disabled until the config is updated",
+                    err
+                );
+                Scrobbler::disabled()
+            }
+        };
+        scrobbler.min_repeat_gap_secs = config.min_repeat_scrobble_gap_secs;
+        scrobbler.correct_metadata = config.correct_scrobble_metadata;
+        scrobbler
+    }
+}
+
+#[derive(Serialize)]
+struct ScrobbleNowResponse {
+    submitted: usize,
+}
+
+/// A track to love or unlove; an empty body means the one that's playing
+#[derive(Deserialize)]
+struct LoveRequest {
+    artist: String,
+    track: String,
+}
+
+/// Loves the requested track on last.fm, or unloves it
+async fn handle_love(
+    scrobbler: &mut Scrobbler,
+    req: &HttpRequest,
+    res: &mut HttpResponse,
+    loved: bool,
+) {
+    let track = if req.body.trim().is_empty() {
+        match &scrobbler.now_playing_start {
+            Some(playing) => LoveRequest {
+                artist: playing.track.artist.clone(),
+                track: playing.track.title.clone(),
+            },
+            None => {
+                error!("nothing is playing to love");
+                res.response_code = HttpResponseCode::BadRequest;
+                return;
             }
+        }
+    } else {
+        match serde_json::from_str::<LoveRequest>(&req.body) {
+            Ok(track) => track,
+            Err(err) => {
+                error!("error parsing json: {} {}", err, req.body);
+                res.response_code = HttpResponseCode::BadRequest;
+                return;
+            }
+        }
+    };
+
+    let last_fm = match &mut scrobbler.last_fm {
+        Some(last_fm) => last_fm,
+        None => {
+            error!("loving tracks needs a last.fm session");
+            res.response_code = HttpResponseCode::NotFound;
+            return;
+        }
+    };
+    let result = if loved {
+        last_fm.love(&track.artist, &track.track).await
+    } else {
+        last_fm.unlove(&track.artist, &track.track).await
+    };
+    match result {
+        Ok(()) => {
+            info!(
+                "set loved to {} for {} - {}",
+                loved, track.artist, track.track
+            );
+            res.response_code = HttpResponseCode::NoContent;
+        }
+        Err(err) => {
+            error!("error setting loved: {}", err);
+            res.response_code = HttpResponseCode::InternalServerError;
+        }
+    }
+}
+
+/// A request to the control port, and where to answer it
+type ControlRequest = (Result<HttpRequest, RequestError>, HttpResponse);
+
+/// Handles one request on the scrobbler's control port
+async fn handle_control(
+    scrobbler: &mut Scrobbler,
+    req: Result<HttpRequest, RequestError>,
+    mut res: HttpResponse,
+) {
+    match req {
+        Ok(req) => match (&req.method, req.path.as_str()) {
+            (HttpMethod::Post, "/scrobble-now") => match scrobbler.flush().await {
+                Ok(submitted) => {
+                    info!("scrobbled {} listens on request", submitted);
+                    res.set_json(&ScrobbleNowResponse { submitted });
+                    res.response_code = HttpResponseCode::Ok;
+                }
+                Err(err) => {
+                    error!("error scrobbling on request: {}", err);
+                    res.response_code = HttpResponseCode::InternalServerError;
+                }
+            },
+            (HttpMethod::Post, "/love") => handle_love(scrobbler, &req, &mut res, true).await,
+            (HttpMethod::Post, "/unlove") => handle_love(scrobbler, &req, &mut res, false).await,
             _ => {
-                Err("last.fm api key, secret, username, and password must be set in config".into())
+                res.response_code = HttpResponseCode::NotFound;
             }
+        },
+        Err(err) => {
+            error!("error parsing request: {:?}", err);
+            res.response_code = err.response_code();
         }
     }
 }
 
+/// Reads requests to `listener` on `web_framework`'s threads, since its io blocks, and passes
+/// them on to be handled on the runtime
+fn control_requests(listener: TcpListener) -> UnboundedReceiver<ControlRequest> {
+    let requests = web_framework::serve(listener, web_framework::DEFAULT_MAX_BODY_BYTES);
+    let (sender, receiver) = unbounded_channel();
+    std::thread::spawn(move || {
+        for request in requests {
+            if sender.send(request).is_err() {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// Waits for the next control request. Never resolves if the control port couldn't be bound.
+async fn next_control_request(
+    control: &mut Option<UnboundedReceiver<ControlRequest>>,
+) -> Option<ControlRequest> {
+    match control {
+        Some(control) => control.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use std::time::Duration;
+
+    use super::{
+        control_requests, handle_control, make_signature, needs_correction, Backoff, LastFM,
+        ListenBrainz, Scrobbler,
+    };
+    use crate::audio_source::AudioMetadata;
+    use crate::player_state::NowPlaying;
+    use crate::storage::PjpConfig;
+
+    /// Nothing is listening here, so calls fail and scrobbles stay queued
+    const UNREACHABLE_API_ROOT: &str = "http://127.0.0.1:1/2.0/";
+
+    /// Serves one request with `response`, sending the request, headers and all, to the returned
+    /// channel. Returns the api root under `path` to point a target at.
+    fn fake_api_responding(path: &str, response: &'static str) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_root = format!("http://{}{}", listener.local_addr().unwrap(), path);
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                request.push_str(&line);
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                response.len()
+            );
+            stream.write_all(head.as_bytes()).unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+            request.push_str(&String::from_utf8(body).unwrap());
+            tx.send(request).unwrap();
+        });
+        (api_root, rx)
+    }
 
-    use super::make_signature;
+    fn fake_api(path: &str) -> (String, mpsc::Receiver<String>) {
+        fake_api_responding(path, "{}")
+    }
+
+    fn fake_last_fm() -> (String, mpsc::Receiver<String>) {
+        fake_api("/2.0/")
+    }
+
+    fn last_fm(api_root: &str) -> LastFM {
+        LastFM {
+            token: "token".into(),
+            username: "user".into(),
+            api_key: "key".into(),
+            secret_key: "secret".into(),
+            to_scrobble: vec![],
+            client: None,
+            api_root: api_root.into(),
+        }
+    }
+
+    /// Scrobbles to last.fm at `api_root`
+    fn restored_scrobbler(start_ts: u64, api_root: &str) -> Scrobbler {
+        let track = NowPlaying {
+            track: AudioMetadata {
+                dur: 200.0,
+                artist: "Artist".into(),
+                title: "Title".into(),
+                album: "Album".into(),
+                gain_db: None,
+                peak: None,
+                cover: None,
+                tech: None,
+            },
+            elapsed: 60.0,
+            start_ts,
+            generation: 1,
+        };
+        Scrobbler {
+            last_fm: Some(last_fm(api_root)),
+            now_playing_start: Some(track.clone()),
+            now_playing_end: Some(track),
+            ..Scrobbler::disabled()
+        }
+    }
 
     #[test]
     fn makes_signature() {
@@ -426,6 +1370,339 @@ mod tests {
         assert_eq!(res.len(), 32);
     }
 
+    #[test]
+    fn backs_off_exponentially_until_reset() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        let mut delays = vec![];
+        for _ in 0..9 {
+            delays.push(backoff.next_delay());
+        }
+        // each is jittered to between half and all of 1, 2, 4 ... 60 seconds
+        for (delay, full) in delays.iter().zip([1, 2, 4, 8, 16, 32, 60, 60, 60]) {
+            let full = Duration::from_secs(full);
+            assert!(
+                *delay <= full && *delay >= full / 2,
+                "{:?} for {:?}",
+                delay,
+                full
+            );
+        }
+        assert!(delays.windows(2).any(|pair| pair[0] * 2 != pair[1]));
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn disabled_with_partial_credentials() {
+        let config = PjpConfig {
+            last_fm_api_key: Some("key".into()),
+            last_fm_secret_key: Some("secret".into()),
+            ..PjpConfig::default()
+        };
+        let mut scrobbler = Scrobbler::try_new_optional(&config).await;
+        assert!(!scrobbler.is_enabled());
+
+        // events are accepted and ignored
+        assert!(scrobbler.set_now_playing(None).await.is_ok());
+        assert!(scrobbler.scrobble().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn resumes_recent_now_playing() {
+        let (api_root, requests) = fake_last_fm();
+        let mut scrobbler = restored_scrobbler(1_000_000, &api_root);
+
+        assert!(scrobbler.resume_now_playing(1_000_100).await);
+        let body = requests.recv().unwrap();
+        assert!(body.contains("method=track.updateNowPlaying"));
+        assert!(body.contains("track=Title"));
+    }
+
+    #[tokio::test]
+    async fn doesnt_resume_stale_now_playing() {
+        let mut scrobbler = restored_scrobbler(1_000_000, UNREACHABLE_API_ROOT);
+
+        assert!(!scrobbler.resume_now_playing(1_000_000 + 3 * 60 * 60).await);
+    }
+
+    #[tokio::test]
+    async fn separates_plays_started_in_the_same_second() {
+        let mut scrobbler = restored_scrobbler(1_000_000, UNREACHABLE_API_ROOT);
+
+        scrobbler.now_playing_start.as_mut().unwrap().elapsed = 0.0;
+        scrobbler.now_playing_end = None;
+
+        let mut first = scrobbler.now_playing_start.clone().unwrap();
+        first.elapsed = 190.0;
+        scrobbler.set_now_playing(Some(first)).await.unwrap();
+        assert!(scrobbler.last_fm.as_mut().unwrap().to_scrobble.is_empty());
+
+        // a different recording with the same tags, skipped to within the same second
+        let mut second = scrobbler.now_playing_start.clone().unwrap();
+        second.elapsed = 3.0;
+        second.generation = 2;
+        scrobbler.set_now_playing(Some(second)).await.unwrap();
+
+        assert_eq!(scrobbler.last_fm.as_mut().unwrap().to_scrobble.len(), 1);
+        assert_eq!(
+            scrobbler.last_fm.as_mut().unwrap().to_scrobble[0].generation,
+            1
+        );
+        assert_eq!(scrobbler.now_playing_start.as_ref().unwrap().generation, 2);
+        assert!(scrobbler.now_playing_end.is_none());
+    }
+
+    /// Sends `request` to the scrobbler's control port and returns the response
+    async fn control_request(scrobbler: &mut Scrobbler, request: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut requests = control_requests(listener);
+        let client = thread::spawn(move || {
+            let mut stream = std::net::TcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            // so the connection closes once the response is sent
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        let (req, res) = requests.recv().await.unwrap();
+        handle_control(scrobbler, req, res).await;
+        client.join().unwrap()
+    }
+
+    #[tokio::test]
+    async fn scrobble_now_submits_queued_listens() {
+        let (api_root, requests) = fake_last_fm();
+        let mut scrobbler = restored_scrobbler(1_000_000, &api_root);
+        let mut second = scrobbler.now_playing_start.clone().unwrap();
+        second.start_ts += 200;
+        second.generation = 2;
+        scrobbler.last_fm.as_mut().unwrap().to_scrobble =
+            vec![scrobbler.now_playing_start.clone().unwrap(), second];
+
+        let response = control_request(
+            &mut scrobbler,
+            "POST /scrobble-now HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("{\"submitted\":2}"));
+        assert!(scrobbler.last_fm.as_mut().unwrap().to_scrobble.is_empty());
+
+        let body = requests.recv().unwrap();
+        assert!(body.contains("method=track.scrobble"));
+        assert!(body.contains("timestamp%5B1%5D=1000200"));
+    }
+
+    #[tokio::test]
+    async fn loves_the_playing_track() {
+        let (api_root, requests) = fake_last_fm();
+        let mut scrobbler = restored_scrobbler(1_000_000, &api_root);
+
+        let response = control_request(
+            &mut scrobbler,
+            "POST /love HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+
+        let request = requests.recv().unwrap();
+        let body = request.split("\r\n\r\n").nth(1).unwrap();
+        let mut params: HashMap<String, String> = body
+            .split('&')
+            .map(|param| {
+                let (name, value) = param.split_once('=').unwrap();
+                (name.to_string(), value.to_string())
+            })
+            .collect();
+        assert_eq!(params["method"], "track.love");
+        assert_eq!(params["artist"], "Artist");
+        assert_eq!(params["track"], "Title");
+        // signed over everything but the signature and format
+        let signature = params.remove("api_sig").unwrap();
+        params.remove("format");
+        assert_eq!(signature, make_signature(&params, "secret"));
+
+        // a track that isn't playing, without a last.fm session to love it on
+        scrobbler.last_fm = None;
+        let response = control_request(
+            &mut scrobbler,
+            "POST /unlove HTTP/1.1\r\nContent-Length: 30\r\n\r\n{\"artist\":\"A\",\"track\":\"Other\"}",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn only_plays_without_an_artist_need_correcting() {
+        let mut play = restored_scrobbler(1_000_000, UNREACHABLE_API_ROOT)
+            .now_playing_start
+            .unwrap();
+        assert!(!needs_correction(&play));
+        play.track.artist = " ".into();
+        assert!(needs_correction(&play));
+        play.track.artist = String::new();
+        assert!(needs_correction(&play));
+    }
+
+    #[tokio::test]
+    async fn looks_up_each_incomplete_track_once() {
+        let (api_root, requests) = fake_api_responding(
+            "/2.0/",
+            r#"{"results":{"trackmatches":{"track":[{"name":"Sinnerman","artist":"Nina Simone"}]}}}"#,
+        );
+        let mut scrobbler = restored_scrobbler(1_000_000, &api_root);
+        let mut play = scrobbler.now_playing_start.clone().unwrap();
+        play.track.artist = String::new();
+        play.track.title = "02 sinnerman".into();
+
+        // off unless it's configured
+        assert_eq!(
+            scrobbler.correct(play.clone()).await.track.title,
+            "02 sinnerman"
+        );
+
+        scrobbler.correct_metadata = true;
+        let corrected = scrobbler.correct(play.clone()).await;
+        assert_eq!(corrected.track.artist, "Nina Simone");
+        assert_eq!(corrected.track.title, "Sinnerman");
+        let request = requests.recv().unwrap();
+        assert!(request.contains("method=track.search"));
+        assert!(request.contains("track=02+sinnerman"));
+
+        // the fake only answers once, so a second lookup would fail and leave it uncorrected
+        let corrected = scrobbler.correct(play).await;
+        assert_eq!(corrected.track.artist, "Nina Simone");
+    }
+
+    /// Plays the restored track through once for each start time, then stops. Returns the
+    /// timestamps that were queued.
+    async fn loop_track(scrobbler: &mut Scrobbler, dur: f64, starts: &[u64]) -> Vec<u64> {
+        let mut play = scrobbler.now_playing_start.clone().unwrap();
+        play.track.dur = dur;
+        scrobbler.now_playing_start = None;
+        scrobbler.now_playing_end = None;
+        for (i, start_ts) in starts.iter().enumerate() {
+            play.start_ts = *start_ts;
+            play.generation = i as u64 + 1;
+            play.elapsed = 0.0;
+            scrobbler.set_now_playing(Some(play.clone())).await.unwrap();
+            play.elapsed = dur - 1.0;
+            scrobbler.set_now_playing(Some(play.clone())).await.unwrap();
+        }
+        scrobbler.set_now_playing(None).await.unwrap();
+        scrobbler
+            .last_fm
+            .as_ref()
+            .unwrap()
+            .to_scrobble
+            .iter()
+            .map(|play| play.start_ts)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn spaces_out_scrobbles_of_a_looping_track() {
+        let mut scrobbler = restored_scrobbler(1_000_000, UNREACHABLE_API_ROOT);
+
+        // one replay's start rounded a second early, and one restarted partway through
+        let queued = loop_track(&mut scrobbler, 30.4, &[1000, 1029, 1060, 1075, 1105]).await;
+        assert_eq!(queued, vec![1000, 1030, 1060, 1105]);
+
+        // with a longer minimum gap, only every other loop is scrobbled
+        let mut scrobbler = restored_scrobbler(1_000_000, UNREACHABLE_API_ROOT);
+        scrobbler.min_repeat_gap_secs = Some(60.0);
+        let queued = loop_track(&mut scrobbler, 30.0, &[1000, 1030, 1060, 1090, 1120]).await;
+        assert_eq!(queued, vec![1000, 1060, 1120]);
+    }
+
+    #[tokio::test]
+    async fn keeps_listens_queued_for_each_target_until_it_takes_them() {
+        let (api_root, requests) = fake_api("/1/");
+        let mut scrobbler = restored_scrobbler(1_000_000, UNREACHABLE_API_ROOT);
+        scrobbler.listenbrainz = Some(ListenBrainz {
+            api_root,
+            ..ListenBrainz::new("lb-token".into())
+        });
+
+        scrobbler.queue_scrobble(scrobbler.now_playing_start.clone().unwrap());
+        // last.fm can't be reached, which doesn't stop ListenBrainz getting the listen
+        assert!(scrobbler.flush().await.is_err());
+
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("POST /1/submit-listens "));
+        assert!(request.contains("authorization: Token lb-token\r\n"));
+        assert!(request.contains("\"listen_type\":\"single\""));
+        assert!(request.contains("\"listened_at\":1000000"));
+        assert!(request.contains("\"track_name\":\"Title\""));
+        assert!(request.contains("\"duration_ms\":200000"));
+        assert!(scrobbler
+            .listenbrainz
+            .as_ref()
+            .unwrap()
+            .to_scrobble
+            .is_empty());
+        assert_eq!(scrobbler.last_fm.as_ref().unwrap().to_scrobble.len(), 1);
+    }
+
+    #[test]
+    fn loads_last_fm_sessions_saved_before_listenbrainz() {
+        let saved = r#"{"token":"token","username":"user","api_key":"key","secret_key":"secret",
+            "to_scrobble":[],"now_playing_start":null,"now_playing_end":null}"#;
+        let scrobbler: Scrobbler = serde_json::from_str(saved).unwrap();
+        assert_eq!(scrobbler.last_fm.as_ref().unwrap().username, "user");
+        assert!(scrobbler.listenbrainz.is_none());
+
+        let scrobbler = Scrobbler {
+            listenbrainz: Some(ListenBrainz::new("lb-token".into())),
+            ..scrobbler
+        };
+        let saved = serde_json::to_string(&scrobbler).unwrap();
+        assert!(!saved.contains("lb-token"));
+        // the queue is saved on its own
+        assert!(!saved.contains("to_scrobble"));
+        let scrobbler: Scrobbler = serde_json::from_str(&saved).unwrap();
+        assert_eq!(scrobbler.last_fm.as_ref().unwrap().username, "user");
+    }
+
+    #[tokio::test]
+    async fn reloads_queued_listens_after_a_crash() {
+        let path = std::env::temp_dir().join(format!(
+            "pjp-test-{}-scrobble-queue.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let with_targets = || Scrobbler {
+            listenbrainz: Some(ListenBrainz {
+                api_root: UNREACHABLE_API_ROOT.into(),
+                ..ListenBrainz::new("lb-token".into())
+            }),
+            ..restored_scrobbler(1_000_000, UNREACHABLE_API_ROOT)
+        };
+
+        let mut scrobbler = with_targets();
+        scrobbler.load_queue(path.clone());
+        let mut play = scrobbler.now_playing_start.clone().unwrap();
+        scrobbler.queue_scrobble(play.clone());
+        play.start_ts += 200;
+        play.generation = 2;
+        scrobbler.queue_scrobble(play);
+        // neither target can be reached, and the scrobbler goes away without saving its session
+        assert!(scrobbler.scrobble().await.is_err());
+        drop(scrobbler);
+
+        let mut scrobbler = with_targets();
+        scrobbler.load_queue(path.clone());
+        let queued = |queue: &Vec<NowPlaying>| queue.iter().map(|play| play.start_ts).collect();
+        let last_fm: Vec<u64> = queued(&scrobbler.last_fm.as_ref().unwrap().to_scrobble);
+        let listenbrainz: Vec<u64> = queued(&scrobbler.listenbrainz.as_ref().unwrap().to_scrobble);
+        assert_eq!(last_fm, [1_000_000, 1_000_200]);
+        assert_eq!(listenbrainz, [1_000_000, 1_000_200]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
     // #[test]
     // fn fetches_token() {
     //     fetch_token(
@@ -441,52 +1718,97 @@ mod tests {
 async fn main() {
     env_logger::init();
 
-    let config = storage::load_config();
+    let mut config = storage::load_config();
+
+    let mut scrobbler = Scrobbler::try_new_optional(&config).await;
 
-    let mut scrobbler = Scrobbler::try_new().await.unwrap();
+    // submit whatever was left queued last time before following new plays
+    match scrobbler.flush().await {
+        Ok(submitted) => debug!("scrobbled {} queued listens", submitted),
+        Err(err) => error!("error scrobbling queued listens: {}", err),
+    }
 
-    let _ = scrobbler.scrobble().await;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    scrobbler.resume_now_playing(now).await;
+
+    let control_address = format!("127.0.0.1:{}", config.scrobbler_port);
+    let mut control = match TcpListener::bind(&control_address) {
+        Ok(listener) => {
+            info!("listening for control requests on {}", control_address);
+            Some(control_requests(listener))
+        }
+        Err(err) => {
+            error!("can't listen on {}: {}", control_address, err);
+            None
+        }
+    };
 
+    let mut backoff = Backoff::new(RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY);
     loop {
+        if !scrobbler.is_enabled() {
+            // check for credentials again in case the config was updated
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            config = storage::load_config();
+            scrobbler = Scrobbler::try_new_optional(&config).await;
+            continue;
+        }
+
         let url = format!("http://127.0.0.1:{}/events", config.port);
         debug!("connecting to {}", url);
         let mut es = EventSource::get(url);
         debug!("created event source");
-        while let Some(event) = es.next().await {
-            match event {
-                Ok(Event::Open) => debug!("connection open"),
-                Ok(Event::Message(message)) => match message.event.as_str() {
-                    "now-playing" => {
-                        let now_playing: NowPlaying = serde_json::from_str(&message.data).unwrap();
-                        match scrobbler.set_now_playing(Some(now_playing)).await {
-                            Ok(()) => debug!("done processing now playing"),
-                            Err(err) => error!("error setting now playing: {}", err),
+        loop {
+            tokio::select! {
+                event = es.next() => {
+                    let event = match event {
+                        Some(event) => event,
+                        None => break,
+                    };
+                    match event {
+                        Ok(Event::Open) => {
+                            debug!("connection open");
+                            backoff.reset();
                         }
-                    }
-                    "playlist-empty" => {
-                        debug!("playlist empty");
-                        match scrobbler.set_now_playing(None).await {
-                            Ok(()) => debug!("done processing now playing"),
-                            Err(err) => error!("error setting now playing: {}", err),
+                        Ok(Event::Message(message)) => match message.event.as_str() {
+                            "now-playing" => {
+                                let now_playing: NowPlaying =
+                                    serde_json::from_str(&message.data).unwrap();
+                                match scrobbler.set_now_playing(Some(now_playing)).await {
+                                    Ok(()) => debug!("done processing now playing"),
+                                    Err(err) => error!("error setting now playing: {}", err),
+                                }
+                            }
+                            "playlist-empty" => {
+                                debug!("playlist empty");
+                                match scrobbler.set_now_playing(None).await {
+                                    Ok(()) => debug!("done processing now playing"),
+                                    Err(err) => error!("error setting now playing: {}", err),
+                                }
+                            }
+                            "paused" => {
+                                debug!("paused");
+                            }
+                            _ => error!("unknown event: {}", message.event),
+                        },
+                        Err(err) => {
+                            println!("Error: {}", err);
+                            es.close();
                         }
                     }
-                    "paused" => {
-                        debug!("paused");
-                    }
-                    _ => error!("unknown event: {}", message.event),
-                },
-                Err(err) => {
-                    println!("Error: {}", err);
-                    es.close();
+                }
+                Some((req, res)) = next_control_request(&mut control) => {
+                    handle_control(&mut scrobbler, req, res).await;
                 }
             }
 
             let _ = storage::save_json("scrobbler", &scrobbler);
         }
 
-        // TODO: exponential backoff(?)
-
-        // reconnect after 5 seconds
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        let delay = backoff.next_delay();
+        debug!("reconnecting in {:?}", delay);
+        tokio::time::sleep(delay).await;
     }
 }
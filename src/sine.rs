@@ -1,36 +1,102 @@
+use std::f32::consts::PI;
+
+use crate::audio_source::{AudioBuffer, AudioMetadata, AudioSource};
+
+const SAMPLE_RATE: u32 = 44100;
+
+/// Always stereo, whatever the number of frequencies
+const CHANNELS: usize = 2;
+
+/// Endless sine tones, one frequency per channel; handy for testing output without a file
 pub struct SineSource {
     pub freqs: Vec<f32>,
     buffer: Option<AudioBuffer>,
     metadata: AudioMetadata,
 }
 
+impl SineSource {
+    pub fn new(freqs: Vec<f32>) -> SineSource {
+        SineSource {
+            freqs,
+            buffer: None,
+            metadata: AudioMetadata {
+                // never ends, so there's no meaningful duration
+                dur: 0.0,
+                artist: String::from(""),
+                title: String::from("sine"),
+                album: String::from(""),
+                gain_db: None,
+                peak: None,
+                cover: None,
+                tech: None,
+            },
+        }
+    }
+}
+
 impl AudioSource for SineSource {
     fn get_buffer(&mut self, offset: u32) -> Option<&AudioBuffer> {
         let mut signal = AudioBuffer {
-            samples: vec![vec![0.0; 1024], vec![0.0; 1024]],
-            sample_rate: 44100.0,
+            samples: vec![vec![0.0; 1024]; CHANNELS],
+            sample_rate: SAMPLE_RATE as f64,
             length: 1024,
             offset,
         };
         sine_wave(&self.freqs, &mut signal);
         self.buffer = Some(signal);
-        Some(&self.buffer.as_ref().unwrap())
+        self.buffer.as_ref()
     }
 
-    fn get_metadata(&mut self) -> &audio_source::AudioMetadata {
+    fn get_metadata(&mut self) -> &AudioMetadata {
         &self.metadata
     }
+
+    fn duration(&mut self) -> Option<f64> {
+        None
+    }
+
+    fn sample_rate(&mut self) -> Option<u32> {
+        Some(SAMPLE_RATE)
+    }
+
+    fn channels(&mut self) -> Option<usize> {
+        Some(CHANNELS)
+    }
 }
 
-fn sine_wave(freqs: &Vec<f32>, signal: &mut AudioBuffer) {
+fn sine_wave(freqs: &[f32], signal: &mut AudioBuffer) {
     // FIXME: rewrite this as an iterator?
     let amplitude = 0.1;
     for (channel_i, channel_samples) in signal.samples.iter_mut().enumerate() {
         let freq = freqs[channel_i % freqs.len()];
-        for i in 0..channel_samples.len() {
+        for (i, sample) in channel_samples.iter_mut().enumerate() {
             let t = (i as f32 + signal.offset as f32) / signal.sample_rate as f32;
-            let sample = amplitude * (2.0 * PI * freq * t).sin();
-            channel_samples[i as usize] = sample;
+            *sample = amplitude * (2.0 * PI * freq * t).sin();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::SineSource;
+    use crate::audio_source::AudioSource;
+
+    #[test]
+    fn generates_sine_waves() {
+        let mut src = SineSource::new(vec![440.0, 660.0]);
+        assert_eq!(src.get_metadata().title, "sine");
+
+        let buf = src.get_buffer(0).unwrap();
+        assert_eq!(buf.offset, 0);
+        assert_eq!(buf.samples.len(), 2);
+        let first = &buf.samples[0];
+        assert_eq!(first[0], 0.0);
+        assert!(first.iter().any(|s| *s != first[0]));
+        assert!(first.iter().all(|s| s.abs() <= 0.1));
+        assert_ne!(buf.samples[0], buf.samples[1]);
+
+        assert_eq!(src.duration(), None);
+        assert_eq!(src.sample_rate(), Some(44100));
+        assert_eq!(src.channels(), Some(2));
+    }
+}
@@ -2,10 +2,33 @@ extern crate directories;
 use log::{debug, info};
 use serde::{Deserialize, Serialize};
 
-use std::fs::{create_dir_all, File};
+use std::ffi::OsString;
+use std::fs::{create_dir_all, rename, File};
+use std::path::{Path, PathBuf};
 
 use directories::ProjectDirs;
 
+/// Playback state to start in after loading the saved player state
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupState {
+    /// Whatever was saved
+    #[default]
+    Resume,
+    Paused,
+    Playing,
+}
+
+/// What to do when the current track can't be read, e.g. because its file was deleted
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DecodeErrorPolicy {
+    /// Move on to the next track
+    #[default]
+    Skip,
+    Pause,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(default)]
 pub struct PjpConfig {
@@ -14,6 +37,58 @@ pub struct PjpConfig {
     pub last_fm_username: Option<String>,
     pub last_fm_password: Option<String>,
     pub last_fm_secret_key: Option<String>,
+    /// User token for scrobbling to ListenBrainz, from its settings page
+    pub listenbrainz_token: Option<String>,
+    /// Normalize loudness using ReplayGain tags
+    pub replaygain: bool,
+    /// Soft-clip the mixed output so peaks from ReplayGain, crossfades or volume over 1 don't
+    /// hard-clip at the device
+    pub limiter: bool,
+    /// Milliseconds to ramp the volume up on play and down on pause, so neither clicks; 0 starts
+    /// and stops dead
+    pub play_pause_fade_ms: u32,
+    /// Output channel `i` plays source channel `channel_map[i]`; empty means identity
+    pub channel_map: Vec<usize>,
+    /// Drop tracks that won't decode from the restored playlist on startup
+    pub validate_decodable_on_start: bool,
+    /// Skip the decode check when the restored playlist is longer than this
+    pub validate_decodable_max_tracks: usize,
+    pub startup_state: StartupState,
+    /// Give up on a single attempt to open a track after this long
+    pub open_timeout_ms: u64,
+    /// Attempts to make after the first failed open before skipping the track
+    pub open_retries: u32,
+    /// Directories tracks may be enqueued from; empty allows any path
+    pub library_roots: Vec<String>,
+    /// Local port the scrobbler listens on for control requests like `POST /scrobble-now`
+    pub scrobbler_port: String,
+    /// Whether to skip or pause when the playing track can't be read
+    pub on_decode_error: DecodeErrorPolicy,
+    /// Replays of a track starting sooner than this after its last scrobble, like a short track
+    /// on repeat, aren't scrobbled; unset means the track's duration
+    pub min_repeat_scrobble_gap_secs: Option<f64>,
+    /// Look up the artist and title on last.fm for listens whose tags have no artist, before
+    /// scrobbling them
+    pub correct_scrobble_metadata: bool,
+    /// How many of the tracks after the current one to open and start decoding in the
+    /// background, so skipping is instant; 0 turns it off
+    pub prefetch_tracks: usize,
+    /// Post a desktop notification when a different track starts; needs the `notifications`
+    /// feature
+    pub notify_track_changes: bool,
+    /// Seconds between `position` events on the event stream; 0 turns them off
+    pub position_event_interval_secs: f64,
+    /// Most tracks to keep a file and decoder open for at once, counting the current one; the
+    /// rest keep just their prefetched start
+    pub max_open_decoders: usize,
+    /// Requests with a bigger body than this are turned down with a 413
+    pub max_body_bytes: usize,
+    /// Origin of a web UI allowed to call the API from a browser, e.g.
+    /// `http://localhost:3000`, or `*` for any; unset sends no CORS headers
+    pub cors_origin: Option<String>,
+    /// When set, every request but `GET /status` needs an `Authorization: Bearer <token>`
+    /// header with this token, or gets a 401
+    pub auth_token: Option<String>,
 }
 
 impl Default for PjpConfig {
@@ -24,57 +99,116 @@ impl Default for PjpConfig {
             last_fm_username: None,
             last_fm_password: None,
             last_fm_secret_key: None,
+            listenbrainz_token: None,
+            replaygain: false,
+            limiter: true,
+            play_pause_fade_ms: 20,
+            channel_map: vec![],
+            validate_decodable_on_start: false,
+            validate_decodable_max_tracks: 500,
+            startup_state: StartupState::Resume,
+            open_timeout_ms: 5000,
+            open_retries: 2,
+            library_roots: vec![],
+            scrobbler_port: "7879".into(),
+            on_decode_error: DecodeErrorPolicy::Skip,
+            min_repeat_scrobble_gap_secs: None,
+            correct_scrobble_metadata: false,
+            prefetch_tracks: 3,
+            notify_track_changes: false,
+            position_event_interval_secs: 2.0,
+            max_open_decoders: crate::player_state::DEFAULT_MAX_OPEN_DECODERS,
+            max_body_bytes: crate::web_framework::DEFAULT_MAX_BODY_BYTES,
+            cors_origin: None,
+            auth_token: None,
         }
     }
 }
 
+/// Overrides where the config is kept, e.g. to run a second instance or test against a scratch
+/// directory
+const CONFIG_DIR_VAR: &str = "PJP_CONFIG_DIR";
+/// Overrides where saved state like the playlist is kept
+const DATA_DIR_VAR: &str = "PJP_DATA_DIR";
+
+fn config_dir() -> PathBuf {
+    config_dir_from(std::env::var_os(CONFIG_DIR_VAR))
+}
+
+/// The config directory, given the value of `CONFIG_DIR_VAR`
+fn config_dir_from(var: Option<OsString>) -> PathBuf {
+    match var {
+        Some(dir) => PathBuf::from(dir),
+        None => ProjectDirs::from("com", "srubin", "pjp")
+            .unwrap()
+            .config_dir()
+            .to_path_buf(),
+    }
+}
+
+fn data_dir() -> PathBuf {
+    data_dir_from(std::env::var_os(DATA_DIR_VAR))
+}
+
+/// The data directory, given the value of `DATA_DIR_VAR`
+fn data_dir_from(var: Option<OsString>) -> PathBuf {
+    match var {
+        Some(dir) => PathBuf::from(dir),
+        None => ProjectDirs::from("com", "srubin", "pjp")
+            .unwrap()
+            .data_local_dir()
+            .to_path_buf(),
+    }
+}
+
 pub fn load_config() -> PjpConfig {
-    let proj_dirs = ProjectDirs::from("com", "srubin", "pjp").unwrap();
-    let config_dir = proj_dirs.config_dir();
+    load_config_in(&config_dir())
+}
+
+fn load_config_in(config_dir: &Path) -> PjpConfig {
     let config_path = config_dir.join("config.json");
 
     match File::open(config_path.clone()) {
         Ok(config_file) => {
             let config: PjpConfig = serde_json::from_reader(config_file).unwrap();
-            info!(
-                "loaded config from {}",
-                config_path.to_str().unwrap(),
-            );
+            info!("loaded config from {}", config_path.to_str().unwrap(),);
             config
         }
         Err(_) => {
             info!("creating and saving default config");
             let config = PjpConfig::default();
-            save_config(&config).unwrap();
+            save_config(config_dir, &config).unwrap();
             config
         }
     }
 }
 
-pub fn save_config(config: &PjpConfig) -> Result<(), Box<dyn std::error::Error>> {
-    let proj_dirs = ProjectDirs::from("com", "srubin", "pjp").unwrap();
-    let config_dir = proj_dirs.config_dir();
+fn save_config(config_dir: &Path, config: &PjpConfig) -> Result<(), Box<dyn std::error::Error>> {
     create_dir_all(config_dir)?;
 
     let config_path = config_dir.join("config.json");
 
     println!("config_path: {:?}", config_path);
-    let config_file = File::create(config_path)?;
-    serde_json::to_writer(config_file, &config)?;
-    Ok(())
+    write_json(&config_path, &config)
+}
+
+/// Where `load_json` and `save_json` keep `name`, making the data directory if it's missing
+pub fn json_path(name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    json_path_in(&data_dir(), name)
+}
+
+fn json_path_in(data_dir: &Path, name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    create_dir_all(data_dir)?;
+    Ok(data_dir.join(format!("{}.json", name)))
 }
 
 pub fn load_json<T>(name: &str) -> Result<T, Box<dyn std::error::Error>>
 where
     for<'de> T: Deserialize<'de>,
 {
-    let proj_dirs = ProjectDirs::from("com", "srubin", "pjp").unwrap();
-    let data_local_dir = proj_dirs.data_local_dir();
-    create_dir_all(data_local_dir)?;
-    let path: std::path::PathBuf = data_local_dir.join(format!("{}.json", name));
+    let path = json_path(name)?;
     debug!("loading {}", path.to_str().unwrap());
-    let file = File::open(path.clone())?;
-    let res = serde_json::from_reader::<File, T>(file)?;
+    let res = read_json(&path)?;
     debug!("loaded {}", path.to_str().unwrap());
     Ok(res)
 }
@@ -83,13 +217,193 @@ pub fn save_json<T>(name: &str, data: &T) -> Result<(), Box<dyn std::error::Erro
 where
     T: Serialize,
 {
-    let proj_dirs = ProjectDirs::from("com", "srubin", "pjp").unwrap();
-    let data_local_dir = proj_dirs.data_local_dir();
-    create_dir_all(data_local_dir)?;
-    let path = data_local_dir.join(format!("{}.json", name));
+    let path = json_path(name)?;
     debug!("saving {}", path.to_str().unwrap());
-    let file = File::create(path.clone())?;
-    serde_json::to_writer(file, data)?;
+    write_json(&path, data)?;
     debug!("saved {}", path.to_str().unwrap());
     Ok(())
 }
+
+/// Named playlists are kept in here, under the data directory, a file each
+const PLAYLISTS_DIR: &str = "playlists";
+
+/// Whether `name` can name a saved playlist's file without reaching outside the playlists
+/// directory
+pub fn is_valid_playlist_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.contains(['/', '\\'])
+        && !name.contains("..")
+        && !name.starts_with('.')
+}
+
+fn playlists_dir() -> PathBuf {
+    data_dir().join(PLAYLISTS_DIR)
+}
+
+/// Where the playlist called `name` is kept in `dir`
+fn playlist_path(dir: &Path, name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if !is_valid_playlist_name(name) {
+        return Err(format!("invalid playlist name {:?}", name).into());
+    }
+    Ok(dir.join(format!("{}.json", name)))
+}
+
+pub fn save_playlist<T>(name: &str, playlist: &T) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: Serialize,
+{
+    let dir = playlists_dir();
+    create_dir_all(&dir)?;
+    write_json(&playlist_path(&dir, name)?, playlist)
+}
+
+pub fn load_playlist<T>(name: &str) -> Result<T, Box<dyn std::error::Error>>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    read_json(&playlist_path(&playlists_dir(), name)?)
+}
+
+/// Names of the saved playlists, in order
+pub fn list_playlists() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    playlist_names(&playlists_dir())
+}
+
+fn playlist_names(dir: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // nothing's been saved yet
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut names = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        // leaves out the temp files of saves that were cut short
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+pub fn read_json<T>(path: &Path) -> Result<T, Box<dyn std::error::Error>>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    let file = File::open(path)?;
+    Ok(serde_json::from_reader::<File, T>(file)?)
+}
+
+/// Where `write_json` writes before moving the file into place
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Writes `data` to a temp file next to `path` and renames it over `path`, so a save that's cut
+/// short leaves the last good file in place rather than a truncated one
+pub fn write_json<T>(path: &Path, data: &T) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: Serialize,
+{
+    let temp_path = temp_path(path);
+    let file = File::create(&temp_path)?;
+    serde_json::to_writer(&file, data)?;
+    file.sync_all()?;
+    rename(&temp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{ser::Error, Serialize, Serializer};
+
+    use super::{
+        config_dir_from, data_dir_from, is_valid_playlist_name, json_path_in, load_config_in,
+        playlist_names, playlist_path, read_json, save_config, temp_path, write_json,
+    };
+
+    /// Fails partway through being written, like a save interrupted by a crash
+    struct CutShort;
+
+    impl Serialize for CutShort {
+        fn serialize<S: Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(S::Error::custom("cut short"))
+        }
+    }
+
+    #[test]
+    fn keeps_the_last_good_file_when_a_save_is_cut_short() {
+        let dir = std::env::temp_dir().join(format!("pjp-test-{}-storage", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("player_state.json");
+
+        write_json(&path, &vec![1, 2, 3]).unwrap();
+        assert!(write_json(&path, &(4, 5, CutShort)).is_err());
+        assert_eq!(std::fs::read_to_string(temp_path(&path)).unwrap(), "[4,5,");
+        assert_eq!(read_json::<Vec<u32>>(&path).unwrap(), [1, 2, 3]);
+
+        // the next save that finishes replaces it
+        write_json(&path, &vec![6]).unwrap();
+        assert_eq!(read_json::<Vec<u32>>(&path).unwrap(), [6]);
+        assert!(!temp_path(&path).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn saves_and_lists_named_playlists() {
+        let dir = std::env::temp_dir().join(format!("pjp-test-{}-playlists", std::process::id()));
+        assert_eq!(playlist_names(&dir).unwrap(), Vec::<String>::new());
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for name in ["road trip", "Évening"] {
+            write_json(&playlist_path(&dir, name).unwrap(), &vec![name]).unwrap();
+        }
+        assert!(write_json(&playlist_path(&dir, "cut").unwrap(), &CutShort).is_err());
+        assert_eq!(playlist_names(&dir).unwrap(), ["road trip", "Évening"]);
+        assert_eq!(
+            read_json::<Vec<String>>(&playlist_path(&dir, "road trip").unwrap()).unwrap(),
+            ["road trip"]
+        );
+
+        for name in ["", "../player_state", "a/b", "a\\b", "..", ".hidden"] {
+            assert!(!is_valid_playlist_name(name), "{:?}", name);
+            assert!(playlist_path(&dir, name).is_err());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn uses_directories_from_the_environment() {
+        let dir = std::env::temp_dir().join(format!("pjp-test-{}-dirs", std::process::id()));
+        // the variables aren't set here, since other tests load from the real directories
+        // in parallel
+        let config_dir = config_dir_from(Some(dir.join("config").into()));
+        let data_dir = data_dir_from(Some(dir.join("data").into()));
+        assert_eq!(config_dir, dir.join("config"));
+        assert_eq!(data_dir, dir.join("data"));
+        assert_ne!(config_dir_from(None), config_dir);
+        assert_ne!(data_dir_from(None), data_dir);
+
+        // a missing config is created with the defaults
+        let mut config = load_config_in(&config_dir);
+        assert!(config_dir.join("config.json").exists());
+        config.port = String::from("9999");
+        save_config(&config_dir, &config).unwrap();
+        assert_eq!(load_config_in(&config_dir).port, config.port);
+
+        let path = json_path_in(&data_dir, "player_state").unwrap();
+        assert_eq!(path, data_dir.join("player_state.json"));
+        write_json(&path, &vec![1, 2, 3]).unwrap();
+        assert_eq!(read_json::<Vec<u32>>(&path).unwrap(), [1, 2, 3]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A unique path in the system temp dir, ending in `name`
+pub fn temp_path(name: &str) -> PathBuf {
+    let n = TEMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+    std::env::temp_dir().join(format!("pjp-test-{}-{}-{}", std::process::id(), n, name))
+}
+
+/// Writes planar `samples` (one Vec per channel) as a 16-bit PCM wav file
+pub fn write_wav(path: &Path, sample_rate: u32, samples: &[Vec<f32>]) {
+    let channels = samples.len() as u16;
+    let frames = samples[0].len() as u32;
+    let bytes_per_frame = channels as u32 * 2;
+    let data_size = frames * bytes_per_frame;
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_size).to_le_bytes());
+    bytes.extend_from_slice(b"WAVE");
+    bytes.extend_from_slice(b"fmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&channels.to_le_bytes());
+    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&(sample_rate * bytes_per_frame).to_le_bytes());
+    bytes.extend_from_slice(&(bytes_per_frame as u16).to_le_bytes());
+    bytes.extend_from_slice(&16u16.to_le_bytes());
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_size.to_le_bytes());
+    for i in 0..frames as usize {
+        for channel in samples {
+            let sample = (channel[i].clamp(-1.0, 1.0) * 32767.0) as i16;
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+    }
+
+    File::create(path).unwrap().write_all(&bytes).unwrap();
+}
+
+/// Puts an ID3v2.3 tag in front of the file at `path`, with a user text (`TXXX`) frame for each
+/// of `tags`, which is how ReplayGain is usually tagged
+pub fn prepend_txxx_tags(path: &Path, tags: &[(&str, &str)]) {
+    let mut frames = Vec::new();
+    for (description, value) in tags {
+        // latin-1, then the description and value separated by a null
+        let mut body = vec![0u8];
+        body.extend_from_slice(description.as_bytes());
+        body.push(0);
+        body.extend_from_slice(value.as_bytes());
+        frames.extend_from_slice(b"TXXX");
+        frames.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frames.extend_from_slice(&[0, 0]);
+        frames.extend(body);
+    }
+
+    let mut bytes = b"ID3\x03\x00\x00".to_vec();
+    // the tag size is syncsafe: 7 bits per byte
+    let size = frames.len() as u32;
+    bytes.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7f) as u8));
+    bytes.extend(frames);
+    bytes.extend(std::fs::read(path).unwrap());
+    File::create(path).unwrap().write_all(&bytes).unwrap();
+}
+
+/// Creates a fifo at a fresh temp path. Opening it for reading blocks until something opens it
+/// for writing, like a file on a network mount that's slow to wake up.
+#[cfg(unix)]
+pub fn make_fifo(name: &str) -> PathBuf {
+    let path = temp_path(name);
+    let status = std::process::Command::new("mkfifo")
+        .arg(&path)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    path
+}
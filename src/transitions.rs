@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransitionMode {
+    /// The next track starts on the frame after the last one ends
+    #[default]
+    Gapless,
+    Crossfade,
+    /// `gap_secs` of silence between tracks
+    Gap,
+}
+
+/// How playback moves from one track to the next
+#[derive(Serialize, Deserialize, PartialEq, Copy, Clone, Debug)]
+#[serde(default)]
+pub struct TransitionPolicy {
+    pub mode: TransitionMode,
+    pub crossfade_secs: f64,
+    pub gap_secs: f64,
+    /// Play consecutive tracks from the same album gaplessly whatever the mode
+    pub respect_album_gapless: bool,
+}
+
+impl Default for TransitionPolicy {
+    fn default() -> Self {
+        TransitionPolicy {
+            mode: TransitionMode::Gapless,
+            crossfade_secs: 0.0,
+            gap_secs: 0.0,
+            respect_album_gapless: true,
+        }
+    }
+}
+
+impl TransitionPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, secs) in [
+            ("crossfade_secs", self.crossfade_secs),
+            ("gap_secs", self.gap_secs),
+        ] {
+            if !secs.is_finite() || secs < 0.0 {
+                return Err(format!("{} must be a non-negative number", name));
+            }
+        }
+        if self.crossfade_secs > 0.0 && self.gap_secs > 0.0 {
+            return Err(String::from("crossfade and gap are mutually exclusive"));
+        }
+
+        match self.mode {
+            TransitionMode::Gapless if self.crossfade_secs > 0.0 || self.gap_secs > 0.0 => {
+                Err(String::from("gapless mode can't have a crossfade or gap"))
+            }
+            TransitionMode::Crossfade if self.crossfade_secs == 0.0 => {
+                Err(String::from("crossfade mode needs crossfade_secs"))
+            }
+            TransitionMode::Gap if self.gap_secs == 0.0 => {
+                Err(String::from("gap mode needs gap_secs"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// This policy with a crossfade of `secs` between tracks, or gapless playback for 0
+    pub fn with_crossfade(self, secs: f64) -> TransitionPolicy {
+        TransitionPolicy {
+            mode: if secs == 0.0 {
+                TransitionMode::Gapless
+            } else {
+                TransitionMode::Crossfade
+            },
+            crossfade_secs: secs,
+            gap_secs: 0.0,
+            ..self
+        }
+    }
+
+    /// Frames to crossfade over at the end of a track, or 0 for no crossfade
+    pub fn crossfade_frames(&self, sample_rate: u32) -> u32 {
+        if self.mode != TransitionMode::Crossfade {
+            return 0;
+        }
+        (self.crossfade_secs * sample_rate as f64) as u32
+    }
+
+    /// Frames of silence to insert after a track, given whether the next one is from the same
+    /// album
+    pub fn gap_frames(&self, same_album: bool, sample_rate: u32) -> u32 {
+        if self.mode != TransitionMode::Gap || (same_album && self.respect_album_gapless) {
+            return 0;
+        }
+        (self.gap_secs * sample_rate as f64) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TransitionMode, TransitionPolicy};
+
+    #[test]
+    fn round_trips_valid_policy() {
+        let json = serde_json::json!({
+            "mode": "gap",
+            "crossfade_secs": 0.0,
+            "gap_secs": 1.5,
+            "respect_album_gapless": false,
+        });
+        let policy: TransitionPolicy = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(policy.mode, TransitionMode::Gap);
+        assert!(policy.validate().is_ok());
+        assert_eq!(serde_json::to_value(policy).unwrap(), json);
+
+        assert!(TransitionPolicy::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_contradictory_policies() {
+        let policy: TransitionPolicy = serde_json::from_value(serde_json::json!({
+            "mode": "gap",
+            "crossfade_secs": 2.0,
+            "gap_secs": 1.0,
+        }))
+        .unwrap();
+        assert!(policy.validate().is_err());
+
+        let policy: TransitionPolicy =
+            serde_json::from_value(serde_json::json!({ "mode": "gapless", "gap_secs": 1.0 }))
+                .unwrap();
+        assert!(policy.validate().is_err());
+
+        let policy: TransitionPolicy =
+            serde_json::from_value(serde_json::json!({ "mode": "gap", "gap_secs": -1.0 })).unwrap();
+        assert!(policy.validate().is_err());
+    }
+
+    #[test]
+    fn sets_crossfade() {
+        let policy = TransitionPolicy::default().with_crossfade(2.0);
+        assert_eq!(policy.mode, TransitionMode::Crossfade);
+        assert!(policy.validate().is_ok());
+        assert_eq!(policy.crossfade_frames(44100), 88200);
+
+        let policy = policy.with_crossfade(0.0);
+        assert_eq!(policy, TransitionPolicy::default());
+        assert_eq!(policy.crossfade_frames(44100), 0);
+
+        assert!(TransitionPolicy::default()
+            .with_crossfade(-1.0)
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn skips_gap_within_an_album() {
+        let policy = TransitionPolicy {
+            mode: TransitionMode::Gap,
+            gap_secs: 0.5,
+            ..TransitionPolicy::default()
+        };
+        assert_eq!(policy.gap_frames(false, 44100), 22050);
+        assert_eq!(policy.gap_frames(true, 44100), 0);
+        assert_eq!(TransitionPolicy::default().gap_frames(false, 44100), 0);
+    }
+}
@@ -1,18 +1,41 @@
 use crate::{
-    audio_source::{AudioBuffer, AudioSource},
-    wav_header::WavHeader,
+    audio_file::{open_file, OpenPolicy},
+    audio_source::{AudioBuffer, AudioMetadata, AudioSource},
+    wav_header::{
+        data_chunk_start, read_info, WavHeader, WavInfo, WAVE_FORMAT_IEEE_FLOAT, WAVE_FORMAT_PCM,
+    },
 };
 
-use std::collections::HashMap;
+use log::error;
+use std::collections::BTreeMap;
 use std::{
     ffi::OsString,
-    io::{Read, Seek},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
 };
 
+/// Most of the file we'll read looking for the data chunk
+const MAX_HEADER_BYTES: u64 = 1 << 20;
+
+/// Frames in each decoded buffer
+const BLOCK_FRAMES: u32 = 1024;
+
+/// Decoded buffers kept in memory, ~5 seconds at 44.1kHz
+const MAX_DECODED_BLOCKS: usize = 220;
+
+/// Blocks to read and decode on each cache miss, so sequential playback reads the file about
+/// every 190ms at 44.1kHz rather than every block
+const READ_AHEAD_BLOCKS: usize = 8;
+
 pub struct WavSource {
     pub filename: OsString,
     header: Option<WavHeader>,
-    decoded_buffers: HashMap<u32, AudioBuffer>,
+    /// Opened on the first read and kept for the rest
+    file: Option<File>,
+    /// Keyed by offset
+    decoded_buffers: BTreeMap<u32, AudioBuffer>,
+    metadata: Option<AudioMetadata>,
+    open_policy: OpenPolicy,
 }
 
 impl WavSource {
@@ -20,116 +43,291 @@ impl WavSource {
         WavSource {
             filename,
             header: None,
-            decoded_buffers: HashMap::new(),
+            file: None,
+            decoded_buffers: BTreeMap::new(),
+            metadata: None,
+            open_policy: OpenPolicy::default(),
+        }
+    }
+
+    /// Sets how patiently the file is opened
+    pub fn set_open_policy(&mut self, policy: OpenPolicy) {
+        self.open_policy = policy;
+    }
+
+    /// Offset of the decoded buffer holding `offset`, if there is one
+    fn find_decoded(&self, offset: u32) -> Option<u32> {
+        self.decoded_buffers
+            .range(..=offset)
+            .next_back()
+            .filter(|(_, buffer)| offset < buffer.offset + buffer.length)
+            .map(|(cached_offset, _)| *cached_offset)
+    }
+
+    /// The header, read the first time it's needed
+    fn header(&mut self) -> Option<WavHeader> {
+        if self.header.is_none() {
+            match self.read_header() {
+                Ok(header) => self.header = Some(header),
+                Err(err) => {
+                    error!("error reading wav header from {:?}: {}", self.filename, err);
+                }
+            }
+        }
+        self.header
+    }
+
+    pub fn read_header(&self) -> Result<WavHeader, Box<dyn std::error::Error>> {
+        let mut file = open_file(&self.filename, self.open_policy)?;
+        let mut header = vec![];
+        // metadata chunks can put the data chunk well past the start, so keep reading until
+        // it turns up
+        let mut window = 1024;
+        loop {
+            let remaining = window - header.len() as u64;
+            let read = file.by_ref().take(remaining).read_to_end(&mut header)?;
+            if header.len() < 12 || &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+                return Err("not a wav file".into());
+            }
+            if let Some(start) = data_chunk_start(&header) {
+                if start + 8 <= header.len() {
+                    break;
+                }
+            }
+            if (read as u64) < remaining || window >= MAX_HEADER_BYTES {
+                return Err("no data chunk in wav file".into());
+            }
+            window *= 2;
+        }
+        if header.len() < 44 {
+            return Err("wav header is too short".into());
         }
+        Ok(WavHeader::from(header))
     }
 
-    fn read_header(&self) -> Result<WavHeader, Box<dyn std::error::Error>> {
-        let mut file = std::fs::File::open(&self.filename)?;
-        let mut header = [0u8; 1024];
-        file.read_exact(&mut header)?;
-        let header = WavHeader::from(header.to_vec());
-        Ok(header)
+    /// Tags from `LIST`/`INFO` chunks, which can come before or after the audio data
+    pub fn read_info(&self, header: &WavHeader) -> Result<WavInfo, Box<dyn std::error::Error>> {
+        let mut file = open_file(&self.filename, self.open_policy)?;
+        let mut info = WavInfo::default();
+
+        let mut before_data = vec![0u8; header.data_chunk_start];
+        file.read_exact(&mut before_data)?;
+        // skip the RIFF header to start at the first chunk
+        read_info(before_data.get(12..).unwrap_or_default(), &mut info);
+
+        let data_size = header.data_size as u64;
+        file.seek(SeekFrom::Start(
+            header.data_start() as u64 + data_size + (data_size & 1),
+        ))?;
+        let mut after_data = vec![];
+        file.take(MAX_HEADER_BYTES).read_to_end(&mut after_data)?;
+        read_info(&after_data, &mut info);
+
+        Ok(info)
     }
 }
 
+/// Decodes one block of interleaved samples into a buffer of `BLOCK_FRAMES` frames, padded with
+/// silence if `bytes` runs out first
+fn decode_block(header: &WavHeader, bytes: &[u8], offset: u32) -> AudioBuffer {
+    let format_type = header.effective_format_type();
+    let mut samples = vec![];
+    for _channel_i in 0..header.number_of_channels {
+        samples.push(vec![0.0; BLOCK_FRAMES as usize]);
+    }
+
+    let mut signal = AudioBuffer {
+        samples,
+        sample_rate: header.sample_rate as f64,
+        length: BLOCK_FRAMES,
+        offset,
+    };
+
+    let bytes_per_sample = header.bits_per_sample as usize / 8;
+
+    for (channel_i, channel_samples) in signal.samples.iter_mut().enumerate() {
+        for i in 0..channel_samples.len() {
+            let sample_i = (i as usize) * bytes_per_sample * header.number_of_channels as usize
+                + channel_i * bytes_per_sample;
+
+            if sample_i + bytes_per_sample > bytes.len() {
+                // the rest is silence
+                break;
+            }
+
+            channel_samples[i] = match header.bits_per_sample {
+                // 8-bit wav is unsigned around a midpoint of 128; wider samples are signed
+                8 => (bytes[sample_i] as f32 - 128.0) / 128.0,
+                16 => {
+                    // s16le
+                    i16::from_le_bytes([bytes[sample_i], bytes[sample_i + 1]]) as f32 / 32768.0
+                }
+                24 => {
+                    // s24le, sign-extended by shifting into the top of an i32
+                    (i32::from_le_bytes([
+                        0,
+                        bytes[sample_i],
+                        bytes[sample_i + 1],
+                        bytes[sample_i + 2],
+                    ]) >> 8) as f32
+                        / 8388608.0
+                }
+                32 => {
+                    let sample_bytes = [
+                        bytes[sample_i],
+                        bytes[sample_i + 1],
+                        bytes[sample_i + 2],
+                        bytes[sample_i + 3],
+                    ];
+                    if format_type == WAVE_FORMAT_IEEE_FLOAT {
+                        // f32le
+                        f32::from_le_bytes(sample_bytes)
+                    } else {
+                        // s32le
+                        i32::from_le_bytes(sample_bytes) as f32 / 2147483648.0
+                    }
+                }
+                _ => unreachable!(),
+            };
+        }
+    }
+    signal
+}
+
 impl AudioSource for WavSource {
     fn get_buffer(&mut self, offset: u32) -> Option<&AudioBuffer> {
-        let header = match self.header {
-            Some(ref header) => header,
-            None => {
-                let header = self.read_header().unwrap();
-                self.header = Some(header);
-                self.header.as_ref().unwrap()
+        let header = self.header()?;
+
+        let format_type = header.effective_format_type();
+        match (format_type, header.bits_per_sample) {
+            (WAVE_FORMAT_PCM, 8 | 16 | 24 | 32) | (WAVE_FORMAT_IEEE_FLOAT, 32) => {}
+            (format_type, bits_per_sample) => {
+                error!(
+                    "unsupported wav format {} with {} bits per sample in {:?}",
+                    format_type, bits_per_sample, self.filename
+                );
+                return None;
             }
-        };
-
-        if header.format_type != 1 {
-            panic!("only PCM is supported right now");
         }
 
         let data_start = header.data_start();
         let data_size = header.data_size as usize;
-
-        let sample_count: usize = 1024;
+        let bytes_per_block = BLOCK_FRAMES as usize * header.bytes_per_frame as usize;
 
         let byte_start = data_start + offset as usize * header.bytes_per_frame as usize;
-        let byte_end = (byte_start + sample_count * header.bytes_per_frame as usize)
-            .min(data_start + data_size as usize);
+        let byte_end =
+            (byte_start + READ_AHEAD_BLOCKS * bytes_per_block).min(data_start + data_size);
 
         if byte_start >= byte_end {
             return None;
         }
 
-        // use already-decoded buffer if possible
-        let quantized_offset = (offset / sample_count as u32) * sample_count as u32;
-        if self.decoded_buffers.contains_key(&quantized_offset) {
-            return Some(&self.decoded_buffers[&quantized_offset]);
+        // use an already-decoded buffer if one holds this offset
+        if let Some(cached_offset) = self.find_decoded(offset) {
+            return Some(&self.decoded_buffers[&cached_offset]);
         }
 
-        // sample_count = sample_count.min((byte_end - byte_start) / header.bytes_per_frame as usize);
-
-        let mut file = std::fs::File::open(&self.filename).unwrap();
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => match open_file(&self.filename, self.open_policy) {
+                Ok(file) => self.file.insert(file),
+                Err(err) => {
+                    error!("error opening {:?}: {}", self.filename, err);
+                    return None;
+                }
+            },
+        };
         let mut buffer = vec![0u8; byte_end - byte_start as usize];
 
-        println!("reading from file {} {}", byte_start, byte_end);
-        file.seek(std::io::SeekFrom::Current(byte_start as i64))
-            .unwrap();
-        file.read_exact(&mut buffer).unwrap();
-
-        let mut samples = vec![];
-        for _channel_i in 0..header.number_of_channels {
-            samples.push(vec![0.0; sample_count as usize]);
+        if let Err(err) = file
+            .seek(SeekFrom::Start(byte_start as u64))
+            .and_then(|_| file.read_exact(&mut buffer))
+        {
+            error!("error reading {:?}: {}", self.filename, err);
+            return None;
         }
 
-        let mut signal = crate::audio_source::AudioBuffer {
-            samples,
-            sample_rate: header.sample_rate as f64,
-            length: sample_count as u32,
-            offset,
-        };
+        // one buffer per block that was read, so nearby reads after this are served from memory
+        for (block_i, block) in buffer.chunks(bytes_per_block).enumerate() {
+            let block_offset = offset + block_i as u32 * BLOCK_FRAMES;
+            let signal = decode_block(&header, block, block_offset);
+            self.decoded_buffers.insert(block_offset, signal);
+        }
 
-        let bytes_per_sample = header.bits_per_sample as usize / 8;
+        // keep memory bounded; drop from the end farthest from what was just read
+        while self.decoded_buffers.len() > MAX_DECODED_BLOCKS {
+            let first = *self.decoded_buffers.keys().next().unwrap();
+            let last = *self.decoded_buffers.keys().next_back().unwrap();
+            if offset - first.min(offset) > last.saturating_sub(offset) {
+                self.decoded_buffers.remove(&first);
+            } else {
+                self.decoded_buffers.remove(&last);
+            }
+        }
 
-        for (channel_i, channel_samples) in signal.samples.iter_mut().enumerate() {
-            for i in 0..channel_samples.len() {
-                let sample_i = (i as usize) * bytes_per_sample * header.number_of_channels as usize
-                    + channel_i * bytes_per_sample;
+        Some(&self.decoded_buffers[&offset])
+    }
 
-                if sample_i >= buffer.len() - 1 {
-                    // the rest is silence
-                    break;
+    fn get_metadata(&mut self) -> &AudioMetadata {
+        if self.metadata.is_none() {
+            let header = self.read_header().ok();
+            let dur = match header {
+                Some(header) if header.bytes_per_second > 0 => {
+                    header.data_size as f64 / header.bytes_per_second as f64
                 }
-
-                channel_samples[i] = match header.bits_per_sample {
-                    16 => {
-                        // s16le
-                        i16::from_le_bytes([buffer[sample_i], buffer[sample_i + 1]]) as f32
-                            / 32768.0
-                    }
-                    32 => {
-                        // f32le
-                        f32::from_le_bytes([
-                            buffer[sample_i],
-                            buffer[sample_i + 1],
-                            buffer[sample_i + 2],
-                            buffer[sample_i + 3],
-                        ])
-                    }
-                    _ => panic!("unsupported bits per sample {}", header.bits_per_sample),
-                };
-            }
+                _ => 0.0,
+            };
+            let info = match header.map(|header| self.read_info(&header)) {
+                Some(Ok(info)) => info,
+                Some(Err(err)) => {
+                    error!("error reading tags from {:?}: {}", self.filename, err);
+                    WavInfo::default()
+                }
+                None => WavInfo::default(),
+            };
+            self.metadata = Some(AudioMetadata {
+                dur,
+                artist: info.artist.unwrap_or_default(),
+                title: info
+                    .title
+                    .unwrap_or_else(|| self.filename.to_string_lossy().to_string()),
+                album: info.album.unwrap_or_default(),
+                gain_db: None,
+                peak: None,
+                cover: None,
+                tech: None,
+            });
         }
+        self.metadata.as_ref().unwrap()
+    }
 
-        self.decoded_buffers.insert(offset, signal);
+    #[cfg(test)]
+    fn duration(&mut self) -> Option<f64> {
+        self.header()
+            .filter(|header| header.bytes_per_second > 0)
+            .map(|header| header.data_size as f64 / header.bytes_per_second as f64)
+    }
 
-        Some(&self.decoded_buffers[&offset])
+    #[cfg(test)]
+    fn sample_rate(&mut self) -> Option<u32> {
+        self.header().map(|header| header.sample_rate)
+    }
+
+    #[cfg(test)]
+    fn channels(&mut self) -> Option<usize> {
+        self.header()
+            .map(|header| header.number_of_channels as usize)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{audio_source::AudioSource, wav::WavSource};
+    use crate::{
+        audio_source::AudioSource,
+        test_utils::{temp_path, write_wav},
+        wav::WavSource,
+        wav_header::{WAVE_FORMAT_EXTENSIBLE, WAVE_FORMAT_IEEE_FLOAT},
+    };
     use std::path::PathBuf;
 
     #[test]
@@ -148,6 +346,113 @@ mod tests {
         assert_eq!(header.data_size, 328982);
     }
 
+    #[test]
+    fn summarizes_wav_header() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/ports.wav");
+
+        let wav_src = WavSource::new(d.into_os_string());
+        let summary = serde_json::to_value(wav_src.read_header().unwrap().summary()).unwrap();
+
+        assert_eq!(summary["format_type"], 1);
+        assert_eq!(summary["number_of_channels"], 1);
+        assert_eq!(summary["sample_rate"], 44100);
+        assert_eq!(summary["bits_per_sample"], 16);
+        assert_eq!(summary["data_size"], 328982);
+        assert_eq!(summary["data_start"], 46);
+    }
+
+    #[test]
+    fn describes_the_audio_from_the_header() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/ports.wav");
+
+        let mut wav_src = WavSource::new(d.into_os_string());
+        assert_eq!(wav_src.sample_rate(), Some(44100));
+        assert_eq!(wav_src.channels(), Some(1));
+        assert_eq!(wav_src.duration(), Some(328982.0 / 88200.0));
+
+        let mut missing = WavSource::new("resources/missing.wav".into());
+        assert_eq!(missing.duration(), None);
+        assert_eq!(missing.sample_rate(), None);
+    }
+
+    #[test]
+    fn rejects_non_wav_files() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("Cargo.toml");
+
+        let wav_src = WavSource::new(d.into_os_string());
+        assert!(wav_src.read_header().is_err());
+    }
+
+    #[test]
+    fn finds_data_chunk_after_a_large_list_chunk() {
+        let path = temp_path("list.wav");
+        write_wav(&path, 44100, &[vec![0.5; 100]]);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let mut list = b"LIST".to_vec();
+        list.extend_from_slice(&2000u32.to_le_bytes());
+        list.extend(vec![0u8; 2000]);
+        bytes.splice(36..36, list);
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut wav_src = WavSource::new(path.into_os_string());
+        let header = wav_src.read_header().unwrap();
+        assert_eq!(header.data_start(), 36 + 2008 + 8);
+        assert_eq!(header.data_size, 200);
+
+        let buf = wav_src.get_buffer(0).unwrap();
+        assert!(buf.samples[0][..100]
+            .iter()
+            .all(|s| (s - 0.5).abs() < 0.001));
+    }
+
+    #[test]
+    fn reads_info_tags_around_the_data() {
+        fn info_chunk(fields: &[(&[u8; 4], &str)]) -> Vec<u8> {
+            let mut body = b"INFO".to_vec();
+            for (id, value) in fields {
+                let mut value = value.as_bytes().to_vec();
+                value.push(0);
+                body.extend_from_slice(*id);
+                body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                if value.len() % 2 == 1 {
+                    value.push(0);
+                }
+                body.extend(value);
+            }
+            let mut chunk = b"LIST".to_vec();
+            chunk.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            chunk.extend(body);
+            chunk
+        }
+
+        let path = temp_path("info.wav");
+        write_wav(&path, 44100, &[vec![0.5; 44100]]);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.splice(
+            36..36,
+            info_chunk(&[(b"INAM", "Ports"), (b"IART", "Artist")]),
+        );
+        bytes.extend(info_chunk(&[(b"IPRD", "Album")]));
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut wav_src = WavSource::new(path.into_os_string());
+        let metadata = wav_src.get_metadata();
+        assert_eq!(metadata.title, "Ports");
+        assert_eq!(metadata.artist, "Artist");
+        assert_eq!(metadata.album, "Album");
+        assert!((metadata.dur - 1.0).abs() < 1e-6);
+
+        // untagged files are titled by filename
+        let path = temp_path("untagged.wav");
+        write_wav(&path, 44100, &[vec![0.5; 100]]);
+        let mut wav_src = WavSource::new(path.clone().into_os_string());
+        assert_eq!(wav_src.get_metadata().title, path.to_str().unwrap());
+        assert_eq!(wav_src.get_metadata().artist, "");
+    }
+
     #[test]
     fn gets_initial_buffer() {
         let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -174,15 +479,182 @@ mod tests {
         let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         d.push("resources/ports.wav");
 
+        // the last buffer is padded with silence...
         let mut wav_src = WavSource::new(d.into_os_string());
-        let buf = wav_src.get_buffer(44100 * 10).unwrap();
+        let buf = wav_src.get_buffer(164000).unwrap();
 
         assert_eq!(buf.samples.len(), 1);
         assert_eq!(buf.length, 1024);
-        assert_eq!(buf.offset, 44100 * 10);
+        assert_eq!(buf.offset, 164000);
         assert_eq!(buf.sample_rate, 44100.0);
-        for sample in buf.samples[0].iter() {
+        for sample in buf.samples[0][491..].iter() {
             assert_eq!(*sample, 0.0);
         }
+
+        // ...and there's nothing to play after that
+        assert!(wav_src.get_buffer(44100 * 10).is_none());
+    }
+
+    #[test]
+    fn reads_24_bit_samples() {
+        let frames: u32 = 2000;
+        let data_size = frames * 6;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data_size).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&44100u32.to_le_bytes());
+        bytes.extend_from_slice(&(44100u32 * 6).to_le_bytes());
+        bytes.extend_from_slice(&6u16.to_le_bytes());
+        bytes.extend_from_slice(&24u16.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_size.to_le_bytes());
+        let mut data = vec![0u8; data_size as usize];
+        data[0..6].copy_from_slice(&[0x00, 0x00, 0x40, 0x00, 0x00, 0xc0]);
+        data[6..12].copy_from_slice(&[0xff, 0xff, 0x7f, 0x00, 0x00, 0x80]);
+        // the very last sample in the file
+        data[data_size as usize - 3..].copy_from_slice(&[0x56, 0x34, 0x12]);
+        bytes.extend_from_slice(&data);
+        let path = temp_path("24bit.wav");
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut wav_src = WavSource::new(path.into_os_string());
+        let buf = wav_src.get_buffer(0).unwrap();
+        assert_eq!(buf.samples.len(), 2);
+        assert_eq!(buf.samples[0][0], 0.5);
+        assert_eq!(buf.samples[1][0], -0.5);
+        assert_eq!(buf.samples[0][1], 8388607.0 / 8388608.0);
+        assert_eq!(buf.samples[1][1], -1.0);
+
+        let buf = wav_src.get_buffer(1024).unwrap();
+        let last = (frames - 1 - 1024) as usize;
+        assert_eq!(buf.samples[0][last], 0.0);
+        assert_eq!(buf.samples[1][last], 0x123456 as f32 / 8388608.0);
+        assert!(buf.samples[1][last + 1..].iter().all(|s| *s == 0.0));
+    }
+
+    #[test]
+    fn reads_unsigned_8_bit_samples() {
+        let data = [128u8, 0, 255, 192, 64, 129];
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&8000u32.to_le_bytes());
+        bytes.extend_from_slice(&8000u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&8u16.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&data);
+        let path = temp_path("8bit.wav");
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut wav_src = WavSource::new(path.into_os_string());
+        let buf = wav_src.get_buffer(0).unwrap();
+        assert_eq!(
+            buf.samples[0][..data.len()],
+            [0.0, -1.0, 127.0 / 128.0, 0.5, -0.5, 1.0 / 128.0]
+        );
+        // past the end is silence, not the 128 midpoint read as a sample
+        assert_eq!(buf.samples[0][data.len()], 0.0);
+    }
+
+    fn write_float_wav(name: &str, format_chunk: &[u8], samples: &[f32]) -> PathBuf {
+        let data_size = samples.len() as u32 * 4;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(20 + format_chunk.len() as u32 + data_size).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&(format_chunk.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(format_chunk);
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_size.to_le_bytes());
+        for sample in samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        let path = temp_path(name);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_float_samples() {
+        // stereo, 44.1 kHz, 32 bits
+        let mut format_chunk = vec![];
+        format_chunk.extend_from_slice(&2u16.to_le_bytes());
+        format_chunk.extend_from_slice(&44100u32.to_le_bytes());
+        format_chunk.extend_from_slice(&(44100u32 * 8).to_le_bytes());
+        format_chunk.extend_from_slice(&8u16.to_le_bytes());
+        format_chunk.extend_from_slice(&32u16.to_le_bytes());
+
+        let plain = [&WAVE_FORMAT_IEEE_FLOAT.to_le_bytes()[..], &format_chunk].concat();
+        let extensible = [
+            &WAVE_FORMAT_EXTENSIBLE.to_le_bytes()[..],
+            &format_chunk,
+            &22u16.to_le_bytes(),
+            &32u16.to_le_bytes(),
+            &3u32.to_le_bytes(),
+            &[3, 0, 0, 0, 0, 0, 16, 0, 128, 0, 0, 170, 0, 56, 155, 113],
+        ]
+        .concat();
+
+        let samples: Vec<f32> = (0..2000).map(|i| ((i % 9) as f32 - 4.0) / 4.0).collect();
+        for format in [plain, extensible] {
+            let path = write_float_wav("float.wav", &format, &samples);
+            let mut wav_src = WavSource::new(path.into_os_string());
+            let buf = wav_src.get_buffer(0).unwrap();
+
+            assert_eq!(buf.samples.len(), 2);
+            assert_eq!(buf.samples[0][0], -1.0);
+            assert_eq!(buf.samples[1][0], -0.75);
+            assert_eq!(buf.samples[0][2], 0.0);
+            for channel in buf.samples.iter() {
+                assert!(channel.iter().all(|s| (-1.0..=1.0).contains(s)));
+            }
+        }
+    }
+
+    #[test]
+    fn reads_non_adjacent_offsets_from_one_file() {
+        let ramp: Vec<f32> = (0..20000).map(|i| (i % 2000) as f32 / 2000.0).collect();
+        let path = temp_path("ramp.wav");
+        write_wav(&path, 44100, std::slice::from_ref(&ramp));
+
+        let mut wav_src = WavSource::new(path.into_os_string());
+        for offset in [15000, 3000, 12000] {
+            let buf = wav_src.get_buffer(offset).unwrap();
+            assert_eq!(buf.offset, offset);
+            for (i, sample) in buf.samples[0].iter().enumerate() {
+                assert!((sample - ramp[offset as usize + i]).abs() < 0.001);
+            }
+        }
+    }
+
+    #[test]
+    fn serves_sequential_reads_from_read_ahead() {
+        let ramp: Vec<f32> = (0..10000).map(|i| (i % 2000) as f32 / 2000.0).collect();
+        let path = temp_path("read-ahead.wav");
+        write_wav(&path, 44100, std::slice::from_ref(&ramp));
+
+        let mut wav_src = WavSource::new(path.clone().into_os_string());
+        assert_eq!(wav_src.get_buffer(0).unwrap().offset, 0);
+
+        // the file can't be read any more, so the next blocks must come from memory
+        std::fs::File::create(&path).unwrap();
+        for offset in [1024, 2048, 3072, 3500, 7168, 8191] {
+            let buf = wav_src.get_buffer(offset).unwrap();
+            assert_eq!(buf.offset, offset / 1024 * 1024);
+            for (i, sample) in buf.samples[0].iter().enumerate() {
+                assert!((sample - ramp[buf.offset as usize + i]).abs() < 0.001);
+            }
+        }
+        assert!(wav_src.get_buffer(8192).is_none());
     }
 }
@@ -1,3 +1,10 @@
+use serde::Serialize;
+
+pub const WAVE_FORMAT_PCM: u16 = 1;
+pub const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+/// The actual format is in the subformat GUID
+pub const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
 #[derive(Debug, Copy, Clone)]
 pub struct WavHeader {
     pub riff: [u8; 4],
@@ -11,22 +18,97 @@ pub struct WavHeader {
     pub bytes_per_second: u32,
     pub bytes_per_frame: u16,
     pub bits_per_sample: u16,
+    /// Format code from the subformat GUID of a `WAVE_FORMAT_EXTENSIBLE` header
+    pub sub_format: Option<u16>,
+    /// Offset of the data chunk marker; other chunks like `LIST` can come before it
+    pub data_chunk_start: usize,
     pub data_chunk_marker: [u8; 4],
     pub data_size: u32,
 }
 
-fn find_chunk(bytes: &Vec<u8>, start: usize, name: &[u8]) -> Option<usize> {
-    for i in start..bytes.len() {
-        for j in 0..name.len() {
-            if bytes[i + j] != name[j] {
-                break;
-            }
-            if j == name.len() - 1 {
-                return Some(i);
-            }
+/// The parts of a wav header that matter for playback, for diagnostics
+#[derive(Serialize, Debug)]
+pub struct WavHeaderSummary {
+    pub format_type: u16,
+    pub number_of_channels: u16,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+    pub bytes_per_frame: u16,
+    pub data_size: u32,
+    pub data_start: usize,
+}
+
+/// Offset of the data chunk marker in the start of a wav file, if `header_bytes` reaches it.
+/// Steps from chunk header to chunk header, since other chunks can contain "data" too.
+pub fn data_chunk_start(header_bytes: &[u8]) -> Option<usize> {
+    // the first chunk follows "RIFF", the file size and "WAVE"
+    let mut pos = 12;
+    while pos + 4 <= header_bytes.len() {
+        if &header_bytes[pos..pos + 4] == b"data" {
+            return Some(pos);
         }
+        if pos + 8 > header_bytes.len() {
+            break;
+        }
+        let size = chunk_size(header_bytes, pos);
+        pos = (pos + 8).saturating_add(size).saturating_add(size & 1);
+    }
+    None
+}
+
+/// Tags from `LIST`/`INFO` chunks
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WavInfo {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+fn chunk_size(bytes: &[u8], pos: usize) -> usize {
+    u32::from_le_bytes([
+        bytes[pos + 4],
+        bytes[pos + 5],
+        bytes[pos + 6],
+        bytes[pos + 7],
+    ]) as usize
+}
+
+/// Fills in `info` from any `LIST`/`INFO` chunks in `bytes`, which should start at a chunk
+/// boundary. Chunks are padded to an even length.
+pub fn read_info(bytes: &[u8], info: &mut WavInfo) {
+    let mut pos = 0;
+    while pos + 8 <= bytes.len() {
+        let size = chunk_size(bytes, pos);
+        let body = pos + 8;
+        let end = body.saturating_add(size).min(bytes.len());
+        if &bytes[pos..pos + 4] == b"LIST" && end >= body + 4 && &bytes[body..body + 4] == b"INFO" {
+            read_info_fields(&bytes[body + 4..end], info);
+        }
+        pos = body.saturating_add(size).saturating_add(size & 1);
+    }
+}
+
+fn read_info_fields(bytes: &[u8], info: &mut WavInfo) {
+    let mut pos = 0;
+    while pos + 8 <= bytes.len() {
+        let size = chunk_size(bytes, pos);
+        let end = (pos + 8).saturating_add(size).min(bytes.len());
+        // values are null-terminated, and sometimes padded with more nulls
+        let value = String::from_utf8_lossy(&bytes[pos + 8..end])
+            .trim_end_matches('\0')
+            .trim()
+            .to_string();
+        let field = match &bytes[pos..pos + 4] {
+            b"INAM" => Some(&mut info.title),
+            b"IART" => Some(&mut info.artist),
+            b"IPRD" => Some(&mut info.album),
+            _ => None,
+        };
+        if let Some(field) = field.filter(|_| !value.is_empty()) {
+            *field = Some(value);
+        }
+        pos = end.saturating_add(size & 1);
     }
-    return None;
 }
 
 impl WavHeader {
@@ -44,11 +126,7 @@ impl WavHeader {
             header_bytes[19],
         ]);
 
-        let data_chunk_start = find_chunk(
-            &header_bytes,
-            20 + format_data_length as usize,
-            "data".as_bytes(),
-        );
+        let data_chunk_start = data_chunk_start(&header_bytes);
 
         if data_chunk_start.is_none() {
             panic!("Could not find data chunk in wav file");
@@ -65,6 +143,15 @@ impl WavHeader {
             header_bytes.len()
         );
 
+        let format_type = u16::from_le_bytes([header_bytes[20], header_bytes[21]]);
+        // the subformat GUID starts 24 bytes into the extensible format chunk; its first two
+        // bytes are the format code
+        let sub_format = if format_type == WAVE_FORMAT_EXTENSIBLE && format_data_length >= 40 {
+            Some(u16::from_le_bytes([header_bytes[44], header_bytes[45]]))
+        } else {
+            None
+        };
+
         // read data from the header buffer into a WavHeader struct
         let header = WavHeader {
             riff: [
@@ -92,7 +179,7 @@ impl WavHeader {
                 header_bytes[15],
             ],
             format_data_length,
-            format_type: u16::from_le_bytes([header_bytes[20], header_bytes[21]]),
+            format_type,
             number_of_channels: u16::from_le_bytes([header_bytes[22], header_bytes[23]]),
             sample_rate: u32::from_le_bytes([
                 header_bytes[24],
@@ -108,6 +195,8 @@ impl WavHeader {
             ]),
             bytes_per_frame: u16::from_le_bytes([header_bytes[32], header_bytes[33]]),
             bits_per_sample: u16::from_le_bytes([header_bytes[34], header_bytes[35]]),
+            sub_format,
+            data_chunk_start,
             data_chunk_marker: [
                 header_bytes[data_chunk_start + 0],
                 header_bytes[data_chunk_start + 1],
@@ -124,8 +213,25 @@ impl WavHeader {
         header
     }
 
+    /// The format of the samples, looking through `WAVE_FORMAT_EXTENSIBLE`
+    pub fn effective_format_type(&self) -> u16 {
+        self.sub_format.unwrap_or(self.format_type)
+    }
+
     pub fn data_start(&self) -> usize {
-        20 + self.format_data_length as usize + 8
+        self.data_chunk_start + 8
+    }
+
+    pub fn summary(&self) -> WavHeaderSummary {
+        WavHeaderSummary {
+            format_type: self.format_type,
+            number_of_channels: self.number_of_channels,
+            sample_rate: self.sample_rate,
+            bits_per_sample: self.bits_per_sample,
+            bytes_per_frame: self.bytes_per_frame,
+            data_size: self.data_size,
+            data_start: self.data_start(),
+        }
     }
 }
 
@@ -149,4 +255,69 @@ mod tests {
         assert_eq!(header.bits_per_sample, 16);
         assert_eq!(header.data_size, 328982);
     }
+
+    #[test]
+    fn doesnt_read_past_the_end_looking_for_chunks() {
+        let mut header_vec = vec![];
+        header_vec.extend_from_slice(b"RIFF\0\0\0\0WAVEfmt ");
+        header_vec.extend_from_slice(&16u32.to_le_bytes());
+        header_vec.extend(vec![0u8; 16]);
+        header_vec.extend_from_slice(b"LIST\0\0\0\0da");
+
+        assert_eq!(super::data_chunk_start(&header_vec), None);
+        header_vec.extend_from_slice(b"ta");
+        assert_eq!(
+            super::data_chunk_start(&header_vec),
+            Some(header_vec.len() - 4)
+        );
+    }
+
+    #[test]
+    fn skips_data_inside_other_chunks() {
+        let mut header_vec = vec![];
+        header_vec.extend_from_slice(b"RIFF\0\0\0\0WAVEfmt ");
+        header_vec.extend_from_slice(&16u32.to_le_bytes());
+        header_vec.extend_from_slice(&[1, 0, 1, 0, 68, 172, 0, 0, 136, 88, 1, 0, 2, 0, 16, 0]);
+        header_vec.extend_from_slice(b"LIST");
+        header_vec.extend_from_slice(&21u32.to_le_bytes());
+        header_vec.extend_from_slice(b"INFOINAM");
+        header_vec.extend_from_slice(&9u32.to_le_bytes());
+        header_vec.extend_from_slice(b"metadata\0\0");
+        let data_start = header_vec.len();
+        header_vec.extend_from_slice(b"data");
+        header_vec.extend_from_slice(&328982u32.to_le_bytes());
+
+        assert_eq!(super::data_chunk_start(&header_vec), Some(data_start));
+        let header = super::WavHeader::from(header_vec);
+        assert_eq!(header.data_start(), data_start + 8);
+        assert_eq!(header.data_size, 328982);
+    }
+
+    #[test]
+    fn reads_extensible_subformat() {
+        let mut header_vec = vec![];
+        header_vec.extend_from_slice(b"RIFF\0\0\0\0WAVEfmt ");
+        header_vec.extend_from_slice(&40u32.to_le_bytes());
+        header_vec.extend_from_slice(&super::WAVE_FORMAT_EXTENSIBLE.to_le_bytes());
+        header_vec.extend_from_slice(&2u16.to_le_bytes());
+        header_vec.extend_from_slice(&48000u32.to_le_bytes());
+        header_vec.extend_from_slice(&(48000u32 * 8).to_le_bytes());
+        header_vec.extend_from_slice(&8u16.to_le_bytes());
+        header_vec.extend_from_slice(&32u16.to_le_bytes());
+        header_vec.extend_from_slice(&22u16.to_le_bytes());
+        header_vec.extend_from_slice(&32u16.to_le_bytes());
+        header_vec.extend_from_slice(&3u32.to_le_bytes());
+        header_vec.extend_from_slice(&[3, 0, 0, 0, 0, 0, 16, 0, 128, 0, 0, 170, 0, 56, 155, 113]);
+        header_vec.extend_from_slice(b"data");
+        header_vec.extend_from_slice(&800u32.to_le_bytes());
+
+        let header = super::WavHeader::from(header_vec);
+        assert_eq!(header.format_type, super::WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(
+            header.effective_format_type(),
+            super::WAVE_FORMAT_IEEE_FLOAT
+        );
+        assert_eq!(header.data_start(), 68);
+        assert_eq!(header.data_size, 800);
+    }
 }
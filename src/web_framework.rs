@@ -1,12 +1,14 @@
 use std::{
-    borrow::BorrowMut,
     collections::HashMap,
     io::{prelude::*, BufReader},
-    net::TcpStream,
+    net::{Shutdown, TcpListener, TcpStream},
     str::FromStr,
+    sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError},
+    thread,
+    time::Duration,
 };
 
-use log::{debug, info};
+use log::{debug, error, info};
 use serde::Serialize;
 
 pub enum HttpMethod {
@@ -15,29 +17,70 @@ pub enum HttpMethod {
     Patch,
     Put,
     Delete,
+    /// Sent by browsers before cross-origin requests, to ask if they're allowed
+    Options,
 }
 
 pub struct HttpRequest {
     pub method: HttpMethod,
     pub path: String,
+    pub query: HashMap<String, String>,
     pub version: String,
     pub headers: HashMap<String, String>,
     pub body: String,
 }
 
+/// Requests with bigger bodies than this are turned down unless configured otherwise
+pub const DEFAULT_MAX_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+/// Events an SSE subscriber can fall behind by before it's disconnected
+const SSE_QUEUE_LEN: usize = 64;
+
+/// A client that stops sending partway through a request, or doesn't send another one on a
+/// kept-alive connection, is given up on after this long
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq)]
+pub enum RequestError {
+    Malformed,
+    BodyTooLarge,
+}
+
+impl RequestError {
+    pub fn response_code(&self) -> HttpResponseCode {
+        match self {
+            RequestError::Malformed => HttpResponseCode::BadRequest,
+            RequestError::BodyTooLarge => HttpResponseCode::PayloadTooLarge,
+        }
+    }
+}
+
 pub enum HttpResponseCode {
     Ok,
+    NoContent,
     NotFound,
     InternalServerError,
     BadRequest,
+    Unauthorized,
+    PayloadTooLarge,
+    ServiceUnavailable,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
 }
 
 pub struct HttpResponse {
     stream: TcpStream,
     pub headers: HashMap<String, String>,
     pub response_code: HttpResponseCode,
-    json_body: Option<String>,
+    body: Option<Vec<u8>>,
     sent_response: bool,
+    /// The headers have gone out for a body that's written until the connection closes
+    streaming: bool,
+    /// Told whether the connection can carry another request once the response is sent
+    keep_alive: Option<Sender<bool>>,
 }
 
 impl FromStr for HttpMethod {
@@ -50,21 +93,26 @@ impl FromStr for HttpMethod {
             "PATCH" => Ok(HttpMethod::Patch),
             "PUT" => Ok(HttpMethod::Put),
             "DELETE" => Ok(HttpMethod::Delete),
+            "OPTIONS" => Ok(HttpMethod::Options),
             _ => Err(()),
         }
     }
 }
 
-impl TryFrom<&mut TcpStream> for HttpRequest {
-    type Error = ();
-
-    fn try_from(stream: &mut TcpStream) -> Result<Self, Self::Error> {
-        let mut buf_reader = BufReader::new(std::io::Read::by_ref(stream));
-
+impl HttpRequest {
+    /// Reads a request from `stream`. Anything malformed is an error rather than a panic, so one
+    /// bad client can't take down the server, and so is a body over `max_body_bytes`, which is
+    /// turned down before allocating anything for it.
+    pub fn read_from<R: BufRead>(
+        buf_reader: &mut R,
+        max_body_bytes: usize,
+    ) -> Result<HttpRequest, RequestError> {
         let mut http_request_lines = Vec::new();
         loop {
             let mut line = String::new();
-            let bytes_read = buf_reader.read_line(&mut line).unwrap();
+            let bytes_read = buf_reader
+                .read_line(&mut line)
+                .map_err(|_| RequestError::Malformed)?;
             line = line.trim().to_string();
             if line.is_empty() || bytes_read == 0 {
                 break;
@@ -72,44 +120,180 @@ impl TryFrom<&mut TcpStream> for HttpRequest {
             http_request_lines.push(line);
         }
 
+        info!("http request: {:?}", http_request_lines);
+
+        let (request_line, header_lines) = http_request_lines
+            .split_first()
+            .ok_or(RequestError::Malformed)?;
+        let parts: Vec<&str> = request_line.split(' ').collect();
+        let (method, target, version) = match parts[..] {
+            [method, target, version] => (method, target, version),
+            _ => return Err(RequestError::Malformed),
+        };
+
         let mut req = HttpRequest {
-            method: HttpMethod::Get,
-            path: String::from(""),
-            version: String::from(""),
+            method: HttpMethod::from_str(method).map_err(|_| RequestError::Malformed)?,
+            path: percent_decode(target),
+            query: HashMap::new(),
+            version: String::from(version),
             headers: HashMap::new(),
             body: String::from(""),
         };
+        if let Some((path, query)) = target.split_once('?') {
+            req.path = percent_decode(path);
+            req.query = parse_query(query);
+        }
 
-        info!("http request: {:?}", http_request_lines);
-
-        for (i, line) in http_request_lines.iter().enumerate() {
-            if i == 0 {
-                let parts: Vec<&str> = line.split(" ").collect();
-                req.method = HttpMethod::from_str(parts[0])?;
-                req.path = String::from(parts[1]);
-                req.version = String::from(parts[2]);
-            } else {
-                let parts: Vec<&str> = line.split(": ").collect();
-                req.headers.insert(
-                    String::from(parts[0]).to_lowercase(),
-                    String::from(parts[1]),
-                );
-            }
+        for line in header_lines {
+            let (name, value) = line.split_once(':').ok_or(RequestError::Malformed)?;
+            req.headers
+                .insert(name.trim().to_lowercase(), String::from(value.trim()));
         }
 
-        // read the body
-        if let Some(header) = req.headers.get("content-length") {
-            if let Ok(content_length) = header.parse::<usize>() {
-                let mut buf = vec![0; content_length];
-                buf_reader.read_exact(&mut buf).unwrap();
-                req.body = String::from_utf8(buf).unwrap();
+        // read the body; a chunked one says how long it is as it goes, so it wins over any length
+        let chunked = req.headers.get("transfer-encoding").is_some_and(|codings| {
+            codings
+                .rsplit(',')
+                .next()
+                .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+        });
+        if chunked {
+            let buf = read_chunked(buf_reader, max_body_bytes)?;
+            req.body = String::from_utf8(buf).map_err(|_| RequestError::Malformed)?;
+        } else if let Some(header) = req.headers.get("content-length") {
+            let content_length = header
+                .parse::<usize>()
+                .map_err(|_| RequestError::Malformed)?;
+            if content_length > max_body_bytes {
+                return Err(RequestError::BodyTooLarge);
             }
+            let mut buf = vec![0; content_length];
+            buf_reader
+                .read_exact(&mut buf)
+                .map_err(|_| RequestError::Malformed)?;
+            req.body = String::from_utf8(buf).map_err(|_| RequestError::Malformed)?;
         }
 
         debug!("http request body: {:?}", req.body);
 
         Ok(req)
     }
+
+    /// HTTP/1.1 connections stay open unless the client asks to close them; HTTP/1.0 ones only
+    /// stay open if it asks for that
+    fn wants_keep_alive(&self) -> bool {
+        match self.headers.get("connection") {
+            Some(connection) => connection.eq_ignore_ascii_case("keep-alive"),
+            None => self.version == "HTTP/1.1",
+        }
+    }
+
+    /// Whether the request has an `Authorization: Bearer` header with `token`, compared in
+    /// constant time so the response time doesn't give away how much of a guess was right
+    pub fn has_bearer_token(&self, token: &str) -> bool {
+        let sent = self
+            .headers
+            .get("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match sent {
+            Some(sent) => constant_time_eq(sent.trim().as_bytes(), token.as_bytes()),
+            None => false,
+        }
+    }
+}
+
+/// Compares every byte whatever the first difference is; only the length can leak
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Reads a body sent with `Transfer-Encoding: chunked`: chunks that each start with their size
+/// in hex on a line of its own, up to one of size zero, then any trailers and a blank line
+fn read_chunked<R: BufRead>(
+    buf_reader: &mut R,
+    max_body_bytes: usize,
+) -> Result<Vec<u8>, RequestError> {
+    let read_line = |buf_reader: &mut R| {
+        let mut line = String::new();
+        match buf_reader.read_line(&mut line) {
+            Ok(0) | Err(_) => Err(RequestError::Malformed),
+            Ok(_) => Ok(line),
+        }
+    };
+    let mut body = vec![];
+    loop {
+        let line = read_line(buf_reader)?;
+        // chunk extensions after a `;` aren't used
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| RequestError::Malformed)?;
+        if size == 0 {
+            break;
+        }
+        if size > max_body_bytes.saturating_sub(body.len()) {
+            return Err(RequestError::BodyTooLarge);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        buf_reader
+            .read_exact(&mut body[start..])
+            .map_err(|_| RequestError::Malformed)?;
+        // the data has to end right where its size said it would
+        let mut end = [0; 2];
+        buf_reader
+            .read_exact(&mut end)
+            .map_err(|_| RequestError::Malformed)?;
+        if &end != b"\r\n" {
+            return Err(RequestError::Malformed);
+        }
+    }
+    // trailers aren't used either
+    while !read_line(buf_reader)?.trim().is_empty() {}
+    Ok(body)
+}
+
+/// Parses `a=1&b=2` into a map, percent-decoding keys and values. Keys without a value map to
+/// an empty string.
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (query_decode(key), query_decode(value)),
+            None => (query_decode(pair), String::from("")),
+        })
+        .collect()
+}
+
+/// A query key or value, where `+` is also a space
+fn query_decode(s: &str) -> String {
+    percent_decode(&s.replace('+', " "))
+}
+
+/// Decodes `%XX` escapes. Malformed escapes are kept as they are.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
 }
 
 impl HttpResponse {
@@ -118,16 +302,54 @@ impl HttpResponse {
             stream,
             headers: HashMap::new(),
             response_code: HttpResponseCode::Ok,
-            json_body: None,
+            body: None,
             sent_response: false,
+            streaming: false,
+            keep_alive: None,
         }
     }
 
+    fn with_keep_alive(stream: TcpStream, keep_alive: Sender<bool>) -> HttpResponse {
+        let mut res = HttpResponse::new(stream);
+        res.headers
+            .insert(String::from("Connection"), String::from("keep-alive"));
+        res.keep_alive = Some(keep_alive);
+        res
+    }
+
     pub fn set_json<T>(&mut self, value: &T)
     where
         T: ?Sized + Serialize,
     {
-        self.json_body = Some(serde_json::to_string(value).unwrap());
+        self.set_body("application/json", serde_json::to_vec(value).unwrap());
+    }
+
+    /// Lets pages from `origin` make requests with any method and a JSON body
+    pub fn allow_origin(&mut self, origin: &str) {
+        self.headers.insert(
+            String::from("Access-Control-Allow-Origin"),
+            String::from(origin),
+        );
+        self.headers.insert(
+            String::from("Access-Control-Allow-Methods"),
+            String::from("GET, POST, PATCH, PUT, DELETE, OPTIONS"),
+        );
+        self.headers.insert(
+            String::from("Access-Control-Allow-Headers"),
+            String::from("Content-Type, Authorization"),
+        );
+    }
+
+    /// Responds with `response_code` and a JSON body telling the client what went wrong
+    pub fn set_error(&mut self, response_code: HttpResponseCode, message: &str) {
+        self.response_code = response_code;
+        self.set_json(&ErrorBody { error: message });
+    }
+
+    pub fn set_body(&mut self, content_type: &str, body: Vec<u8>) {
+        self.headers
+            .insert(String::from("Content-Type"), String::from(content_type));
+        self.body = Some(body);
     }
 
     fn send_response(&mut self) {
@@ -141,21 +363,28 @@ impl HttpResponse {
 
         response.push_str(match self.response_code {
             HttpResponseCode::Ok => "200 OK",
+            HttpResponseCode::NoContent => "204 No Content",
             HttpResponseCode::NotFound => "404 Not Found",
             HttpResponseCode::InternalServerError => "500 Internal Server Error",
             HttpResponseCode::BadRequest => "400 Bad Request",
+            HttpResponseCode::Unauthorized => "401 Unauthorized",
+            HttpResponseCode::PayloadTooLarge => "413 Payload Too Large",
+            HttpResponseCode::ServiceUnavailable => "503 Service Unavailable",
         });
 
         response.push_str("\r\n");
 
-        // encode json body if we have one
-        if let Some(json_body) = &self.json_body {
-            self.headers.insert(
-                String::from("Content-Type"),
-                String::from("application/json"),
-            );
+        // a kept-alive client needs to know where the body ends, even when there isn't one
+        let content_length = match &self.body {
+            Some(body) => Some(body.len()),
+            None if self.streaming || matches!(self.response_code, HttpResponseCode::NoContent) => {
+                None
+            }
+            None => Some(0),
+        };
+        if let Some(content_length) = content_length {
             self.headers
-                .insert(String::from("Content-Length"), json_body.len().to_string());
+                .insert(String::from("Content-Length"), content_length.to_string());
         }
 
         for (key, value) in &self.headers {
@@ -167,13 +396,17 @@ impl HttpResponse {
 
         response.push_str("\r\n");
 
-        if let Some(json_body) = &self.json_body {
-            response.push_str(json_body);
+        let mut response = response.into_bytes();
+        if let Some(body) = &self.body {
+            response.extend_from_slice(body);
         }
 
-        self.stream.write_all(response.as_bytes()).unwrap();
+        self.stream.write_all(&response).unwrap();
 
         self.sent_response = true;
+        if let Some(keep_alive) = self.keep_alive.take() {
+            let _ = keep_alive.send(!self.streaming);
+        }
     }
 
     pub fn prep_sse(&mut self) -> Result<(), Box<dyn std::error::Error>> {
@@ -181,20 +414,125 @@ impl HttpResponse {
             String::from("Content-Type"),
             String::from("text/event-stream"),
         );
+        self.streaming = true;
+        self.send_response();
+        Ok(())
+    }
+
+    /// Sends the headers for a body of unknown length, which is then written with
+    /// `send_body_chunk` until the connection closes
+    pub fn prep_stream(&mut self, content_type: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.headers
+            .insert(String::from("Content-Type"), String::from(content_type));
+        self.headers
+            .insert(String::from("Connection"), String::from("close"));
+        self.streaming = true;
         self.send_response();
         Ok(())
     }
 
+    pub fn send_body_chunk(&mut self, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.stream.write_all(data)?;
+        Ok(())
+    }
+}
+
+/// An SSE connection, sent only the event types it asked for with `?types=a,b`, or all of them
+/// if it didn't ask. Events are written by a thread of its own, so a client that's slow to read
+/// can't hold up whoever sends them; one that falls `SSE_QUEUE_LEN` events behind is
+/// disconnected.
+pub struct EventSubscriber {
+    queue: SyncSender<String>,
+    stream: TcpStream,
+    types: Option<Vec<String>>,
+}
+
+impl EventSubscriber {
+    /// Takes over `res`, which `prep_sse` has already been called on
+    pub fn new(
+        res: HttpResponse,
+        query: &HashMap<String, String>,
+    ) -> std::io::Result<EventSubscriber> {
+        let stream = res.stream.try_clone()?;
+        let (queue, frames) = sync_channel::<String>(SSE_QUEUE_LEN);
+        thread::spawn(move || {
+            let mut res = res;
+            for frame in frames {
+                if let Err(err) = res.send_body_chunk(frame.as_bytes()) {
+                    info!("stopping sse writer: {}", err);
+                    break;
+                }
+            }
+        });
+
+        let types = query.get("types").map(|types| {
+            types
+                .split(',')
+                .map(|event| event.trim().to_string())
+                .filter(|event| !event.is_empty())
+                .collect()
+        });
+        Ok(EventSubscriber {
+            queue,
+            stream,
+            types,
+        })
+    }
+
+    pub fn wants(&self, event: &str) -> bool {
+        match &self.types {
+            Some(types) => types.iter().any(|t| t == event),
+            None => true,
+        }
+    }
+
+    /// Queues the event if the subscriber wants it. An error means the subscriber is gone.
     pub fn send_sse(
         &mut self,
         id: u32,
         event: &str,
         data: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let response = format!("id: {}\nevent: {}\ndata: {}\n\n", id, event, data);
-        self.stream.write_all(response.as_bytes())?;
-        Ok(())
+        if !self.wants(event) {
+            return Ok(());
+        }
+        self.queue(sse_event(id, event, data))
+    }
+
+    /// Queues an SSE comment, which clients ignore; used as a keep-alive so idle proxies don't
+    /// close the connection
+    pub fn send_sse_comment(&mut self, comment: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.queue(sse_comment(comment))
+    }
+
+    fn queue(&mut self, frame: String) -> Result<(), Box<dyn std::error::Error>> {
+        match self.queue.try_send(frame) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                // the writer is stuck on the client; this gets it unstuck so it can finish
+                let _ = self.stream.shutdown(Shutdown::Both);
+                Err("subscriber isn't keeping up".into())
+            }
+            Err(TrySendError::Disconnected(_)) => Err("subscriber disconnected".into()),
+        }
+    }
+}
+
+/// A line break in `data` would end the field early, so each line goes in a `data:` field of
+/// its own, which clients join back up with `\n`
+fn sse_event(id: u32, event: &str, data: &str) -> String {
+    let mut frame = format!("id: {}\nevent: {}\n", id, event);
+    for line in data.replace("\r\n", "\n").split(['\r', '\n']) {
+        frame.push_str("data: ");
+        frame.push_str(line);
+        frame.push('\n');
     }
+    frame.push('\n');
+    frame
+}
+
+fn sse_comment(comment: &str) -> String {
+    format!(": {}\n\n", comment)
 }
 
 impl Drop for HttpResponse {
@@ -203,8 +541,429 @@ impl Drop for HttpResponse {
     }
 }
 
-pub fn handle_connection(mut stream: TcpStream) -> (Result<HttpRequest, ()>, HttpResponse) {
-    let req = HttpRequest::try_from(stream.borrow_mut());
-    let res: HttpResponse = HttpResponse::new(stream);
-    (req, res)
+/// Accepts connections to `listener` and reads each one's requests on its own thread, so an idle
+/// kept-alive client doesn't hold up anyone else. Requests come out of the returned receiver in
+/// the order they arrive.
+pub fn serve(
+    listener: TcpListener,
+    max_body_bytes: usize,
+) -> Receiver<(Result<HttpRequest, RequestError>, HttpResponse)> {
+    let (requests, receiver) = channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let requests = requests.clone();
+                    thread::spawn(move || keep_alive(stream, max_body_bytes, requests));
+                }
+                Err(err) => {
+                    error!("error accepting connection: {}", err);
+                }
+            }
+        }
+    });
+    receiver
+}
+
+/// Reads requests from `stream` until the client closes it or asks to, goes quiet, or a response
+/// takes over the connection. The next request isn't read until the last one's response is sent,
+/// and the same reader is used throughout so pipelined requests it has buffered aren't lost.
+fn keep_alive(
+    stream: TcpStream,
+    max_body_bytes: usize,
+    requests: Sender<(Result<HttpRequest, RequestError>, HttpResponse)>,
+) {
+    let mut reader = match stream
+        .set_read_timeout(Some(READ_TIMEOUT))
+        .and_then(|_| stream.try_clone())
+    {
+        Ok(reader) => BufReader::new(reader),
+        Err(err) => {
+            error!("error setting up connection: {}", err);
+            return;
+        }
+    };
+
+    loop {
+        // the client closed the connection or went quiet
+        match reader.fill_buf() {
+            Ok(buf) if !buf.is_empty() => {}
+            _ => return,
+        }
+
+        let res_stream = match stream.try_clone() {
+            Ok(res_stream) => res_stream,
+            Err(err) => {
+                error!("error setting up response: {}", err);
+                return;
+            }
+        };
+        let req = HttpRequest::read_from(&mut reader, max_body_bytes);
+        // where a bad request ends can't be trusted, so it's the last one
+        let keep_alive = matches!(&req, Ok(req) if req.wants_keep_alive());
+        let (sent, reusable) = channel();
+        let res = if keep_alive {
+            HttpResponse::with_keep_alive(res_stream, sent)
+        } else {
+            let mut res = HttpResponse::new(res_stream);
+            res.headers
+                .insert(String::from("Connection"), String::from("close"));
+            res
+        };
+        if requests.send((req, res)).is_err() || !keep_alive {
+            return;
+        }
+        // once the response is sent it says whether the connection can carry another request,
+        // which it can't if a stream has taken it over
+        if reusable.recv() != Ok(true) {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::{Duration, Instant};
+
+    use super::{
+        EventSubscriber, HttpMethod, HttpRequest, HttpResponse, HttpResponseCode, RequestError,
+    };
+
+    fn parse(request: &str) -> Result<HttpRequest, RequestError> {
+        HttpRequest::read_from(&mut std::io::Cursor::new(request.as_bytes()), 1024)
+    }
+
+    #[test]
+    fn parses_requests() {
+        let req = parse(
+            "POST /skip?index=2 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\ntrue",
+        )
+        .unwrap();
+        assert!(matches!(req.method, HttpMethod::Post));
+        assert_eq!(req.path, "/skip");
+        assert_eq!(req.query["index"], "2");
+        assert_eq!(req.version, "HTTP/1.1");
+        assert_eq!(req.headers["host"], "localhost");
+        assert_eq!(req.body, "true");
+    }
+
+    #[test]
+    fn parses_query_strings() {
+        let req = parse(
+            "GET /library?path=%2FMusic%2FAlbum+One%2F01%20%C3%A9t%C3%A9.flac&recursive&count=10&bad=%zz HTTP/1.1\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(req.path, "/library");
+        assert_eq!(req.query["path"], "/Music/Album One/01 été.flac");
+        assert_eq!(req.query["recursive"], "");
+        assert_eq!(req.query["count"], "10");
+        assert_eq!(req.query["bad"], "%zz");
+        assert_eq!(req.query.len(), 4);
+    }
+
+    #[test]
+    fn decodes_request_paths() {
+        let dir = std::env::temp_dir().join(format!("pjp-test-{}-decoded", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("My Song été+1.mp3"), b"").unwrap();
+
+        let req = parse("GET /My%20Song%20%C3%A9t%C3%A9+1.mp3?a=b HTTP/1.1\r\n\r\n").unwrap();
+        let on_disk: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(on_disk, [std::ffi::OsString::from(&req.path[1..])]);
+        assert!(dir.join(&req.path[1..]).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_oversized_bodies() {
+        let request = "POST /add HTTP/1.1\r\nContent-Length: 4000000000\r\n\r\n[]";
+        assert_eq!(parse(request).err(), Some(RequestError::BodyTooLarge));
+
+        let body = "x".repeat(1024);
+        let request = format!("POST /add HTTP/1.1\r\nContent-Length: 1024\r\n\r\n{}", body);
+        assert_eq!(parse(&request).unwrap().body, body);
+    }
+
+    #[test]
+    fn reads_chunked_bodies() {
+        let req = parse(
+            "POST /add HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             7\r\n[\"a.mp3\r\nB;ext=1\r\n\", \"b.mp3\"]\r\n0\r\n\r\nGET /status",
+        )
+        .unwrap();
+        assert_eq!(req.body, "[\"a.mp3\", \"b.mp3\"]");
+
+        // the length is only a guess when the body is chunked
+        let req = parse(
+            "POST /add HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: gzip, Chunked\r\n\r\n\
+             2\r\n[]\r\n0\r\nExpires: never\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(req.body, "[]");
+
+        for body in [
+            "zz\r\n[]\r\n0\r\n\r\n",
+            "3\r\n[]\r\n0\r\n\r\n",
+            "2\r\n[]0\r\n\r\n",
+            "2\r\n[]\r\n",
+            "-2\r\n",
+        ] {
+            let request = format!(
+                "POST /add HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+                body
+            );
+            assert_eq!(
+                parse(&request).err(),
+                Some(RequestError::Malformed),
+                "{:?}",
+                body
+            );
+        }
+        let request = "POST /add HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       400\r\n";
+        assert!(parse(request).is_err());
+        let request = "POST /add HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       200\r\n";
+        assert_eq!(
+            parse(&format!("{}{}\r\n201\r\n", request, "x".repeat(512))).err(),
+            Some(RequestError::BodyTooLarge)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        for request in [
+            "",
+            "GET /status\r\n\r\n",
+            "FETCH /status HTTP/1.1\r\n\r\n",
+            "GET /status HTTP/1.1\r\nHost\r\n\r\n",
+            "POST /volume HTTP/1.1\r\nContent-Length: four\r\n\r\n0.5",
+            "POST /volume HTTP/1.1\r\nContent-Length: 10\r\n\r\n0.5",
+        ] {
+            assert!(parse(request).is_err(), "{:?}", request);
+        }
+        let mut invalid_body = b"POST /add HTTP/1.1\r\nContent-Length: 2\r\n\r\n".to_vec();
+        invalid_body.extend_from_slice(&[0xff, 0xfe]);
+        assert!(HttpRequest::read_from(&mut std::io::Cursor::new(invalid_body), 1024).is_err());
+    }
+
+    #[test]
+    fn serves_pipelined_requests_on_one_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let requests = super::serve(listener, 1024);
+
+        client
+            .write_all(
+                b"POST /volume HTTP/1.1\r\nContent-Length: 3\r\n\r\n0.5\
+                  GET /status HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        let (req, mut res) = requests.recv().unwrap();
+        let req = req.unwrap();
+        assert_eq!(req.path, "/volume");
+        assert_eq!(req.body, "0.5");
+        res.set_json(&1);
+        drop(res);
+
+        let (req, mut res) = requests.recv().unwrap();
+        assert_eq!(req.unwrap().path, "/status");
+        res.set_json(&2);
+        drop(res);
+
+        // the second request asked to close, so this ends
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        let responses: Vec<&str> = received.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].contains("Connection: keep-alive\r\n"));
+        assert!(responses[0].ends_with("\r\n\r\n1"));
+        assert!(responses[1].contains("Connection: close\r\n"));
+        assert!(responses[1].ends_with("\r\n\r\n2"));
+    }
+
+    #[test]
+    fn answers_preflight_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let requests = super::serve(listener, 1024);
+
+        client
+            .write_all(
+                b"OPTIONS /add HTTP/1.1\r\nOrigin: http://localhost:3000\r\n\
+                  Access-Control-Request-Method: POST\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let (req, mut res) = requests.recv().unwrap();
+        let req = req.unwrap();
+        assert!(matches!(req.method, HttpMethod::Options));
+        assert_eq!(req.path, "/add");
+        res.allow_origin("http://localhost:3000");
+        res.response_code = HttpResponseCode::NoContent;
+        drop(res);
+
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        assert!(received.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(received.contains("Access-Control-Allow-Origin: http://localhost:3000\r\n"));
+        assert!(received
+            .contains("Access-Control-Allow-Methods: GET, POST, PATCH, PUT, DELETE, OPTIONS\r\n"));
+        assert!(received.contains("Access-Control-Allow-Headers: Content-Type, Authorization\r\n"));
+        assert!(received.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn checks_bearer_tokens() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let requests = super::serve(listener, 1024);
+
+        client
+            .write_all(
+                b"POST /clear HTTP/1.1\r\n\r\n\
+                  POST /clear HTTP/1.1\r\nAuthorization: Bearer s3cret!\r\n\r\n\
+                  POST /clear HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\
+                  Connection: close\r\n\r\n",
+            )
+            .unwrap();
+        for _ in 0..3 {
+            let (req, mut res) = requests.recv().unwrap();
+            if req.unwrap().has_bearer_token("s3cret") {
+                res.response_code = HttpResponseCode::Ok;
+            } else {
+                res.set_error(HttpResponseCode::Unauthorized, "missing or wrong token");
+            }
+        }
+
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        let statuses: Vec<&str> = received
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|response| &response[..3])
+            .collect();
+        assert_eq!(statuses, ["401", "401", "200"]);
+        assert!(
+            !parse("GET / HTTP/1.1\r\nAuthorization: Basic s3cret\r\n\r\n")
+                .unwrap()
+                .has_bearer_token("s3cret")
+        );
+    }
+
+    #[test]
+    fn sends_error_messages_as_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        HttpResponse::new(stream).set_error(
+            HttpResponseCode::BadRequest,
+            "expected value at line 1 column 1",
+        );
+
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        assert!(received.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(received.contains("Content-Type: application/json\r\n"));
+        assert!(received.contains("Content-Length: 45\r\n"));
+        assert!(received.ends_with("\r\n\r\n{\"error\":\"expected value at line 1 column 1\"}"));
+    }
+
+    #[test]
+    fn sends_sse_events_and_comments() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let req = parse("GET /events HTTP/1.1\r\n\r\n").unwrap();
+        let mut res = HttpResponse::new(stream);
+        res.prep_sse().unwrap();
+        {
+            let mut sub = EventSubscriber::new(res, &req.query).unwrap();
+            sub.send_sse(1, "paused", "").unwrap();
+            sub.send_sse_comment("keep-alive").unwrap();
+        }
+
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(received.contains("Content-Type: text/event-stream\r\n"));
+        assert!(received.ends_with("\r\n\r\nid: 1\nevent: paused\ndata: \n\n: keep-alive\n\n"));
+    }
+
+    #[test]
+    fn splits_multi_line_data() {
+        assert_eq!(
+            super::sse_event(2, "track", "{\"title\":\"Side A\nSide B\"}"),
+            "id: 2\nevent: track\ndata: {\"title\":\"Side A\ndata: Side B\"}\n\n"
+        );
+        assert_eq!(
+            super::sse_event(3, "track", "one\r\ntwo\rthree\n"),
+            "id: 3\nevent: track\ndata: one\ndata: two\ndata: three\ndata: \n\n"
+        );
+    }
+
+    #[test]
+    fn sends_subscribers_only_the_events_they_asked_for() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let req = parse("GET /events?types=now-playing,track-changed HTTP/1.1\r\n\r\n").unwrap();
+        let mut res = HttpResponse::new(stream);
+        res.prep_sse().unwrap();
+        {
+            let mut sub = EventSubscriber::new(res, &req.query).unwrap();
+            assert!(sub.wants("now-playing"));
+            assert!(!sub.wants("paused"));
+            sub.send_sse(1, "paused", "").unwrap();
+            sub.send_sse(2, "now-playing", "{}").unwrap();
+            sub.send_sse(3, "position", "{}").unwrap();
+        }
+
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        assert!(received.ends_with("\r\n\r\nid: 2\nevent: now-playing\ndata: {}\n\n"));
+
+        let req = parse("GET /events HTTP/1.1\r\n\r\n").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let sub = EventSubscriber::new(HttpResponse::new(stream), &req.query).unwrap();
+        assert!(sub.wants("paused") && sub.wants("position"));
+    }
+
+    #[test]
+    fn disconnects_subscribers_that_fall_behind() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut res = HttpResponse::new(stream);
+        res.prep_sse().unwrap();
+        let mut sub = EventSubscriber::new(res, &HashMap::new()).unwrap();
+
+        // the client isn't reading, so once the socket's buffers fill up the queue does too
+        let data = "x".repeat(64 * 1024);
+        let start = Instant::now();
+        let sent = (0..10_000)
+            .take_while(|id| sub.send_sse(*id, "now-playing", &data).is_ok())
+            .count();
+        assert!(sent < 10_000);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(sub.send_sse_comment("keep-alive").is_err());
+
+        // what was written before the disconnect is still there, then the connection ends
+        // without the events that were still queued
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        assert!(received.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(received.len() < sent * data.len());
+    }
 }